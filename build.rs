@@ -0,0 +1,38 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Embed the commit the proxy is built from and when, reported by `--version` and `/status`
+fn main() {
+    let hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| String::from("unknown"));
+    // uncommitted changes make the hash alone misleading
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .map_or(false, |status| !status.is_empty());
+    let hash = if dirty { hash + "-dirty" } else { hash };
+    println!("cargo:rustc-env=LSP_GIT_HASH={}", hash);
+
+    // reproducible builds pin the build date, see https://reproducible-builds.org/specs/source-date-epoch/
+    let build_epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    println!("cargo:rustc-env=LSP_BUILD_EPOCH={}", build_epoch);
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in [".git/HEAD", ".git/index"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
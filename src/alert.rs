@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often intervals are checked for having ended, at most
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Coalesces repeated identical alerts, e.g. the spawn failures caused by a broken jar
+///
+/// Only the first alert with a key is reported right away, the repetitions within the following interval
+/// are counted instead and summarized with their count once the interval is over.
+pub struct Alerts<K> {
+    interval: Duration,
+    windows: Mutex<HashMap<K, Window>>,
+}
+
+/// The interval following a reported alert
+struct Window {
+    started: Instant,
+    repeated: u64,
+}
+
+impl<K> Alerts<K>
+where
+    K: Clone + Eq + Hash + Send + 'static,
+{
+    /// Coalesce alerts within `interval`, passing the key and count of the repetitions to `summarize`
+    /// at the end of every interval in which an alert was repeated
+    ///
+    /// Alerts are not coalesced at all with an interval of zero.
+    pub fn start<F>(interval: Duration, summarize: F) -> Arc<Self>
+    where
+        F: Fn(&K, u64) + Send + 'static,
+    {
+        let alerts = Arc::new(Self {
+            interval,
+            windows: Mutex::default(),
+        });
+        if interval == Duration::default() {
+            return alerts;
+        }
+        let checked = alerts.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(checked.interval.min(MAX_CHECK_INTERVAL));
+            for (key, repeated) in checked.ended() {
+                summarize(&key, repeated);
+            }
+        });
+        alerts
+    }
+
+    /// Whether the alert with `key` is to be reported now, otherwise it is counted for the summary
+    pub fn report(&self, key: &K) -> bool {
+        if self.interval == Duration::default() {
+            return true;
+        }
+        let mut windows = self.lock();
+        match windows.get_mut(key) {
+            Some(window) => {
+                window.repeated += 1;
+                false
+            }
+            None => {
+                windows.insert(
+                    key.clone(),
+                    Window {
+                        started: Instant::now(),
+                        repeated: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Remove the windows whose interval is over, returning the keys of those with repetitions and their count
+    fn ended(&self) -> Vec<(K, u64)> {
+        let mut ended = Vec::new();
+        self.lock().retain(|key, window| {
+            if window.started.elapsed() < self.interval {
+                return true;
+            }
+            if window.repeated > 0 {
+                ended.push((key.clone(), window.repeated));
+            }
+            false
+        });
+        ended
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, Window>> {
+        // a poisoned lock only means another thread panicked, the windows are still valid
+        self.windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
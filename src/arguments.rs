@@ -0,0 +1,591 @@
+use crate::error::ParseBackpressureError;
+use crate::error::ParseClientVersionError;
+use crate::error::ParseCpuListError;
+use crate::error::ParseEnvVarError;
+use crate::error::ParseNetworkError;
+use crate::error::ParsePortListError;
+use crate::error::ParsePortRangeError::{self, MissingEndSeperator, StartLargerThanEnd};
+use crate::error::ParseSpawnDirectionError;
+use crate::error::ParseSubnetQuotaError;
+use crate::error::ShortAdminTokenError;
+use rand::Rng;
+use std::fmt::{Debug, Display, Formatter};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub struct PortRange {
+    pub range: RangeInclusive<u16>,
+}
+
+impl FromStr for PortRange {
+    type Err = ParsePortRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or(MissingEndSeperator)?;
+        let start = start.trim().parse()?;
+        let end = end.trim().parse()?;
+
+        if start > end {
+            Err(StartLargerThanEnd)
+        } else {
+            Ok(PortRange { range: start..=end })
+        }
+    }
+}
+
+/// A set of ports, given as a comma separated list of ports and port ranges,
+/// where a port or range prefixed with `!` is excluded, e.g. `6000-6100,!6050,7000-7010`
+#[derive(PartialEq)]
+pub struct PortList {
+    /// The ports in ascending order
+    pub ports: Vec<u16>,
+}
+
+impl PortList {
+    /// Pick one of the ports at random
+    pub fn choose(&self, rng: &mut impl Rng) -> u16 {
+        self.ports[rng.gen_range(0..self.ports.len())]
+    }
+}
+
+impl FromStr for PortList {
+    type Err = ParsePortListError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ports = Vec::new();
+        let mut excluded = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (part, target) = match part.strip_prefix('!') {
+                Some(part) => (part.trim(), &mut excluded),
+                None => (part, &mut ports),
+            };
+            if part.contains('-') {
+                target.extend(part.parse::<PortRange>()?.range);
+            } else {
+                target.push(part.parse()?);
+            }
+        }
+        if ports.is_empty() {
+            return Err(ParsePortListError::Empty);
+        }
+
+        ports.sort_unstable();
+        ports.dedup();
+        excluded.sort_unstable();
+        ports.retain(|port| excluded.binary_search(port).is_err());
+
+        if ports.is_empty() {
+            Err(ParsePortListError::AllExcluded)
+        } else {
+            Ok(PortList { ports })
+        }
+    }
+}
+
+/// The ports as a list of ports and ranges, e.g. `6000-6049,6051-6100`, rather than each port on its own
+impl Debug for PortList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for &port in &self.ports {
+            match ranges.last_mut() {
+                Some((_, end)) if u32::from(*end) + 1 == u32::from(port) => *end = port,
+                _ => ranges.push((port, port)),
+            }
+        }
+        for (index, (start, end)) in ranges.into_iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`, or a single address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a prefix of zero leaves no bits to compare, and shifting by the full width would overflow
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| ParseNetworkError::InvalidAddress(address.to_string()))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| ParseNetworkError::InvalidPrefixLength(prefix_len.to_string()))?,
+            None => max_len,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)?;
+        Ok(())
+    }
+}
+
+/// A comma separated list of networks, e.g. `10.0.0.0/8,192.168.1.17,fd00::/8`
+#[derive(Debug, PartialEq)]
+pub struct NetworkList {
+    pub networks: Vec<Network>,
+}
+
+impl NetworkList {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+impl FromStr for NetworkList {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let networks = s
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(NetworkList { networks })
+    }
+}
+
+/// How the proxy and a spawned language server connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnDirection {
+    /// The server listens on its port and the proxy connects to it
+    Listen,
+    /// The proxy listens on the port and the server connects to it
+    Connect,
+}
+
+impl FromStr for SpawnDirection {
+    type Err = ParseSpawnDirectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "listen" => Ok(Self::Listen),
+            "connect" => Ok(Self::Connect),
+            direction => Err(ParseSpawnDirectionError {
+                direction: direction.to_string(),
+            }),
+        }
+    }
+}
+
+/// What to do when the receiving side of a session can't keep up with the sending side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Stop reading from the sender until the receiver caught up
+    Block,
+    /// End the session
+    Drop,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self::Block
+    }
+}
+
+impl FromStr for Backpressure {
+    type Err = ParseBackpressureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            policy => Err(ParseBackpressureError {
+                policy: policy.to_string(),
+            }),
+        }
+    }
+}
+
+/// A comma separated list of CPU indices and ranges, e.g. `0-3,6`
+#[derive(Debug, PartialEq)]
+pub struct CpuList {
+    pub cpus: Vec<usize>,
+}
+
+impl FromStr for CpuList {
+    type Err = ParseCpuListError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let invalid = || ParseCpuListError::InvalidCpu(part.to_string());
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let start: usize = start.trim().parse().map_err(|_| invalid())?;
+                    let end: usize = end.trim().parse().map_err(|_| invalid())?;
+                    if start > end {
+                        return Err(invalid());
+                    }
+                    cpus.extend(start..=end);
+                }
+                None => cpus.push(part.parse().map_err(|_| invalid())?),
+            }
+        }
+        if cpus.is_empty() {
+            return Err(ParseCpuListError::Empty);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuList { cpus })
+    }
+}
+
+/// The oldest version of a client to serve, by the name it reports in the `clientInfo` of its `initialize` request,
+/// e.g. `KIELER VS Code=0.9.0`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientVersion {
+    pub name: String,
+    pub version: String,
+}
+
+impl ClientVersion {
+    /// Whether `version` of the client is older than this one
+    pub fn is_newer_than(&self, version: &str) -> bool {
+        version_numbers(&self.version) > version_numbers(version)
+    }
+}
+
+impl FromStr for ClientVersion {
+    type Err = ParseClientVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // client names may contain anything, versions don't contain a '='
+        let (name, version) = s
+            .rsplit_once('=')
+            .ok_or(ParseClientVersionError::MissingSeparator)?;
+        if name.trim().is_empty() {
+            return Err(ParseClientVersionError::EmptyName);
+        }
+        if version_numbers(version).is_empty() {
+            return Err(ParseClientVersionError::InvalidVersion(version.to_string()));
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            version: version.trim().to_string(),
+        })
+    }
+}
+
+/// The numbers of a version, e.g. `[1, 2, 3]` for `1.2.3-beta`, which compare like the versions
+pub fn version_numbers(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|number| number.parse().ok())
+        .collect()
+}
+
+/// The most sessions the clients of a network may have at once, e.g. `10.0.1.0/24=32`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubnetQuota {
+    pub network: Network,
+    pub limit: usize,
+}
+
+impl FromStr for SubnetQuota {
+    type Err = ParseSubnetQuotaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, limit) = s
+            .split_once('=')
+            .ok_or(ParseSubnetQuotaError::MissingSeparator)?;
+        let network = network
+            .parse()
+            .map_err(ParseSubnetQuotaError::InvalidNetwork)?;
+        let limit = limit
+            .trim()
+            .parse()
+            .map_err(|_| ParseSubnetQuotaError::InvalidLimit(limit.to_string()))?;
+        Ok(Self { network, limit })
+    }
+}
+
+impl Display for SubnetQuota {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.network, self.limit)?;
+        Ok(())
+    }
+}
+
+/// An environment variable to set for the language servers, e.g. `KIELER_WORKSPACE={workspace}`
+#[derive(Clone, PartialEq)]
+pub struct EnvVar {
+    pub name: String,
+    /// The value, with placeholders of the launch
+    pub value: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = ParseEnvVarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // names don't contain a '=', values may
+        let (name, value) = s
+            .split_once('=')
+            .ok_or(ParseEnvVarError::MissingSeparator)?;
+        if name.trim().is_empty() {
+            return Err(ParseEnvVarError::EmptyName);
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Redacts the value, which may be a secret like a license key
+impl Debug for EnvVar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=<redacted>", self.name)?;
+        Ok(())
+    }
+}
+
+/// How long an admin token has to be at least, so that it can't be guessed
+const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+/// The secret the http endpoints changing the proxy require, sent as `Authorization: Bearer <token>`
+#[derive(Clone, PartialEq)]
+pub struct AdminToken(String);
+
+impl FromStr for AdminToken {
+    type Err = ShortAdminTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let token = s.trim();
+        if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+            return Err(ShortAdminTokenError {
+                length: token.len(),
+                min_length: MIN_ADMIN_TOKEN_LENGTH,
+            });
+        }
+        Ok(Self(token.to_string()))
+    }
+}
+
+impl AdminToken {
+    /// Whether a request with the `authorization` header presents this token
+    pub fn authorizes(&self, authorization: Option<&str>) -> bool {
+        let presented = authorization
+            .and_then(|value| value.trim().strip_prefix("Bearer "))
+            .map_or("", str::trim);
+        // compares all bytes, so that the time taken doesn't tell how much of the token was right
+        presented.len() == self.0.len()
+            && presented
+                .bytes()
+                .zip(self.0.bytes())
+                .fold(0, |difference, (presented, token)| {
+                    difference | (presented ^ token)
+                })
+                == 0
+    }
+}
+
+impl Debug for AdminToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(s: &str) -> Vec<u16> {
+        s.parse::<PortList>().unwrap().ports
+    }
+
+    #[test]
+    fn parses_a_range() {
+        assert_eq!(
+            "5008-65535".parse::<PortRange>().unwrap(),
+            PortRange {
+                range: 5008..=65535
+            }
+        );
+        assert_eq!(ports("5008-5010"), [5008, 5009, 5010]);
+    }
+
+    #[test]
+    fn parses_lists_of_ports_and_ranges() {
+        assert_eq!(ports("5007"), [5007]);
+        assert_eq!(ports("5009, 5007,5008-5009"), [5007, 5008, 5009]);
+        assert_eq!(ports("7000-7001,,6000"), [6000, 7000, 7001]);
+    }
+
+    #[test]
+    fn excludes_ports_and_ranges() {
+        assert_eq!(ports("6000-6004,!6002"), [6000, 6001, 6003, 6004]);
+        assert_eq!(ports("!6001-6003,6000-6004"), [6000, 6004]);
+        assert_eq!(ports("6000-6002,! 6001,7000,!8000"), [6000, 6002, 7000]);
+    }
+
+    #[test]
+    fn rejects_invalid_lists() {
+        assert!(matches!(
+            "".parse::<PortList>(),
+            Err(ParsePortListError::Empty)
+        ));
+        assert!(matches!(
+            "!6000".parse::<PortList>(),
+            Err(ParsePortListError::Empty)
+        ));
+        assert!(matches!(
+            "6000-6001,!6000-6001".parse::<PortList>(),
+            Err(ParsePortListError::AllExcluded)
+        ));
+        assert!(matches!(
+            "6000,70000".parse::<PortList>(),
+            Err(ParsePortListError::ParsePort(_))
+        ));
+        assert!(matches!(
+            "6001-6000".parse::<PortList>(),
+            Err(ParsePortListError::ParseRange(StartLargerThanEnd))
+        ));
+        assert!(matches!(
+            "!6000-x".parse::<PortList>(),
+            Err(ParsePortListError::ParseRange(
+                ParsePortRangeError::ParseInt(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn chooses_an_allowed_port() {
+        let list = "6000-6002,!6001".parse::<PortList>().unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            assert!(matches!(list.choose(&mut rng), 6000 | 6002));
+        }
+    }
+
+    fn networks(s: &str) -> NetworkList {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_networks() {
+        let list = networks("10.0.0.0/8, 192.168.1.17,fd00::/8");
+        assert!(list.contains("10.1.2.3".parse().unwrap()));
+        assert!(list.contains("192.168.1.17".parse().unwrap()));
+        assert!(!list.contains("192.168.1.18".parse().unwrap()));
+        assert!(list.contains("fd12::1".parse().unwrap()));
+        assert!(!list.contains("fe80::1".parse().unwrap()));
+        assert!(networks("0.0.0.0/0").contains("8.8.8.8".parse().unwrap()));
+        assert!(!networks("0.0.0.0/0").contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("fd00::/129".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn parses_subnet_quotas() {
+        let quota = "10.0.1.0/24 = 32".parse::<SubnetQuota>().unwrap();
+        assert!(quota.network.contains("10.0.1.200".parse().unwrap()));
+        assert_eq!(quota.limit, 32);
+        assert_eq!(quota.to_string(), "10.0.1.0/24=32");
+        assert!("10.0.1.0/24".parse::<SubnetQuota>().is_err());
+        assert!("10.0.1.0/33=4".parse::<SubnetQuota>().is_err());
+        assert!("10.0.1.0/24=many".parse::<SubnetQuota>().is_err());
+    }
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!("2-4,0,3".parse::<CpuList>().unwrap().cpus, [0, 2, 3, 4]);
+        assert!("".parse::<CpuList>().is_err());
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("one".parse::<CpuList>().is_err());
+    }
+
+    #[test]
+    fn compares_client_versions() {
+        let minimum = "KIELER VS Code=0.10.2".parse::<ClientVersion>().unwrap();
+        assert_eq!(minimum.name, "KIELER VS Code");
+        assert!(minimum.is_newer_than("0.9.7"));
+        assert!(minimum.is_newer_than("0.10.1-beta"));
+        assert!(!minimum.is_newer_than("0.10.2"));
+        assert!(!minimum.is_newer_than("1.0"));
+        assert!("KIELER".parse::<ClientVersion>().is_err());
+        assert!("=1.0".parse::<ClientVersion>().is_err());
+        assert!("KIELER=latest".parse::<ClientVersion>().is_err());
+    }
+
+    #[test]
+    fn parses_env_vars() {
+        let var = "JAVA_OPTS=-Dworkspace={workspace} -Da=b"
+            .parse::<EnvVar>()
+            .unwrap();
+        assert_eq!(var.name, "JAVA_OPTS");
+        assert_eq!(var.value, "-Dworkspace={workspace} -Da=b");
+        assert_eq!("EMPTY=".parse::<EnvVar>().unwrap().value, "");
+        assert!("JAVA_OPTS".parse::<EnvVar>().is_err());
+        assert!("=value".parse::<EnvVar>().is_err());
+    }
+
+    #[test]
+    fn admin_token_authorizes_only_its_bearer() {
+        assert!("too-short".parse::<AdminToken>().is_err());
+        let token: AdminToken = " 0123456789abcdef ".parse().unwrap();
+        assert!(token.authorizes(Some("Bearer 0123456789abcdef")));
+        assert!(!token.authorizes(Some("Bearer 0123456789abcdeF")));
+        assert!(!token.authorizes(Some("Bearer 0123456789abcde")));
+        assert!(!token.authorizes(Some("Basic 0123456789abcdef")));
+        assert!(!token.authorizes(Some("0123456789abcdef")));
+        assert!(!token.authorizes(None));
+        assert_eq!(format!("{:?}", token), "<redacted>");
+    }
+}
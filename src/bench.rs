@@ -0,0 +1,270 @@
+use crate::json::{self, Value};
+use crate::lsp::{Message, MessageReader};
+use log::{debug, warn};
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// How long to wait for the proxy to answer, including spawning a server for `initialize`
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+/// The size of the synthetic document edited by each session
+const DOCUMENT_SIZE: usize = 1024;
+
+/// Open concurrent editor sessions against a running proxy and report how it copes,
+/// e.g. to size hosts before a semester starts
+#[derive(Debug, StructOpt)]
+pub struct BenchArguments {
+    /// The address of the proxy
+    #[structopt(long = "address", default_value = "localhost:5007")]
+    address: String,
+
+    /// The number of concurrent sessions
+    #[structopt(long = "clients", default_value = "10")]
+    clients: usize,
+
+    /// How long each session sends synthetic traffic after its initialize handshake, in seconds
+    #[structopt(long = "duration", default_value = "30")]
+    duration: u64,
+}
+
+/// What a single session measured
+#[derive(Default)]
+struct SessionResult {
+    connect: Duration,
+    /// From connecting until the `initialize` response, which includes spawning the server
+    spawn: Duration,
+    requests: Vec<Duration>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// Run the benchmark and print its report
+pub fn run(args: &BenchArguments) -> Result<(), String> {
+    println!(
+        "Opening {} sessions to {} for {}s of traffic each",
+        args.clients, args.address, args.duration
+    );
+    let duration = Duration::from_secs(args.duration);
+    let sessions: Vec<_> = (0..args.clients)
+        .map(|index| {
+            let address = args.address.clone();
+            std::thread::spawn(move || run_session(&address, index, duration))
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for (index, session) in sessions.into_iter().enumerate() {
+        match session.join() {
+            Ok(Ok(result)) => results.push(result),
+            Ok(Err(err)) => {
+                warn!("Session {} failed: {}", index, err);
+                failed += 1;
+            }
+            Err(_) => {
+                warn!("Session {} panicked", index);
+                failed += 1;
+            }
+        }
+    }
+    if results.is_empty() {
+        return Err(format!("All {} sessions failed", failed));
+    }
+
+    let requests: Vec<Duration> = results
+        .iter()
+        .flat_map(|result| result.requests.iter().copied())
+        .collect();
+    let bytes: u64 = results
+        .iter()
+        .map(|result| result.bytes_sent + result.bytes_received)
+        .sum();
+    let seconds = duration.as_secs_f64().max(f64::EPSILON);
+    let requests_per_second = requests.len() as f64 / seconds;
+    println!("{} sessions completed, {} failed", results.len(), failed);
+    println!(
+        "connect latency: {}",
+        Latencies::new(results.iter().map(|result| result.connect).collect())
+    );
+    println!(
+        "spawn latency:   {}",
+        Latencies::new(results.iter().map(|result| result.spawn).collect())
+    );
+    println!("request latency: {}", Latencies::new(requests));
+    println!(
+        "throughput:      {:.1} requests/s, {:.1} KiB/s",
+        requests_per_second,
+        bytes as f64 / 1024.0 / seconds
+    );
+    Ok(())
+}
+
+fn run_session(address: &str, index: usize, duration: Duration) -> std::io::Result<SessionResult> {
+    let mut result = SessionResult::default();
+    let started = Instant::now();
+    let con = TcpStream::connect(address)?;
+    result.connect = started.elapsed();
+    con.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    // a notification followed by a request would otherwise wait for the acknowledgement of the former
+    con.set_nodelay(true)?;
+    let mut session = Session {
+        con: con.try_clone()?,
+        reader: MessageReader::new(con),
+        next_id: 1,
+        result,
+    };
+
+    let uri = format!("file:///bench/session-{}.txt", index);
+    session.request(
+        "initialize",
+        "{\"processId\":null,\"rootUri\":\"file:///bench\",\"capabilities\":{}}",
+    )?;
+    session.result.spawn = started.elapsed();
+    debug!(
+        "Session {} initialized after {:.1?}",
+        index, session.result.spawn
+    );
+    session.notify("initialized", "{}")?;
+    session.notify(
+        "textDocument/didOpen",
+        &format!(
+            "{{\"textDocument\":{{\"uri\":{},\"languageId\":\"plaintext\",\"version\":1,\"text\":{}}}}}",
+            json::quote(&uri),
+            json::quote(&document(1))
+        ),
+    )?;
+
+    let traffic_started = Instant::now();
+    let mut version = 1;
+    while traffic_started.elapsed() < duration {
+        version += 1;
+        session.notify(
+            "textDocument/didChange",
+            &format!(
+                "{{\"textDocument\":{{\"uri\":{},\"version\":{}}},\"contentChanges\":[{{\"text\":{}}}]}}",
+                json::quote(&uri),
+                version,
+                json::quote(&document(version))
+            ),
+        )?;
+        let sent = Instant::now();
+        session.request(
+            "textDocument/hover",
+            &format!(
+                "{{\"textDocument\":{{\"uri\":{}}},\"position\":{{\"line\":0,\"character\":0}}}}",
+                json::quote(&uri)
+            ),
+        )?;
+        session.result.requests.push(sent.elapsed());
+    }
+
+    session.request("shutdown", "null")?;
+    session.notify("exit", "{}")?;
+    Ok(session.result)
+}
+
+/// The text of the synthetic document in `version`
+fn document(version: u64) -> String {
+    let line = format!("version {} of a synthetic document\n", version);
+    line.repeat(DOCUMENT_SIZE / line.len() + 1)
+}
+
+struct Session {
+    con: TcpStream,
+    reader: MessageReader<TcpStream>,
+    next_id: u64,
+    result: SessionResult,
+}
+
+impl Session {
+    fn notify(&mut self, method: &str, params: &str) -> std::io::Result<()> {
+        self.send(format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":{},\"params\":{}}}",
+            json::quote(method),
+            params
+        ))
+    }
+
+    /// Send a request and wait for its response, answering requests of the server meanwhile
+    fn request(&mut self, method: &str, params: &str) -> std::io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":{},\"params\":{}}}",
+            id,
+            json::quote(method),
+            params
+        ))?;
+
+        loop {
+            let message = self.reader.read_message()?.ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!(
+                        "the session ended while waiting for the {} response",
+                        method
+                    ),
+                )
+            })?;
+            self.result.bytes_received += message.to_bytes().len() as u64;
+            let content = String::from_utf8_lossy(&message.content);
+            let message_id = json::top_level_value(&content, "id");
+            if json::has_top_level_field(&content, "method") {
+                // e.g. client/registerCapability, which the server waits for
+                if let Some(server_id) = message_id {
+                    self.send(format!(
+                        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":null}}",
+                        server_id.to_json()
+                    ))?;
+                }
+            } else if message_id.as_ref().and_then(Value::as_u64) == Some(id) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send(&mut self, content: String) -> std::io::Result<()> {
+        let message = Message {
+            headers: Vec::new(),
+            content: content.into_bytes(),
+        };
+        let bytes = message.to_bytes();
+        self.result.bytes_sent += bytes.len() as u64;
+        self.con.write_all(&bytes)
+    }
+}
+
+/// The distribution of measured latencies
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        Self(latencies)
+    }
+
+    fn percentile(&self, percent: usize) -> Duration {
+        let index = (self.0.len() * percent / 100).min(self.0.len() - 1);
+        self.0[index]
+    }
+}
+
+impl Display for Latencies {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "none measured")?;
+        } else {
+            write!(
+                f,
+                "p50 {:.1?}, p95 {:.1?}, max {:.1?} of {}",
+                self.percentile(50),
+                self.percentile(95),
+                self.0[self.0.len() - 1],
+                self.0.len()
+            )?;
+        }
+        Ok(())
+    }
+}
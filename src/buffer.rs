@@ -0,0 +1,147 @@
+use crate::arguments::Backpressure;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+/// Decouples reading from one side of a session from writing to the other,
+/// so that a slow receiver holds up the sender only once more than the capacity is waiting for it
+pub struct SendBuffer {
+    shared: Arc<Shared>,
+    writer: JoinHandle<()>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Notified whenever data was queued or written
+    changed: Condvar,
+    /// How many bytes may wait for the receiver
+    capacity: usize,
+    policy: Backpressure,
+}
+
+#[derive(Default)]
+struct Queue {
+    chunks: VecDeque<Vec<u8>>,
+    bytes: usize,
+    /// Whether no more data is queued, so that the writer finishes once the queue is empty
+    closed: bool,
+    /// Why writing failed, after which queued data is discarded
+    failed: Option<(ErrorKind, String)>,
+}
+
+impl SendBuffer {
+    /// Start writing the queued data to `tx` in the background,
+    /// each chunk while holding the lock like the relay does
+    pub fn start(tx: Arc<Mutex<TcpStream>>, capacity: usize, policy: Backpressure) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            changed: Condvar::new(),
+            capacity,
+            policy,
+        });
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.write_queued(&tx))
+        };
+        Self { shared, writer }
+    }
+
+    /// Queue `data` for the receiver, waiting for room or failing once it fell too far behind,
+    /// depending on the policy
+    ///
+    /// Fails with the error writing to the receiver failed with, once it did.
+    pub fn push(&self, data: &[u8]) -> std::io::Result<()> {
+        let mut queue = self.shared.lock();
+        // data larger than the capacity is queued on its own, instead of never
+        while queue.failed.is_none()
+            && queue.bytes > 0
+            && queue.bytes + data.len() > self.shared.capacity
+        {
+            match self.shared.policy {
+                Backpressure::Block => queue = self.shared.wait(queue),
+                Backpressure::Drop => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "the receiver fell more than {} bytes behind",
+                            self.shared.capacity
+                        ),
+                    ))
+                }
+            }
+        }
+        queue.error()?;
+        queue.bytes += data.len();
+        queue.chunks.push_back(data.to_vec());
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Wait until the queued data is written, failing if writing it failed
+    pub fn finish(self) -> std::io::Result<()> {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        // a panicked writer has nothing left to write
+        let _ = self.writer.join();
+        let queue = self.shared.lock();
+        queue.error()
+    }
+}
+
+impl Queue {
+    /// The error writing to the receiver failed with, if it did
+    fn error(&self) -> std::io::Result<()> {
+        match &self.failed {
+            Some((kind, message)) => Err(std::io::Error::new(*kind, message.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    fn write_queued(&self, tx: &Mutex<TcpStream>) {
+        loop {
+            let chunk = {
+                let mut queue = self.lock();
+                while queue.chunks.is_empty() && !queue.closed {
+                    queue = self.wait(queue);
+                }
+                match queue.chunks.pop_front() {
+                    Some(chunk) => chunk,
+                    None => return,
+                }
+            };
+            // a poisoned lock only means another writer panicked, the stream itself is still usable
+            let written = tx
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .write_all(&chunk);
+            let mut queue = self.lock();
+            queue.bytes -= chunk.len();
+            if let Err(err) = written {
+                queue.failed = Some((err.kind(), err.to_string()));
+                queue.chunks.clear();
+                queue.bytes = 0;
+                self.changed.notify_all();
+                return;
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    fn wait<'a>(&self, queue: MutexGuard<'a, Queue>) -> MutexGuard<'a, Queue> {
+        // a poisoned lock only means another thread panicked, the queue is still valid
+        self.changed
+            .wait(queue)
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // a poisoned lock only means another thread panicked, the queue is still valid
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,13 @@
+use crate::session_log::format_rfc3339;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The version of the proxy with the commit it was built from, e.g. `0.1.0 (3f2a1b9c4d5e)`, as `--version` reports it
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("LSP_GIT_HASH"), ")");
+/// The commit the proxy was built from, with `-dirty` appended if it had uncommitted changes
+pub const GIT_HASH: &str = env!("LSP_GIT_HASH");
+
+/// When the proxy was built, or the `SOURCE_DATE_EPOCH` of a reproducible build
+pub fn build_date() -> String {
+    let epoch = env!("LSP_BUILD_EPOCH").parse().unwrap_or_default();
+    format_rfc3339(UNIX_EPOCH + Duration::from_secs(epoch))
+}
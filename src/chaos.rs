@@ -0,0 +1,144 @@
+use crate::error::ParseChaosError;
+use crate::process;
+use crate::relay::Direction;
+use log::warn;
+use rand::Rng;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The longest a spawn is delayed by
+const MAX_SPAWN_DELAY: Duration = Duration::from_secs(10);
+/// The latest a server is killed after its session started
+const MAX_KILL_DELAY: Duration = Duration::from_secs(60);
+/// The most bytes dropped or duplicated at once
+const MAX_MANGLED: usize = 16;
+
+/// Faults injected on purpose, to test how clients cope with an unreliable proxy
+///
+/// Parsed from a comma separated list of faults with their probability, e.g. `kill=0.1,drop=0.01`:
+/// - `spawn-delay`: delay spawning a server by up to 10 seconds, per spawn
+/// - `kill`: kill the server within the first minute of its session, per session
+/// - `drop`: drop a few of the relayed bytes, per chunk of relayed traffic
+/// - `duplicate`: relay a few bytes twice, per chunk of relayed traffic
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chaos {
+    spawn_delay: f64,
+    kill: f64,
+    drop: f64,
+    duplicate: f64,
+}
+
+impl FromStr for Chaos {
+    type Err = ParseChaosError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Self::default();
+        for fault in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (name, probability) = fault.split_once('=').unwrap_or((fault, "1"));
+            let probability = probability
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|probability| (0.0..=1.0).contains(probability))
+                .ok_or_else(|| ParseChaosError::InvalidProbability(probability.to_string()))?;
+            let field = match name.trim() {
+                "spawn-delay" => &mut chaos.spawn_delay,
+                "kill" => &mut chaos.kill,
+                "drop" => &mut chaos.drop,
+                "duplicate" => &mut chaos.duplicate,
+                _ => return Err(ParseChaosError::UnknownFault(name.to_string())),
+            };
+            *field = probability;
+        }
+        Ok(chaos)
+    }
+}
+
+impl Chaos {
+    /// Maybe delay spawning the server of `client`
+    pub fn delay_spawn(&self, client: &str) {
+        if happens(self.spawn_delay) {
+            let delay = rand::thread_rng().gen_range(Duration::default()..MAX_SPAWN_DELAY);
+            warn!("[{}] Chaos: delaying the spawn by {:.1?}", client, delay);
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Maybe kill the server with `pid` of `client` some time into its session
+    ///
+    /// The kill is called off once the returned handle is dropped, which has to happen before the server is waited for
+    pub fn schedule_kill(&self, client: &str, pid: u32) -> Option<ScheduledKill> {
+        if !happens(self.kill) {
+            return None;
+        }
+        let delay = rand::thread_rng().gen_range(Duration::default()..MAX_KILL_DELAY);
+        let called_off = Arc::new(Mutex::new(false));
+        let scheduled = ScheduledKill {
+            called_off: called_off.clone(),
+        };
+        let client = client.to_string();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            // hold the lock while killing, so the session can't reap the server and free its pid meanwhile
+            let called_off = called_off
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !*called_off {
+                warn!(
+                    "[{}] Chaos: killing the LSP with pid {} after {:.1?}",
+                    client, pid, delay
+                );
+                if let Err(err) = process::kill(pid) {
+                    warn!("[{}] Chaos: failed to kill the LSP: {}", client, err);
+                }
+            }
+        });
+        Some(scheduled)
+    }
+
+    /// Maybe drop or duplicate some of the `data` relayed in `direction`
+    pub fn mangle<'a>(&self, direction: Direction, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut data = Cow::Borrowed(data);
+        if !data.is_empty() && happens(self.drop) {
+            let (start, end) = random_range(data.len());
+            warn!("Chaos: dropping {} {} bytes", end - start, direction);
+            data.to_mut().drain(start..end);
+        }
+        if !data.is_empty() && happens(self.duplicate) {
+            let (start, end) = random_range(data.len());
+            warn!("Chaos: duplicating {} {} bytes", end - start, direction);
+            let duplicate = data[start..end].to_vec();
+            data.to_mut().splice(end..end, duplicate);
+        }
+        data
+    }
+}
+
+/// A kill of a server that is pending until dropped
+pub struct ScheduledKill {
+    called_off: Arc<Mutex<bool>>,
+}
+
+impl Drop for ScheduledKill {
+    fn drop(&mut self) {
+        // a poisoned lock only means the killing thread panicked, the flag is still valid
+        *self
+            .called_off
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+    }
+}
+
+fn happens(probability: f64) -> bool {
+    probability > 0.0 && rand::thread_rng().gen_bool(probability)
+}
+
+/// A random non-empty range of at most `MAX_MANGLED` bytes within `len` bytes
+fn random_range(len: usize) -> (usize, usize) {
+    let mut rng = rand::thread_rng();
+    let start = rng.gen_range(0..len);
+    let end = rng.gen_range(start + 1..=len.min(start + MAX_MANGLED));
+    (start, end)
+}
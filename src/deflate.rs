@@ -0,0 +1,470 @@
+/// How far back a match may reach
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions are tried per match, trading compression for speed
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const NO_POSITION: usize = usize::MAX;
+
+const END_OF_BLOCK: u16 = 256;
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compress `data` into a raw DEFLATE stream (RFC 1951) of a single block with the fixed Huffman codes
+///
+/// The fixed codes spare transmitting code tables, which pays off for the repetitive JSON of LSP messages
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // the final block, compressed with the fixed codes
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut head = vec![NO_POSITION; 1 << HASH_BITS];
+    // a ring buffer by position, as matches can't reach back farther than the window anyway
+    let mut previous = vec![NO_POSITION; data.len().min(WINDOW_SIZE)];
+    let mut position = 0;
+    while position < data.len() {
+        let (length, distance) = longest_match(data, position, &head, &previous);
+        let step = if length >= MIN_MATCH {
+            writer.write_length(length);
+            writer.write_distance(distance);
+            length
+        } else {
+            writer.write_symbol(u16::from(data[position]));
+            1
+        };
+        for inserted in position..position + step {
+            insert(data, inserted, &mut head, &mut previous);
+        }
+        position += step;
+    }
+
+    writer.write_symbol(END_OF_BLOCK);
+    writer.finish()
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    let key = u32::from(data[position]) << 16
+        | u32::from(data[position + 1]) << 8
+        | u32::from(data[position + 2]);
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Remember `position` as the latest occurrence of the bytes starting there,
+/// chained to the previous one in the ring buffer `previous`
+fn insert(data: &[u8], position: usize, head: &mut [usize], previous: &mut [usize]) {
+    if position + MIN_MATCH <= data.len() {
+        let hash = hash(data, position);
+        previous[position % WINDOW_SIZE] = head[hash];
+        head[hash] = position;
+    }
+}
+
+/// The length and distance of the longest earlier occurrence of the bytes at `position`
+fn longest_match(
+    data: &[u8],
+    position: usize,
+    head: &[usize],
+    previous: &[usize],
+) -> (usize, usize) {
+    if position + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max_length = MAX_MATCH.min(data.len() - position);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(data, position)];
+    for _ in 0..MAX_CHAIN {
+        if candidate == NO_POSITION || position - candidate > WINDOW_SIZE {
+            break;
+        }
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[position..position + max_length])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best.0 {
+            best = (length, position - candidate);
+            if length == max_length {
+                break;
+            }
+        }
+        // the entry of a candidate within the window wasn't overwritten by a later position yet
+        candidate = previous[candidate % WINDOW_SIZE];
+    }
+    best
+}
+
+/// Writes bits starting at the least significant bit of each byte, as DEFLATE demands
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    buffered: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.buffer |= u64::from(value) << self.buffered;
+        self.buffered += count;
+        while self.buffered >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.buffered -= 8;
+        }
+    }
+
+    /// Huffman codes are written starting at their most significant bit
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Write a literal/length symbol with its fixed code
+    fn write_symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_length(&mut self, length: usize) {
+        let index = LENGTH_BASES
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .unwrap_or(0);
+        self.write_symbol(257 + index as u16);
+        self.write_bits(
+            (length - usize::from(LENGTH_BASES[index])) as u32,
+            u32::from(LENGTH_EXTRA_BITS[index]),
+        );
+    }
+
+    fn write_distance(&mut self, distance: usize) {
+        let index = DISTANCE_BASES
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .unwrap_or(0);
+        self.write_code(index as u32, 5);
+        self.write_bits(
+            (distance - usize::from(DISTANCE_BASES[index])) as u32,
+            u32::from(DISTANCE_EXTRA_BITS[index]),
+        );
+    }
+
+    /// Pad the last byte with zeros and return the written bytes
+    fn finish(mut self) -> Vec<u8> {
+        if self.buffered > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// The order the code lengths of the code length alphabet are transmitted in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const MAX_CODE_LENGTH: usize = 15;
+
+/// Decompress a raw DEFLATE stream (RFC 1951) with blocks of any kind, e.g. an entry of a zip file,
+/// or nothing if it is malformed
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = BitReader {
+        data,
+        position: 0,
+        buffer: 0,
+        buffered: 0,
+    };
+    let mut output = Vec::new();
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            0 => {
+                reader.align();
+                let length = reader.read_bits(16)?;
+                let complement = reader.read_bits(16)?;
+                if length != !complement & 0xffff {
+                    return None;
+                }
+                for _ in 0..length {
+                    output.push(reader.read_bits(8)? as u8);
+                }
+            }
+            1 => {
+                let mut lengths = [0; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return None,
+        }
+        if last {
+            return Some(output);
+        }
+    }
+}
+
+/// Decode the symbols of a compressed block until its end
+fn inflate_block(
+    reader: &mut BitReader<'_>,
+    output: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Option<()> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            END_OF_BLOCK => return Some(()),
+            _ => {
+                let index = usize::from(symbol - 257);
+                let length = usize::from(*LENGTH_BASES.get(index)?)
+                    + reader.read_bits(u32::from(LENGTH_EXTRA_BITS[index]))? as usize;
+                let index = usize::from(distances.decode(reader)?);
+                let distance = usize::from(*DISTANCE_BASES.get(index)?)
+                    + reader.read_bits(u32::from(DISTANCE_EXTRA_BITS[index]))? as usize;
+                if distance > output.len() {
+                    return None;
+                }
+                // byte by byte, as the match may overlap the bytes it produces
+                let start = output.len() - distance;
+                for offset in 0..length {
+                    output.push(output[start + offset]);
+                }
+            }
+        }
+    }
+}
+
+/// Read the literal/length and distance codes transmitted at the start of a dynamic block
+fn read_dynamic_codes(reader: &mut BitReader<'_>) -> Option<(Huffman, Huffman)> {
+    let literal_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+    let mut code_length_lengths = [0; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[index] = reader.read_bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last()?, reader.read_bits(2)? + 3),
+            17 => (0, reader.read_bits(3)? + 3),
+            _ => (0, reader.read_bits(7)? + 11),
+        };
+        lengths.extend(std::iter::repeat(length).take(repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return None;
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    Some((
+        Huffman::new(literal_lengths)?,
+        Huffman::new(distance_lengths)?,
+    ))
+}
+
+/// A canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    /// The number of codes of each length
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    /// The symbols ordered by their codes
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// The code of symbols with the code `lengths`, where 0 means the symbol is not used
+    fn new(lengths: &[u8]) -> Option<Self> {
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        for &length in lengths {
+            *counts.get_mut(usize::from(length))? += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; MAX_CODE_LENGTH + 1];
+        for length in 1..MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+        Some(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Option<u16> {
+        // the first code and the index of its symbol among those of the current length
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for &count in &self.counts[1..] {
+            code |= reader.read_bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+/// Reads bits starting at the least significant bit of each byte, as DEFLATE demands
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    buffered: u32,
+}
+
+impl BitReader<'_> {
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        while self.buffered < count {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            self.buffer |= u64::from(byte) << self.buffered;
+            self.buffered += 8;
+        }
+        let value = (self.buffer & ((1 << count) - 1)) as u32;
+        self.buffer >>= count;
+        self.buffered -= count;
+        Some(value)
+    }
+
+    /// Skip to the start of the next byte, as stored blocks start there
+    fn align(&mut self) {
+        let skipped = self.buffered % 8;
+        self.buffer >>= skipped;
+        self.buffered -= skipped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &[u8] = b"Manifest-Version: 1.0\r\nBundle-Version: 0.4.2\r\nImplementation-Version: 0.4.2\r\nCreated-By: Maven\r\n\r\n";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The input of [`ZLIB_DYNAMIC`]
+    fn numbered_lines() -> Vec<u8> {
+        (0..30)
+            .map(|line| format!("line {}: {}\n", line, line * line))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// [`MANIFEST`] deflated by zlib without compression, in a stored block
+    const ZLIB_STORED: &str = "0162009dff4d616e69666573742d56657273696f6e3a20312e300d0a42756e646c652d56657273696f6e3a20302e342e320d0a496d706c656d656e746174696f6e2d56657273696f6e3a20302e342e320d0a437265617465642d42793a204d6176656e0d0a0d0a";
+    /// [`MANIFEST`] deflated by zlib at level 9, in a block with the fixed codes
+    const ZLIB_FIXED: &str = "f34dcccb4c4b2d2ed10d4b2d2acecccfb35230d433e0e5722acd4bc94945081ae899e819f17279e616e4a4e6a6e6952496008531a49d8b52134b5253749d2aad147c13cb52f378b978b900";
+    /// [`numbered_lines`] deflated by zlib at level 9, in a block with dynamic codes
+    const ZLIB_DYNAMIC: &str = "3d90410e04210804effb0a9e2088a8fc670f9b4ce6ffc76d2278eb8874953ebff74bcda97d9e48ecc42789939ed49df6498aa99d389c649c684e3d4f2796f2ee72b22cd84e2b5b19286e050b9ad40440d6dce01ea46ce2c0ee2470808bcc161635015c56ed00dfa5da7618d6bb60a065203050ad493c79e58ec06048b6090cc64c8ec0c0ca406060770283797760306f5b7c01387f";
+
+    /// Text and noise from a linear congruential generator, compressible but not trivially
+    fn mixed(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|index| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                if index % 1000 < 700 {
+                    b"lsp_on_demand "[index % 14]
+                } else {
+                    (state >> 16) as u8
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        for data in [
+            Vec::new(),
+            b"a".to_vec(),
+            MANIFEST.to_vec(),
+            vec![b'x'; 100_000],
+            b"abc".repeat(20_000),
+            mixed(200_000),
+            mixed(WINDOW_SIZE).repeat(3),
+        ] {
+            assert_eq!(decompress(&compress(&data)), Some(data));
+        }
+    }
+
+    #[test]
+    fn matches_reach_back_the_whole_window() {
+        let block = mixed(WINDOW_SIZE);
+        let once = compress(&block).len();
+        // each repetition is found exactly a window back, so it adds little
+        assert!(compress(&block.repeat(3)).len() < once + once / 10);
+    }
+
+    #[test]
+    fn inflates_zlib_streams() {
+        assert_eq!(decompress(&hex(ZLIB_STORED)).as_deref(), Some(MANIFEST));
+        assert_eq!(decompress(&hex(ZLIB_FIXED)).as_deref(), Some(MANIFEST));
+        assert_eq!(decompress(&hex(ZLIB_DYNAMIC)), Some(numbered_lines()));
+    }
+
+    #[test]
+    fn rejects_truncated_streams() {
+        for stream in [
+            hex(ZLIB_STORED),
+            hex(ZLIB_FIXED),
+            hex(ZLIB_DYNAMIC),
+            compress(&mixed(5_000)),
+        ] {
+            for len in 0..stream.len() {
+                assert_eq!(decompress(&stream[..len]), None, "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_streams() {
+        // the reserved block type 3
+        assert_eq!(decompress(&[0x07]), None);
+        // a stored block whose length and its complement don't match
+        assert_eq!(decompress(&[0x01, 0x01, 0x00, 0x00, 0x00, b'a']), None);
+        // a fixed block starting with a match of distance 1, before anything to copy
+        assert_eq!(decompress(&[0x03, 0x02, 0x00, 0x00]), None);
+    }
+}
@@ -1,7 +1,12 @@
 use crate::error::ParsePortRangeError::*;
+use crate::guard::MIB;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
 use std::num::ParseIntError;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ParsePortRangeError {
@@ -40,3 +45,526 @@ impl Display for ParsePortRangeError {
 }
 
 impl Error for ParsePortRangeError {}
+
+#[derive(Debug)]
+pub enum ParsePortListError {
+    Empty,
+    AllExcluded,
+    ParsePort(ParseIntError),
+    ParseRange(ParsePortRangeError),
+}
+
+impl From<ParseIntError> for ParsePortListError {
+    fn from(int_err: ParseIntError) -> Self {
+        Self::ParsePort(int_err)
+    }
+}
+
+impl From<ParsePortRangeError> for ParsePortListError {
+    fn from(range_err: ParsePortRangeError) -> Self {
+        Self::ParseRange(range_err)
+    }
+}
+
+impl Display for ParsePortListError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "at least one port should be given")?,
+            Self::AllExcluded => {
+                write!(f, "at least one of the ports given should not be excluded")?
+            }
+            Self::ParsePort(int_err) => write!(
+                f,
+                "the ports should be integers in the range {}-{}: {}",
+                u16::MIN,
+                u16::MAX,
+                int_err
+            )?,
+            Self::ParseRange(range_err) => write!(f, "{}", range_err)?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParsePortListError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Empty | Self::AllExcluded => None,
+            Self::ParsePort(int_err) => Some(int_err),
+            Self::ParseRange(range_err) => Some(range_err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseHttpEndpointError {
+    UnsupportedScheme,
+    MissingHost,
+    ParsePort(ParseIntError),
+}
+
+impl From<ParseIntError> for ParseHttpEndpointError {
+    fn from(int_err: ParseIntError) -> Self {
+        Self::ParsePort(int_err)
+    }
+}
+
+impl Display for ParseHttpEndpointError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedScheme => write!(
+                f,
+                "the endpoint should be a plain http url starting with 'http://'"
+            )?,
+            Self::MissingHost => write!(f, "the endpoint url should contain a host")?,
+            Self::ParsePort(int_err) => write!(
+                f,
+                "the port of the endpoint should be an integer in the range {}-{}: {}",
+                u16::MIN,
+                u16::MAX,
+                int_err
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseHttpEndpointError {}
+
+/// Why a language server could not be started for a session
+#[derive(Debug)]
+pub enum ServerError {
+    SpawnFailed(std::io::Error),
+    PortConflict(u16),
+    ExitedWithStatus(ExitStatus),
+    Timeout(Duration),
+    /// The pre-spawn hook failed, as described
+    PreSpawnHookFailed(String),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SpawnFailed(io_err) => {
+                write!(f, "failed to spawn the server process: {}", io_err)?
+            }
+            Self::PortConflict(port) => write!(f, "port {} is already in use", port)?,
+            Self::ExitedWithStatus(status) => write!(
+                f,
+                "the server exited before accepting connections with {}",
+                status
+            )?,
+            Self::Timeout(timeout) => write!(
+                f,
+                "the server did not accept connections within {}s",
+                timeout.as_secs()
+            )?,
+            Self::PreSpawnHookFailed(failure) => write!(f, "the pre-spawn hook {}", failure)?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::SpawnFailed(io_err) => Some(io_err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseUriMappingError {
+    MissingSeparator,
+    EmptyRoot,
+}
+
+impl Display for ParseUriMappingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator => write!(
+                f,
+                "the client root should be separated from the server root by a '='"
+            )?,
+            Self::EmptyRoot => write!(f, "neither the client nor the server root should be empty")?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseUriMappingError {}
+
+#[derive(Debug)]
+pub enum ParseMergePatchError {
+    InvalidJson,
+    NotAnObject,
+}
+
+impl Display for ParseMergePatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidJson => write!(f, "the patch is not valid JSON")?,
+            Self::NotAnObject => write!(
+                f,
+                "the patch should be a JSON object, as it would replace the whole result otherwise"
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseMergePatchError {}
+
+/// Why a new session is refused to protect the running ones
+#[derive(Debug)]
+pub enum GuardrailError {
+    LowDiskSpace {
+        path: PathBuf,
+        available: u64,
+        required: u64,
+    },
+    LowFileDescriptors {
+        available: u64,
+        required: u64,
+    },
+    LowMemory {
+        available: u64,
+        required: u64,
+    },
+    TooManyClientSessions {
+        client: IpAddr,
+        limit: usize,
+    },
+    SubnetQuotaExhausted {
+        network: String,
+        limit: usize,
+    },
+    Maintenance,
+}
+
+impl Display for GuardrailError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LowDiskSpace {
+                path,
+                available,
+                required,
+            } => write!(
+                f,
+                "only {} MiB of disk space are free in {}, at least {} MiB are required",
+                available / MIB,
+                path.display(),
+                required / MIB
+            )?,
+            Self::LowFileDescriptors {
+                available,
+                required,
+            } => write!(
+                f,
+                "only {} file descriptors are available, at least {} are required",
+                available, required
+            )?,
+            Self::LowMemory {
+                available,
+                required,
+            } => write!(
+                f,
+                "only {} MiB of memory are available, at least {} MiB are required to start another language server",
+                available / MIB,
+                required / MIB
+            )?,
+            Self::TooManyClientSessions { client, limit } => write!(
+                f,
+                "{} already has the maximum of {} sessions",
+                client, limit
+            )?,
+            Self::SubnetQuotaExhausted { network, limit } => write!(
+                f,
+                "the clients of {} already have the maximum of {} sessions",
+                network, limit
+            )?,
+            Self::Maintenance => write!(
+                f,
+                "the proxy is about to shut down for maintenance, please reconnect later"
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for GuardrailError {}
+
+/// An LSP message longer than allowed, whose content was skipped
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub length: usize,
+    pub limit: usize,
+    /// The start of the content
+    pub prefix: Vec<u8>,
+}
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the message of {} bytes exceeds the maximum of {} bytes",
+            self.length, self.limit
+        )?;
+        Ok(())
+    }
+}
+
+impl Error for MessageTooLarge {}
+
+#[derive(Debug)]
+pub enum ParseLogFilterError {
+    InvalidLevel(String),
+    EmptyModule,
+}
+
+impl Display for ParseLogFilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidLevel(level) => write!(
+                f,
+                "'{}' should be one of off, error, warn, info, debug or trace",
+                level
+            )?,
+            Self::EmptyModule => write!(f, "the module before a '=' should not be empty")?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseLogFilterError {}
+
+#[derive(Debug)]
+pub enum ParseChaosError {
+    UnknownFault(String),
+    InvalidProbability(String),
+}
+
+impl Display for ParseChaosError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownFault(fault) => write!(
+                f,
+                "unknown fault '{}', expected spawn-delay, kill, drop or duplicate",
+                fault
+            )?,
+            Self::InvalidProbability(probability) => write!(
+                f,
+                "'{}' should be a probability between 0 and 1",
+                probability
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseChaosError {}
+
+#[derive(Debug)]
+pub enum ParseNetworkError {
+    InvalidAddress(String),
+    InvalidPrefixLength(String),
+}
+
+impl Display for ParseNetworkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress(address) => {
+                write!(f, "'{}' should be an IPv4 or IPv6 address", address)?
+            }
+            Self::InvalidPrefixLength(prefix_len) => write!(
+                f,
+                "'{}' should be a prefix length of at most 32 bits for IPv4 or 128 bits for IPv6",
+                prefix_len
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseNetworkError {}
+
+#[derive(Debug)]
+pub enum ParseSubnetQuotaError {
+    MissingSeparator,
+    InvalidNetwork(ParseNetworkError),
+    InvalidLimit(String),
+}
+
+impl Display for ParseSubnetQuotaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator => write!(
+                f,
+                "the network should be separated from the number of sessions by a '='"
+            )?,
+            Self::InvalidNetwork(err) => write!(f, "{}", err)?,
+            Self::InvalidLimit(limit) => write!(f, "'{}' should be a number of sessions", limit)?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseSubnetQuotaError {}
+
+#[derive(Debug)]
+pub enum ParseCpuListError {
+    Empty,
+    InvalidCpu(String),
+}
+
+impl Display for ParseCpuListError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "at least one CPU should be given")?,
+            Self::InvalidCpu(cpu) => write!(
+                f,
+                "'{}' should be the index of a CPU or a range of them like 0-3",
+                cpu
+            )?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseCpuListError {}
+
+#[derive(Debug)]
+pub enum ParseClientVersionError {
+    MissingSeparator,
+    EmptyName,
+    InvalidVersion(String),
+}
+
+impl Display for ParseClientVersionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator => write!(
+                f,
+                "the client name should be separated from the version by a '='"
+            )?,
+            Self::EmptyName => write!(f, "the client name should not be empty")?,
+            Self::InvalidVersion(version) => {
+                write!(f, "'{}' should be a version like 1.2.3", version)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseClientVersionError {}
+
+/// A client older than the oldest version configured for it
+#[derive(Debug)]
+pub struct OutdatedClient {
+    pub name: String,
+    pub version: String,
+    pub minimum: String,
+}
+
+impl Display for OutdatedClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} is outdated, please update it to version {} or newer",
+            self.name, self.version, self.minimum
+        )?;
+        Ok(())
+    }
+}
+
+impl Error for OutdatedClient {}
+
+#[derive(Debug)]
+pub struct ParseSpawnDirectionError {
+    pub direction: String,
+}
+
+impl Display for ParseSpawnDirectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' should be either 'listen' or 'connect'",
+            self.direction
+        )?;
+        Ok(())
+    }
+}
+
+impl Error for ParseSpawnDirectionError {}
+
+#[derive(Debug)]
+pub struct ParseBackpressureError {
+    pub policy: String,
+}
+
+impl Display for ParseBackpressureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' should be either 'block' or 'drop'", self.policy)?;
+        Ok(())
+    }
+}
+
+impl Error for ParseBackpressureError {}
+
+#[derive(Debug)]
+pub enum ParseEnvVarError {
+    MissingSeparator,
+    EmptyName,
+}
+
+impl Display for ParseEnvVarError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator => {
+                write!(f, "the name should be separated from the value by a '='")?
+            }
+            Self::EmptyName => write!(f, "the name should not be empty")?,
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseEnvVarError {}
+
+#[derive(Debug)]
+pub struct ShortAdminTokenError {
+    pub length: usize,
+    pub min_length: usize,
+}
+
+impl Display for ShortAdminTokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the token should be at least {} characters long, not {}",
+            self.min_length, self.length
+        )?;
+        Ok(())
+    }
+}
+
+impl Error for ShortAdminTokenError {}
+
+#[derive(Debug)]
+pub struct UnknownEventError {
+    pub event: String,
+}
+
+impl Display for UnknownEventError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown event '{}', expected server-crash, spawn-failed, pool-exhausted or proxy-shutdown",
+            self.event
+        )?;
+        Ok(())
+    }
+}
+
+impl Error for UnknownEventError {}
@@ -0,0 +1,110 @@
+use crate::json;
+use crate::lsp::Message;
+use crate::stats::Statistics;
+use crate::workers::{Ticket, WorkerPool};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often a waiting client is told its position in the queue
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(10);
+/// The `window/showMessage` type of informational messages
+const MESSAGE_TYPE_INFO: i32 = 3;
+
+/// Tells a client waiting for a session worker its position in the queue via `window/showMessage`,
+/// so that the user sees why the editor does not respond, until its session starts
+#[derive(Clone, Default)]
+pub struct QueueFeedback {
+    /// Set once the session started, after which the connection belongs to the session
+    started: Arc<Mutex<bool>>,
+}
+
+impl QueueFeedback {
+    /// Stop the feedback, before the session uses the connection
+    pub fn session_started(&self) {
+        // a poisoned lock only means the feedback thread panicked, the flag is still valid
+        *self
+            .started
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+    }
+
+    /// Report the position of the session queued with `ticket` to `client_con` from a background thread
+    ///
+    /// The wait is estimated from the average duration of the sessions served so far.
+    pub fn start(
+        &self,
+        client_con: TcpStream,
+        ticket: Ticket,
+        workers: WorkerPool,
+        stats: Arc<Statistics>,
+    ) {
+        if workers.position(ticket).is_none() {
+            // a worker already took the session
+            return;
+        }
+        let started = self.started.clone();
+        std::thread::spawn(move || {
+            let mut client_con = client_con;
+            loop {
+                let ahead = match workers.position(ticket) {
+                    Some(ahead) => ahead,
+                    None => return,
+                };
+                {
+                    // hold the lock while writing, so that the session can't start writing meanwhile
+                    let started = started
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if *started {
+                        return;
+                    }
+                    let message = waiting_message(ahead, estimate(ahead, &workers, &stats));
+                    if notify(&mut client_con, &message).is_err() {
+                        return;
+                    }
+                }
+                std::thread::sleep(FEEDBACK_INTERVAL);
+            }
+        });
+    }
+}
+
+/// How long a session with `ahead` sessions queued before it has to wait, if there is a basis to estimate it on
+fn estimate(ahead: usize, workers: &WorkerPool, stats: &Statistics) -> Option<Duration> {
+    let report = stats.report();
+    if report.sessions_served == 0 {
+        return None;
+    }
+    // every worker finishing a session lets the next queued one start
+    let rounds = ahead / workers.state().workers.max(1) + 1;
+    Some(report.average_session_duration * rounds as u32)
+}
+
+fn waiting_message(ahead: usize, wait: Option<Duration>) -> String {
+    let position = match ahead {
+        0 => String::from("you are next"),
+        ahead => format!("{} ahead of you", ahead),
+    };
+    match wait {
+        Some(wait) => format!(
+            "Waiting for a language server slot ({}, about {} min)",
+            position,
+            (wait.as_secs() + 59) / 60
+        ),
+        None => format!("Waiting for a language server slot ({})", position),
+    }
+}
+
+fn notify(client_con: &mut TcpStream, message: &str) -> std::io::Result<()> {
+    Message {
+        headers: Vec::new(),
+        content: format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"window/showMessage\",\"params\":{{\"type\":{},\"message\":{}}}}}",
+            MESSAGE_TYPE_INFO,
+            json::quote(message)
+        )
+        .into_bytes(),
+    }
+    .write_to(client_con)
+}
@@ -0,0 +1,898 @@
+use crate::error::{ParseMergePatchError, ParseUriMappingError};
+use crate::json::Value;
+use crate::lsp::{Message, METHOD_NOT_FOUND, REQUEST_CANCELLED};
+use crate::relay::Direction;
+use crate::{deflate, json};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// A stage of the relay inspecting, rewriting or dropping LSP messages, requires `--parse-lsp`
+pub trait MessageFilter: Debug + Send + Sync {
+    /// Filter a `message` relayed in `direction`, returning nothing drops it
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message>;
+
+    /// Answer a request relayed in `direction` before it is filtered, returning the response,
+    /// usually an error, to send back to its sender instead of relaying it
+    fn reject(&self, _direction: Direction, _message: &Message) -> Option<Message> {
+        None
+    }
+
+    /// Further messages to relay after a `message` relayed in `direction`, before it is filtered,
+    /// e.g. copies of a response for requests that were not relayed
+    fn fan_out(&self, _direction: Direction, _message: &Message) -> Vec<Message> {
+        Vec::new()
+    }
+}
+
+/// Pass `message` through all `filters` in order
+pub fn apply(
+    filters: &[std::sync::Arc<dyn MessageFilter>],
+    direction: Direction,
+    message: Message,
+) -> Option<Message> {
+    filters
+        .iter()
+        .try_fold(message, |message, filter| filter.filter(direction, message))
+}
+
+/// The error response of the first of the `filters` rejecting `message`, if any
+pub fn reject(
+    filters: &[std::sync::Arc<dyn MessageFilter>],
+    direction: Direction,
+    message: &Message,
+) -> Option<Message> {
+    filters
+        .iter()
+        .find_map(|filter| filter.reject(direction, message))
+}
+
+/// The copies of `message` all `filters` fan it out to
+pub fn fan_out(
+    filters: &[std::sync::Arc<dyn MessageFilter>],
+    direction: Direction,
+    message: &Message,
+) -> Vec<Message> {
+    filters
+        .iter()
+        .flat_map(|filter| filter.fan_out(direction, message))
+        .collect()
+}
+
+/// Drops the notifications of the given methods, e.g. `telemetry/event`
+#[derive(Debug)]
+pub struct DropNotifications {
+    pub methods: Vec<String>,
+}
+
+impl MessageFilter for DropNotifications {
+    fn filter(&self, _direction: Direction, message: Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        // requests and responses have an id, only notifications are dropped
+        if json::has_top_level_field(&content, "id") {
+            return Some(message);
+        }
+        match json::top_level_string_field(&content, "method") {
+            Some(method) if self.methods.contains(&method) => None,
+            _ => Some(message),
+        }
+    }
+}
+
+/// Keeps clients from calling the given methods on the server, e.g. `workspace/executeCommand`,
+/// rejecting their requests with `MethodNotFound` and dropping their notifications
+#[derive(Debug)]
+pub struct DenyMethods {
+    pub methods: Vec<String>,
+}
+
+impl DenyMethods {
+    /// Whether `content` is a message from the client calling a denied method
+    fn denies(&self, direction: Direction, content: &str) -> bool {
+        direction == Direction::ClientToServer
+            && json::top_level_string_field(content, "method")
+                .map_or(false, |method| self.methods.contains(&method))
+    }
+}
+
+impl MessageFilter for DenyMethods {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        if self.denies(direction, &content) {
+            debug!("Dropping denied notification: {}", content);
+            return None;
+        }
+        Some(message)
+    }
+
+    fn reject(&self, direction: Direction, message: &Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        let id = json::top_level_value(&content, "id")?;
+        if !self.denies(direction, &content) {
+            return None;
+        }
+        let method = json::top_level_string_field(&content, "method")?;
+        info!("Rejecting denied {} request {}", method, id.to_json());
+        Some(Message::error_response(
+            &id,
+            METHOD_NOT_FOUND,
+            &format!("{} is not allowed by the proxy", method),
+        ))
+    }
+}
+
+/// The members holding document and workspace URIs,
+/// workspace folders hold theirs in a `uri` member as well
+const URI_FIELDS: &[&str] = &["uri", "rootUri", "targetUri"];
+
+/// Rewrites file URIs below the client's root to the server's root and back,
+/// for clients whose files live at a different path than the server sees them
+#[derive(Debug, Clone)]
+pub struct UriMapping {
+    pub client_root: String,
+    pub server_root: String,
+}
+
+impl FromStr for UriMapping {
+    type Err = ParseUriMappingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client_root, server_root) = s
+            .split_once('=')
+            .ok_or(ParseUriMappingError::MissingSeparator)?;
+        if client_root.is_empty() || server_root.is_empty() {
+            return Err(ParseUriMappingError::EmptyRoot);
+        }
+        Ok(Self {
+            client_root: client_root.to_string(),
+            server_root: server_root.to_string(),
+        })
+    }
+}
+
+impl MessageFilter for UriMapping {
+    fn filter(&self, direction: Direction, mut message: Message) -> Option<Message> {
+        let (from, to) = match direction {
+            Direction::ClientToServer => (&self.client_root, &self.server_root),
+            Direction::ServerToClient => (&self.server_root, &self.client_root),
+        };
+        // rewriting lossily converted content would corrupt it, so it is relayed as it is
+        let content = match std::str::from_utf8(&message.content) {
+            Ok(content) => content,
+            Err(err) => {
+                debug!(
+                    "Not rewriting the URIs of a message that isn't UTF-8: {}",
+                    err
+                );
+                return Some(message);
+            }
+        };
+        if content.contains(from.as_str()) {
+            let rewritten = json::rewrite_string_fields(content, URI_FIELDS, |uri| {
+                uri.strip_prefix(from.as_str())
+                    .map(|path| format!("{}{}", to, path))
+            });
+            message.content = rewritten.into_bytes();
+        }
+        Some(message)
+    }
+}
+
+/// A JSON merge patch (RFC 7386) of an object, e.g. `{"capabilities":{"semanticTokensProvider":null}}`
+#[derive(Clone)]
+pub struct MergePatch(Value);
+
+/// The patch as JSON, as it was given
+impl Debug for MergePatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.to_json())?;
+        Ok(())
+    }
+}
+
+impl FromStr for MergePatch {
+    type Err = ParseMergePatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Value::parse(s) {
+            Some(patch @ Value::Object(_)) => Ok(Self(patch)),
+            Some(_) => Err(ParseMergePatchError::NotAnObject),
+            None => Err(ParseMergePatchError::InvalidJson),
+        }
+    }
+}
+
+/// Applies merge patches to the result of the server's response to `initialize`,
+/// e.g. to hide a capability that is broken for all clients
+#[derive(Debug)]
+pub struct InitializePatch {
+    patches: Vec<MergePatch>,
+    /// The id of the client's `initialize` request, until the server answered it
+    request: Mutex<Option<Value>>,
+}
+
+impl InitializePatch {
+    pub fn new(patches: Vec<MergePatch>) -> Self {
+        Self {
+            patches,
+            request: Mutex::new(None),
+        }
+    }
+}
+
+impl MessageFilter for InitializePatch {
+    fn filter(&self, direction: Direction, mut message: Message) -> Option<Message> {
+        // a poisoned lock only means another relay thread panicked, the id is still valid
+        let mut request = self
+            .request
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let content = String::from_utf8_lossy(&message.content);
+        match direction {
+            Direction::ClientToServer => {
+                if json::top_level_string_field(&content, "method").as_deref() == Some("initialize")
+                {
+                    *request = json::top_level_value(&content, "id");
+                }
+            }
+            Direction::ServerToClient => {
+                let id = match &*request {
+                    Some(id) => id,
+                    None => return Some(message),
+                };
+                if json::has_top_level_field(&content, "method")
+                    || json::top_level_value(&content, "id").as_ref() != Some(id)
+                {
+                    return Some(message);
+                }
+                *request = None;
+                let mut response = match Value::parse(&content) {
+                    Some(Value::Object(members)) => members,
+                    _ => return Some(message),
+                };
+                // failed requests have no result to patch
+                if let Some((_, result)) = response.iter_mut().find(|(name, _)| name == "result") {
+                    for MergePatch(patch) in &self.patches {
+                        result.merge_patch(patch);
+                    }
+                    message.content = Value::Object(response).to_json().into_bytes();
+                }
+            }
+        }
+        Some(message)
+    }
+}
+
+/// How many results a session may cache, all are dropped once there are more
+const MAX_CACHED_RESULTS: usize = 1024;
+
+/// The members of request parameters naming the progress reports of the request
+const PROGRESS_TOKENS: &[&str] = &["workDoneToken", "partialResultToken"];
+
+/// Answers repeated requests of the given methods, e.g. `textDocument/documentSymbol`, from a cache,
+/// as long as the client didn't notify the server about changes to the document they are about
+#[derive(Debug)]
+pub struct ResponseCache {
+    methods: Vec<String>,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    /// The key and document of the requests forwarded to the server, by their id
+    pending: HashMap<String, (String, Option<String>)>,
+    /// The document and result of the answered requests, by their key
+    results: HashMap<String, (Option<String>, Value)>,
+}
+
+impl Cache {
+    /// Forget the results about the document `uri`, or about all documents
+    fn invalidate(&mut self, uri: Option<&str>) {
+        match uri {
+            Some(uri) => {
+                let affected = |document: &Option<String>| document.as_deref() == Some(uri);
+                self.results.retain(|_, (document, _)| !affected(document));
+                // their results may predate the change
+                self.pending.retain(|_, (_, document)| !affected(document));
+            }
+            None => {
+                self.results.clear();
+                self.pending.clear();
+            }
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn new(methods: Vec<String>) -> Self {
+        Self {
+            methods,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// The id, cache key and document of a request of a cached method
+    fn cacheable(&self, request: &Value) -> Option<(Value, String, Option<String>)> {
+        let method = request.get("method")?.as_str()?;
+        if !self.methods.iter().any(|cached| cached == method) {
+            return None;
+        }
+        let id = request.get("id")?.clone();
+        let params = request.get("params").unwrap_or(&Value::Null);
+        let uri = params
+            .pointer(&["textDocument", "uri"])
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        // the progress tokens differ between otherwise identical requests
+        let params = match params {
+            Value::Object(members) => Value::Object(
+                members
+                    .iter()
+                    .filter(|(name, _)| !PROGRESS_TOKENS.contains(&name.as_str()))
+                    .cloned()
+                    .collect(),
+            ),
+            params => params.clone(),
+        };
+        Some((id, format!("{} {}", method, params.to_json()), uri))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        // a poisoned lock only means another relay thread panicked, the cache is still valid
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MessageFilter for ResponseCache {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        let mut cache = self.lock();
+        match direction {
+            Direction::ClientToServer => {
+                let method = match json::top_level_string_field(&content, "method") {
+                    Some(method) => method,
+                    None => return Some(message),
+                };
+                if json::has_top_level_field(&content, "id") {
+                    let request = Value::parse(&content);
+                    // the responses to requests for partial results leave out what was reported as progress
+                    let partial = request
+                        .as_ref()
+                        .and_then(|request| request.pointer(&["params", "partialResultToken"]))
+                        .is_some();
+                    if let Some((id, key, uri)) = request
+                        .as_ref()
+                        .filter(|_| !partial)
+                        .and_then(|request| self.cacheable(request))
+                    {
+                        cache.pending.insert(id.to_json(), (key, uri));
+                    }
+                } else if !method.starts_with("$/") && method != "initialized" {
+                    // notifications about a document, like didChange, only affect the results about it
+                    let uri = Value::parse(&content).and_then(|notification| {
+                        notification
+                            .pointer(&["params", "textDocument", "uri"])
+                            .and_then(Value::as_str)
+                            .map(str::to_string)
+                    });
+                    cache.invalidate(uri.as_deref());
+                }
+            }
+            Direction::ServerToClient => {
+                if cache.pending.is_empty() || json::has_top_level_field(&content, "method") {
+                    return Some(message);
+                }
+                let id = match json::top_level_value(&content, "id") {
+                    Some(id) => id,
+                    None => return Some(message),
+                };
+                if let Some((key, uri)) = cache.pending.remove(&id.to_json()) {
+                    // failed requests are not cached, they may succeed when retried
+                    if let Some(result) = json::top_level_value(&content, "result") {
+                        if cache.results.len() >= MAX_CACHED_RESULTS {
+                            cache.results.clear();
+                        }
+                        cache.results.insert(key, (uri, result));
+                    }
+                }
+            }
+        }
+        Some(message)
+    }
+
+    fn reject(&self, direction: Direction, message: &Message) -> Option<Message> {
+        if direction != Direction::ClientToServer {
+            return None;
+        }
+        let content = String::from_utf8_lossy(&message.content);
+        let (id, key, _) = self.cacheable(&Value::parse(&content)?)?;
+        let cache = self.lock();
+        let (_, result) = cache.results.get(&key)?;
+        debug!("Answering request {} from the cache: {}", id.to_json(), key);
+        Some(Message::response(&id, result))
+    }
+}
+
+/// Relays only the first of identical requests of the given methods while it is in flight,
+/// answering its duplicates, e.g. retries of clients that gave up waiting, with copies of its response
+#[derive(Debug)]
+pub struct RequestCoalescing {
+    methods: Vec<String>,
+    in_flight: Mutex<InFlight>,
+}
+
+#[derive(Debug, Default)]
+struct InFlight {
+    /// The id of the request relayed for each method and parameters
+    requests: HashMap<String, String>,
+    /// The method and parameters and the ids of the duplicates of each relayed request, by its id
+    duplicates: HashMap<String, (String, Vec<Value>)>,
+}
+
+impl RequestCoalescing {
+    pub fn new(methods: Vec<String>) -> Self {
+        Self {
+            methods,
+            in_flight: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, InFlight> {
+        // a poisoned lock only means another relay thread panicked, the requests are still valid
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MessageFilter for RequestCoalescing {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        if direction != Direction::ClientToServer {
+            return Some(message);
+        }
+        let content = String::from_utf8_lossy(&message.content);
+        let method = match json::top_level_string_field(&content, "method") {
+            Some(method) if self.methods.contains(&method) => method,
+            _ => return Some(message),
+        };
+        let request = match Value::parse(&content) {
+            Some(request) => request,
+            None => return Some(message),
+        };
+        let id = match request.get("id") {
+            Some(id) => id,
+            None => return Some(message),
+        };
+        let key = format!(
+            "{} {}",
+            method,
+            request.get("params").unwrap_or(&Value::Null).to_json()
+        );
+        let mut in_flight = self.lock();
+        let relayed = match in_flight.requests.get(&key) {
+            Some(relayed) => relayed.clone(),
+            None => {
+                in_flight.requests.insert(key.clone(), id.to_json());
+                in_flight.duplicates.insert(id.to_json(), (key, Vec::new()));
+                return Some(message);
+            }
+        };
+        debug!(
+            "Coalescing {} request {} with request {}",
+            method,
+            id.to_json(),
+            relayed
+        );
+        if let Some((_, duplicates)) = in_flight.duplicates.get_mut(&relayed) {
+            duplicates.push(id.clone());
+        }
+        None
+    }
+
+    fn reject(&self, direction: Direction, message: &Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        if direction != Direction::ClientToServer
+            || json::top_level_string_field(&content, "method").as_deref()
+                != Some("$/cancelRequest")
+        {
+            return None;
+        }
+        // the server doesn't know about the duplicates, so the proxy has to answer their cancellation
+        let cancelled = Value::parse(&content)?.pointer(&["params", "id"])?.clone();
+        let mut in_flight = self.lock();
+        let duplicates = in_flight
+            .duplicates
+            .values_mut()
+            .map(|(_, duplicates)| duplicates)
+            .find(|duplicates| duplicates.contains(&cancelled))?;
+        duplicates.retain(|duplicate| *duplicate != cancelled);
+        Some(Message::error_response(
+            &cancelled,
+            REQUEST_CANCELLED,
+            "the request was cancelled",
+        ))
+    }
+
+    fn fan_out(&self, direction: Direction, message: &Message) -> Vec<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        if direction != Direction::ServerToClient || json::has_top_level_field(&content, "method") {
+            return Vec::new();
+        }
+        let mut in_flight = self.lock();
+        if in_flight.duplicates.is_empty() {
+            return Vec::new();
+        }
+        let id = match json::top_level_value(&content, "id") {
+            Some(id) => id.to_json(),
+            None => return Vec::new(),
+        };
+        let (key, duplicates) = match in_flight.duplicates.remove(&id) {
+            Some(relayed) => relayed,
+            None => return Vec::new(),
+        };
+        in_flight.requests.remove(&key);
+        let mut response = match Value::parse(&content) {
+            Some(Value::Object(members)) if !duplicates.is_empty() => members,
+            _ => return Vec::new(),
+        };
+        duplicates
+            .into_iter()
+            .map(|duplicate| {
+                if let Some((_, id)) = response.iter_mut().find(|(name, _)| name == "id") {
+                    *id = duplicate;
+                }
+                Message {
+                    headers: message.headers.clone(),
+                    content: Value::Object(response.clone()).to_json().into_bytes(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Compresses large messages to clients that accept it with DEFLATE, see the README
+#[derive(Debug)]
+pub struct Compression {
+    /// Only messages with more content than this many bytes are compressed
+    threshold: usize,
+    /// Whether the client sent an `Accept-Encoding: deflate` header
+    accepted: AtomicBool,
+}
+
+impl Compression {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            accepted: AtomicBool::new(false),
+        }
+    }
+}
+
+impl MessageFilter for Compression {
+    fn filter(&self, direction: Direction, mut message: Message) -> Option<Message> {
+        match direction {
+            Direction::ClientToServer => {
+                // the header is meant for the proxy, not the server
+                let headers = message.headers.len();
+                message.headers.retain(|header| !accepts_deflate(header));
+                if message.headers.len() != headers {
+                    self.accepted.store(true, Ordering::SeqCst);
+                }
+            }
+            Direction::ServerToClient => {
+                if message.content.len() > self.threshold && self.accepted.load(Ordering::SeqCst) {
+                    message.content = deflate::compress(&message.content);
+                    message
+                        .headers
+                        .push(String::from("Content-Encoding: deflate"));
+                }
+            }
+        }
+        Some(message)
+    }
+}
+
+/// Whether `header` is an `Accept-Encoding` header listing `deflate`
+fn accepts_deflate(header: &str) -> bool {
+    match header.split_once(':') {
+        Some((name, value)) => {
+            name.trim().eq_ignore_ascii_case("Accept-Encoding")
+                && value
+                    .split(',')
+                    .any(|encoding| encoding.trim().eq_ignore_ascii_case("deflate"))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message {
+            headers: Vec::new(),
+            content: content.as_bytes().to_vec(),
+        }
+    }
+
+    fn content(message: &Message) -> &str {
+        std::str::from_utf8(&message.content).unwrap()
+    }
+
+    fn symbols_request(id: u64, uri: &str, extra_params: &str) -> Message {
+        message(&format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/documentSymbol","params":{{"textDocument":{{"uri":{}}}{}}}}}"#,
+            id,
+            json::quote(uri),
+            extra_params
+        ))
+    }
+
+    /// Relay `request` to the server, and its `response` back
+    fn round_trip(filter: &dyn MessageFilter, request: Message, response: &str) {
+        assert!(filter.reject(Direction::ClientToServer, &request).is_none());
+        assert!(filter.filter(Direction::ClientToServer, request).is_some());
+        assert!(filter
+            .filter(Direction::ServerToClient, message(response))
+            .is_some());
+    }
+
+    fn symbols_cache() -> ResponseCache {
+        let cache = ResponseCache::new(vec![String::from("textDocument/documentSymbol")]);
+        round_trip(
+            &cache,
+            symbols_request(1, "file:///a.java", r#","workDoneToken":"w1""#),
+            r#"{"jsonrpc":"2.0","id":1,"result":[{"name":"A"}]}"#,
+        );
+        cache
+    }
+
+    #[test]
+    fn answers_repeated_requests_from_the_cache() {
+        let cache = symbols_cache();
+        // a new progress token doesn't make the request any different
+        let request = symbols_request(2, "file:///a.java", r#","workDoneToken":"w2""#);
+        let response = cache.reject(Direction::ClientToServer, &request).unwrap();
+        assert_eq!(
+            content(&response),
+            r#"{"jsonrpc":"2.0","id":2,"result":[{"name":"A"}]}"#
+        );
+        let request = symbols_request(3, "file:///a.java", "");
+        assert!(cache.reject(Direction::ClientToServer, &request).is_some());
+        // responses from the server are relayed to the client, not answered
+        assert!(cache.reject(Direction::ServerToClient, &request).is_none());
+    }
+
+    #[test]
+    fn relays_other_requests() {
+        let cache = symbols_cache();
+        let other_document = symbols_request(2, "file:///b.java", "");
+        assert!(cache
+            .reject(Direction::ClientToServer, &other_document)
+            .is_none());
+        let other_method = message(
+            r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.java"}}}"#,
+        );
+        assert!(cache
+            .reject(Direction::ClientToServer, &other_method)
+            .is_none());
+    }
+
+    #[test]
+    fn invalidates_results_on_changes() {
+        let cache = symbols_cache();
+        let did_change = |uri: &str| {
+            message(&format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":{},"version":2}},"contentChanges":[]}}}}"#,
+                json::quote(uri)
+            ))
+        };
+        let request = symbols_request(2, "file:///a.java", "");
+
+        cache.filter(Direction::ClientToServer, did_change("file:///b.java"));
+        assert!(cache.reject(Direction::ClientToServer, &request).is_some());
+
+        cache.filter(Direction::ClientToServer, did_change("file:///a.java"));
+        assert!(cache.reject(Direction::ClientToServer, &request).is_none());
+
+        // notifications not about a document may affect any result
+        round_trip(
+            &cache,
+            symbols_request(3, "file:///a.java", ""),
+            r#"{"jsonrpc":"2.0","id":3,"result":[]}"#,
+        );
+        assert!(cache.reject(Direction::ClientToServer, &request).is_some());
+        cache.filter(
+            Direction::ClientToServer,
+            message(r#"{"jsonrpc":"2.0","method":"workspace/didChangeConfiguration","params":{}}"#),
+        );
+        assert!(cache.reject(Direction::ClientToServer, &request).is_none());
+    }
+
+    #[test]
+    fn caches_neither_failures_nor_partial_results() {
+        let cache = ResponseCache::new(vec![String::from("textDocument/documentSymbol")]);
+        let request = symbols_request(9, "file:///a.java", "");
+        round_trip(
+            &cache,
+            symbols_request(1, "file:///a.java", ""),
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32801,"message":"modified"}}"#,
+        );
+        assert!(cache.reject(Direction::ClientToServer, &request).is_none());
+        round_trip(
+            &cache,
+            symbols_request(2, "file:///a.java", r#","partialResultToken":"p""#),
+            r#"{"jsonrpc":"2.0","id":2,"result":[]}"#,
+        );
+        assert!(cache.reject(Direction::ClientToServer, &request).is_none());
+    }
+
+    #[test]
+    fn rejects_denied_requests_and_drops_denied_notifications() {
+        let deny = DenyMethods {
+            methods: vec![String::from("workspace/executeCommand")],
+        };
+        let request = message(
+            r#"{"jsonrpc":"2.0","id":"7","method":"workspace/executeCommand","params":{"command":"rm"}}"#,
+        );
+        let response = deny.reject(Direction::ClientToServer, &request).unwrap();
+        assert_eq!(
+            content(&response),
+            format!(
+                r#"{{"jsonrpc":"2.0","id":"7","error":{{"code":{},"message":"workspace/executeCommand is not allowed by the proxy"}}}}"#,
+                METHOD_NOT_FOUND
+            )
+        );
+        let notification =
+            message(r#"{"jsonrpc":"2.0","method":"workspace/executeCommand","params":{}}"#);
+        assert!(deny
+            .reject(Direction::ClientToServer, &notification)
+            .is_none());
+        assert!(deny
+            .filter(Direction::ClientToServer, notification)
+            .is_none());
+
+        let allowed =
+            message(r#"{"jsonrpc":"2.0","id":8,"method":"textDocument/hover","params":{}}"#);
+        assert!(deny.reject(Direction::ClientToServer, &allowed).is_none());
+        assert!(deny.filter(Direction::ClientToServer, allowed).is_some());
+        // only the client is kept from calling the methods
+        assert!(deny.reject(Direction::ServerToClient, &request).is_none());
+        assert!(deny.filter(Direction::ServerToClient, request).is_some());
+    }
+
+    #[test]
+    fn maps_uris_between_the_roots() {
+        let mapping: UriMapping = "file:///home/dev/project=file:///srv/project"
+            .parse()
+            .unwrap();
+        let request = message(
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"rootUri":"file:///home/dev/project","workspaceFolders":[{"uri":"file:///home/dev/project/lib","name":"file:///home/dev/project/lib"}],"textDocument":{"uri":"file:///home/dev/other/A.java"}}}"#,
+        );
+        let mapped = mapping.filter(Direction::ClientToServer, request).unwrap();
+        assert_eq!(
+            content(&mapped),
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"rootUri":"file:///srv/project","workspaceFolders":[{"uri":"file:///srv/project/lib","name":"file:///home/dev/project/lib"}],"textDocument":{"uri":"file:///home/dev/other/A.java"}}}"#
+        );
+
+        let response = message(
+            r#"{"jsonrpc":"2.0","id":2,"result":[{"targetUri":"file:///srv/project/src/A.java"}]}"#,
+        );
+        let mapped = mapping.filter(Direction::ServerToClient, response).unwrap();
+        assert_eq!(
+            content(&mapped),
+            r#"{"jsonrpc":"2.0","id":2,"result":[{"targetUri":"file:///home/dev/project/src/A.java"}]}"#
+        );
+    }
+
+    #[test]
+    fn relays_content_that_is_not_utf8_as_it_is() {
+        let mapping: UriMapping = "file:///home/dev/project=file:///srv/project"
+            .parse()
+            .unwrap();
+        let mut content =
+            br#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///home/dev/project/A.java","text":"x"#.to_vec();
+        content.extend_from_slice(b"\xff\xfe\"}}}");
+        let relayed = mapping
+            .filter(
+                Direction::ClientToServer,
+                Message {
+                    headers: Vec::new(),
+                    content: content.clone(),
+                },
+            )
+            .unwrap();
+        assert_eq!(relayed.content, content);
+    }
+
+    fn hover_request(id: u64, line: u64) -> Message {
+        message(&format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/hover","params":{{"position":{{"line":{},"character":0}}}}}}"#,
+            id, line
+        ))
+    }
+
+    fn hover_coalescing() -> RequestCoalescing {
+        RequestCoalescing::new(vec![String::from("textDocument/hover")])
+    }
+
+    #[test]
+    fn answers_duplicate_requests_with_copies_of_the_response() {
+        let coalescing = hover_coalescing();
+        assert!(coalescing
+            .filter(Direction::ClientToServer, hover_request(1, 3))
+            .is_some());
+        assert!(coalescing
+            .filter(Direction::ClientToServer, hover_request(2, 3))
+            .is_none());
+        assert!(coalescing
+            .filter(Direction::ClientToServer, hover_request(3, 3))
+            .is_none());
+        // requests with other parameters are not duplicates
+        assert!(coalescing
+            .filter(Direction::ClientToServer, hover_request(4, 5))
+            .is_some());
+
+        let response = message(r#"{"jsonrpc":"2.0","id":1,"result":{"contents":"int x"}}"#);
+        let copies = coalescing.fan_out(Direction::ServerToClient, &response);
+        let copies: Vec<_> = copies.iter().map(content).collect();
+        assert_eq!(
+            copies,
+            [
+                r#"{"jsonrpc":"2.0","id":2,"result":{"contents":"int x"}}"#,
+                r#"{"jsonrpc":"2.0","id":3,"result":{"contents":"int x"}}"#,
+            ]
+        );
+        assert!(coalescing
+            .filter(Direction::ServerToClient, response)
+            .is_some());
+
+        // once answered, the request is relayed again
+        assert!(coalescing
+            .filter(Direction::ClientToServer, hover_request(5, 3))
+            .is_some());
+        let response = message(r#"{"jsonrpc":"2.0","id":4,"result":null}"#);
+        assert!(coalescing
+            .fan_out(Direction::ServerToClient, &response)
+            .is_empty());
+    }
+
+    #[test]
+    fn answers_the_cancellation_of_duplicates() {
+        let coalescing = hover_coalescing();
+        coalescing.filter(Direction::ClientToServer, hover_request(1, 3));
+        coalescing.filter(Direction::ClientToServer, hover_request(2, 3));
+
+        let cancel = |id: u64| {
+            message(&format!(
+                r#"{{"jsonrpc":"2.0","method":"$/cancelRequest","params":{{"id":{}}}}}"#,
+                id
+            ))
+        };
+        // the server cancels the relayed request itself
+        assert!(coalescing
+            .reject(Direction::ClientToServer, &cancel(1))
+            .is_none());
+        let response = coalescing
+            .reject(Direction::ClientToServer, &cancel(2))
+            .unwrap();
+        assert_eq!(
+            content(&response),
+            format!(
+                r#"{{"jsonrpc":"2.0","id":2,"error":{{"code":{},"message":"the request was cancelled"}}}}"#,
+                REQUEST_CANCELLED
+            )
+        );
+
+        let response = message(r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        assert!(coalescing
+            .fan_out(Direction::ServerToClient, &response)
+            .is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::arguments::ClientVersion;
+use crate::error::OutdatedClient;
+use crate::json::Value;
+use crate::lsp::{Message, MessageReader, MESSAGE_TYPE_WARNING};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a new client to send its `initialize` request
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest `initialize` request looked at, longer ones pass without being checked
+const MAX_INITIALIZE_LEN: usize = 1024 * 1024;
+
+/// The oldest client versions to serve and to serve without a warning
+#[derive(Debug, Default)]
+pub struct ClientGate {
+    /// Clients older than these are refused
+    pub required: Vec<ClientVersion>,
+    /// Clients older than these are served, but asked to update
+    pub recommended: Vec<ClientVersion>,
+}
+
+/// Whether a client passed the gate
+pub enum Verdict {
+    Pass,
+    Warn(OutdatedClient),
+    Refuse(OutdatedClient),
+}
+
+impl ClientGate {
+    /// Check the `clientInfo` of the `initialize` request the client on `con` starts with,
+    /// before any server is started for it
+    ///
+    /// The request is only peeked at, so that it is still relayed to the server.
+    /// Clients not reporting their name and version pass.
+    pub fn check(&self, con: &TcpStream) -> Verdict {
+        if self.required.is_empty() && self.recommended.is_empty() {
+            return Verdict::Pass;
+        }
+        let (name, version) = match peek_initialize(con).as_ref().and_then(client_info) {
+            Some(client_info) => client_info,
+            None => return Verdict::Pass,
+        };
+        let outdated = |minimums: &[ClientVersion]| {
+            minimums
+                .iter()
+                .find(|minimum| minimum.name == name && minimum.is_newer_than(&version))
+                .map(|minimum| OutdatedClient {
+                    name: name.clone(),
+                    version: version.clone(),
+                    minimum: minimum.version.clone(),
+                })
+        };
+        if let Some(outdated) = outdated(&self.required) {
+            Verdict::Refuse(outdated)
+        } else if let Some(outdated) = outdated(&self.recommended) {
+            Verdict::Warn(outdated)
+        } else {
+            Verdict::Pass
+        }
+    }
+}
+
+/// Ask the user of the client on `con` to update it, which is served nonetheless
+pub fn warn(mut con: &TcpStream, outdated: &OutdatedClient) {
+    let _ = Message::show_message(MESSAGE_TYPE_WARNING, &outdated.to_string()).write_to(&mut con);
+}
+
+/// The name and version of the client in its `initialize` request
+fn client_info(message: &Message) -> Option<(String, String)> {
+    let request = Value::parse(&String::from_utf8_lossy(&message.content))?;
+    if request.get("method")?.as_str()? != "initialize" {
+        return None;
+    }
+    let client_info = request.pointer(&["params", "clientInfo"])?;
+    Some((
+        client_info.get("name")?.as_str()?.to_string(),
+        client_info.get("version")?.as_str()?.to_string(),
+    ))
+}
+
+/// The first message the client sends, without consuming it,
+/// or nothing if it doesn't send a complete one in time
+fn peek_initialize(con: &TcpStream) -> Option<Message> {
+    if con.set_read_timeout(Some(INITIALIZE_TIMEOUT)).is_err() {
+        return None;
+    }
+    let message = peek_message(con);
+    let _ = con.set_read_timeout(None);
+    message
+}
+
+fn peek_message(con: &TcpStream) -> Option<Message> {
+    let deadline = Instant::now() + INITIALIZE_TIMEOUT;
+    let mut buffer = vec![0; 4096];
+    loop {
+        let len = con.peek(&mut buffer).ok()?;
+        if len == 0 {
+            return None;
+        }
+        let mut reader = MessageReader::with_max_content_length(&buffer[..len], MAX_INITIALIZE_LEN);
+        match reader.read_message() {
+            Ok(message) => return message,
+            // only part of the message has arrived so far
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {}
+            Err(_) => return None,
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        if len == buffer.len() {
+            buffer.resize(buffer.len() * 2, 0);
+        } else {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
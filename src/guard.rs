@@ -0,0 +1,393 @@
+use crate::arguments::SubnetQuota;
+use crate::error::GuardrailError;
+use crate::json::Value;
+use crate::lsp::{Message, MessageReader, MESSAGE_TYPE_ERROR};
+use crate::shutdown;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+pub const MIB: u64 = 1024 * 1024;
+
+/// How long a refused client has to send its first request before the connection is closed
+const REFUSAL_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `RequestFailed` error code of LSP
+const REQUEST_FAILED: i32 = -32803;
+
+/// Thresholds below which new sessions are refused instead of failing to spawn their server
+#[derive(Debug, Default)]
+pub struct Guardrails {
+    /// The free space in bytes required in each of `directories`
+    pub min_free_disk: Option<u64>,
+    /// The directories servers and synchronized workspaces write to
+    pub directories: Vec<PathBuf>,
+    pub min_free_fds: Option<u64>,
+    /// The available memory in bytes required, on the host and within the limit of the cgroup of the proxy
+    pub min_free_memory: Option<u64>,
+    /// How many sessions a single client IP may have at once
+    pub max_sessions_per_client: Option<usize>,
+    /// The number of sessions of each client IP, while limited
+    pub client_sessions: Mutex<HashMap<IpAddr, usize>>,
+    /// How many sessions the clients of a network may have at once, the first quota containing a client applies
+    pub subnet_quotas: Vec<SubnetQuota>,
+    /// The number of sessions counted against each of the `subnet_quotas`
+    pub subnet_sessions: Mutex<Vec<usize>>,
+}
+
+/// A session admitted by the guardrails, counted against the quotas of its client and its subnet until dropped
+pub struct Admission {
+    guardrails: Arc<Guardrails>,
+    client: Option<IpAddr>,
+    /// The index of the subnet quota counted against
+    subnet: Option<usize>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(subnet) = self.subnet {
+            self.guardrails.lock_subnet_sessions()[subnet] -= 1;
+        }
+        if let Some(client) = self.client {
+            let mut sessions = self.guardrails.lock_client_sessions();
+            if let Some(count) = sessions.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    sessions.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+impl Guardrails {
+    /// Check whether another session of `client` may start, counting it against the quotas of the client and its subnet
+    ///
+    /// Sessions of unknown clients are not limited by the quotas
+    pub fn admit(self: &Arc<Self>, client: Option<IpAddr>) -> Result<Admission, GuardrailError> {
+        if shutdown::in_maintenance() {
+            return Err(GuardrailError::Maintenance);
+        }
+        self.check()?;
+        // dropping the admission on a refusal releases the quotas counted against so far
+        let mut admission = Admission {
+            guardrails: self.clone(),
+            client: None,
+            subnet: None,
+        };
+        let client = match client {
+            Some(client) => client,
+            None => return Ok(admission),
+        };
+        if let Some(index) = self
+            .subnet_quotas
+            .iter()
+            .position(|quota| quota.network.contains(client))
+        {
+            let quota = &self.subnet_quotas[index];
+            let mut sessions = self.lock_subnet_sessions();
+            if sessions[index] >= quota.limit {
+                return Err(GuardrailError::SubnetQuotaExhausted {
+                    network: quota.network.to_string(),
+                    limit: quota.limit,
+                });
+            }
+            sessions[index] += 1;
+            admission.subnet = Some(index);
+        }
+        if let Some(limit) = self.max_sessions_per_client {
+            let mut sessions = self.lock_client_sessions();
+            let count = sessions.entry(client).or_insert(0);
+            if *count >= limit {
+                return Err(GuardrailError::TooManyClientSessions { client, limit });
+            }
+            *count += 1;
+            admission.client = Some(client);
+        }
+        Ok(admission)
+    }
+
+    /// The number of sessions counted against each of the subnet quotas, in order
+    pub fn subnet_sessions(&self) -> Vec<usize> {
+        self.lock_subnet_sessions().clone()
+    }
+
+    fn lock_subnet_sessions(&self) -> MutexGuard<'_, Vec<usize>> {
+        // a poisoned lock only means another thread panicked, the counts are still valid
+        self.subnet_sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_client_sessions(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        // a poisoned lock only means another thread panicked, the counts are still valid
+        self.client_sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Check whether there are enough resources left for another session
+    fn check(&self) -> Result<(), GuardrailError> {
+        if let Some(required) = self.min_free_disk {
+            for path in &self.directories {
+                match imp::free_disk(path) {
+                    Some(available) if available < required => {
+                        return Err(GuardrailError::LowDiskSpace {
+                            path: path.clone(),
+                            available,
+                            required,
+                        })
+                    }
+                    Some(_) => {}
+                    None => debug!(
+                        "Unable to determine the free disk space in {}",
+                        path.display()
+                    ),
+                }
+            }
+        }
+        if let Some(required) = self.min_free_fds {
+            match imp::free_fds() {
+                Some(available) if available < required => {
+                    return Err(GuardrailError::LowFileDescriptors {
+                        available,
+                        required,
+                    })
+                }
+                Some(_) => {}
+                None => debug!("Unable to determine the number of available file descriptors"),
+            }
+        }
+        if let Some(required) = self.min_free_memory {
+            match imp::free_memory() {
+                Some(available) if available < required => {
+                    return Err(GuardrailError::LowMemory {
+                        available,
+                        required,
+                    })
+                }
+                Some(_) => {}
+                None => debug!("Unable to determine the available memory"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Raise the soft limit on open file descriptors to the hard limit, as every session takes several,
+/// e.g. for the connections to the client and the server and the pipes of the server
+pub fn raise_fd_limit() {
+    match imp::raise_fd_limit() {
+        Ok(Some((from, to))) => info!(
+            "Raised the limit on open file descriptors from {} to {}",
+            from, to
+        ),
+        Ok(None) => {}
+        // some platforms have a lower limit than the hard limit claims, the current one still works
+        Err(err) => debug!(
+            "Failed to raise the limit on open file descriptors: {}",
+            err
+        ),
+    }
+}
+
+/// The memory in bytes of the host or the limit of the cgroup of the proxy, whichever is less, if known
+pub fn total_memory() -> Option<u64> {
+    imp::total_memory()
+}
+
+/// Tell the client why its session is refused
+///
+/// The error is shown to the user via `window/showMessage`,
+/// and the client's first request, usually `initialize`, fails with it.
+pub fn refuse(mut client_con: TcpStream, reason: &impl Display) {
+    let reason = format!("The language server is unavailable: {}", reason);
+    let notification = Message::show_message(MESSAGE_TYPE_ERROR, &reason);
+    if notification.write_to(&mut client_con).is_err() {
+        return;
+    }
+
+    if client_con
+        .set_read_timeout(Some(REFUSAL_READ_TIMEOUT))
+        .is_err()
+    {
+        return;
+    }
+    let reader = match client_con.try_clone() {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+    let mut reader = MessageReader::new(reader);
+    while let Ok(Some(message)) = reader.read_message() {
+        let id = Value::parse(&String::from_utf8_lossy(&message.content))
+            .and_then(|request| request.get("id").cloned());
+        // notifications have no id and can't be answered
+        if let Some(id) = id {
+            let response = Message::error_response(&id, REQUEST_FAILED, &reason);
+            let _ = response.write_to(&mut client_con);
+            break;
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// The free space in bytes available to unprivileged users on the file system of `path`
+    pub fn free_disk(path: &Path) -> Option<u64> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        // Safety: statvfs is plain old data, zeroed is a valid value
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // Safety: path is a valid nul-terminated string and stat a valid statvfs to fill
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+    }
+
+    /// The number of file descriptors the process may still open
+    pub fn free_fds() -> Option<u64> {
+        // Safety: rlimit is plain old data, zeroed is a valid value
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        // Safety: limit is a valid rlimit to fill
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return None;
+        }
+        if limit.rlim_cur == libc::RLIM_INFINITY {
+            return Some(u64::MAX);
+        }
+        let open = std::fs::read_dir("/proc/self/fd")
+            .or_else(|_| std::fs::read_dir("/dev/fd"))
+            .ok()?
+            .count() as u64;
+        Some((limit.rlim_cur as u64).saturating_sub(open))
+    }
+
+    /// The memory in bytes available without swapping, on the host or within the limit of the cgroup
+    /// of the proxy, which the servers it spawns share, whichever is less
+    pub fn free_memory() -> Option<u64> {
+        // including the caches the kernel drops when memory is needed
+        let host = meminfo("MemAvailable:")?;
+        let cgroup = cgroup_memory().map(|(limit, usage)| limit.saturating_sub(usage));
+        Some(cgroup.map_or(host, |cgroup| cgroup.min(host)))
+    }
+
+    /// The memory in bytes of the host or the limit of the cgroup of the proxy, whichever is less
+    pub fn total_memory() -> Option<u64> {
+        let host = meminfo("MemTotal:")?;
+        Some(cgroup_memory().map_or(host, |(limit, _)| limit.min(host)))
+    }
+
+    /// The value in bytes of the `field` of /proc/meminfo, which lists KiB
+    fn meminfo(field: &str) -> Option<u64> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let kib = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(field))?
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        Some(kib.saturating_mul(1024))
+    }
+
+    /// The memory limit of the cgroup of the proxy and its usage in bytes, if it has a limit
+    fn cgroup_memory() -> Option<(u64, u64)> {
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        for line in cgroups.lines() {
+            // `0::/path` for cgroup v2, `4:memory:/path` for the memory controller of cgroup v1
+            let mut fields = line.splitn(3, ':');
+            let (controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(_), Some(controllers), Some(path)) => (controllers, path),
+                _ => continue,
+            };
+            let (root, limit, usage) = match controllers {
+                "" => ("/sys/fs/cgroup", "memory.max", "memory.current"),
+                _ if controllers
+                    .split(',')
+                    .any(|controller| controller == "memory") =>
+                {
+                    (
+                        "/sys/fs/cgroup/memory",
+                        "memory.limit_in_bytes",
+                        "memory.usage_in_bytes",
+                    )
+                }
+                _ => continue,
+            };
+            // in containers the cgroup of the proxy is usually mounted as the root
+            let directory = Path::new(root).join(path.trim_start_matches('/'));
+            let directory = if directory.join(limit).is_file() {
+                directory
+            } else {
+                Path::new(root).to_path_buf()
+            };
+            let read = |file: &str| {
+                std::fs::read_to_string(directory.join(file))
+                    .ok()?
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            };
+            // an unlimited cgroup v2 has the limit `max`
+            if let (Some(limit), Some(usage)) = (read(limit), read(usage)) {
+                return Some((limit, usage));
+            }
+        }
+        None
+    }
+
+    /// Raise the soft limit on open file descriptors to the hard limit,
+    /// returning the previous and the new limit if it was lower
+    pub fn raise_fd_limit() -> std::io::Result<Option<(u64, u64)>> {
+        // Safety: rlimit is plain old data, zeroed is a valid value
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        // Safety: limit is a valid rlimit to fill
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if limit.rlim_cur >= limit.rlim_max {
+            return Ok(None);
+        }
+        let previous = limit.rlim_cur;
+        limit.rlim_cur = limit.rlim_max;
+        // Safety: limit is a valid rlimit
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Some((previous as u64, limit.rlim_cur as u64)))
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::path::Path;
+
+    pub fn free_disk(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn free_fds() -> Option<u64> {
+        None
+    }
+
+    pub fn free_memory() -> Option<u64> {
+        None
+    }
+
+    pub fn total_memory() -> Option<u64> {
+        None
+    }
+
+    pub fn raise_fd_limit() -> std::io::Result<Option<(u64, u64)>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::TcpListener;
+use std::process::Child;
+
+/// Environment variable naming the comma separated file descriptors of inherited lsp listeners
+const LISTENER_FDS_VAR: &str = "LSP_INHERITED_LISTENER_FDS";
+/// Environment variable naming the file descriptor of an inherited http listener
+const HTTP_LISTENER_FD_VAR: &str = "LSP_INHERITED_HTTP_LISTENER_FD";
+/// Environment variable naming the file descriptor of an inherited multiplexing listener
+const MUX_LISTENER_FD_VAR: &str = "LSP_INHERITED_MUX_LISTENER_FD";
+
+/// Take over the lsp listeners handed to us by a previous instance or by systemd socket activation
+pub fn inherited_listeners() -> Vec<TcpListener> {
+    let listeners = imp::inherited(LISTENER_FDS_VAR);
+    if listeners.is_empty() {
+        imp::systemd_listeners()
+    } else {
+        listeners
+    }
+}
+
+/// Take over the http listener handed to us by a previous instance
+pub fn inherited_http_listener() -> Option<TcpListener> {
+    imp::inherited(HTTP_LISTENER_FD_VAR).pop()
+}
+
+/// Take over the multiplexing listener handed to us by a previous instance
+pub fn inherited_mux_listener() -> Option<TcpListener> {
+    imp::inherited(MUX_LISTENER_FD_VAR).pop()
+}
+
+/// Start a new instance of the (possibly updated) executable with the same arguments
+/// handing it our listening sockets, so that it can accept connections while we drain ours
+pub fn spawn_successor(
+    listeners: &[TcpListener],
+    http_listener: Option<&TcpListener>,
+    mux_listener: Option<&TcpListener>,
+) -> std::io::Result<Child> {
+    imp::spawn_successor(listeners, http_listener, mux_listener)
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{HTTP_LISTENER_FD_VAR, LISTENER_FDS_VAR, MUX_LISTENER_FD_VAR};
+    use log::{info, warn};
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command};
+
+    /// The first file descriptor passed by systemd socket activation, see sd_listen_fds(3)
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    pub fn inherited(var: &str) -> Vec<TcpListener> {
+        let value = match std::env::var(var) {
+            Ok(value) => value,
+            Err(_) => return Vec::new(),
+        };
+        // don't pass the descriptors on to our own children
+        std::env::remove_var(var);
+
+        let mut listeners = Vec::new();
+        for fd in value.split(',') {
+            match fd.trim().parse::<RawFd>() {
+                Ok(fd) => {
+                    info!("Taking over listener from file descriptor {}", fd);
+                    listeners.push(adopt(fd));
+                }
+                Err(err) => warn!(
+                    "Ignoring invalid file descriptor {:?} in {}: {}",
+                    fd, var, err
+                ),
+            }
+        }
+        listeners
+    }
+
+    pub fn systemd_listeners() -> Vec<TcpListener> {
+        let (pid, fds) = match (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) {
+            (Ok(pid), Ok(fds)) => (pid, fds),
+            _ => return Vec::new(),
+        };
+        if pid.parse() != Ok(std::process::id()) {
+            return Vec::new();
+        }
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        let fds = fds.parse::<RawFd>().unwrap_or(0);
+        info!(
+            "Using {} listeners passed by systemd socket activation",
+            fds
+        );
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+            .map(adopt)
+            .collect()
+    }
+
+    fn adopt(fd: RawFd) -> TcpListener {
+        // Safety: the descriptor was explicitly passed to us as a listening socket
+        // and nothing else in this process refers to it
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // exec does not close the inherited descriptor, so it is our job to keep it from leaking again
+        // Safety: fcntl on an owned descriptor has no memory safety implications
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+        listener
+    }
+
+    pub fn spawn_successor(
+        listeners: &[TcpListener],
+        http_listener: Option<&TcpListener>,
+        mux_listener: Option<&TcpListener>,
+    ) -> std::io::Result<Child> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(std::env::args_os().skip(1));
+
+        let mut fds: Vec<RawFd> = listeners.iter().map(AsRawFd::as_raw_fd).collect();
+        let fd_list: Vec<String> = fds.iter().map(ToString::to_string).collect();
+        command.env(LISTENER_FDS_VAR, fd_list.join(","));
+        if let Some(http_listener) = http_listener {
+            fds.push(http_listener.as_raw_fd());
+            command.env(HTTP_LISTENER_FD_VAR, http_listener.as_raw_fd().to_string());
+        }
+        if let Some(mux_listener) = mux_listener {
+            fds.push(mux_listener.as_raw_fd());
+            command.env(MUX_LISTENER_FD_VAR, mux_listener.as_raw_fd().to_string());
+        }
+
+        // Safety: only the async-signal-safe fcntl is called between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                for &fd in &fds {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        command.spawn()
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::net::TcpListener;
+    use std::process::Child;
+
+    pub fn inherited(_var: &str) -> Vec<TcpListener> {
+        Vec::new()
+    }
+
+    pub fn systemd_listeners() -> Vec<TcpListener> {
+        Vec::new()
+    }
+
+    pub fn spawn_successor(
+        _listeners: &[TcpListener],
+        _http_listener: Option<&TcpListener>,
+        _mux_listener: Option<&TcpListener>,
+    ) -> std::io::Result<Child> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "handing off listeners is only supported on unix",
+        ))
+    }
+}
@@ -0,0 +1,67 @@
+use crate::error::ServerError;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+/// Commands run around the lifetime of each language server, for site specific setup and cleanup
+///
+/// Each gets the id of the session as `LSP_SESSION_ID` and the port of the server as `LSP_SERVER_PORT`,
+/// the post-exit hook also the pid of the server as `LSP_SERVER_PID`
+/// and its exit code as `LSP_SERVER_EXIT_CODE`, unless it was ended by a signal.
+#[derive(Debug, Default)]
+pub struct ServerHooks {
+    /// Run before each server is spawned, which is only spawned if this succeeds
+    pub pre_spawn: Option<PathBuf>,
+    /// Run once each server exited
+    pub post_exit: Option<PathBuf>,
+}
+
+impl ServerHooks {
+    /// Run the pre-spawn hook for the server of session `session_id` about to be spawned on `port`,
+    /// failing unless it succeeds
+    pub fn pre_spawn(&self, session_id: u64, port: u16) -> Result<(), ServerError> {
+        let hook = match &self.pre_spawn {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        run(hook, &instance_env(session_id, port)).map_err(ServerError::PreSpawnHookFailed)
+    }
+
+    /// Run the post-exit hook for the server with `pid` of session `session_id` on `port`,
+    /// which exited with `status`, logging if it fails
+    pub fn post_exit(&self, session_id: u64, port: u16, pid: u32, status: ExitStatus) {
+        let hook = match &self.post_exit {
+            Some(hook) => hook,
+            None => return,
+        };
+        let mut env = instance_env(session_id, port);
+        env.push(("LSP_SERVER_PID", pid.to_string()));
+        if let Some(code) = status.code() {
+            env.push(("LSP_SERVER_EXIT_CODE", code.to_string()));
+        }
+        if let Err(err) = run(hook, &env) {
+            warn!("The post-exit hook for the server with pid {} {}", pid, err);
+        }
+    }
+}
+
+fn instance_env(session_id: u64, port: u16) -> Vec<(&'static str, String)> {
+    vec![
+        ("LSP_SESSION_ID", session_id.to_string()),
+        ("LSP_SERVER_PORT", port.to_string()),
+    ]
+}
+
+/// Run `hook` with the additional `env` and wait for it, describing how it failed if it did
+fn run(hook: &Path, env: &[(&'static str, String)]) -> Result<(), String> {
+    debug!("Running hook {} with {:?}", hook.display(), env);
+    let status = Command::new(hook)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .status()
+        .map_err(|err| format!("could not be run: {}", err))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("failed with {}", status))
+    }
+}
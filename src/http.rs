@@ -0,0 +1,396 @@
+use crate::error::ParseHttpEndpointError;
+use log::{debug, error, warn};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The parts of an HTTP request the handlers care about
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The value of the `Authorization` header, if any
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found\n")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// How long a client may take to send its request, however slowly it trickles in
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADER_LINES: usize = 100;
+/// How long the request line and each header line may be
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// How long the request line and the headers may be together
+const MAX_HEAD_LENGTH: usize = 32 * 1024;
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+/// How many requests are handled at once, further connections are refused until one is answered
+const MAX_CONCURRENT_REQUESTS: usize = 32;
+
+/// Serve HTTP/1.1 requests on `listener` from a background thread
+///
+/// Every request is answered by `handler` on its own thread and the connection is closed afterwards.
+pub fn serve<H>(listener: TcpListener, handler: H)
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let handling = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Err(err) => error!("Failed to accept http connection: {}", err),
+                Ok(con) => {
+                    if handling.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_REQUESTS {
+                        handling.fetch_sub(1, Ordering::SeqCst);
+                        warn!(
+                            "Refusing http connection, {} requests are being handled already",
+                            MAX_CONCURRENT_REQUESTS
+                        );
+                        // the response fits into the socket's buffer, so this doesn't block accepting
+                        let _ = con.set_write_timeout(Some(REQUEST_TIMEOUT));
+                        let _ = write_response(con, &Response::text(503, "too many requests\n"));
+                        continue;
+                    }
+                    let handled = Handling(handling.clone());
+                    let handler = handler.clone();
+                    std::thread::spawn(move || {
+                        if let Err(err) = handle_request(con, &*handler) {
+                            debug!("Failed to handle http request: {}", err);
+                        }
+                        drop(handled);
+                    });
+                }
+            }
+        }
+    });
+}
+
+/// Counts a request as being handled until dropped
+struct Handling(Arc<AtomicUsize>);
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads from a connection until a deadline, rather than timing out each read on its own
+struct Deadline {
+    con: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.con.set_read_timeout(Some(remaining))?;
+        self.con.read(buf)
+    }
+}
+
+fn handle_request(con: TcpStream, handler: &dyn Fn(&Request) -> Response) -> std::io::Result<()> {
+    con.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(Deadline {
+        con: con.try_clone()?,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+
+    let (request_line, headers) = match read_head(&mut reader) {
+        Ok(head) => head,
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            debug!("Refusing http request: {}", err);
+            return write_response(con, &Response::text(431, format!("{}\n", err)));
+        }
+        Err(err) => return Err(err),
+    };
+    // only the length of the body and the authorization are needed from the headers
+    let mut content_length = 0;
+    let mut authorization = None;
+    for header in headers {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or_default();
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return write_response(con, &Response::text(413, "payload too large\n"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => {
+            let path = target.split('?').next().unwrap_or_default();
+            handler(&Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                authorization,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+        _ => {
+            warn!("Received malformed http request line: {:?}", request_line);
+            Response::text(400, "bad request\n")
+        }
+    };
+
+    write_response(con, &response)
+}
+
+/// Read the request line and the header lines of a request, all of which have to be consumed before responding
+///
+/// A head exceeding the limits on its length fails with `InvalidData`
+fn read_head(reader: &mut impl BufRead) -> std::io::Result<(String, Vec<String>)> {
+    let mut remaining = MAX_HEAD_LENGTH;
+    let request_line = read_head_line(reader, &mut remaining)?;
+    let mut headers = Vec::new();
+    loop {
+        let header = read_head_line(reader, &mut remaining)?;
+        if header.is_empty() {
+            return Ok((request_line, headers));
+        }
+        if headers.len() >= MAX_HEADER_LINES {
+            return Err(invalid_data("too many header lines"));
+        }
+        headers.push(header);
+    }
+}
+
+/// Read a line of the head without its line ending, counting it against the `remaining` length of the head
+fn read_head_line(reader: &mut impl BufRead, remaining: &mut usize) -> std::io::Result<String> {
+    let limit = MAX_LINE_LENGTH.min(*remaining);
+    let mut line = Vec::new();
+    let read = reader.take(limit as u64).read_until(b'\n', &mut line)?;
+    *remaining -= read;
+    if !line.ends_with(b"\n") {
+        return Err(if read < limit {
+            ErrorKind::UnexpectedEof.into()
+        } else if limit < MAX_LINE_LENGTH {
+            invalid_data("request header fields too large")
+        } else {
+            invalid_data("request header line too long")
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn write_response(mut con: TcpStream, response: &Response) -> std::io::Result<()> {
+    write!(
+        con,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    con.flush()
+}
+
+/// An endpoint to post requests to, e.g. an OTLP/HTTP collector
+///
+/// Only plain `http://` endpoints are supported
+#[derive(Clone)]
+pub struct HttpEndpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for HttpEndpoint {
+    type Err = ParseHttpEndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or(ParseHttpEndpointError::UnsupportedScheme)?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(ParseHttpEndpointError::MissingHost);
+        }
+        let path = match path {
+            "" => String::from("/"),
+            path => path.to_string(),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+impl HttpEndpoint {
+    /// The endpoint with `path` instead of the root path, for endpoints given without a path
+    pub fn or_path(mut self, path: &str) -> Self {
+        if self.path == "/" {
+            self.path = path.to_string();
+        }
+        self
+    }
+
+    /// The endpoint of `path` below the path of this one, e.g. of a file in a directory
+    pub fn join(&self, path: &str) -> Self {
+        Self {
+            host: self.host.clone(),
+            port: self.port,
+            path: format!(
+                "{}/{}",
+                self.path.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            ),
+        }
+    }
+}
+
+impl Display for HttpEndpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Redacts the path, which may contain a secret, like the token of a webhook
+impl Debug for HttpEndpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}/<redacted>", self.host, self.port)?;
+        Ok(())
+    }
+}
+
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a download may stall, rather than how long it may take
+const GET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Post the JSON `body` to `endpoint`, failing unless it responds with a success status
+pub fn post(endpoint: &HttpEndpoint, body: &str) -> std::io::Result<()> {
+    // IPv6 literals are bracketed in the url, but not when resolving them
+    let host = endpoint.host.trim_start_matches('[').trim_end_matches(']');
+    let mut stream = TcpStream::connect((host, endpoint.port))?;
+    stream.set_read_timeout(Some(POST_TIMEOUT))?;
+    stream.set_write_timeout(Some(POST_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.host,
+        endpoint.port,
+        body.len(),
+        body
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} responded with '{}'", endpoint, status_line),
+        )),
+    }
+}
+
+/// Get the body of `endpoint`, failing unless it responds with `200 OK`
+///
+/// Redirects are not followed.
+pub fn get(endpoint: &HttpEndpoint) -> std::io::Result<Vec<u8>> {
+    let host = endpoint.host.trim_start_matches('[').trim_end_matches(']');
+    let mut stream = TcpStream::connect((host, endpoint.port))?;
+    stream.set_read_timeout(Some(GET_TIMEOUT))?;
+    stream.set_write_timeout(Some(GET_TIMEOUT))?;
+    // HTTP/1.0 keeps servers from sending the body in chunks, it simply ends with the connection
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}:{}\r\n\r\n",
+        endpoint.path, endpoint.host, endpoint.port
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} responded without a complete header", endpoint),
+            )
+        })?;
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let status_line = header.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} responded with '{}'", endpoint, status_line),
+        ));
+    }
+    Ok(response.split_off(header_end + 4))
+}
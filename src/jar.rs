@@ -0,0 +1,424 @@
+use crate::deflate;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The file next to the binary listing the default jar per platform,
+/// for packagers shipping the proxy with another language server
+const MANIFEST_NAME: &str = "lsp_on_demand.jars";
+/// The manifest entry used for platforms without an entry of their own
+const FALLBACK_PLATFORM: &str = "default";
+
+/// How the jars of the language server for this platform are named, e.g. `kieler-language-server.linux.jar`
+const PLATFORM_SUFFIX: &str = {
+    if cfg!(target_os = "windows") {
+        ".win.jar"
+    } else if cfg!(target_os = "macos") {
+        ".osx.jar"
+    } else if cfg!(target_os = "linux") {
+        ".linux.jar"
+    } else {
+        ".unknown.jar"
+    }
+};
+/// What the name of a language server jar contains before its version, if any
+const SERVER_MARKER: &str = "-language-server";
+
+/// Where a jar keeps its manifest, which names the version of the jar
+const MANIFEST_ENTRY: &str = "META-INF/MANIFEST.MF";
+/// The attributes of the manifest naming the version, in order of preference,
+/// `Bundle-Version` for OSGi bundles like the KIELER language server
+const VERSION_ATTRIBUTES: [&str; 2] = ["Implementation-Version", "Bundle-Version"];
+const END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+const CENTRAL_DIRECTORY_HEADER: &[u8] = b"PK\x01\x02";
+const CENTRAL_DIRECTORY_HEADER_LEN: usize = 46;
+const LOCAL_FILE_HEADER: &[u8] = b"PK\x03\x04";
+const LOCAL_FILE_HEADER_LEN: usize = 30;
+/// The longest comment a zip file may end with, after the end of its central directory
+const MAX_ZIP_COMMENT_LEN: usize = 0xffff;
+
+const DEFAULT_JAR_PATH: &str = {
+    if cfg!(target_os = "windows") {
+        "./server/kieler-language-server.win.jar"
+    } else if cfg!(target_os = "macos") {
+        "./server/kieler-language-server.osx.jar"
+    } else if cfg!(target_os = "linux") {
+        "./server/kieler-language-server.linux.jar"
+    } else {
+        "./server/kieler-language-server.unknown.jar"
+    }
+};
+
+/// The jar to use when none was configured
+///
+/// That is the one listed for this platform in the manifest next to the binary,
+/// relative to the manifest, or else the KIELER language server
+pub fn default_jar() -> Result<PathBuf, String> {
+    let manifest = match std::env::current_exe() {
+        Ok(exe) => exe.with_file_name(MANIFEST_NAME),
+        Err(err) => {
+            warn!(
+                "Can't locate the binary to look for {}: {}",
+                MANIFEST_NAME, err
+            );
+            return Ok(PathBuf::from(DEFAULT_JAR_PATH));
+        }
+    };
+    let contents = match std::fs::read_to_string(&manifest) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(PathBuf::from(DEFAULT_JAR_PATH));
+        }
+        Err(err) => return Err(format!("Failed to read {}: {}", manifest.display(), err)),
+    };
+
+    let platform = std::env::consts::OS;
+    let listed = lookup(&contents, platform).map_err(|line| {
+        format!(
+            "Invalid entry in line {} of {}, expected `<platform> = <jar>`",
+            line,
+            manifest.display()
+        )
+    })?;
+    match listed {
+        Some(jar) => {
+            let jar = manifest
+                .parent()
+                .unwrap_or_else(|| Path::new("."))
+                .join(jar);
+            info!(
+                "Using the jar {} listed for {} in {}",
+                jar.display(),
+                platform,
+                manifest.display()
+            );
+            Ok(jar)
+        }
+        None => {
+            warn!(
+                "{} lists no jar for {}, using {}",
+                manifest.display(),
+                platform,
+                DEFAULT_JAR_PATH
+            );
+            Ok(PathBuf::from(DEFAULT_JAR_PATH))
+        }
+    }
+}
+
+/// The jar listed for `platform` in the manifest `contents`, or for the fallback platform,
+/// failing with the number of the first malformed line
+///
+/// Each line lists a jar as `<platform> = <jar>`, blank lines and lines starting with `#` are ignored
+fn lookup<'a>(contents: &'a str, platform: &str) -> Result<Option<&'a str>, usize> {
+    let mut fallback = None;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, jar) = match line.split_once('=') {
+            Some((key, jar)) if !key.trim().is_empty() && !jar.trim().is_empty() => {
+                (key.trim(), jar.trim())
+            }
+            _ => return Err(index + 1),
+        };
+        if key == platform {
+            return Ok(Some(jar));
+        } else if key == FALLBACK_PLATFORM {
+            fallback = Some(jar);
+        }
+    }
+    Ok(fallback)
+}
+
+/// The newest language server jar for this platform in `dir`
+///
+/// Jars are named like `*-language-server*.<platform>.jar`, the newest has the highest version,
+/// taken from the numbers following `-language-server`, or was modified last among those of the same version
+pub fn discover(dir: &Path) -> Result<PathBuf, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| format!("Failed to read jar directory {}: {}", dir.display(), err))?;
+    let mut newest: Option<((Vec<u64>, SystemTime), PathBuf)> = None;
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let version = match name.to_str().and_then(version) {
+            Some(version) => version,
+            None => continue,
+        };
+        let modified = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let key = (version, modified);
+        if newest.as_ref().map_or(true, |(newest, _)| key > *newest) {
+            newest = Some((key, entry.path()));
+        }
+    }
+
+    match newest {
+        Some((_, jar)) => {
+            info!(
+                "Using the newest jar found in {}: {}",
+                dir.display(),
+                jar.display()
+            );
+            Ok(jar)
+        }
+        None => Err(format!(
+            "Found no *{}*{} in {}",
+            SERVER_MARKER,
+            PLATFORM_SUFFIX,
+            dir.display()
+        )),
+    }
+}
+
+/// The version of the language server `jar` as its manifest names it, or else as its name includes it, if at all
+pub fn server_version(jar: &Path) -> Option<String> {
+    match manifest_version(jar) {
+        Ok(Some(version)) => return Some(version),
+        Ok(None) => {}
+        Err(err) => warn!("Failed to read the manifest of {}: {}", jar.display(), err),
+    }
+    name_version(jar)
+}
+
+/// The version named by the main section of the manifest of `jar`, if any
+fn manifest_version(jar: &Path) -> std::io::Result<Option<String>> {
+    let manifest = match read_zip_entry(jar, MANIFEST_ENTRY)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    let manifest = String::from_utf8_lossy(&manifest);
+    let attributes = main_attributes(&manifest);
+    Ok(VERSION_ATTRIBUTES.iter().find_map(|wanted| {
+        attributes
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.clone())
+    }))
+}
+
+/// The `name: value` attributes of the main section of a `manifest`, which ends at the first blank line
+///
+/// Lines starting with a space continue the value of the previous line.
+fn main_attributes(manifest: &str) -> Vec<(String, String)> {
+    let mut attributes: Vec<(String, String)> = Vec::new();
+    for line in manifest.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        match (line.strip_prefix(' '), attributes.last_mut()) {
+            (Some(continuation), Some((_, value))) => value.push_str(continuation),
+            _ => {
+                if let Some((name, value)) = line.split_once(':') {
+                    attributes.push((name.trim().to_string(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+    attributes
+}
+
+/// The contents of the entry `name` of the `zip` file, if it has one
+///
+/// Only entries that are stored or deflated, as in jars, can be read, and zip64 files are not supported.
+fn read_zip_entry(zip: &Path, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(zip)?;
+    let len = file.metadata()?.len();
+    zip_entry(&mut file, len, name)
+}
+
+/// The contents of the entry `name` of the zip `file` of `len` bytes, if it has one
+///
+/// Lengths and offsets pointing past the end of the file are rejected before anything is allocated for them.
+fn zip_entry(
+    file: &mut (impl Read + Seek),
+    len: u64,
+    name: &str,
+) -> std::io::Result<Option<Vec<u8>>> {
+    // the end of the central directory is followed by nothing but the comment of the file
+    let tail_len = len.min((END_OF_CENTRAL_DIRECTORY_LEN + MAX_ZIP_COMMENT_LEN) as u64);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = tail
+        .windows(END_OF_CENTRAL_DIRECTORY.len())
+        .rposition(|window| window == END_OF_CENTRAL_DIRECTORY)
+        .filter(|&end| end + END_OF_CENTRAL_DIRECTORY_LEN <= tail.len())
+        .ok_or_else(|| invalid_zip("it has no central directory"))?;
+    let directory_len = read_u32(&tail, end + 12);
+    let directory_offset = read_u32(&tail, end + 16);
+    if u64::from(directory_offset) + u64::from(directory_len) > len {
+        return Err(invalid_zip("its central directory is truncated"));
+    }
+
+    file.seek(SeekFrom::Start(u64::from(directory_offset)))?;
+    let mut directory = vec![0; directory_len as usize];
+    file.read_exact(&mut directory)?;
+    let mut position = 0;
+    while position + CENTRAL_DIRECTORY_HEADER_LEN <= directory.len()
+        && directory[position..].starts_with(CENTRAL_DIRECTORY_HEADER)
+    {
+        let method = read_u16(&directory, position + 10);
+        let compressed_len = read_u32(&directory, position + 20);
+        let name_len = usize::from(read_u16(&directory, position + 28));
+        let extra_len = usize::from(read_u16(&directory, position + 30));
+        let comment_len = usize::from(read_u16(&directory, position + 32));
+        let local_offset = read_u32(&directory, position + 42);
+        let name_start = position + CENTRAL_DIRECTORY_HEADER_LEN;
+        if directory.get(name_start..name_start + name_len) == Some(name.as_bytes()) {
+            return read_local_entry(file, len, local_offset, method, compressed_len).map(Some);
+        }
+        position = name_start + name_len + extra_len + comment_len;
+    }
+    Ok(None)
+}
+
+/// The contents of the entry with its local header at `offset` of the `file` of `len` bytes,
+/// compressed with `method` to `compressed_len` bytes
+fn read_local_entry(
+    file: &mut (impl Read + Seek),
+    len: u64,
+    offset: u32,
+    method: u16,
+    compressed_len: u32,
+) -> std::io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(u64::from(offset)))?;
+    let mut header = [0; LOCAL_FILE_HEADER_LEN];
+    file.read_exact(&mut header)?;
+    if !header.starts_with(LOCAL_FILE_HEADER) {
+        return Err(invalid_zip("an entry has no local header"));
+    }
+    // the local name and extra field may differ from those in the central directory
+    let skipped = u64::from(read_u16(&header, 26)) + u64::from(read_u16(&header, 28));
+    let data_offset = u64::from(offset) + LOCAL_FILE_HEADER_LEN as u64 + skipped;
+    if data_offset + u64::from(compressed_len) > len {
+        return Err(invalid_zip("an entry is truncated"));
+    }
+    file.seek(SeekFrom::Start(data_offset))?;
+    let mut compressed = vec![0; compressed_len as usize];
+    file.read_exact(&mut compressed)?;
+    match method {
+        0 => Ok(compressed),
+        8 => deflate::decompress(&compressed).ok_or_else(|| invalid_zip("an entry is corrupt")),
+        _ => Err(invalid_zip(&format!(
+            "an entry is compressed with the unsupported method {}",
+            method
+        ))),
+    }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn invalid_zip(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("not a valid jar, {}", reason),
+    )
+}
+
+/// The version the name of the language server `jar` includes, e.g. `0.4.2` for `kieler-language-server-0.4.2.linux.jar`
+fn name_version(jar: &Path) -> Option<String> {
+    let version = jar.file_name()?.to_str().and_then(version)?;
+    if version.is_empty() {
+        return None;
+    }
+    let numbers: Vec<String> = version.iter().map(u64::to_string).collect();
+    Some(numbers.join("."))
+}
+
+/// The version of the language server jar for this platform named `name`, if it is one
+///
+/// The version consists of the numbers between the server marker and the platform suffix,
+/// e.g. `[0, 4, 2]` for `kieler-language-server-0.4.2.linux.jar` and none for `kieler-language-server.linux.jar`
+fn version(name: &str) -> Option<Vec<u64>> {
+    let stem = name.strip_suffix(PLATFORM_SUFFIX)?;
+    let start = stem.find(SERVER_MARKER)? + SERVER_MARKER.len();
+    Some(
+        stem[start..]
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|number| number.parse().ok())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Jars holding nothing but `MANIFEST`, stored and deflated
+    const STORED_JAR: &[u8] = include_bytes!("../tests/fixtures/manifest-stored.jar");
+    const DEFLATED_JAR: &[u8] = include_bytes!("../tests/fixtures/manifest-deflated.jar");
+    const MANIFEST: &str = "Manifest-Version: 1.0\r\n\
+        Main-Class: de.cau.cs.kieler.language.server.LanguageServ\r\n erLauncher\r\n\
+        Bundle-Version: 0.4.2\r\n\
+        \r\n\
+        Name: lib\r\n\
+        Implementation-Version: 9\r\n\
+        \r\n";
+
+    fn entry(jar: &[u8], name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        zip_entry(&mut Cursor::new(jar), jar.len() as u64, name)
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        for jar in [STORED_JAR, DEFLATED_JAR] {
+            let manifest = entry(jar, MANIFEST_ENTRY).unwrap().unwrap();
+            assert_eq!(String::from_utf8(manifest).unwrap(), MANIFEST);
+            assert_eq!(entry(jar, "META-INF/missing").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn parses_the_main_section_of_manifests() {
+        let attribute = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            main_attributes(MANIFEST),
+            vec![
+                attribute("Manifest-Version", "1.0"),
+                attribute(
+                    "Main-Class",
+                    "de.cau.cs.kieler.language.server.LanguageServerLauncher"
+                ),
+                attribute("Bundle-Version", "0.4.2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_jars() {
+        for jar in [STORED_JAR, DEFLATED_JAR] {
+            for len in 0..jar.len() {
+                assert!(entry(&jar[..len], MANIFEST_ENTRY).is_err(), "{}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn survives_corrupt_jars() {
+        for jar in [STORED_JAR, DEFLATED_JAR] {
+            for position in 0..jar.len() {
+                for flipped in [0x01, 0x80, 0xff] {
+                    let mut corrupt = jar.to_vec();
+                    corrupt[position] ^= flipped;
+                    // whatever is left of the entry, reading it must not panic
+                    let _ = entry(&corrupt, MANIFEST_ENTRY);
+                }
+            }
+        }
+    }
+}
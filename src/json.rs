@@ -0,0 +1,571 @@
+use std::fmt::Write;
+
+/// Quote and escape `value` as a JSON string literal
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quote `value` as a JSON string literal or produce `null` if there is no value
+pub fn quote_optional(value: Option<&str>) -> String {
+    value.map_or_else(|| String::from("null"), quote)
+}
+
+/// Find the first occurrence of `"field": "<string>"` in `text` and return the unescaped string
+///
+/// This does not parse the surrounding document,
+/// so the field may be found at any nesting depth.
+pub fn find_string_field(text: &str, field: &str) -> Option<String> {
+    let key = quote(field);
+    let mut rest = text;
+    while let Some(index) = rest.find(&key) {
+        rest = &rest[index + key.len()..];
+        let value = rest.trim_start();
+        if let Some(value) = value.strip_prefix(':') {
+            if let Some(string) = parse_string(value.trim_start()) {
+                return Some(string);
+            }
+        }
+    }
+    None
+}
+
+/// Find `"field": "<string>"` among the members of the outermost object of `text`
+/// and return the unescaped string
+pub fn top_level_string_field(text: &str, field: &str) -> Option<String> {
+    parse_string(find_top_level_value(text, field)?)
+}
+
+/// Whether the outermost object of `text` has a member named `field`
+pub fn has_top_level_field(text: &str, field: &str) -> bool {
+    find_top_level_value(text, field).is_some()
+}
+
+/// Parse the value of the member `field` of the outermost object of `text`
+///
+/// Only the value itself needs to be complete, so this also works on the start of a truncated document
+pub fn top_level_value(text: &str, field: &str) -> Option<Value> {
+    parse_value(find_top_level_value(text, field)?, 0).map(|(value, _rest)| value)
+}
+
+/// Find the member `field` of the outermost object of `text` and return the text starting at its value
+fn find_top_level_value<'a>(text: &'a str, field: &str) -> Option<&'a str> {
+    let mut depth = 0;
+    let mut chars = text.char_indices();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            '"' => {
+                let mut escaped = false;
+                let mut end = None;
+                for (index, c) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == '"' {
+                        end = Some(index);
+                        break;
+                    }
+                }
+                let end = end?;
+                if depth == 1 {
+                    if let Some(value) = text[end + 1..].trim_start().strip_prefix(':') {
+                        if parse_string(&text[start..=end]).as_deref() == Some(field) {
+                            return Some(value.trim_start());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rewrite the string values of all members named one of `fields` in `text`, at any nesting depth
+///
+/// `rewrite` returns the new value of a member, or nothing to keep the current one
+pub fn rewrite_string_fields<F>(text: &str, fields: &[&str], rewrite: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    // quotes and backslashes are ascii, so working on bytes never splits a character
+    let bytes = text.as_bytes();
+    let mut rewritten = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'"' {
+            index += 1;
+            continue;
+        }
+        let key_end = match string_end(bytes, index) {
+            Some(key_end) => key_end,
+            None => break,
+        };
+        let colon = skip_whitespace(bytes, key_end + 1);
+        let value_start = skip_whitespace(bytes, colon + 1);
+        if bytes.get(colon) != Some(&b':') || bytes.get(value_start) != Some(&b'"') {
+            index = key_end + 1;
+            continue;
+        }
+        let value_end = match string_end(bytes, value_start) {
+            Some(value_end) => value_end,
+            None => break,
+        };
+
+        let is_field = parse_string(&text[index..=key_end])
+            .map_or(false, |key| fields.contains(&key.as_str()));
+        if is_field {
+            if let Some(value) =
+                parse_string(&text[value_start..=value_end]).and_then(|value| rewrite(&value))
+            {
+                rewritten.push_str(&text[copied..value_start]);
+                rewritten.push_str(&quote(&value));
+                copied = value_end + 1;
+            }
+        }
+        index = value_end + 1;
+    }
+    rewritten.push_str(&text[copied..]);
+    rewritten
+}
+
+/// The index of the quote closing the string literal starting at `start`
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut escaped = false;
+    for (index, &byte) in bytes.iter().enumerate().skip(start + 1) {
+        if escaped {
+            escaped = false;
+        } else if byte == b'\\' {
+            escaped = true;
+        } else if byte == b'"' {
+            return Some(index);
+        }
+    }
+    None
+}
+
+fn skip_whitespace(bytes: &[u8], mut index: usize) -> usize {
+    while bytes.get(index).map_or(false, u8::is_ascii_whitespace) {
+        index += 1;
+    }
+    index
+}
+
+/// Parse the JSON string literal at the start of `text`
+fn parse_string(text: &str) -> Option<String> {
+    parse_string_prefix(text).map(|(string, _rest)| string)
+}
+
+/// Parse the JSON string literal at the start of `text`, returning it and the text following it
+fn parse_string_prefix(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.chars();
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some((string, chars.as_str())),
+            '\\' => match chars.next()? {
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                '/' => string.push('/'),
+                'b' => string.push('\u{8}'),
+                'f' => string.push('\u{c}'),
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let high = parse_hex4(&mut chars)?;
+                    let code_point = if (0xD800..0xDC00).contains(&high) {
+                        // a surrogate pair encodes a code point outside the basic multilingual plane
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex4(&mut chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    } else {
+                        high
+                    };
+                    string.push(char::from_u32(code_point)?);
+                }
+                _ => return None,
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut std::str::Chars<'_>) -> Option<u32> {
+    let mut value = 0;
+    for _ in 0..4 {
+        value = value * 16 + chars.next()?.to_digit(16)?;
+    }
+    Some(value)
+}
+
+/// How deeply arrays and objects may be nested in a parsed document
+const MAX_DEPTH: usize = 128;
+
+/// A parsed JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// A number, kept as written so that no precision is lost
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// The members of an object, in the order they were written
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Parse `text` as a single JSON document
+    pub fn parse(text: &str) -> Option<Self> {
+        let (value, rest) = parse_value(text, 0)?;
+        if rest.trim_start().is_empty() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// The member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Follow `path` through nested objects
+    pub fn pointer(&self, path: &[&str]) -> Option<&Value> {
+        path.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    /// Apply a JSON merge patch (RFC 7386): `null` members of `patch` remove the member,
+    /// objects are merged recursively and any other value replaces the current one
+    pub fn merge_patch(&mut self, patch: &Value) {
+        let patch_members = match patch {
+            Self::Object(members) => members,
+            _ => {
+                *self = patch.clone();
+                return;
+            }
+        };
+        if !matches!(self, Self::Object(_)) {
+            *self = Self::Object(Vec::new());
+        }
+        if let Self::Object(members) = self {
+            for (name, value) in patch_members {
+                if *value == Self::Null {
+                    members.retain(|(member, _)| member != name);
+                } else if let Some((_, target)) =
+                    members.iter_mut().find(|(member, _)| member == name)
+                {
+                    target.merge_patch(value);
+                } else {
+                    // merged into nothing, so that nested nulls are left out
+                    let mut target = Self::Null;
+                    target.merge_patch(value);
+                    members.push((name.clone(), target));
+                }
+            }
+        }
+    }
+
+    /// Serialize the value as compact JSON
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        self.write_json(&mut json);
+        json
+    }
+
+    fn write_json(&self, json: &mut String) {
+        match self {
+            Self::Null => json.push_str("null"),
+            Self::Bool(value) => json.push_str(if *value { "true" } else { "false" }),
+            Self::Number(number) => json.push_str(number),
+            Self::String(string) => json.push_str(&quote(string)),
+            Self::Array(elements) => {
+                json.push('[');
+                for (index, element) in elements.iter().enumerate() {
+                    if index > 0 {
+                        json.push(',');
+                    }
+                    element.write_json(json);
+                }
+                json.push(']');
+            }
+            Self::Object(members) => {
+                json.push('{');
+                for (index, (name, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        json.push(',');
+                    }
+                    json.push_str(&quote(name));
+                    json.push(':');
+                    value.write_json(json);
+                }
+                json.push('}');
+            }
+        }
+    }
+}
+
+/// Parse the JSON value at the start of `text`, returning it and the text following it
+fn parse_value(text: &str, depth: usize) -> Option<(Value, &str)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let text = text.trim_start();
+    match text.chars().next()? {
+        'n' => Some((Value::Null, text.strip_prefix("null")?)),
+        't' => Some((Value::Bool(true), text.strip_prefix("true")?)),
+        'f' => Some((Value::Bool(false), text.strip_prefix("false")?)),
+        '"' => {
+            let (string, rest) = parse_string_prefix(text)?;
+            Some((Value::String(string), rest))
+        }
+        '[' => {
+            let mut elements = Vec::new();
+            let mut rest = text[1..].trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(elements), rest));
+            }
+            loop {
+                let (element, after) = parse_value(rest, depth + 1)?;
+                elements.push(element);
+                let after = after.trim_start();
+                if let Some(after) = after.strip_prefix(',') {
+                    rest = after;
+                } else {
+                    return Some((Value::Array(elements), after.strip_prefix(']')?));
+                }
+            }
+        }
+        '{' => {
+            let mut members = Vec::new();
+            let mut rest = text[1..].trim_start();
+            if let Some(rest) = rest.strip_prefix('}') {
+                return Some((Value::Object(members), rest));
+            }
+            loop {
+                let (name, after) = parse_string_prefix(rest.trim_start())?;
+                let after = after.trim_start().strip_prefix(':')?;
+                let (value, after) = parse_value(after, depth + 1)?;
+                members.push((name, value));
+                let after = after.trim_start();
+                if let Some(after) = after.strip_prefix(',') {
+                    rest = after;
+                } else {
+                    return Some((Value::Object(members), after.strip_prefix('}')?));
+                }
+            }
+        }
+        '-' | '0'..='9' => {
+            let end = text
+                .find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+                .unwrap_or(text.len());
+            let number = &text[..end];
+            if !is_number(number) {
+                return None;
+            }
+            Some((Value::Number(number.to_string()), &text[end..]))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `text` is a number as JSON writes them, e.g. `-1.5e+3` but not `01` or `1.`
+fn is_number(text: &str) -> bool {
+    let digits =
+        |text: &str| text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = text.strip_prefix('-').unwrap_or(text);
+    let integer = digits(rest);
+    if integer == 0 || (integer > 1 && rest.starts_with('0')) {
+        return false;
+    }
+    let mut rest = &rest[integer..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = digits(fraction);
+        if len == 0 {
+            return false;
+        }
+        rest = &fraction[len..];
+    }
+    if let Some(exponent) = rest.strip_prefix(|c| c == 'e' || c == 'E') {
+        let exponent = exponent
+            .strip_prefix(|c| c == '+' || c == '-')
+            .unwrap_or(exponent);
+        let len = digits(exponent);
+        if len == 0 {
+            return false;
+        }
+        rest = &exponent[len..];
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Option<String> {
+        Value::parse(text).and_then(|value| value.as_str().map(str::to_string))
+    }
+
+    #[test]
+    fn quotes_and_unescapes_strings() {
+        let text = "a\"b\\c/\n\r\t\u{1}\u{8}é😀";
+        let quoted = quote(text);
+        assert_eq!(quoted, "\"a\\\"b\\\\c/\\n\\r\\t\\u0001\\u0008é😀\"");
+        assert_eq!(string(&quoted).as_deref(), Some(text));
+        assert_eq!(
+            string(r#""\/\b\f\u00e9\u00E9""#).as_deref(),
+            Some("/\u{8}\u{c}éé")
+        );
+        assert_eq!(quote_optional(None), "null");
+    }
+
+    #[test]
+    fn decodes_surrogate_pairs() {
+        assert_eq!(string(r#""\ud83d\ude00""#).as_deref(), Some("😀"));
+        assert_eq!(string(r#""\uD83D\uDE00""#).as_deref(), Some("😀"));
+        // a lone high surrogate, one followed by no or an out of range low surrogate, and a lone low surrogate
+        assert_eq!(string(r#""\ud83d""#), None);
+        assert_eq!(string(r#""\ud83dx""#), None);
+        assert_eq!(string(r#""\ud83d\u0041""#), None);
+        assert_eq!(string(r#""\ud83d\ue000""#), None);
+        assert_eq!(string(r#""\ud83d\uffff""#), None);
+        assert_eq!(string(r#""\ude00""#), None);
+    }
+
+    #[test]
+    fn keeps_numbers_as_written() {
+        for number in [
+            "0",
+            "-0",
+            "42",
+            "-1.5e+3",
+            "1E-7",
+            "0.25",
+            "12345678901234567890123",
+        ] {
+            assert_eq!(
+                Value::parse(number),
+                Some(Value::Number(number.to_string()))
+            );
+        }
+        assert_eq!(Value::parse("7").and_then(|value| value.as_u64()), Some(7));
+        for number in [
+            "-", "01", "-01", "1.", ".5", "1e", "1e+", "1.e3", "+1", "1-2", "0x10",
+        ] {
+            assert_eq!(Value::parse(number), None, "{}", number);
+        }
+    }
+
+    #[test]
+    fn limits_the_nesting_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(Value::parse(&nested(MAX_DEPTH + 2)), None);
+        let objects = format!(
+            "{}1{}",
+            "{\"a\":".repeat(MAX_DEPTH + 2),
+            "}".repeat(MAX_DEPTH + 2)
+        );
+        assert_eq!(Value::parse(&objects), None);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for text in [
+            "",
+            " ",
+            "{",
+            "}",
+            "[1,]",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{\"a\":}",
+            "{a:1}",
+            "{\"a\":1,}",
+            "tru",
+            "nul",
+            "\"abc",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "{\"a\":1} x",
+            "[] []",
+        ] {
+            assert_eq!(Value::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn serializes_compactly() {
+        let text = r#" { "a" : [ 1 , true , null , "x\"y" ] , "b" : { } , "c" : -2.5e3 } "#;
+        assert_eq!(
+            Value::parse(text).unwrap().to_json(),
+            r#"{"a":[1,true,null,"x\"y"],"b":{},"c":-2.5e3}"#
+        );
+    }
+
+    #[test]
+    fn applies_merge_patches() {
+        // the example of RFC 7386
+        let mut target =
+            Value::parse(r#"{"title":"Goodbye!","author":{"givenName":"John","familyName":"Doe"},"tags":["example","sample"],"content":"This will be unchanged"}"#).unwrap();
+        let patch = Value::parse(r#"{"title":"Hello!","phoneNumber":"+01-123-456-7890","author":{"familyName":null},"tags":["example"]}"#).unwrap();
+        target.merge_patch(&patch);
+        assert_eq!(
+            target.to_json(),
+            r#"{"title":"Hello!","author":{"givenName":"John"},"tags":["example"],"content":"This will be unchanged","phoneNumber":"+01-123-456-7890"}"#
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+/// The name of the java executable
+const JAVA: &str = if cfg!(windows) { "java.exe" } else { "java" };
+/// The directory next to the binary a runtime may be bundled in
+const BUNDLED_JRE: &str = "jre";
+
+/// The java executable to run the language server with, the first of
+///
+/// 1. the `configured` one, from `--jvm` or `JAVA_PATH`
+/// 2. the one of `JAVA_HOME`
+/// 3. the one bundled in `jre` next to the binary
+/// 4. the one found on the `PATH`
+pub fn resolve(configured: Option<PathBuf>) -> PathBuf {
+    if let Some(java) = configured {
+        info!("Using java {}, as configured", java.display());
+        return java;
+    }
+
+    if let Some(java_home) = std::env::var_os("JAVA_HOME") {
+        let java = runtime_java(Path::new(&java_home));
+        if java.is_file() {
+            info!("Using java {}, from JAVA_HOME", java.display());
+            return java;
+        }
+        warn!(
+            "JAVA_HOME is set, but {} does not exist, ignoring it",
+            java.display()
+        );
+    }
+
+    if let Some(bundled) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| runtime_java(&dir.join(BUNDLED_JRE))))
+    {
+        if bundled.is_file() {
+            info!("Using java {}, bundled with the proxy", bundled.display());
+            return bundled;
+        }
+    }
+
+    info!("Using java from the PATH, as neither JAVA_HOME nor a bundled runtime provide one");
+    PathBuf::from(JAVA)
+}
+
+/// The java executable of the runtime installed in `home`
+fn runtime_java(home: &Path) -> PathBuf {
+    home.join("bin").join(JAVA)
+}
@@ -0,0 +1,158 @@
+use crate::json::Value;
+use crate::process;
+use log::warn;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// Hands out the ports to spawn servers on in ascending order instead of at random,
+/// recording every lease with the pid of the proxy and when it was taken in a file,
+/// so that the ports in use can be audited against the firewall
+///
+/// Instances sharing the file, e.g. during a zero-downtime upgrade, skip the ports leased by each other,
+/// leases of instances that are no longer running are dropped.
+pub struct PortLeases {
+    path: PathBuf,
+    /// The ports that may be leased, in ascending order
+    ports: Vec<u16>,
+    /// The ports leased by this instance with the unix time they were leased at
+    leased: Mutex<BTreeMap<u16, u64>>,
+}
+
+/// A port leased for a single server, released when dropped
+pub struct Lease {
+    leases: Arc<PortLeases>,
+    port: u16,
+}
+
+/// A lease recorded in the lease file
+struct Entry {
+    port: u16,
+    pid: u32,
+    leased: u64,
+}
+
+impl PortLeases {
+    /// Lease `ports` via the lease file at `path`, dropping the stale leases in it
+    pub fn open(path: &Path, ports: &[u16]) -> std::io::Result<Self> {
+        let leases = Self {
+            path: path.to_path_buf(),
+            ports: ports.to_vec(),
+            leased: Mutex::default(),
+        };
+        leases.write(&leases.lock())?;
+        Ok(leases)
+    }
+
+    /// Lease the lowest port not leased by any instance, if there is one left
+    pub fn lease(self: &Arc<Self>) -> std::io::Result<Option<Lease>> {
+        let mut leased = self.lock();
+        let others = self.read_others()?;
+        let port = self.ports.iter().copied().find(|port| {
+            !leased.contains_key(port) && !others.iter().any(|entry| entry.port == *port)
+        });
+        let port = match port {
+            Some(port) => port,
+            None => return Ok(None),
+        };
+        leased.insert(port, unix_time());
+        if let Err(err) = self.write(&leased) {
+            leased.remove(&port);
+            return Err(err);
+        }
+        Ok(Some(Lease {
+            leases: self.clone(),
+            port,
+        }))
+    }
+
+    fn release(&self, port: u16) {
+        let mut leased = self.lock();
+        leased.remove(&port);
+        if let Err(err) = self.write(&leased) {
+            warn!(
+                "Failed to release port {} in lease file {}: {}",
+                port,
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    /// The leases of other instances that are still running
+    fn read_others(&self) -> std::io::Result<Vec<Entry>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let own = std::process::id();
+        Ok(contents
+            .lines()
+            .filter_map(parse_entry)
+            .filter(|entry| entry.pid != own && process::is_running(entry.pid))
+            .collect())
+    }
+
+    /// Replace the file at once with the leases of this and the other running instances,
+    /// so that it is never read half written
+    fn write(&self, leased: &BTreeMap<u16, u64>) -> std::io::Result<()> {
+        let own = std::process::id();
+        let mut entries = self.read_others()?;
+        entries.extend(leased.iter().map(|(&port, &leased)| Entry {
+            port,
+            pid: own,
+            leased,
+        }));
+        entries.sort_by_key(|entry| entry.port);
+        let contents: String = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"port\":{},\"pid\":{},\"leased\":{}}}\n",
+                    entry.port, entry.pid, entry.leased
+                )
+            })
+            .collect();
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(format!(".{}.tmp", own));
+        std::fs::write(&temp_path, contents)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u16, u64>> {
+        // a poisoned lock only means another thread panicked, the leases are still valid
+        self.leased
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Lease {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.leases.release(self.port);
+    }
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let entry = Value::parse(line)?;
+    Some(Entry {
+        port: entry.get("port")?.as_u64()? as u16,
+        pid: entry.get("pid")?.as_u64()? as u32,
+        leased: entry.get("leased")?.as_u64()?,
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
@@ -0,0 +1,454 @@
+use crate::error::ParseLogFilterError;
+use crate::session_log::format_rfc3339;
+use crate::socket;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Where log records are written in addition to stderr
+#[derive(Debug, Clone, Default)]
+pub struct Sinks {
+    pub file: Option<FileSink>,
+    /// Send records to the local syslog daemon or journald
+    pub syslog: bool,
+}
+
+/// A log file that is rotated once it grows too large or too old
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    pub path: PathBuf,
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    /// How many rotated files are kept, as `<path>.1` being the most recent one
+    pub keep: usize,
+}
+
+/// Which records are logged, in the `RUST_LOG` syntax of a default level
+/// followed by levels for modules and their submodules, e.g. `info,lsp_on_demand::relay=trace`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    /// The levels of modules, the most specific matching module applies
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// Log everything at `level` and above
+    pub fn level(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            modules: Vec::new(),
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let target = metadata.target();
+        let level = self
+            .modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::")
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level);
+        metadata.level() <= level
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ParseLogFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| ParseLogFilterError::InvalidLevel(level.to_string()))
+        };
+        let mut filter = Self::level(LevelFilter::Off);
+        for directive in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(ParseLogFilterError::EmptyModule);
+                    }
+                    filter
+                        .modules
+                        .push((module.to_string(), parse_level(level.trim())?));
+                }
+                // like env_logger, a bare module name enables everything for it
+                None => match parse_level(directive) {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter
+                        .modules
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// The target of the protocol traces of traced clients, which are logged regardless of the filter
+pub const PROTOCOL_TARGET: &str = "lsp_on_demand::protocol";
+
+/// The filter of the installed logger, which can be changed while running
+#[derive(Debug)]
+pub struct Levels {
+    configured: Filter,
+    current: RwLock<Filter>,
+    /// The addresses and IPs of the clients whose protocol is traced
+    traced: RwLock<Vec<String>>,
+}
+
+impl Levels {
+    pub fn current(&self) -> Filter {
+        self.read().clone()
+    }
+
+    pub fn set(&self, filter: Filter) {
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = filter;
+        self.update_max_level();
+    }
+
+    pub fn traced(&self) -> Vec<String> {
+        self.traced
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Trace the protocol of the clients with these addresses, as in the log, or IPs
+    pub fn set_traced(&self, clients: Vec<String>) {
+        *self
+            .traced
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = clients;
+        self.update_max_level();
+    }
+
+    /// Whether the protocol of `client` is to be traced
+    pub fn is_traced(&self, client: &str) -> bool {
+        let ip = client
+            .parse::<SocketAddr>()
+            .ok()
+            .map(|address| socket::unmap_ipv4(address.ip()));
+        self.traced
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .any(|traced| {
+                traced == client
+                    || traced.parse::<IpAddr>().ok().map(socket::unmap_ipv4) == ip && ip.is_some()
+            })
+    }
+
+    /// Protocol traces are logged at trace level, so that level has to pass while any client is traced
+    fn update_max_level(&self) {
+        let traced = !self.traced().is_empty();
+        let filtered = self.read().max_level();
+        log::set_max_level(if traced { LevelFilter::Trace } else { filtered });
+    }
+
+    /// Switch from the configured filter to logging everything at debug, then trace, then back again
+    pub fn cycle(&self) -> Filter {
+        let debug = Filter::level(LevelFilter::Debug);
+        let trace = Filter::level(LevelFilter::Trace);
+        let current = self.current();
+        let next = if current == self.configured && self.configured != debug {
+            debug
+        } else if current == debug {
+            trace
+        } else {
+            self.configured.clone()
+        };
+        self.set(next.clone());
+        next
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == PROTOCOL_TARGET || self.read().enabled(metadata)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Filter> {
+        // a poisoned lock only means another thread panicked, the filter is still valid
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Logs the traffic of a session while its client is traced
+#[derive(Debug, Clone)]
+pub struct ProtocolTrace {
+    client: String,
+    levels: Arc<Levels>,
+}
+
+impl ProtocolTrace {
+    pub fn new(client: String, levels: Arc<Levels>) -> Self {
+        Self { client, levels }
+    }
+
+    /// Log `data` relayed in `direction`, if the client is traced
+    pub fn relayed(&self, direction: impl Display, data: &[u8]) {
+        if self.levels.is_traced(&self.client) {
+            log::trace!(
+                target: PROTOCOL_TARGET,
+                "[{}] {}: {}",
+                self.client,
+                direction,
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
+}
+
+/// Install a logger filtering by `filter` that writes to stderr via `stderr`,
+/// and to the configured `sinks` as well
+///
+/// `stderr` has to let through all records, the filter of the returned levels decides which are logged
+pub fn init(stderr: Box<dyn Log>, filter: Filter, sinks: &Sinks) -> std::io::Result<Arc<Levels>> {
+    let file = match &sinks.file {
+        Some(sink) => Some(Mutex::new(RotatingFile::open(sink.clone())?)),
+        None => None,
+    };
+    let syslog = if sinks.syslog {
+        Some(imp::Syslog::connect()?)
+    } else {
+        None
+    };
+    let levels = Arc::new(Levels {
+        configured: filter.clone(),
+        current: RwLock::new(filter.clone()),
+        traced: RwLock::default(),
+    });
+    let logger = Logger {
+        levels: levels.clone(),
+        stderr,
+        file,
+        syslog,
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+    log::set_max_level(filter.max_level());
+    Ok(levels)
+}
+
+struct Logger {
+    levels: Arc<Levels>,
+    stderr: Box<dyn Log>,
+    file: Option<Mutex<RotatingFile>>,
+    syslog: Option<imp::Syslog>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.levels.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.stderr.log(record);
+        if let Some(file) = &self.file {
+            let line = format!(
+                "{} {:<5} {} > {}\n",
+                format_rfc3339(SystemTime::now()),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            lock(file).write(&line);
+        }
+        if let Some(syslog) = &self.syslog {
+            syslog.send(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(file) = &self.file {
+            let _ = lock(file).file.flush();
+        }
+    }
+}
+
+fn lock(file: &Mutex<RotatingFile>) -> MutexGuard<'_, RotatingFile> {
+    // a poisoned lock only means another thread panicked while logging, the file is still usable
+    file.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct RotatingFile {
+    sink: FileSink,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    fn open(sink: FileSink) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&sink.path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            sink,
+            file,
+            opened: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, line: &str) {
+        let too_large = self
+            .sink
+            .max_size
+            .map_or(false, |max_size| self.size + line.len() as u64 > max_size);
+        let too_old = self
+            .sink
+            .max_age
+            .map_or(false, |max_age| self.opened.elapsed() >= max_age);
+        if (too_large || too_old) && self.size > 0 {
+            if let Err(err) = self.rotate() {
+                // logging the failure would end up here again
+                eprintln!(
+                    "Failed to rotate log file {}: {}",
+                    self.sink.path.display(),
+                    err
+                );
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    /// Shift the rotated files by one, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |index: usize| {
+            let mut path = self.sink.path.as_os_str().to_owned();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        if self.sink.keep > 0 {
+            for index in (1..self.sink.keep).rev() {
+                let from = rotated(index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.sink.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.sink.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use log::{Level, Record};
+    use std::os::unix::net::UnixDatagram;
+
+    /// The daemon facility, as the proxy is a system service
+    const FACILITY: u8 = 3;
+    /// Where both syslog daemons and journald receive messages
+    const SYSLOG_SOCKET: &str = "/dev/log";
+
+    pub struct Syslog {
+        socket: UnixDatagram,
+        tag: String,
+    }
+
+    impl Syslog {
+        pub fn connect() -> std::io::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(SYSLOG_SOCKET).map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("can't connect to syslog at {}: {}", SYSLOG_SOCKET, err),
+                )
+            })?;
+            Ok(Self {
+                socket,
+                tag: format!("lsp_on_demand[{}]", std::process::id()),
+            })
+        }
+
+        /// Send a record in the RFC 3164 format, leaving the timestamp and hostname to the daemon
+        pub fn send(&self, record: &Record<'_>) {
+            let priority = FACILITY * 8 + severity(record.level());
+            let message = format!(
+                "<{}>{}: {} > {}",
+                priority,
+                self.tag,
+                record.target(),
+                record.args()
+            );
+            // there is nowhere left to report a failure to
+            let _ = self.socket.send(message.as_bytes());
+        }
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use log::Record;
+
+    pub struct Syslog;
+
+    impl Syslog {
+        pub fn connect() -> std::io::Result<Self> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "syslog is only supported on unix",
+            ))
+        }
+
+        pub fn send(&self, _record: &Record<'_>) {}
+    }
+}
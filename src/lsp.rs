@@ -0,0 +1,311 @@
+use crate::error::MessageTooLarge;
+use crate::json::{self, Value};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+
+/// The longest header line accepted, real headers are far shorter
+const MAX_HEADER_LINE: usize = 8 * 1024;
+const MAX_HEADER_LINES: usize = 32;
+/// The longest content accepted unless configured otherwise, far more than any real message,
+/// so that a bogus Content-Length can't make the proxy allocate unbounded memory
+pub const DEFAULT_MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+/// How much of an oversized message is kept, to answer the request it contains
+const OVERSIZED_PREFIX: usize = 4 * 1024;
+
+/// The `InvalidRequest` error code of JSON-RPC
+pub const INVALID_REQUEST: i32 = -32600;
+/// The `MethodNotFound` error code of JSON-RPC
+pub const METHOD_NOT_FOUND: i32 = -32601;
+/// The `ServerCancelled` error code of LSP
+pub const SERVER_CANCELLED: i32 = -32802;
+/// The `RequestCancelled` error code of LSP
+pub const REQUEST_CANCELLED: i32 = -32800;
+
+/// The `window/showMessage` type of errors
+pub const MESSAGE_TYPE_ERROR: i32 = 1;
+/// The `window/showMessage` type of warnings
+pub const MESSAGE_TYPE_WARNING: i32 = 2;
+
+/// The id of the `shutdown` request the proxy sends in place of a client, whose response is not relayed
+pub const PROXY_SHUTDOWN_ID: &str = "lsp_on_demand/shutdown";
+
+/// A single message of the LSP base protocol
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// The header lines other than `Content-Length`, without their line endings
+    pub headers: Vec<String>,
+    pub content: Vec<u8>,
+}
+
+impl Message {
+    /// A JSON-RPC notification without parameters
+    pub fn notification(method: &str) -> Self {
+        Self {
+            headers: Vec::new(),
+            content: format!("{{\"jsonrpc\":\"2.0\",\"method\":{}}}", json::quote(method))
+                .into_bytes(),
+        }
+    }
+
+    /// A `window/showMessage` notification showing `text` to the user as the given type, e.g. 1 for errors
+    pub fn show_message(message_type: i32, text: &str) -> Self {
+        Self {
+            headers: Vec::new(),
+            content: format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"window/showMessage\",\"params\":{{\"type\":{},\"message\":{}}}}}",
+                message_type,
+                json::quote(text)
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// A JSON-RPC response to the request `id`, reporting its `result`
+    pub fn response(id: &Value, result: &Value) -> Self {
+        Self {
+            headers: Vec::new(),
+            content: format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
+                id.to_json(),
+                result.to_json()
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// A JSON-RPC response to the request `id`, reporting that it failed
+    pub fn error_response(id: &Value, code: i32, message: &str) -> Self {
+        Self {
+            headers: Vec::new(),
+            content: format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":{}}}}}",
+                id.to_json(),
+                code,
+                json::quote(message)
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// The `shutdown` request and `exit` notification ending a session, sent by the proxy in place of a client
+    /// that left without them, so that the server exits on its own
+    pub fn shutdown_and_exit() -> Vec<u8> {
+        let shutdown = Self {
+            headers: Vec::new(),
+            content: format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"shutdown\"}}",
+                json::quote(PROXY_SHUTDOWN_ID)
+            )
+            .into_bytes(),
+        };
+        let mut bytes = shutdown.to_bytes();
+        bytes.extend_from_slice(&Self::notification("exit").to_bytes());
+        bytes
+    }
+
+    /// Whether this is the response to the `shutdown` request sent by the proxy
+    pub fn answers_proxy_shutdown(&self) -> bool {
+        let content = String::from_utf8_lossy(&self.content);
+        !json::has_top_level_field(&content, "method")
+            && json::top_level_string_field(&content, "id").as_deref() == Some(PROXY_SHUTDOWN_ID)
+    }
+
+    /// The message as sent over the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("Content-Length: {}\r\n", self.content.len()).into_bytes();
+        for header in &self.headers {
+            bytes.extend_from_slice(header.as_bytes());
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&self.content);
+        bytes
+    }
+
+    /// Write the whole message at once, so that messages written by different threads don't interleave
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+/// Reads base protocol messages from a stream
+pub struct MessageReader<R> {
+    reader: BufReader<R>,
+    max_content_length: usize,
+}
+
+impl<R: Read> MessageReader<R> {
+    /// Reject messages whose content is longer than [`DEFAULT_MAX_CONTENT_LENGTH`]
+    pub fn new(reader: R) -> Self {
+        Self::with_max_content_length(reader, DEFAULT_MAX_CONTENT_LENGTH)
+    }
+
+    /// Reject messages whose content is longer than `max_content_length` bytes
+    pub fn with_max_content_length(reader: R, max_content_length: usize) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            max_content_length,
+        }
+    }
+
+    /// Read the next message, or nothing if the stream ended between two messages
+    ///
+    /// A message exceeding the maximum content length is skipped and reported
+    /// as an `InvalidData` error wrapping [`MessageTooLarge`], after which reading can continue
+    pub fn read_message(&mut self) -> std::io::Result<Option<Message>> {
+        let mut headers = Vec::new();
+        let mut content_length = None;
+        loop {
+            let line = match self.read_header_line()? {
+                Some(line) => line,
+                None if headers.is_empty() && content_length.is_none() => return Ok(None),
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADER_LINES {
+                return Err(invalid_data("too many header lines"));
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_data("header line without a ':'"))?;
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                let length = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| invalid_data("invalid Content-Length"))?;
+                content_length = Some(length);
+            } else {
+                headers.push(line);
+            }
+        }
+
+        let content_length =
+            content_length.ok_or_else(|| invalid_data("message without Content-Length"))?;
+        if content_length > self.max_content_length {
+            return Err(self.skip_oversized(content_length, self.max_content_length));
+        }
+        // grown as the content arrives rather than sized by the header up front
+        let mut content = Vec::new();
+        (&mut self.reader)
+            .take(content_length as u64)
+            .read_to_end(&mut content)?;
+        if content.len() < content_length {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(Message { headers, content }))
+    }
+
+    /// Skip the content of an oversized message, keeping only its start
+    fn skip_oversized(&mut self, length: usize, limit: usize) -> std::io::Error {
+        let mut prefix = Vec::new();
+        let kept = (&mut self.reader)
+            .take(length.min(OVERSIZED_PREFIX) as u64)
+            .read_to_end(&mut prefix);
+        let skipped = kept.and_then(|kept| {
+            std::io::copy(
+                &mut (&mut self.reader).take((length - kept) as u64),
+                &mut std::io::sink(),
+            )
+            .map(|skipped| kept as u64 + skipped)
+        });
+        match skipped {
+            Ok(skipped) if skipped == length as u64 => std::io::Error::new(
+                ErrorKind::InvalidData,
+                MessageTooLarge {
+                    length,
+                    limit,
+                    prefix,
+                },
+            ),
+            Ok(_) => ErrorKind::UnexpectedEof.into(),
+            Err(err) => err,
+        }
+    }
+
+    /// Read a header line without its line ending, or nothing at the end of the stream
+    fn read_header_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let read = (&mut self.reader)
+            .take(MAX_HEADER_LINE as u64)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with(b"\n") {
+            return Err(if read < MAX_HEADER_LINE {
+                ErrorKind::UnexpectedEof.into()
+            } else {
+                invalid_data("header line too long")
+            });
+        }
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| invalid_data("header line is not valid utf-8"))
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_oversized_messages_without_allocating_for_them() {
+        let stream = b"Content-Length: 99999999999999\r\n\r\n{}Content-Length: 2\r\n\r\n{}";
+        let mut reader = MessageReader::new(&stream[..]);
+        // the bogus length swallows the rest of the stream, which ends early
+        let err = reader.read_message().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let stream = b"Content-Length: 5\r\n\r\n12345Content-Length: 2\r\n\r\n{}";
+        let mut reader = MessageReader::with_max_content_length(&stream[..], 4);
+        let err = reader.read_message().unwrap_err();
+        let too_large = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<MessageTooLarge>())
+            .unwrap();
+        assert_eq!((too_large.length, too_large.limit), (5, 4));
+        assert_eq!(reader.read_message().unwrap().unwrap().content, b"{}");
+    }
+
+    #[test]
+    fn recognizes_the_response_to_the_proxy_shutdown() {
+        let bytes = Message::shutdown_and_exit();
+        let mut reader = MessageReader::new(&bytes[..]);
+        let shutdown = reader.read_message().unwrap().unwrap();
+        let exit = reader.read_message().unwrap().unwrap();
+        assert!(reader.read_message().unwrap().is_none());
+        let content = String::from_utf8_lossy(&shutdown.content);
+        assert_eq!(
+            json::top_level_string_field(&content, "method").as_deref(),
+            Some("shutdown")
+        );
+        assert_eq!(exit.content, Message::notification("exit").content);
+        // the request itself is not its response
+        assert!(!shutdown.answers_proxy_shutdown());
+
+        let id = Value::String(PROXY_SHUTDOWN_ID.to_string());
+        assert!(Message::response(&id, &Value::Null).answers_proxy_shutdown());
+        assert!(Message::error_response(&id, INVALID_REQUEST, "shut down").answers_proxy_shutdown());
+        let other = Value::String("shutdown".to_string());
+        assert!(!Message::response(&other, &Value::Null).answers_proxy_shutdown());
+        let number = Value::Number("1".to_string());
+        assert!(!Message::response(&number, &Value::Null).answers_proxy_shutdown());
+    }
+
+    #[test]
+    fn fails_on_truncated_content() {
+        let stream = b"Content-Length: 10\r\n\r\n{}";
+        let err = MessageReader::new(&stream[..]).read_message().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
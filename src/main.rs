@@ -1,268 +1,2519 @@
 use log::{debug, error, info, warn, LevelFilter};
 use rand::Rng;
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
-use crate::error::ParsePortRangeError;
-use std::fmt::Debug;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
-use std::ops::RangeInclusive;
-use std::path::PathBuf;
-use std::process::Command;
-use std::str::FromStr;
-use std::time::Duration;
-
-use crate::ParsePortRangeError::{MissingEndSeperator, StartLargerThanEnd};
+use crate::alert::Alerts;
+use crate::arguments::{
+    AdminToken, Backpressure, ClientVersion, CpuList, EnvVar, NetworkList, PortList,
+    SpawnDirection, SubnetQuota,
+};
+use crate::chaos::Chaos;
+use crate::error::ServerError;
+use crate::feedback::QueueFeedback;
+use crate::filter::{
+    Compression, DenyMethods, DropNotifications, InitializePatch, MergePatch, MessageFilter,
+    RequestCoalescing, ResponseCache, UriMapping,
+};
+use crate::gate::{ClientGate, Verdict};
+use crate::guard::Guardrails;
+use crate::hooks::ServerHooks;
+use crate::http::HttpEndpoint;
+use crate::lease::PortLeases;
+use crate::logging::ProtocolTrace;
+use crate::mdns::Advertisement;
+use crate::metrics::{RequestLatencies, RequestTimer};
+use crate::notice::ConnectedClients;
+use crate::observe::Observable;
+use crate::otlp::{Exporter, SessionTrace};
+use crate::process::{Reaper, ServerGroups};
+use crate::relay::{RelayOptions, RelayOutcome, Throttle};
+use crate::session::Session;
+use crate::session_log::{ExitReason, SessionLog, SessionRecord};
+use crate::socket::ListenerOptions;
+use crate::state::StateFile;
+use crate::stats::Statistics;
+use crate::status::{ActiveSessions, Status};
+use crate::tempdir::ServerTempDir;
+use crate::template::LaunchContext;
+use crate::transport::{ClientTransport, ServerTransport, ACCEPT_POLL_INTERVAL};
+use crate::watchdog::RequestWatchdog;
+use crate::webhook::{Event, Webhooks};
+use crate::workers::{Priority, WorkerPool};
+use crate::workspace::WorkspaceSync;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroU64;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+mod alert;
+mod arguments;
+mod bench;
+mod buffer;
+mod build_info;
+mod chaos;
+mod checksum;
+mod deflate;
 mod error;
+mod feedback;
+mod filter;
+mod gate;
+mod guard;
+mod handoff;
+mod hooks;
+mod http;
+mod jar;
+mod json;
+mod jvm;
+mod lease;
+mod logging;
+mod lsp;
+mod manpage;
+mod mdns;
+mod metrics;
+mod mux;
+mod notice;
+mod observe;
+mod otlp;
+mod process;
+mod redirect;
+mod relay;
+mod resume;
+mod session;
+mod session_log;
+mod shutdown;
+mod socket;
+mod state;
+mod stats;
+mod status;
+mod tempdir;
+mod template;
+mod transport;
+mod update;
+mod upgrade;
+mod watchdog;
+mod webhook;
+mod workers;
+mod workspace;
 
 /// This program waits for connections and
 /// for each connection spawns a new language server and relays the messages in both directions
-#[derive(StructOpt)]
+#[derive(Debug, StructOpt)]
+#[structopt(version = build_info::VERSION)]
 struct Arguments {
     /// The Path to the java executable
-    #[structopt(long = "jvm", env = "JAVA_PATH", default_value = "java")]
+    ///
+    /// Defaults to the one of JAVA_HOME, then to the one of a runtime bundled in `jre` next to the binary,
+    /// and then to the one on the PATH
+    #[structopt(long = "jvm", env = "JAVA_PATH")]
+    jvm: Option<PathBuf>,
+
+    /// The java executable actually used, either the configured or the default one
+    #[structopt(skip)]
     java: PathBuf,
 
+    /// Run this executable as the language server instead of a jar, e.g. a GraalVM native image
+    #[structopt(
+        long = "server",
+        env = "LSP_SERVER",
+        conflicts_with_all = &["jvm", "jar", "jar-dir"]
+    )]
+    server: Option<PathBuf>,
+
+    /// The arguments to pass to the `--server` executable, separated by whitespace,
+    /// with placeholders like `{port}` replaced, see the README
+    #[structopt(
+        long = "server-args",
+        env = "LSP_SERVER_ARGS",
+        default_value = "--port {port}",
+        allow_hyphen_values = true
+    )]
+    server_args: String,
+
     /// The Path to the lsp jar
-    #[structopt(long="jar", env = "LSP_JAR_PATH", default_value = DEFAULT_JAR_PATH)]
+    ///
+    /// Defaults to the jar listed for the platform in `lsp_on_demand.jars` next to the binary, see the README,
+    /// or else ./server/kieler-language-server.<platform>.jar
+    #[structopt(long = "jar", env = "LSP_JAR_PATH")]
+    jar: Option<PathBuf>,
+
+    /// Additional arguments to pass to the JVM before the jar, separated by whitespace,
+    /// with placeholders like `{session_id}` replaced, see the README
+    #[structopt(long = "jvm-args", env = "LSP_JVM_ARGS", allow_hyphen_values = true)]
+    jvm_args: Option<String>,
+
+    /// Give each JVM this fraction of the memory of the host, or of the limit of the cgroup of the proxy,
+    /// divided by the number of session workers and overflow workers, as its maximum heap, e.g. 0.75
+    ///
+    /// A `-Xmx` in `--jvm-args` takes precedence.
+    #[structopt(long = "auto-heap", env = "LSP_AUTO_HEAP", conflicts_with = "server")]
+    auto_heap: Option<f64>,
+
+    /// The maximum heap of each JVM in MiB as computed for `--auto-heap`
+    #[structopt(skip)]
+    heap_mib: Option<u64>,
+
+    /// Use the newest `*-language-server*.<platform>.jar` in this directory, by version and then modification time,
+    /// instead of a fixed jar
+    #[structopt(long = "jar-dir", env = "LSP_JAR_DIR", conflicts_with = "jar")]
+    jar_dir: Option<PathBuf>,
+
+    /// A new release of the language server jar to try on some of the sessions before switching everyone to it
+    #[structopt(long = "canary-jar", env = "LSP_CANARY_JAR", conflicts_with = "server")]
+    canary_jar: Option<PathBuf>,
+
+    /// The percentage of new sessions to start with the canary jar, between 0 and 100
+    #[structopt(
+        long = "canary-percent",
+        env = "LSP_CANARY_PERCENT",
+        default_value = "10"
+    )]
+    canary_percent: u8,
+
+    /// The jar actually used, either the configured or the default one
+    #[structopt(skip)]
     lsp_jar: PathBuf,
 
-    /// The port to listen on for incoming connections
+    /// The version of the jar actually used, as its manifest or else its name states it
+    #[structopt(skip)]
+    server_version: Option<String>,
+
+    /// The ports to listen on for incoming connections
+    ///
+    /// A comma separated list of ports and port ranges, e.g. 5007,5009 or 5007-5010,
+    /// ports and ranges prefixed with `!` are excluded
     #[structopt(
         short = "p",
         long = "port",
         env = "LSP_LISTEN_PORT",
         default_value = "5007"
     )]
-    lsp_listen_port: u16,
+    lsp_listen_ports: PortList,
 
-    /// The range of ports to use for spawning language servers
+    /// Write the ports listened on to this file once listening, one per line
     ///
-    /// The port is chosen randomly, without taking into account ports already in use!
+    /// Together with `--port 0`, which lets the OS choose a free port,
+    /// this lets scripts and IDE plugins launching the proxy find out where to connect.
+    /// The ports are also printed to stdout as `LSP_PORT=<port>` lines
+    #[structopt(long = "port-file", env = "LSP_PORT_FILE")]
+    port_file: Option<PathBuf>,
+
+    /// The ports to use for spawning language servers, in the same format as the listen ports,
+    /// e.g. 6000-6100,!6050 to leave out a reserved port
+    ///
+    /// The port is chosen randomly, without taking into account ports already in use, unless `--port-lease-file` is given!
     #[structopt(
         short = "s",
         long = "spawn",
         env = "LSP_SPAWN_PORTS",
         default_value = "5008-65535"
     )]
-    lsp_spawn_ports: PortRange,
-}
+    lsp_spawn_ports: PortList,
 
-#[derive(Debug)]
-struct PortRange {
-    range: RangeInclusive<u16>,
-}
+    /// Take the ports to spawn servers on in ascending order instead of at random,
+    /// recording each lease with the pid of the proxy and a timestamp in this file, e.g. for firewall audits
+    ///
+    /// Sessions are refused while all ports are leased
+    #[structopt(long = "port-lease-file", env = "LSP_PORT_LEASE_FILE")]
+    port_lease_file: Option<PathBuf>,
 
-impl FromStr for PortRange {
-    type Err = ParsePortRangeError;
+    /// Write the shutdown report as JSON to this file when exiting
+    #[structopt(long = "report-file", env = "LSP_REPORT_FILE")]
+    report_file: Option<PathBuf>,
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (start, end) = s.split_once('-').ok_or(MissingEndSeperator)?;
-        let start = start.trim().parse()?;
-        let end = end.trim().parse()?;
+    /// Append a JSON record for every completed session to this file
+    #[structopt(long = "session-log", env = "LSP_SESSION_LOG")]
+    session_log: Option<PathBuf>,
 
-        if start > end {
-            Err(StartLargerThanEnd)
-        } else {
-            Ok(PortRange { range: start..=end })
-        }
-    }
+    /// Export a trace for every session to this OTLP/HTTP collector, e.g. http://localhost:4318
+    #[structopt(long = "otlp-endpoint", env = "LSP_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<HttpEndpoint>,
+
+    /// Post a JSON document to this webhook for events like crashed servers, e.g. http://localhost:9000/hooks/lsp
+    #[structopt(long = "webhook", env = "LSP_WEBHOOK")]
+    webhook: Option<HttpEndpoint>,
+
+    /// Only notify the webhook of these events, of server-crash, spawn-failed, pool-exhausted and proxy-shutdown,
+    /// instead of all of them
+    #[structopt(
+        long = "webhook-events",
+        env = "LSP_WEBHOOK_EVENTS",
+        use_delimiter = true,
+        requires = "webhook"
+    )]
+    webhook_events: Vec<Event>,
+
+    /// Report only the first of repeated identical failures within this many seconds right away,
+    /// in the log and to the webhook, and summarize the others with their count at the end of the interval
+    #[structopt(
+        long = "alert-interval",
+        env = "LSP_ALERT_INTERVAL",
+        default_value = "60"
+    )]
+    alert_interval: u64,
+
+    /// Serve the `/healthz`, `/readyz` and `/status` http endpoints on this address, e.g. 0.0.0.0:8080
+    #[structopt(long = "http", env = "LSP_HTTP_ADDRESS")]
+    http_address: Option<SocketAddr>,
+
+    /// Require this token, at least 16 characters, as `Authorization: Bearer <token>`
+    /// for the http endpoints changing the proxy, which are refused without one, see the README
+    #[structopt(long = "admin-token", env = "LSP_ADMIN_TOKEN")]
+    admin_token: Option<AdminToken>,
+
+    /// The number of listeners to accept connections with
+    ///
+    /// More than one listener binds the port with SO_REUSEPORT (unix only),
+    /// so that accepting connections is spread across multiple threads
+    #[structopt(long = "listeners", env = "LSP_LISTENERS", default_value = "1")]
+    listeners: usize,
+
+    /// How many connections may wait to be accepted by each listener (unix only),
+    /// e.g. more when a whole lab connects at the start of a class
+    #[structopt(long = "listen-backlog", env = "LSP_LISTEN_BACKLOG")]
+    listen_backlog: Option<u32>,
+
+    /// Bind separate IPv6 and IPv4 listeners instead of one IPv6 listener with an IPv4 fallback
+    ///
+    /// Needed where IPv6 sockets can't also accept IPv4 connections, e.g. when IPV6_V6ONLY is enforced
+    #[structopt(long = "dual-stack")]
+    dual_stack: bool,
+
+    /// Serve a single client and exit once its session ended,
+    /// for IDE plugins launching the proxy themselves, e.g. together with `--port 0`
+    #[structopt(long = "single")]
+    single: bool,
+
+    /// Let clients opting in with a preamble connect to their language server directly,
+    /// instead of relaying their session, see the README
+    #[structopt(long = "redirect")]
+    redirect: bool,
+
+    /// Accept connections starting with an HTTP/1.1 CONNECT or Upgrade request,
+    /// so that the proxy can sit behind a reverse proxy like nginx, see the README
+    #[structopt(long = "http-upgrade")]
+    http_upgrade: bool,
+
+    /// Tell clients waiting for a session worker their position in the queue and the estimated wait
+    /// via `window/showMessage`, every ten seconds
+    ///
+    /// Not for clients sending a preamble, as those of `--redirect`, `--resume-window` and `--http-upgrade` do
+    #[structopt(
+        long = "queue-feedback",
+        conflicts_with_all = &["redirect", "resume-window", "http-upgrade"]
+    )]
+    queue_feedback: bool,
+
+    /// Serve a single session over stdin and stdout instead of listening, and exit once it ended,
+    /// for editors that only support language servers speaking LSP via stdio
+    #[structopt(long = "stdio", conflicts_with = "rendezvous")]
+    stdio: bool,
+
+    /// Instead of listening, dial out to this relay host, e.g. relay.example.org:5007,
+    /// and serve a session over each outbound connection once the relay starts forwarding a client
+    ///
+    /// Allows running behind NAT or a firewall without opening inbound ports
+    #[structopt(long = "rendezvous", env = "LSP_RENDEZVOUS")]
+    rendezvous: Option<String>,
+
+    /// Also listen on this port for connections multiplexing several sessions,
+    /// see the README for the framing protocol
+    #[structopt(long = "mux-port", env = "LSP_MUX_PORT")]
+    mux_port: Option<u16>,
+
+    /// Let observers connecting to this port attach to a session read-only, see the README
+    #[structopt(long = "observer-port", env = "LSP_OBSERVER_PORT")]
+    observer_port: Option<u16>,
+
+    /// Let clients resume their session within this many seconds after their connection dropped
+    ///
+    /// Only sessions of clients opting in with a preamble can be resumed, see the README
+    #[structopt(long = "resume-window", env = "LSP_RESUME_WINDOW")]
+    resume_window: Option<u64>,
+
+    /// Relay no more than this many kilobits per second per session, in both directions together,
+    /// so that one session can't starve the others on a constrained uplink
+    #[structopt(long = "max-kbps-per-session", env = "LSP_MAX_KBPS_PER_SESSION")]
+    max_kbps_per_session: Option<NonZeroU64>,
+
+    /// Buffer up to this many KiB per direction of a session for a receiver that can't keep up,
+    /// instead of waiting for it to receive each write
+    #[structopt(long = "send-buffer", env = "LSP_SEND_BUFFER")]
+    send_buffer: Option<usize>,
+
+    /// What to do once the send buffer of a session is full, `block` to stop reading from the sender
+    /// until the receiver caught up, or `drop` to end the session
+    #[structopt(
+        long = "backpressure",
+        env = "LSP_BACKPRESSURE",
+        default_value = "block"
+    )]
+    backpressure: Backpressure,
+
+    /// Keep running checksums of what each direction of a session received and sent,
+    /// logged when the session ends, to diagnose reports of corrupted traffic
+    #[structopt(long = "checksums")]
+    checksums: bool,
+
+    /// Relay the sessions of clients on the same host with as little delay as possible,
+    /// as raw bytes moved within the kernel on linux, e.g. for local development
+    ///
+    /// These sessions skip everything that looks at the traffic, like `--parse-lsp`, throttling,
+    /// send buffers, checksums, observers and protocol traces.
+    #[structopt(long = "local-fast-path")]
+    local_fast_path: bool,
+
+    /// Parse the LSP base protocol and relay whole messages instead of raw bytes
+    #[structopt(long = "parse-lsp")]
+    parse_lsp: bool,
+
+    /// Send a `$/lspOnDemand/keepalive` notification to clients whose connection was idle this many seconds,
+    /// so that NAT gateways don't drop long-lived sessions
+    #[structopt(long = "keepalive", env = "LSP_KEEPALIVE", requires = "parse-lsp")]
+    keepalive: Option<u64>,
+
+    /// Warn about requests the server did not answer within this many seconds
+    #[structopt(
+        long = "request-deadline",
+        env = "LSP_REQUEST_DEADLINE",
+        requires = "parse-lsp"
+    )]
+    request_deadline: Option<u64>,
+
+    /// Cancel requests the server did not answer within the `--request-deadline`,
+    /// answering them with an error, so that the client doesn't wait forever
+    #[structopt(long = "cancel-slow-requests", requires = "request-deadline")]
+    cancel_slow_requests: bool,
+
+    /// Refuse new sessions and exit once the running ones ended as soon as this file exists,
+    /// like `PUT /maintenance` does, see the README
+    #[structopt(long = "maintenance-file", env = "LSP_MAINTENANCE_FILE")]
+    maintenance_file: Option<PathBuf>,
+
+    /// Show this message to the clients of the running sessions when the proxy shuts down,
+    /// before their connection is closed, requires --parse-lsp to be shown
+    #[structopt(
+        long = "shutdown-message",
+        env = "LSP_SHUTDOWN_MESSAGE",
+        default_value = "The language server is shutting down for maintenance"
+    )]
+    shutdown_message: String,
+
+    /// Reject LSP messages with a Content-Length above this many bytes in either direction,
+    /// answering requests with an error instead of relaying them, 64 MiB if not given
+    #[structopt(
+        long = "max-message-size",
+        env = "LSP_MAX_MESSAGE_SIZE",
+        requires = "parse-lsp"
+    )]
+    max_message_size: Option<usize>,
+
+    /// Drop the notifications of this method, e.g. telemetry/event, can be given multiple times
+    #[structopt(
+        long = "drop-notification",
+        env = "LSP_DROP_NOTIFICATIONS",
+        use_delimiter = true,
+        requires = "parse-lsp"
+    )]
+    drop_notifications: Vec<String>,
+
+    /// Keep clients from calling this method, e.g. workspace/executeCommand,
+    /// answering their requests with an error and dropping their notifications, can be given multiple times
+    #[structopt(
+        long = "deny-method",
+        env = "LSP_DENY_METHODS",
+        use_delimiter = true,
+        requires = "parse-lsp"
+    )]
+    deny_methods: Vec<String>,
+
+    /// Answer repeated requests of this method from a cache while their document is unchanged,
+    /// e.g. textDocument/documentSymbol, for read-only requests that are expensive for the server,
+    /// can be given multiple times
+    ///
+    /// Cached results are sent as the server sent them, so URIs can't be rewritten at the same time
+    #[structopt(
+        long = "cache-responses",
+        env = "LSP_CACHE_RESPONSES",
+        use_delimiter = true,
+        requires = "parse-lsp",
+        conflicts_with = "rewrite-uris"
+    )]
+    cache_responses: Vec<String>,
+
+    /// Relay only one of identical requests of this method at a time, answering the others, e.g. retries,
+    /// with copies of its response, can be given multiple times
+    #[structopt(
+        long = "coalesce-requests",
+        env = "LSP_COALESCE_REQUESTS",
+        use_delimiter = true,
+        requires = "parse-lsp"
+    )]
+    coalesce_requests: Vec<String>,
+
+    /// Rewrite the `uri`, `rootUri` and `targetUri` members below a client root to a server root and back,
+    /// e.g. file:///home/student/=file:///workspaces/, can be given multiple times
+    ///
+    /// Needed when the editor and the language server see the workspace at different paths
+    #[structopt(long = "rewrite-uri", env = "LSP_REWRITE_URI", requires = "parse-lsp")]
+    rewrite_uris: Vec<UriMapping>,
+
+    /// Compress messages with more content than this many bytes to clients accepting DEFLATE,
+    /// see the README
+    #[structopt(
+        long = "compression-threshold",
+        env = "LSP_COMPRESSION_THRESHOLD",
+        requires = "parse-lsp"
+    )]
+    compression_threshold: Option<usize>,
+
+    /// Relay the raw bytes of clients from these networks instead of parsing their messages,
+    /// e.g. to rule out the parser when debugging a client,
+    /// which also disables everything else requiring `--parse-lsp` for them
+    #[structopt(
+        long = "passthrough-clients",
+        env = "LSP_PASSTHROUGH_CLIENTS",
+        requires = "parse-lsp"
+    )]
+    passthrough_clients: Option<NetworkList>,
+
+    /// Apply this JSON merge patch to the result of the server's response to `initialize`,
+    /// e.g. {"capabilities":{"semanticTokensProvider":null}} to hide a broken capability from all clients,
+    /// can be given multiple times
+    #[structopt(
+        long = "patch-initialize-result",
+        env = "LSP_PATCH_INITIALIZE_RESULT",
+        requires = "parse-lsp"
+    )]
+    initialize_patches: Vec<MergePatch>,
+
+    /// Mirror the workspace of each client into a new directory below this one,
+    /// for remote clients whose files the language server can't read otherwise, see the README
+    #[structopt(
+        long = "sync-workspace",
+        env = "LSP_SYNC_WORKSPACE",
+        requires = "parse-lsp"
+    )]
+    sync_workspace: Option<PathBuf>,
+
+    /// Give each language server its own temporary directory below this one,
+    /// removed when the session ends
+    ///
+    /// Leftovers of instances that did not exit cleanly are removed on startup
+    #[structopt(long = "server-temp-dir", env = "LSP_SERVER_TEMP_DIR")]
+    server_temp_dir: Option<PathBuf>,
+
+    /// Set this environment variable for the language servers, e.g. `KIELER_SESSION={session_id}`,
+    /// with placeholders like `{port}` replaced, see the README, can be given multiple times
+    #[structopt(long = "server-env", env = "LSP_SERVER_ENV")]
+    server_env: Vec<EnvVar>,
+
+    /// Run the language servers in this working directory, e.g. `{workspace}`,
+    /// with placeholders like `{session_id}` replaced, see the README
+    #[structopt(long = "server-cwd", env = "LSP_SERVER_CWD")]
+    server_cwd: Option<String>,
+
+    /// Run this command before spawning each language server, e.g. to set up a license,
+    /// the server is only spawned if it succeeds, see the README for its environment
+    #[structopt(long = "pre-spawn-hook", env = "LSP_PRE_SPAWN_HOOK")]
+    pre_spawn_hook: Option<PathBuf>,
+
+    /// Run this command once each language server exited, e.g. to clean up after it
+    #[structopt(long = "post-exit-hook", env = "LSP_POST_EXIT_HOOK")]
+    post_exit_hook: Option<PathBuf>,
+
+    /// Run the language servers as this user, so that a compromised server can't act as the proxy (Unix only)
+    ///
+    /// Requires the proxy to run as root, the servers run with the primary group of the user only
+    #[structopt(long = "spawn-user", env = "LSP_SPAWN_USER")]
+    spawn_user: Option<String>,
+
+    /// The user the language servers actually run as, looked up from `--spawn-user`
+    #[structopt(skip)]
+    server_user: Option<process::User>,
+
+    /// Run the language servers through this sandbox, a command separated by whitespace
+    /// the server command is appended to, e.g. `bwrap --ro-bind / / --dev /dev --bind {temp-dir} {temp-dir}`
+    ///
+    /// Placeholders like `{port}` and `{temp-dir}` are replaced, see the README
+    #[structopt(long = "sandbox", env = "LSP_SANDBOX", allow_hyphen_values = true)]
+    sandbox: Option<String>,
+
+    /// Keep the language servers from gaining privileges, e.g. via setuid binaries (Linux only)
+    #[structopt(long = "no-new-privs")]
+    no_new_privs: bool,
+
+    /// Run the language servers with this niceness, e.g. 10, so that their background compilation
+    /// does not starve interactive processes on shared machines (Unix only)
+    #[structopt(
+        long = "server-nice",
+        env = "LSP_SERVER_NICE",
+        allow_hyphen_values = true
+    )]
+    server_nice: Option<i32>,
+
+    /// Restrict the language servers to these CPUs, a comma separated list of indices and ranges,
+    /// e.g. 0-3,6 (Linux only)
+    #[structopt(long = "server-cpus", env = "LSP_SERVER_CPUS")]
+    server_cpus: Option<CpuList>,
+
+    /// Record the spawned language servers in a file in this directory,
+    /// to find the servers left behind by an instance that crashed on the next startup
+    #[structopt(long = "state-dir", env = "LSP_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Kill the language servers left behind by crashed instances found on startup,
+    /// instead of only reporting them, requires `--state-dir`
+    #[structopt(long = "kill-orphans", requires = "state-dir")]
+    kill_orphans: bool,
+
+    /// Refuse new sessions while less than this many MiB are free in the directories
+    /// servers and synchronized workspaces write to
+    #[structopt(long = "min-free-disk", env = "LSP_MIN_FREE_DISK")]
+    min_free_disk: Option<u64>,
+
+    /// Refuse new sessions while the proxy may open less than this many more file descriptors
+    #[structopt(long = "min-free-fds", env = "LSP_MIN_FREE_FDS")]
+    min_free_fds: Option<u64>,
+
+    /// Refuse new sessions while less than this many MiB of memory are available,
+    /// on the host or within the memory limit of the cgroup of the proxy,
+    /// e.g. a little more than the heap of a language server, so that starting one doesn't push the host into swap
+    #[structopt(long = "min-free-memory", env = "LSP_MIN_FREE_MEMORY")]
+    min_free_memory: Option<u64>,
+
+    /// Refuse new sessions of a client IP that already has this many sessions,
+    /// so that a single user can't take all sessions
+    #[structopt(long = "max-sessions-per-client", env = "LSP_MAX_SESSIONS_PER_CLIENT")]
+    max_sessions_per_client: Option<usize>,
+
+    /// Refuse new sessions of the clients of a network that already have this many sessions,
+    /// e.g. 10.0.1.0/24=32, so that one group of users can't take the sessions left to another,
+    /// the first quota whose network contains a client applies to it
+    #[structopt(long = "subnet-quota", env = "LSP_SUBNET_QUOTAS", use_delimiter = true)]
+    subnet_quotas: Vec<SubnetQuota>,
+
+    /// Refuse sessions of clients older than this, by the name and version they report on `initialize`,
+    /// e.g. `KIELER VS Code=0.9.0`, so that outdated plugins with known protocol bugs don't take a server
+    #[structopt(
+        long = "min-client-version",
+        env = "LSP_MIN_CLIENT_VERSIONS",
+        use_delimiter = true
+    )]
+    min_client_versions: Vec<ClientVersion>,
+
+    /// Ask the users of clients older than this to update them, like `--min-client-version`,
+    /// but still serving them
+    #[structopt(
+        long = "recommended-client-version",
+        env = "LSP_RECOMMENDED_CLIENT_VERSIONS",
+        use_delimiter = true
+    )]
+    recommended_client_versions: Vec<ClientVersion>,
+
+    /// Serve clients from these networks before all others while all session workers are busy,
+    /// e.g. the instructor machines as 10.0.1.0/24,10.0.2.17
+    #[structopt(long = "priority-clients", env = "LSP_PRIORITY_CLIENTS")]
+    priority_clients: Option<NetworkList>,
+
+    /// Advertise the proxy via mDNS as a `_lsp._tcp` service with this instance name
+    #[structopt(long = "mdns-name", env = "LSP_MDNS_NAME")]
+    mdns_name: Option<String>,
+
+    /// The number of threads serving sessions, which limits the number of concurrent sessions
+    #[structopt(
+        long = "session-workers",
+        env = "LSP_SESSION_WORKERS",
+        default_value = "64"
+    )]
+    session_workers: usize,
+
+    /// The number of additional threads started on demand while all session workers are busy,
+    /// each serving a single session and exiting once it ends instead of staying idle
+    #[structopt(long = "overflow", env = "LSP_OVERFLOW", default_value = "0")]
+    overflow: usize,
+
+    /// The number of accepted connections that may wait for a free session worker,
+    /// further connections are rejected
+    #[structopt(
+        long = "session-queue",
+        env = "LSP_SESSION_QUEUE",
+        default_value = "64"
+    )]
+    session_queue: usize,
+
+    /// How long to wait for a spawned language server to accept connections, in seconds
+    #[structopt(
+        long = "startup-timeout",
+        env = "LSP_STARTUP_TIMEOUT",
+        default_value = "120"
+    )]
+    startup_timeout: u64,
+
+    /// How long to give a spawned language server to start up before checking whether it is ready, in seconds
+    #[structopt(long = "startup-delay", env = "LSP_STARTUP_DELAY", default_value = "5")]
+    startup_delay: u64,
+
+    /// Whether spawned servers listen on their port for the proxy to connect (listen),
+    /// or connect to the proxy listening on it themselves (connect), as some Xtext setups do
+    ///
+    /// In connect mode the java server is additionally passed -Dhost=127.0.0.1
+    /// and `{host}` is replaced by that address in the arguments of a native server.
+    #[structopt(
+        long = "spawn-direction",
+        env = "LSP_SPAWN_DIRECTION",
+        default_value = "listen",
+        conflicts_with = "redirect"
+    )]
+    spawn_direction: SpawnDirection,
+
+    /// Additionally write the log to this file
+    #[structopt(long = "log-file", env = "LSP_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it would grow beyond this many MiB
+    #[structopt(long = "log-max-size", env = "LSP_LOG_MAX_SIZE", requires = "log-file")]
+    log_max_size: Option<u64>,
+
+    /// Rotate the log file once it was written to for this many seconds
+    #[structopt(long = "log-max-age", env = "LSP_LOG_MAX_AGE", requires = "log-file")]
+    log_max_age: Option<u64>,
+
+    /// How many rotated log files to keep, named like the log file with a `.1`, `.2`, ... suffix
+    #[structopt(long = "log-keep", env = "LSP_LOG_KEEP", default_value = "5")]
+    log_keep: usize,
+
+    /// Additionally send the log to the local syslog daemon or journald
+    #[structopt(long = "syslog")]
+    syslog: bool,
+
+    /// How long a language server may take to exit after being sent SIGTERM at the end of its session,
+    /// before it is killed with SIGKILL, in seconds
+    ///
+    /// Without a grace period servers are killed right away
+    #[structopt(long = "kill-grace", env = "LSP_KILL_GRACE", default_value = "0")]
+    kill_grace: u64,
+
+    /// Inject faults to test how clients cope with them, e.g. `spawn-delay=0.5,kill=0.1,drop=0.01,duplicate=0.01`
+    ///
+    /// The probabilities apply per spawn, per session and per chunk of relayed traffic respectively
+    #[structopt(long = "chaos", hidden = true)]
+    chaos: Option<Chaos>,
+
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+// tools run instead of the proxy, a doc comment would replace the description of the proxy in the help
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Benchmark a running proxy with concurrent synthetic editor sessions
+    Bench(bench::BenchArguments),
+    /// Print the configuration the proxy would run with, from the options, their environment variables
+    /// and the defaults, with the jar and java executable resolved and secrets redacted
+    PrintConfig,
+    /// Print the completions of the options for `shell`, e.g. for packagers to ship
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Print a man page of the options, e.g. for packagers to ship
+    Manpage,
+    /// Replace the binary with the latest release published below a URL, see the README
+    SelfUpdate(update::UpdateArguments),
 }
 
 fn main() -> Result<(), String> {
+    // the filter is applied before records reach the formatter, so that it can be changed at runtime
     let mut logger_builder = pretty_env_logger::formatted_builder();
     logger_builder
         .filter_level(LevelFilter::Trace)
         .format_timestamp_secs();
 
-    if let Ok(value) = std::env::var("RUST_LOG") {
-        logger_builder.parse_filters(&value);
-    }
-    logger_builder.init();
+    let log_filter = match std::env::var("RUST_LOG") {
+        Ok(value) => value
+            .parse()
+            .map_err(|err| format!("Invalid RUST_LOG {:?}: {}", value, err))?,
+        Err(_) => logging::Filter::level(LevelFilter::Trace),
+    };
 
-    let args = Arguments::from_args();
+    let mut args = Arguments::from_args();
 
-    if !args.lsp_jar.exists() || !args.lsp_jar.is_file() {
-        return Err(format!(
-            "Can't find language server jar at {}",
-            args.lsp_jar.display()
-        ));
-    }
+    let log_levels = logging::init(
+        Box::new(logger_builder.build()),
+        log_filter,
+        &log_sinks(&args),
+    )
+    .map_err(|err| format!("Failed to set up logging: {}", err))?;
 
-    let sock_ipv4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, args.lsp_listen_port));
-    let sock_ipv6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, args.lsp_listen_port));
+    match &args.command {
+        Some(Subcommand::Bench(bench)) => return bench::run(bench),
+        Some(Subcommand::SelfUpdate(update)) => return update::run(update),
+        Some(Subcommand::Completions { shell }) => {
+            Arguments::clap().gen_completions_to(
+                env!("CARGO_PKG_NAME"),
+                *shell,
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Subcommand::Manpage) => {
+            print!("{}", manpage::render(&mut Arguments::clap())?);
+            return Ok(());
+        }
+        Some(Subcommand::PrintConfig) | None => {}
+    }
 
+    guard::raise_fd_limit();
     info!(
-        "Attempting to start listening on {} or {}",
-        sock_ipv6, sock_ipv4
+        "Starting lsp_on_demand {}, built {}",
+        build_info::VERSION,
+        build_info::build_date()
     );
 
-    // try to bind via IPv6 and fallback to IPv4
-    // some systems binding an IPv6 socket also binds a corresponding IPv4 socket
-    // the connections of the latter are then received by the IPv6 socket
-    // by using IPv4-Compatible (deprecated) or IPv4-Mapped IPv6 addresses
-    // so preferring IPv6 may allow us to handle both with one socket
-    // See [RFC 3493](https://datatracker.ietf.org/doc/html/rfc3493) Sections 3.7 and 5.3
-    let socks = [sock_ipv6, sock_ipv4];
+    if let Some(server) = &args.server {
+        info!("Running {} as the language server", server.display());
+    } else {
+        args.java = jvm::resolve(args.jvm.clone());
+        args.lsp_jar = match (args.jar.clone(), &args.jar_dir) {
+            (Some(jar), _) => jar,
+            (None, Some(jar_dir)) => jar::discover(jar_dir)?,
+            (None, None) => jar::default_jar()?,
+        };
+        if !args.lsp_jar.exists() || !args.lsp_jar.is_file() {
+            return Err(format!(
+                "Can't find language server jar at {}",
+                args.lsp_jar.display()
+            ));
+        }
+        args.server_version = jar::server_version(&args.lsp_jar);
+        info!(
+            "Serving the language server jar {}, version {}",
+            args.lsp_jar.display(),
+            args.server_version.as_deref().unwrap_or("unknown")
+        );
+        if let Some(canary_jar) = &args.canary_jar {
+            if !canary_jar.is_file() {
+                return Err(format!(
+                    "Can't find canary language server jar at {}",
+                    canary_jar.display()
+                ));
+            }
+            if args.canary_percent > 100 {
+                return Err(format!(
+                    "The canary percentage {} is larger than 100",
+                    args.canary_percent
+                ));
+            }
+            info!(
+                "Starting {}% of the sessions with the canary jar {}, version {}",
+                args.canary_percent,
+                canary_jar.display(),
+                jar::server_version(canary_jar)
+                    .as_deref()
+                    .unwrap_or("unknown")
+            );
+        }
+        if let Some(factor) = args.auto_heap {
+            if !(factor > 0.0 && factor <= 1.0) {
+                return Err(format!(
+                    "The heap factor {} should be larger than 0 and at most 1",
+                    factor
+                ));
+            }
+            let servers = (args.session_workers + args.overflow).max(1);
+            match guard::total_memory() {
+                Some(total) => {
+                    let heap = (total / guard::MIB) as f64 * factor / servers as f64;
+                    // the JVM refuses heaps below 2 MiB
+                    let heap = (heap as u64).max(2);
+                    info!(
+                        "Giving each of up to {} language servers a maximum heap of {} MiB",
+                        servers, heap
+                    );
+                    args.heap_mib = Some(heap);
+                }
+                None => warn!("Unable to determine the memory of the host, leaving the heap of the language servers to the JVM"),
+            }
+        }
+    }
+
+    if let Some(name) = &args.spawn_user {
+        let user = process::User::lookup(name)
+            .map_err(|err| format!("Can't run the language servers as {}: {}", name, err))?;
+        if !user.may_switch_to() {
+            return Err(format!(
+                "Can't run the language servers as {}, the proxy has to run as root or as {} itself",
+                name, name
+            ));
+        }
+        info!(
+            "Running the language servers as {} (uid {}, gid {})",
+            user.name, user.uid, user.gid
+        );
+        args.server_user = Some(user);
+    }
+
+    if let Some(sandbox) = &args.sandbox {
+        if sandbox.trim().is_empty() {
+            return Err("The sandbox command is empty".to_string());
+        }
+        info!(
+            "Running the language servers through the sandbox {}",
+            sandbox
+        );
+    }
+
+    if let Some(Subcommand::PrintConfig) = &args.command {
+        println!("{:#?}", args);
+        return Ok(());
+    }
+
+    if let Some(base) = &args.server_temp_dir {
+        tempdir::sweep(base);
+    }
 
-    let listener = std::net::TcpListener::bind(socks.as_slice()).unwrap();
+    // orphans may still hold the ports we are about to spawn servers on
+    let state = match &args.state_dir {
+        Some(dir) => {
+            let mut servers = vec![args.server.as_ref().unwrap_or(&args.lsp_jar).as_path()];
+            // servers of the canary may have been orphaned as well
+            servers.extend(args.canary_jar.as_deref());
+            state::reap_orphans(dir, &servers, args.kill_orphans);
+            Some(Arc::new(StateFile::create(dir).map_err(|err| {
+                format!("Failed to create state file in {}: {}", dir.display(), err)
+            })?))
+        }
+        None => None,
+    };
 
-    let address = listener
-        .local_addr()
-        .map_or_else(|_| String::from("unknown"), |address| address.to_string());
+    let port_leases = match &args.port_lease_file {
+        Some(path) => Some(Arc::new(
+            PortLeases::open(path, &args.lsp_spawn_ports.ports).map_err(|err| {
+                format!("Failed to open port lease file {}: {}", path.display(), err)
+            })?,
+        )),
+        None => None,
+    };
 
-    let mut rng = rand::thread_rng();
+    let session_log = match &args.session_log {
+        Some(path) => Some(Arc::new(SessionLog::open(path).map_err(|err| {
+            format!("Failed to open session log {}: {}", path.display(), err)
+        })?)),
+        None => None,
+    };
+
+    let exporter = args.otlp_endpoint.clone().map(Exporter::start);
+    let webhooks = args.webhook.clone().map(|endpoint| {
+        let events = if args.webhook_events.is_empty() {
+            Event::ALL.to_vec()
+        } else {
+            args.webhook_events.clone()
+        };
+        Webhooks::start(endpoint, events, Duration::from_secs(args.alert_interval))
+    });
+
+    if let Some(chaos) = &args.chaos {
+        warn!(
+            "Injecting faults, this is meant for testing only: {:?}",
+            chaos
+        );
+    }
+
+    if args.session_workers == 0 {
+        return Err(String::from("At least one session worker is required"));
+    }
+    let workers = WorkerPool::new(args.session_workers, args.overflow, args.session_queue)
+        .map_err(|err| format!("Failed to start session workers: {}", err))?;
+
+    let ready = Arc::new(AtomicBool::new(false));
+    let stats = Arc::new(Statistics::new());
+    let sessions = Arc::new(ActiveSessions::default());
+    let guardrails = Arc::new(guardrails(&args));
+    let http_listener = match (handoff::inherited_http_listener(), args.http_address) {
+        (Some(http_listener), _) => Some(http_listener),
+        (None, Some(http_address)) => {
+            Some(std::net::TcpListener::bind(http_address).map_err(|err| {
+                format!("Failed to bind http listener on {}: {}", http_address, err)
+            })?)
+        }
+        (None, None) => None,
+    };
+    // only recorded while they can be scraped
+    let latencies = http_listener
+        .as_ref()
+        .map(|_| Arc::new(RequestLatencies::default()));
+    if let (Some(http_listener), Some(latencies)) = (&http_listener, &latencies) {
+        let http_listener = http_listener
+            .try_clone()
+            .map_err(|err| format!("Failed to clone http listener: {}", err))?;
+        if let Ok(http_address) = http_listener.local_addr() {
+            info!(
+                "Serving /healthz, /readyz, /status, /log-level and /traced-clients on {}",
+                http_address
+            );
+        }
+        let ready = ready.clone();
+        let stats = stats.clone();
+        let status = Status {
+            config: config_summary(&args),
+            workers: workers.clone(),
+            sessions: sessions.clone(),
+            guardrails: guardrails.clone(),
+        };
+        let log_levels = log_levels.clone();
+        let latencies = latencies.clone();
+        let admin_token = args.admin_token.clone();
+        http::serve(http_listener, move |request| {
+            health_endpoints(
+                request,
+                &ready,
+                &stats,
+                &status,
+                &log_levels,
+                &latencies,
+                admin_token.as_ref(),
+            )
+        });
+    }
 
-    info!("Waiting for connections on {}", address);
+    let mux_listener = match (handoff::inherited_mux_listener(), args.mux_port) {
+        (Some(mux_listener), _) => Some(mux_listener),
+        (None, Some(mux_port)) => {
+            let socks = [
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, mux_port)),
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, mux_port)),
+            ];
+            Some(TcpListener::bind(socks.as_slice()).map_err(|err| {
+                format!(
+                    "Failed to bind multiplexing listener on port {}: {}",
+                    mux_port, err
+                )
+            })?)
+        }
+        (None, None) => None,
+    };
+
+    let observable = match args.observer_port {
+        Some(observer_port) => {
+            let socks = [
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, observer_port)),
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, observer_port)),
+            ];
+            let listener = TcpListener::bind(socks.as_slice()).map_err(|err| {
+                format!(
+                    "Failed to bind observer listener on port {}: {}",
+                    observer_port, err
+                )
+            })?;
+            info!("Accepting session observers on port {}", observer_port);
+            let observable = Arc::new(Observable::default());
+            observable.serve(listener);
+            Some(observable)
+        }
+        None => None,
+    };
+
+    let mut listeners = handoff::inherited_listeners();
+    if listeners.is_empty() && args.rendezvous.is_none() && !args.stdio {
+        // with multiple listeners the kernel distributes the incoming connections between them
+        let options = ListenerOptions {
+            reuse_port: args.listeners > 1,
+            only_v6: args.dual_stack,
+            backlog: args.listen_backlog,
+        };
+
+        // all listeners, whatever port they are bound to, feed the same session workers
+        for &port in &args.lsp_listen_ports.ports {
+            let sock_ipv4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+            let sock_ipv6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+
+            // try to bind via IPv6 and fallback to IPv4
+            // some systems binding an IPv6 socket also binds a corresponding IPv4 socket
+            // the connections of the latter are then received by the IPv6 socket
+            // by using IPv4-Compatible (deprecated) or IPv4-Mapped IPv6 addresses
+            // so preferring IPv6 may allow us to handle both with one socket
+            // See [RFC 3493](https://datatracker.ietf.org/doc/html/rfc3493) Sections 3.7 and 5.3
+            //
+            // in dual stack mode both are bound separately instead, each served by its own accept thread
+            let mut socks: Vec<Vec<SocketAddr>> = if args.dual_stack {
+                info!(
+                    "Attempting to start listening on {} and {}",
+                    sock_ipv6, sock_ipv4
+                );
+                vec![vec![sock_ipv6], vec![sock_ipv4]]
+            } else {
+                info!(
+                    "Attempting to start listening on {} or {}",
+                    sock_ipv6, sock_ipv4
+                );
+                vec![vec![sock_ipv6, sock_ipv4]]
+            };
+
+            let mut port = port;
+            for index in 0..socks.len() {
+                for _ in 0..args.listeners.max(1) {
+                    let listener =
+                        socket::bind(socks[index].as_slice(), options).map_err(|err| {
+                            format!("Failed to bind listener on port {}: {}", port, err)
+                        })?;
+                    if port == 0 {
+                        // the OS chose a port, the other listeners have to share it
+                        port = listener
+                            .local_addr()
+                            .map_err(|err| format!("Failed to get the chosen port: {}", err))?
+                            .port();
+                        socks
+                            .iter_mut()
+                            .flatten()
+                            .for_each(|sock| sock.set_port(port));
+                    }
+                    listeners.push(listener);
+                }
+            }
+        }
+    }
+
+    shutdown::install_handlers();
 
-    for connection in listener.incoming() {
-        match connection {
-            Err(err) => error!("{}", err),
-            Ok(con) => handle_connection(
-                con,
-                rng.gen_range(args.lsp_spawn_ports.range.clone()),
+    let context = Context {
+        stats,
+        workers,
+        session_log,
+        exporter,
+        resumable: args
+            .resume_window
+            .map(|window| Arc::new(resume::Registry::new(Duration::from_secs(window)))),
+        guardrails,
+        client_gate: Arc::new(ClientGate {
+            required: args.min_client_versions.clone(),
+            recommended: args.recommended_client_versions.clone(),
+        }),
+        state,
+        servers: Arc::default(),
+        reaper: Reaper::start(),
+        kill_grace: Duration::from_secs(args.kill_grace),
+        startup_delay: Duration::from_secs(args.startup_delay),
+        server_transport: transport::for_direction(args.spawn_direction),
+        hooks: Arc::new(ServerHooks {
+            pre_spawn: args.pre_spawn_hook.clone(),
+            post_exit: args.post_exit_hook.clone(),
+        }),
+        log_levels: log_levels.clone(),
+        chaos: args.chaos.clone().map(Arc::new),
+        port_leases,
+        sessions,
+        observable,
+        clients: Arc::default(),
+        webhooks,
+        spawn_failures: spawn_failure_alerts(Duration::from_secs(args.alert_interval)),
+        latencies,
+    };
+    let args = Arc::new(args);
+    let accepting = Arc::new(AtomicBool::new(true));
+
+    let mut acceptors = Vec::new();
+    let all_listeners = listeners
+        .iter()
+        .map(|listener| (listener, false))
+        .chain(mux_listener.iter().map(|listener| (listener, true)));
+    for (listener, multiplexed) in all_listeners {
+        // the listener is polled so that a requested shutdown is noticed between connections
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| format!("Failed to configure listener: {}", err))?;
+        let listener = listener
+            .try_clone()
+            .map_err(|err| format!("Failed to clone listener: {}", err))?;
+
+        let address = listener
+            .local_addr()
+            .map_or_else(|_| String::from("unknown"), |address| address.to_string());
+        if multiplexed {
+            info!("Waiting for multiplexed connections on {}", address);
+        } else {
+            info!("Waiting for connections on {}", address);
+        }
+
+        let args = args.clone();
+        let context = context.clone();
+        let accepting = accepting.clone();
+        acceptors.push(std::thread::spawn(move || {
+            serve_clients(
+                transport::Listener::new(listener),
                 &args,
-            ),
+                &context,
+                &accepting,
+                multiplexed,
+            )
+        }));
+    }
+    if let Some(rendezvous) = &args.rendezvous {
+        info!("Serving sessions via rendezvous host {}", rendezvous);
+        let rendezvous = rendezvous.clone();
+        let args = args.clone();
+        let context = context.clone();
+        let accepting = accepting.clone();
+        acceptors.push(std::thread::spawn(move || {
+            serve_clients(
+                transport::Rendezvous::new(rendezvous),
+                &args,
+                &context,
+                &accepting,
+                false,
+            )
+        }));
+    }
+    let stdio_output = if args.stdio {
+        info!("Serving a single session over stdin and stdout");
+        let (stdio, output) = transport::Stdio::start()
+            .map_err(|err| format!("Failed to serve stdin and stdout: {}", err))?;
+        let args = args.clone();
+        let context = context.clone();
+        let accepting = accepting.clone();
+        acceptors.push(std::thread::spawn(move || {
+            serve_clients(stdio, &args, &context, &accepting, false)
+        }));
+        Some(output)
+    } else {
+        None
+    };
+    ready.store(true, Ordering::SeqCst);
+
+    let listen_ports = bound_ports(&listeners);
+    for port in &listen_ports {
+        // for wrappers launching the proxy with --port 0 to find out where to connect
+        println!("LSP_PORT={}", port);
+    }
+    if let Some(port_file) = &args.port_file {
+        write_port_file(port_file, &listen_ports)
+            .map_err(|err| format!("Failed to write port file {}: {}", port_file.display(), err))?;
+    }
+
+    let advertisement = match &args.mdns_name {
+        Some(name) => {
+            // advertise the port actually bound, which differs from the configured one for inherited listeners
+            let port = listeners
+                .first()
+                .and_then(|listener| listener.local_addr().ok())
+                .map_or(args.lsp_listen_ports.ports[0], |address| address.port());
+            Some(Advertisement::start(name, port)?)
         }
+        None => None,
+    };
+
+    let mut handed_off = false;
+    let mut maintenance = false;
+    while !shutdown::requested() {
+        let maintenance_requested = shutdown::in_maintenance()
+            || args
+                .maintenance_file
+                .as_ref()
+                .map_or(false, |path| path.exists());
+        if maintenance_requested && !maintenance {
+            shutdown::start_maintenance();
+            maintenance = true;
+            ready.store(false, Ordering::SeqCst);
+            warn!(
+                "Refusing new sessions for maintenance, exiting once the {} active sessions ended",
+                context.stats.active_sessions()
+            );
+        }
+        if maintenance && context.stats.active_sessions() == 0 {
+            info!("All sessions ended, exiting for maintenance");
+            break;
+        }
+        if shutdown::take_log_cycle_request() {
+            let filter = log_levels.cycle();
+            warn!("Changed the log filter to {}", filter);
+        }
+        if shutdown::take_handoff_request() {
+            match handoff::spawn_successor(
+                &listeners,
+                http_listener.as_ref(),
+                mux_listener.as_ref(),
+            ) {
+                Ok(successor) => {
+                    info!(
+                        "Handed off listeners to new instance with pid {}",
+                        successor.id()
+                    );
+                    handed_off = true;
+                    shutdown::start_draining();
+                    break;
+                }
+                Err(err) => error!("Failed to hand off listeners to a new instance: {}", err),
+            }
+        }
+        std::thread::sleep(ACCEPT_POLL_INTERVAL);
+    }
+
+    if let Some(advertisement) = &advertisement {
+        // after a handoff the new instance keeps advertising the same service
+        if !handed_off {
+            advertisement.withdraw();
+        }
+    }
+    if let Some(port_file) = &args.port_file {
+        // after a handoff the new instance listens on the same ports
+        if !handed_off {
+            if let Err(err) = std::fs::remove_file(port_file) {
+                warn!(
+                    "Failed to remove port file {}: {}",
+                    port_file.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    accepting.store(false, Ordering::SeqCst);
+    for acceptor in acceptors {
+        if let Err(_err) = acceptor.join() {
+            warn!("Failed to join panicked accept thread");
+        }
+    }
+
+    if let Some(stdio_output) = stdio_output {
+        // unless interrupted, let the last output of the session reach the editor
+        if context.stats.active_sessions() == 0 && stdio_output.join().is_err() {
+            warn!("Failed to join panicked stdout thread");
+        }
+    }
+
+    if handed_off {
+        // the new instance accepts all new connections, let the existing sessions finish undisturbed
+        info!(
+            "Draining {} active sessions before exiting",
+            context.stats.active_sessions()
+        );
+        while context.stats.active_sessions() > 0 && !shutdown::requested() {
+            std::thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
+
+    ready.store(false, Ordering::SeqCst);
+    info!("Shutting down!");
+    let notified = context.clients.say_goodbye(&args.shutdown_message);
+    if notified > 0 {
+        info!("Told {} clients that the proxy is shutting down", notified);
+    }
+    let asked = context.clients.shut_down_servers(LSP_SHUTDOWN_GRACE);
+    if asked > 0 {
+        info!("Asked {} language servers to shut down", asked);
+    }
+    context.servers.kill_all(context.kill_grace);
+    // give the sessions of the stopped servers a chance to be accounted for
+    let stopped = Instant::now();
+    while context.stats.active_sessions() > 0 && stopped.elapsed() < UNFINISHED_SESSION_GRACE {
+        std::thread::sleep(ACCEPT_POLL_INTERVAL);
+    }
+    write_report(&context.stats, args.report_file.as_deref());
+    if let Some(webhooks) = &context.webhooks {
+        webhooks.notify_now(Event::ProxyShutdown, &context.stats.report().to_string());
     }
 
     Ok(())
 }
 
-const DEFAULT_JAR_PATH: &str = {
-    if cfg!(target_os = "windows") {
-        "./server/kieler-language-server.win.jar"
-    } else if cfg!(target_os = "macos") {
-        "./server/kieler-language-server.osx.jar"
-    } else if cfg!(target_os = "linux") {
-        "./server/kieler-language-server.linux.jar"
-    } else {
-        "./server/kieler-language-server.unknown.jar"
+/// The distinct ports `listeners` are bound to, in ascending order
+fn bound_ports(listeners: &[TcpListener]) -> Vec<u16> {
+    let mut ports: Vec<u16> = listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .map(|address| address.port())
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+/// Write the `ports`, one per line, to `path`
+///
+/// The file is replaced at once, so that wrappers polling for it never read a partial file
+fn write_port_file(path: &Path, ports: &[u16]) -> std::io::Result<()> {
+    let contents: String = ports.iter().map(|port| format!("{}\n", port)).collect();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Serve a session for every client of the `transport` until `accepting` is cleared or a shutdown is requested
+///
+/// Each `multiplexed` connection is served by its own thread, opening a session per stream
+fn serve_clients(
+    mut transport: impl ClientTransport,
+    args: &Arc<Arguments>,
+    context: &Context,
+    accepting: &AtomicBool,
+    multiplexed: bool,
+) {
+    let mut rng = rand::thread_rng();
+    let keep_going = || accepting.load(Ordering::SeqCst) && !shutdown::requested();
+    while let Some(con) = transport.next_client(&keep_going) {
+        if args.single && !accepting.swap(false, Ordering::SeqCst) {
+            // another transport already got the single client
+            continue;
+        }
+        if multiplexed {
+            let args = args.clone();
+            let context = context.clone();
+            std::thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                mux::serve(con, |stream| {
+                    handle_connection(
+                        stream,
+                        args.lsp_spawn_ports.choose(&mut rng),
+                        &args,
+                        context.clone(),
+                    )
+                })
+            });
+            continue;
+        }
+        handle_connection(
+            con,
+            args.lsp_spawn_ports.choose(&mut rng),
+            args,
+            context.clone(),
+        )
     }
-};
+}
 
-fn lsp_command(port: u16, args: &Arguments) -> Command {
-    let mut command = std::process::Command::new(&args.java);
-    command
-        .args(&[
-            &format!("-Dport={}", port),
-            "-Dfile.encoding=UTF-8",
-            "-Djava.awt.headless=true",
-            "-Dlog4j.configuration=file:server/log4j.properties",
-            "-XX:+IgnoreUnrecognizedVMOptions",
-            "-XX:+ShowCodeDetailsInExceptionMessages",
-            "-jar",
-        ])
-        .arg(&args.lsp_jar);
-    command
+/// Services shared between all connection handlers
+#[derive(Clone)]
+struct Context {
+    stats: Arc<Statistics>,
+    workers: WorkerPool,
+    session_log: Option<Arc<SessionLog>>,
+    exporter: Option<Exporter>,
+    resumable: Option<Arc<resume::Registry>>,
+    guardrails: Arc<Guardrails>,
+    client_gate: Arc<ClientGate>,
+    /// Where the spawned servers are recorded, to reap them should the proxy crash
+    state: Option<Arc<StateFile>>,
+    /// The language servers still running, killed when the proxy exits
+    servers: Arc<ServerGroups>,
+    reaper: Arc<Reaper>,
+    /// How long servers may take to exit after being terminated, before they are killed
+    kill_grace: Duration,
+    /// How long to wait before checking whether a spawned server is ready
+    startup_delay: Duration,
+    /// How the spawned servers are reached
+    server_transport: Arc<dyn ServerTransport>,
+    /// Run before each server is spawned and after it exited
+    hooks: Arc<ServerHooks>,
+    log_levels: Arc<logging::Levels>,
+    /// The faults to inject, for testing only
+    chaos: Option<Arc<Chaos>>,
+    /// Where the ports to spawn servers on are leased from, instead of choosing them at random
+    port_leases: Option<Arc<PortLeases>>,
+    /// The sessions being served, as listed by `/status`
+    sessions: Arc<ActiveSessions>,
+    /// The sessions observers can attach to, if enabled
+    observable: Option<Arc<Observable>>,
+    /// The clients of the sessions relayed a message at a time, told about the shutdown
+    clients: Arc<ConnectedClients>,
+    webhooks: Option<Webhooks>,
+    /// Coalesces the logs of identical spawn failures, keyed by their error
+    spawn_failures: Arc<Alerts<String>>,
+    /// How long the servers took to answer requests, for `/metrics`, if served
+    latencies: Option<Arc<RequestLatencies>>,
 }
 
-fn relay_connection(mut rx: TcpStream, mut tx: TcpStream) {
-    let mut buf = [0; 1024];
-    loop {
-        match rx.read(&mut buf) {
-            Ok(0) | Err(_) => break,
-            Ok(bytes) => {
-                let _ = tx.write_all(&buf[..bytes]);
+/// How long a server that closed its connection may take to exit, before it is assumed to still be running
+const SERVER_EXIT_GRACE: Duration = Duration::from_millis(250);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long servers asked to shut down on exit may take to close their connections before they are stopped
+const LSP_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How long sessions may take to wind down after their servers were stopped on exit
+const UNFINISHED_SESSION_GRACE: Duration = Duration::from_secs(2);
+/// How many times the usual readiness latency a server may take before a warning is logged
+const READINESS_DEGRADED_FACTOR: u32 = 2;
+
+/// The http endpoints a `PUT` to which changes the proxy, so that it requires the admin token
+const ADMIN_ENDPOINTS: &[&str] = &["/log-level", "/traced-clients", "/maintenance"];
+
+/// `/healthz` reports whether the process is alive at all,
+/// `/readyz` whether it is currently accepting language server connections
+/// and `/status` the current statistics as JSON,
+/// `/metrics` how long the servers took to answer requests in the Prometheus text format
+///
+/// `/log-level` reports the current log filter, which a `PUT` with a new filter as the body replaces,
+/// `/traced-clients` the clients whose protocol is traced, one per line, which a `PUT` replaces likewise
+///
+/// A `PUT` to the [`ADMIN_ENDPOINTS`] is refused unless it presents the `admin_token`
+fn health_endpoints(
+    request: &http::Request,
+    ready: &AtomicBool,
+    stats: &Statistics,
+    status: &Status,
+    log_levels: &logging::Levels,
+    latencies: &RequestLatencies,
+    admin_token: Option<&AdminToken>,
+) -> http::Response {
+    if request.method == "PUT" && ADMIN_ENDPOINTS.contains(&request.path.as_str()) {
+        match admin_token {
+            None => {
+                return http::Response::text(403, "changing the proxy requires --admin-token\n")
             }
+            Some(token) if !token.authorizes(request.authorization.as_deref()) => {
+                warn!("Refused an unauthorized PUT to {}", request.path);
+                return http::Response::text(401, "unauthorized\n");
+            }
+            Some(_) => {}
+        }
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/log-level") => http::Response::text(200, format!("{}\n", log_levels.current())),
+        ("PUT", "/log-level") => match request.body.trim().parse() {
+            Ok(filter) => {
+                log_levels.set(filter);
+                warn!("Changed the log filter to {}", log_levels.current());
+                http::Response::text(200, format!("{}\n", log_levels.current()))
+            }
+            Err(err) => http::Response::text(400, format!("invalid log filter: {}\n", err)),
+        },
+        ("GET", "/traced-clients") => http::Response::text(
+            200,
+            log_levels
+                .traced()
+                .iter()
+                .map(|client| format!("{}\n", client))
+                .collect::<String>(),
+        ),
+        ("PUT", "/traced-clients") => {
+            let clients: Vec<String> = request
+                .body
+                .lines()
+                .map(str::trim)
+                .filter(|client| !client.is_empty())
+                .map(String::from)
+                .collect();
+            warn!("Tracing the protocol of clients {:?}", clients);
+            log_levels.set_traced(clients);
+            http::Response::text(200, "ok\n")
         }
+        ("PUT", "/maintenance") => {
+            shutdown::start_maintenance();
+            http::Response::text(200, "draining\n")
+        }
+        ("GET", "/maintenance") if shutdown::in_maintenance() => {
+            http::Response::text(200, "draining\n")
+        }
+        ("GET", "/maintenance") => http::Response::text(200, "serving\n"),
+        (_, "/log-level" | "/traced-clients" | "/maintenance") => {
+            http::Response::text(405, "method not allowed\n")
+        }
+        ("GET", "/healthz") => http::Response::text(200, "ok\n"),
+        ("GET", "/readyz") if ready.load(Ordering::SeqCst) => http::Response::text(200, "ready\n"),
+        ("GET", "/readyz") => http::Response::text(503, "not ready\n"),
+        ("GET", "/status") => http::Response::json(200, status.to_json(&stats.report())),
+        ("GET", "/metrics") => http::Response::text(200, latencies.to_prometheus()),
+        ("GET", _) => http::Response::not_found(),
+        _ => http::Response::text(405, "method not allowed\n"),
     }
 }
 
-fn handle_connection(client_con: TcpStream, port: u16, args: &Arguments) {
-    let mut lsp_cmd = lsp_command(port, args);
+/// The configuration as reported by `/status`, a JSON object
+fn config_summary(args: &Arguments) -> String {
+    let listen_ports: Vec<String> = args
+        .lsp_listen_ports
+        .ports
+        .iter()
+        .map(u16::to_string)
+        .collect();
+    let spawn_ports = &args.lsp_spawn_ports.ports;
+    let server = args.server.as_ref().unwrap_or(&args.lsp_jar);
+    let java = args.server.is_none().then(|| args.java.to_string_lossy());
+    let server_version = &args.server_version;
+    format!(
+        concat!(
+            "{{",
+            "\"version\":{},",
+            "\"git_hash\":{},",
+            "\"build_date\":\"{}\",",
+            "\"listen_ports\":[{}],",
+            "\"spawn_ports\":{{\"first\":{},\"last\":{},\"count\":{}}},",
+            "\"server\":{},",
+            "\"server_version\":{},",
+            "\"java\":{},",
+            "\"session_workers\":{},",
+            "\"overflow\":{},",
+            "\"session_queue\":{},",
+            "\"startup_timeout_secs\":{},",
+            "\"parse_lsp\":{},",
+            "\"max_sessions_per_client\":{},",
+            "\"resume_window_secs\":{}",
+            "}}"
+        ),
+        json::quote(env!("CARGO_PKG_VERSION")),
+        json::quote(build_info::GIT_HASH),
+        build_info::build_date(),
+        listen_ports.join(","),
+        spawn_ports[0],
+        spawn_ports[spawn_ports.len() - 1],
+        spawn_ports.len(),
+        json::quote(&server.to_string_lossy()),
+        json::quote_optional(server_version.as_deref()),
+        json::quote_optional(java.as_deref()),
+        args.session_workers,
+        args.overflow,
+        args.session_queue,
+        args.startup_timeout,
+        args.parse_lsp,
+        optional_number(args.max_sessions_per_client),
+        optional_number(args.resume_window),
+    )
+}
 
-    std::thread::spawn(move || {
-        let lsp_addrs = [
-            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
-            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
-        ];
-        let mut lsp = format!("{} or {}", lsp_addrs[0], lsp_addrs[1]);
+/// The `Server` header of HTTP handshakes, e.g. `lsp_on_demand/0.1.0 (3f2a1b9c4d5e) language-server/0.4.2`,
+/// naming the version of the jar if it has one
+fn server_header(args: &Arguments) -> String {
+    let proxy = format!("lsp_on_demand/{}", build_info::VERSION);
+    match &args.server_version {
+        Some(server_version) => format!("{} language-server/{}", proxy, server_version),
+        None => proxy,
+    }
+}
 
-        let client_addr = client_con.peer_addr().ok();
-        let client = match client_addr {
-            Some(addr) => addr.to_string(),
-            None => String::from("unknown"),
-        };
+fn optional_number<T: ToString>(number: Option<T>) -> String {
+    number.map_or_else(|| String::from("null"), |number| number.to_string())
+}
 
-        info!(
-            "[{}] attempting to spawn LSP on port {}\n> {:?}",
-            client, port, lsp_cmd
-        );
+fn write_report(stats: &Statistics, report_file: Option<&std::path::Path>) {
+    let report = stats.report();
+    info!("Shutdown report: {}", report);
 
-        let mut lsp_proc = match lsp_cmd.spawn() {
-            Ok(child) => child,
-            Err(err) => {
-                error!("[{}] Failed to spawn child lsp process: {}", client, err);
-                return;
+    if let Some(path) = report_file {
+        match std::fs::write(path, report.to_json()) {
+            Ok(()) => info!("Wrote shutdown report to {}", path.display()),
+            Err(err) => error!(
+                "Failed to write shutdown report to {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+/// The command starting the language server of the `launch`, from the `jar` unless a native server is configured
+fn lsp_command(
+    launch: &LaunchContext,
+    args: &Arguments,
+    jar: &Path,
+    temp_dir: Option<&ServerTempDir>,
+) -> Command {
+    let mut command = match &args.server {
+        Some(server) => native_command(server, &args.server_args, launch),
+        None => java_command(launch, args, jar, temp_dir),
+    };
+    if let Some(sandbox) = &args.sandbox {
+        command = sandboxed(&command, sandbox, launch);
+    }
+    if let Some(temp_dir) = temp_dir {
+        command
+            .env("TMPDIR", temp_dir.path())
+            .env("TMP", temp_dir.path())
+            .env("TEMP", temp_dir.path());
+    }
+    for var in &args.server_env {
+        command.env(&var.name, launch.render(&var.value));
+    }
+    if let Some(cwd) = &args.server_cwd {
+        command.current_dir(launch.render(cwd));
+    }
+    if let Some(user) = &args.server_user {
+        user.run(&mut command);
+    }
+    if args.no_new_privs {
+        process::no_new_privileges(&mut command);
+    }
+    if let Some(nice) = args.server_nice {
+        process::set_niceness(&mut command, nice);
+    }
+    if let Some(cpus) = &args.server_cpus {
+        process::pin_to_cpus(&mut command, &cpus.cpus);
+    }
+    // the server may fork processes of its own, which are to be killed along with it
+    process::own_process_group(&mut command);
+    command
+}
+
+fn java_command(
+    launch: &LaunchContext,
+    args: &Arguments,
+    jar: &Path,
+    temp_dir: Option<&ServerTempDir>,
+) -> Command {
+    let mut command = std::process::Command::new(&args.java);
+    if let Some(temp_dir) = temp_dir {
+        command.arg(format!("-Djava.io.tmpdir={}", temp_dir.path().display()));
+    }
+    if args.spawn_direction == SpawnDirection::Connect {
+        command.arg(format!("-Dhost={}", Ipv4Addr::LOCALHOST));
+    }
+    command.args([
+        &format!("-Dport={}", launch.port),
+        "-Dfile.encoding=UTF-8",
+        "-Djava.awt.headless=true",
+        "-Dlog4j.configuration=file:server/log4j.properties",
+        "-XX:+IgnoreUnrecognizedVMOptions",
+        "-XX:+ShowCodeDetailsInExceptionMessages",
+    ]);
+    if let Some(heap_mib) = args.heap_mib {
+        command.arg(format!("-Xmx{}m", heap_mib));
+    }
+    // after the defaults, which they may override
+    if let Some(jvm_args) = &args.jvm_args {
+        command.args(jvm_args.split_whitespace().map(|arg| launch.render(arg)));
+    }
+    command.arg("-jar").arg(jar);
+    command
+}
+
+/// Run `server` with the `template` arguments, with the placeholders of the `launch` replaced
+fn native_command(server: &Path, template: &str, launch: &LaunchContext) -> Command {
+    let mut command = std::process::Command::new(server);
+    command.args(template.split_whitespace().map(|arg| launch.render(arg)));
+    command
+}
+
+/// Run `command` through the `sandbox` command, with the placeholders of the `launch` replaced
+fn sandboxed(command: &Command, sandbox: &str, launch: &LaunchContext) -> Command {
+    let mut words = sandbox.split_whitespace().map(|word| launch.render(word));
+    let mut wrapped = Command::new(words.next().unwrap_or_default());
+    wrapped
+        .args(words)
+        .arg(command.get_program())
+        .args(command.get_args());
+    wrapped
+}
+
+fn handle_connection(client_con: TcpStream, port: u16, args: &Arc<Arguments>, context: Context) {
+    let stats = context.stats.clone();
+    stats.session_opened();
+    let workers = context.workers.clone();
+    let webhooks = context.webhooks.clone();
+    let single = args.single || args.stdio;
+    let redirect_enabled = args.redirect;
+    let http_upgrade = args.http_upgrade.then(|| server_header(args));
+    let session_args = args.clone();
+    // a clone, as the connection is moved into the session
+    let feedback = if args.queue_feedback {
+        client_con
+            .try_clone()
+            .map_err(|err| warn!("Can't tell the client its position in the queue: {}", err))
+            .ok()
+            .map(|feedback_con| (QueueFeedback::default(), feedback_con))
+    } else {
+        None
+    };
+    // the only lookup before the session is queued, all other setup is left to its worker
+    let priority = match &args.priority_clients {
+        Some(priority_clients)
+            if client_con.peer_addr().map_or(false, |addr| {
+                priority_clients.contains(socket::unmap_ipv4(addr.ip()))
+            }) =>
+        {
+            Priority::High
+        }
+        _ => Priority::Normal,
+    };
+    let session_feedback = feedback.as_ref().map(|(feedback, _)| feedback.clone());
+    let session = move || {
+        if let Some(feedback) = &session_feedback {
+            feedback.session_started();
+        }
+        let lease = match &context.port_leases {
+            Some(leases) => match leases.lease() {
+                Ok(Some(lease)) => Some(lease),
+                Ok(None) => {
+                    warn!("Rejected connection, all ports to spawn servers on are leased");
+                    context.stats.session_rejected();
+                    return;
+                }
+                Err(err) => {
+                    error!("Rejected connection, failed to lease a port: {}", err);
+                    context.stats.session_rejected();
+                    return;
+                }
+            },
+            None => None,
+        };
+        let port = lease.as_ref().map_or(port, |lease| lease.port());
+        let temp_dir = match &session_args.server_temp_dir {
+            Some(base) => match ServerTempDir::create(base) {
+                Ok(temp_dir) => {
+                    if let Some(user) = &session_args.server_user {
+                        if let Err(err) = user.give(temp_dir.path()) {
+                            warn!(
+                                "Failed to hand the temporary directory {} to {}: {}",
+                                temp_dir.path().display(),
+                                user.name,
+                                err
+                            );
+                        }
+                    }
+                    Some(temp_dir)
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to create a temporary directory for the LSP below {}: {}",
+                        base.display(),
+                        err
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let canary = session_args.canary_jar.is_some()
+            && rand::thread_rng().gen_range(0..100) < session_args.canary_percent;
+        let startup_timeout = Duration::from_secs(session_args.startup_timeout);
+        // taken before a resumable session replaces the connection with a local one
+        let client_ip = client_con
+            .peer_addr()
+            .ok()
+            .map(|addr| socket::unmap_ipv4(addr.ip()));
+        let passthrough = match (&session_args.passthrough_clients, client_ip) {
+            (Some(passthrough_clients), Some(ip)) => passthrough_clients.contains(ip),
+            _ => false,
+        };
+        let workspace_sync =
+            session_args.sync_workspace
+                .as_ref()
+                .and_then(|base| match WorkspaceSync::new(base) {
+                    Ok(sync) => Some(Arc::new(sync)),
+                    Err(err) => {
+                        error!(
+                        "Failed to create a directory to synchronize the workspace into below {}: {}",
+                        base.display(),
+                        err
+                    );
+                        None
+                    }
+                });
+        let watchdog = session_args.request_deadline.map(|deadline| {
+            Arc::new(RequestWatchdog::new(
+                Duration::from_secs(deadline),
+                session_args.cancel_slow_requests,
+            ))
+        });
+        let fast_path =
+            session_args.local_fast_path && client_ip.map_or(false, |ip| ip.is_loopback());
+        let relay_options = if fast_path {
+            RelayOptions {
+                low_latency: true,
+                ..RelayOptions::default()
+            }
+        } else {
+            RelayOptions {
+                parse_messages: session_args.parse_lsp && !passthrough,
+                keepalive: session_args.keepalive.map(Duration::from_secs),
+                filters: message_filters(
+                    &session_args,
+                    &context,
+                    workspace_sync.clone(),
+                    watchdog.as_ref(),
+                ),
+                max_message_size: session_args.max_message_size,
+                throttle: session_args
+                    .max_kbps_per_session
+                    .map(|kbps| Arc::new(Throttle::new(kbps.get().saturating_mul(1000) / 8))),
+                protocol_trace: None,
+                observed: None,
+                chaos: context.chaos.clone(),
+                watchdog,
+                send_buffer: session_args.send_buffer.map(|kib| kib.saturating_mul(1024)),
+                backpressure: session_args.backpressure,
+                checksums: session_args.checksums,
+                low_latency: false,
             }
         };
 
-        let client_read = match client_con.try_clone() {
-            Ok(x) => x,
-            Err(err) => {
-                error!(
-                    "[{}] Failed to clone client stream, for independent processing of writes and reads: {}",
-                    client, err
-                );
-                return;
-            }
+        // the placeholders of the launch include the id of the session, only known once it is listed
+        let launch = |session_id| {
+            let launch = LaunchContext {
+                port,
+                session_id,
+                workspace: workspace_sync
+                    .as_ref()
+                    .map(|sync| sync.directory().to_path_buf())
+                    .or_else(|| std::env::current_dir().ok())
+                    .unwrap_or_default(),
+                tmpdir: temp_dir
+                    .as_ref()
+                    .map(|temp_dir| temp_dir.path().to_path_buf())
+                    .unwrap_or_else(std::env::temp_dir),
+                client_ip,
+            };
+            let jar = match (&session_args.canary_jar, canary) {
+                (Some(canary_jar), true) => canary_jar,
+                _ => &session_args.lsp_jar,
+            };
+            lsp_command(&launch, &session_args, jar, temp_dir.as_ref())
         };
-        let client_write = client_con;
+        let client = client_con
+            .peer_addr()
+            .map_or_else(|_| String::from("unknown"), |addr| addr.to_string());
 
-        debug!("[{}] Giving the LSP time to startup!", client);
-        std::thread::sleep(Duration::from_secs(5));
-        info!("[{}] Attempting to connect to LSP at {}", client, lsp);
+        if let Some(server_header) = &http_upgrade {
+            match upgrade::accept(&client_con, server_header) {
+                Ok(true) => debug!("[{}] Accepted HTTP handshake", client),
+                Ok(false) => {}
+                Err(err) => {
+                    warn!("[{}] Failed HTTP handshake: {}", client, err);
+                    context.stats.session_closed(Duration::default(), false);
+                    return;
+                }
+            }
+        }
 
-        let server_con = loop {
-            let server_con = std::net::TcpStream::connect(lsp_addrs.as_slice());
-            if let Ok(con) = server_con {
-                if let Ok(lsp_addr) = con.peer_addr() {
-                    lsp = lsp_addr.to_string();
+        if redirect_enabled {
+            match redirect::requested(&client_con) {
+                Ok(false) => {}
+                Ok(true) => {
+                    let admission = match context.guardrails.admit(client_ip) {
+                        Ok(admission) => admission,
+                        Err(err) => {
+                            warn!("[{}] Refusing session: {}", client, err);
+                            redirect::send_unavailable(&client_con);
+                            context.stats.session_rejected();
+                            return;
+                        }
+                    };
+                    let listed = context.sessions.list(&client, port);
+                    let lsp_cmd = launch(listed.id());
+                    let mut record = SessionRecord::new(listed.id(), client, port);
+                    record.canary = canary;
+                    let mut trace = SessionTrace::new();
+                    let exit_reason = serve_redirected(
+                        client_con,
+                        lsp_cmd,
+                        startup_timeout,
+                        &context,
+                        &mut record,
+                        &mut trace,
+                    );
+                    // the session no longer counts against the quota once it is logged as ended
+                    drop(admission);
+                    drop(listed);
+                    finish_session(record, trace, exit_reason, &context);
+                    return;
+                }
+                Err(err) => {
+                    warn!("[{}] Failed to read redirect preamble: {}", client, err);
+                    context.stats.session_closed(Duration::default(), false);
+                    return;
                 }
-                info!("[{}] Connected to LSP at {}", client, lsp);
-                break con;
-            } else if let Ok(Some(_exit)) = lsp_proc.try_wait() {
-                return;
-            } else {
-                std::thread::sleep(Duration::from_secs(1));
-                info!("[{}] Re-Attempting to connect to LSP at {}", client, lsp);
             }
+        }
+
+        let client_con = match &context.resumable {
+            Some(resumable) => match resumable.negotiate(client_con, &client) {
+                Some(con) => con,
+                None => {
+                    // the connection was handed to an existing session, or the handshake failed
+                    context.stats.session_closed(Duration::default(), false);
+                    return;
+                }
+            },
+            None => client_con,
         };
 
-        let server_read = match server_con.try_clone() {
-            Ok(x) => x,
+        match context.client_gate.check(&client_con) {
+            Verdict::Pass => {}
+            Verdict::Warn(outdated) => {
+                info!("[{}] Serving outdated client: {}", client, outdated);
+                gate::warn(&client_con, &outdated);
+            }
+            Verdict::Refuse(outdated) => {
+                warn!("[{}] Refusing session: {}", client, outdated);
+                guard::refuse(client_con, &outdated);
+                context.stats.session_rejected();
+                return;
+            }
+        }
+
+        let admission = match context.guardrails.admit(client_ip) {
+            Ok(admission) => admission,
             Err(err) => {
-                error!(
-                    "[{}] Failed to clone server stream, for independent processing of writes and reads: {}",
-                    client, err
-                );
+                warn!("[{}] Refusing session: {}", client, err);
+                guard::refuse(client_con, &err);
+                context.stats.session_rejected();
                 return;
             }
         };
-        let server_write = server_con;
 
-        let join_handle = std::thread::spawn(move || relay_connection(server_read, client_write));
+        let listed = context.sessions.list(&client, port);
+        let relay_options = if relay_options.low_latency {
+            debug!("[{}] Relaying via the local fast path", client);
+            relay_options
+        } else {
+            RelayOptions {
+                protocol_trace: Some(ProtocolTrace::new(
+                    client.clone(),
+                    context.log_levels.clone(),
+                )),
+                observed: context
+                    .observable
+                    .as_ref()
+                    .map(|observable| Arc::new(observable.register(listed.id()))),
+                ..relay_options
+            }
+        };
+        let lsp_cmd = launch(listed.id());
+        let mut record = SessionRecord::new(listed.id(), client, port);
+        record.canary = canary;
+        let mut trace = SessionTrace::new();
+        let exit_reason = serve_connection(
+            client_con,
+            lsp_cmd,
+            startup_timeout,
+            &relay_options,
+            &context,
+            &mut record,
+            &mut trace,
+        );
+        drop(admission);
+        drop(listed);
+        finish_session(record, trace, exit_reason, &context);
+        // the server has exited, so its temporary files can go
+        drop(temp_dir);
+        // as can its port
+        drop(lease);
+    };
+    let session_stats = stats.clone();
+    let queued = workers.try_execute(priority, move || {
+        // the worker survives a panicking session, which must not keep counting as active though
+        if std::panic::catch_unwind(AssertUnwindSafe(session)).is_err() {
+            error!("A session panicked");
+            session_stats.session_closed(Duration::default(), false);
+        }
+        if single {
+            info!("The single session ended");
+            shutdown::request();
+        }
+    });
+
+    if let (Some(ticket), Some((feedback, feedback_con))) = (queued, feedback) {
+        feedback.start(feedback_con, ticket, workers, stats);
+    } else if queued.is_none() {
+        // dropping the job closed the client connection
+        warn!("Rejected connection, all session workers are busy and the queue is full");
+        stats.session_rejected();
+        if let Some(webhooks) = &webhooks {
+            webhooks.notify(
+                Event::PoolExhausted,
+                None,
+                "rejected a connection, all session workers are busy and the queue is full",
+            );
+        }
+    }
+}
 
-        relay_connection(client_read, server_write);
+/// Account for a finished session in the statistics, the session log and the exported traces
+fn finish_session(
+    mut record: SessionRecord,
+    mut trace: SessionTrace,
+    exit_reason: ExitReason,
+    context: &Context,
+) {
+    record.finish(exit_reason);
+    info!(
+        "[{}] Session ended after {:.1?}: {}",
+        record.client,
+        record.duration(),
+        exit_reason.as_str()
+    );
 
-        debug!("[{}] Killing LSP at {}", client, lsp);
-        if let Err(err) = lsp_proc.kill() {
-            warn!("[{}] Failed to kill lsp child process: {}", client, err);
-            info!("[{}] Will not wait for lsp child process", client)
-        } else {
-            // only wait on lsp process if it was killed successfully
-            if let Err(err) = lsp_proc.wait() {
-                warn!("[{}] Failed to wait for lsp child process: {}", client, err)
+    if exit_reason == ExitReason::ServerCrashed {
+        if let Some(webhooks) = &context.webhooks {
+            let status = record.server_exit.map_or_else(
+                || String::from("an unknown status"),
+                |status| status.to_string(),
+            );
+            webhooks.notify(
+                Event::ServerCrash,
+                Some(&record.client),
+                &format!("the language server crashed with {}", status),
+            );
+        }
+    }
+
+    let stats = &context.stats;
+    stats.add_bytes_relayed(record.bytes_client_to_server + record.bytes_server_to_client);
+    stats.session_closed(record.duration(), exit_reason.served());
+    stats.session_ended(exit_reason);
+
+    if let Some(session_log) = &context.session_log {
+        if let Err(err) = session_log.append(&record) {
+            error!(
+                "[{}] Failed to append to session log: {}",
+                record.client, err
+            );
+        }
+    }
+
+    if let Some(exporter) = &context.exporter {
+        trace.set_attribute("client.address", &record.client);
+        trace.set_attribute("server.port", record.port);
+        trace.set_attribute("session.exit_reason", exit_reason.as_str());
+        if let Some(root_uri) = &record.root_uri {
+            trace.set_attribute("lsp.root_uri", root_uri);
+        }
+        trace.finish();
+        exporter.export(trace);
+    }
+}
+
+/// Account for a language server of `client` that failed to start
+///
+/// Only the first of identical failures within the alert interval is logged as an error,
+/// e.g. every spawn fails with a broken jar, the others are summarized by [`spawn_failure_alerts`]
+fn spawn_failed(context: &Context, client: &str, err: &ServerError) {
+    context.stats.spawn_failed();
+    let details = format!("failed to start the language server: {}", err);
+    if context.spawn_failures.report(&details) {
+        error!("[{}] Failed to start LSP: {}", client, err);
+    } else {
+        debug!("[{}] Failed to start LSP: {}", client, err);
+    }
+    if let Some(webhooks) = &context.webhooks {
+        webhooks.notify(Event::SpawnFailed, Some(client), &details);
+    }
+}
+
+/// Coalesce the logs of identical spawn failures within `interval`
+fn spawn_failure_alerts(interval: Duration) -> Arc<Alerts<String>> {
+    Alerts::start(interval, move |details: &String, repeated| {
+        error!(
+            "Repeatedly {}, {} more times in the last {:.0?}",
+            details, repeated, interval
+        );
+    })
+}
+
+/// The additional log destinations configured by `args`
+fn log_sinks(args: &Arguments) -> logging::Sinks {
+    logging::Sinks {
+        file: args.log_file.clone().map(|path| logging::FileSink {
+            path,
+            max_size: args.log_max_size.map(|mib| mib.saturating_mul(guard::MIB)),
+            max_age: args.log_max_age.map(Duration::from_secs),
+            keep: args.log_keep,
+        }),
+        syslog: args.syslog,
+    }
+}
+
+/// The resource thresholds configured by `args`
+fn guardrails(args: &Arguments) -> Guardrails {
+    let mut directories = Vec::new();
+    if args.min_free_disk.is_some() {
+        directories.push(
+            args.server_temp_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir),
+        );
+        directories.extend(args.sync_workspace.clone());
+    }
+    Guardrails {
+        min_free_disk: args.min_free_disk.map(|mib| mib.saturating_mul(guard::MIB)),
+        directories,
+        min_free_fds: args.min_free_fds,
+        min_free_memory: args
+            .min_free_memory
+            .map(|mib| mib.saturating_mul(guard::MIB)),
+        max_sessions_per_client: args.max_sessions_per_client,
+        client_sessions: Mutex::default(),
+        subnet_quotas: args.subnet_quotas.clone(),
+        subnet_sessions: Mutex::new(vec![0; args.subnet_quotas.len()]),
+    }
+}
+
+/// The message filters configured by `args`, including the `workspace_sync` and the `watchdog` of the session
+fn message_filters(
+    args: &Arguments,
+    context: &Context,
+    workspace_sync: Option<Arc<WorkspaceSync>>,
+    watchdog: Option<&Arc<RequestWatchdog>>,
+) -> Vec<Arc<dyn MessageFilter>> {
+    let mut filters: Vec<Arc<dyn MessageFilter>> = Vec::new();
+    if let Some(sync) = workspace_sync {
+        filters.push(sync);
+    }
+    if !args.drop_notifications.is_empty() {
+        filters.push(Arc::new(DropNotifications {
+            methods: args.drop_notifications.clone(),
+        }));
+    }
+    if !args.deny_methods.is_empty() {
+        filters.push(Arc::new(DenyMethods {
+            methods: args.deny_methods.clone(),
+        }));
+    }
+    // after the methods are denied, so that denied requests are not answered from the cache
+    // or wait for one that was relayed, and before caching, which only sees the relayed requests
+    if !args.coalesce_requests.is_empty() {
+        filters.push(Arc::new(RequestCoalescing::new(
+            args.coalesce_requests.clone(),
+        )));
+    }
+    if !args.cache_responses.is_empty() {
+        filters.push(Arc::new(ResponseCache::new(args.cache_responses.clone())));
+    }
+    for mapping in &args.rewrite_uris {
+        filters.push(Arc::new(mapping.clone()));
+    }
+    if !args.initialize_patches.is_empty() {
+        filters.push(Arc::new(InitializePatch::new(
+            args.initialize_patches.clone(),
+        )));
+    }
+    // after the others, so that they only see the requests actually relayed to the server
+    if let Some(latencies) = &context.latencies {
+        filters.push(Arc::new(RequestTimer::new(latencies.clone())));
+    }
+    if let Some(watchdog) = watchdog {
+        filters.push(watchdog.clone());
+    }
+    // last, so that the other filters see messages to the client uncompressed
+    if let Some(threshold) = args.compression_threshold {
+        filters.push(Arc::new(Compression::new(threshold)));
+    }
+    filters
+}
+
+/// Spawn a language server on `port` and wait until `probe` finds it ready, returning what the probe returned
+fn start_server<T>(
+    mut lsp_cmd: Command,
+    port: u16,
+    client: &str,
+    startup_timeout: Duration,
+    context: &Context,
+    trace: &mut SessionTrace,
+    probe: impl Fn(u16) -> Option<T>,
+) -> Result<(Child, T), ServerError> {
+    info!(
+        "[{}] attempting to spawn LSP on port {}\n> {:?}",
+        client, port, lsp_cmd
+    );
+
+    if let Some(chaos) = &context.chaos {
+        chaos.delay_spawn(client);
+    }
+    let spawn_span = trace.start_span("server spawn");
+    let started = Instant::now();
+    let spawned = lsp_cmd.spawn();
+    trace.end_span(spawn_span);
+    let mut lsp_proc = spawned.map_err(ServerError::SpawnFailed)?;
+    context.servers.spawned(&lsp_proc);
+    if let Some(state) = &context.state {
+        state.server_spawned(lsp_proc.id(), port);
+    }
+
+    let readiness_span = trace.start_span("readiness wait");
+    debug!("[{}] Giving the LSP time to startup!", client);
+    std::thread::sleep(context.startup_delay);
+    info!(
+        "[{}] Waiting for the LSP on port {} to become ready",
+        client, port
+    );
+
+    loop {
+        if let Some(ready) = probe(port) {
+            info!("[{}] LSP on port {} is ready", client, port);
+            trace.end_span(readiness_span);
+            record_readiness(&context.stats, client, started.elapsed());
+            return Ok((lsp_proc, ready));
+        } else if let Ok(Some(status)) = lsp_proc.try_wait() {
+            server_reaped(context, lsp_proc.id());
+            return Err(ServerError::ExitedWithStatus(status));
+        } else if started.elapsed() >= startup_timeout {
+            if let Err(err) = process::kill_group(&mut lsp_proc).and_then(|()| lsp_proc.wait()) {
+                warn!(
+                    "[{}] Failed to kill unresponsive lsp child process: {}",
+                    client, err
+                );
+            } else {
+                server_reaped(context, lsp_proc.id());
             }
+            return Err(ServerError::Timeout(startup_timeout));
+        } else {
+            std::thread::sleep(Duration::from_secs(1));
+            info!(
+                "[{}] Re-Checking whether the LSP on port {} is ready",
+                client, port
+            );
         }
-        if let Err(_err) = join_handle.join() {
+    }
+}
+
+/// Forget a server that is no longer running, so that it isn't mistaken for an orphan
+fn server_reaped(context: &Context, pid: u32) {
+    context.servers.reaped(pid);
+    if let Some(state) = &context.state {
+        state.server_reaped(pid);
+    }
+}
+
+/// Stop the server of the session of `record` and wait for it, returning how it exited
+///
+/// A server that can't be killed or waited for is left to the reaper, so that it doesn't linger as a zombie.
+/// The post-exit hook runs once the server exited.
+fn stop_server(
+    context: &Context,
+    record: &SessionRecord,
+    mut lsp_proc: Child,
+) -> Option<ExitStatus> {
+    let client = &record.client;
+    let (session_id, port) = (record.id, record.port);
+    let pid = lsp_proc.id();
+    if let Ok(Some(status)) = lsp_proc.try_wait() {
+        debug!("[{}] LSP exited on its own with {}", client, status);
+        server_reaped(context, pid);
+        context.hooks.post_exit(session_id, port, pid, status);
+        return Some(status);
+    }
+
+    debug!("[{}] Stopping LSP with pid {}", client, pid);
+    match process::stop_group(&mut lsp_proc, context.kill_grace) {
+        Ok(status) => {
+            server_reaped(context, pid);
+            context.hooks.post_exit(session_id, port, pid, status);
+            Some(status)
+        }
+        Err(err) => {
             warn!(
-                "[{}] Failed to join panicked server -> client relay thread",
-                client
+                "[{}] Failed to kill lsp child process, waiting for it in the background: {}",
+                client, err
             );
+            let reaped_context = context.clone();
+            let client = client.to_string();
+            context.reaper.adopt(lsp_proc, move |status| {
+                info!("[{}] LSP with pid {} exited with {}", client, pid, status);
+                server_reaped(&reaped_context, pid);
+                reaped_context
+                    .hooks
+                    .post_exit(session_id, port, pid, status);
+            });
+            None
+        }
+    }
+}
+
+/// Record the readiness latency of a server and warn when it is far above the usual one,
+/// e.g. because the host is under memory pressure
+fn record_readiness(stats: &Statistics, client: &str, latency: Duration) {
+    if let Some(usual) = stats.usual_readiness_latency() {
+        if latency > usual * READINESS_DEGRADED_FACTOR {
+            warn!(
+                "[{}] The LSP took {:.1?} to accept connections, usually it takes {:.1?}",
+                client, latency, usual
+            );
+        }
+    }
+    stats.server_ready(latency);
+}
+
+/// Spawn a language server for a client that connects to it directly, and kill it once the client is done
+///
+/// The client keeps its connection to the proxy open for as long as it uses the server
+fn serve_redirected(
+    client_con: TcpStream,
+    lsp_cmd: Command,
+    startup_timeout: Duration,
+    context: &Context,
+    record: &mut SessionRecord,
+    trace: &mut SessionTrace,
+) -> ExitReason {
+    let port = record.port;
+    let client = record.client.clone();
+
+    // connecting would take the only connection some servers accept, so only check that it listens
+    let started = transport::check_port_available(port)
+        .and_then(|()| context.hooks.pre_spawn(record.id, port))
+        .and_then(|()| {
+            start_server(
+                lsp_cmd,
+                port,
+                &client,
+                startup_timeout,
+                context,
+                trace,
+                transport::server_listening,
+            )
+        });
+    let (mut lsp_proc, ()) = match started {
+        Ok(started) => started,
+        Err(err) => {
+            spawn_failed(context, &client, &err);
+            redirect::send_unavailable(&client_con);
+            return ExitReason::from(&err);
         }
-        info!("[{}] Finished handling a connection and cleanup!", client)
+    };
+
+    let redirect_span = trace.start_span("redirect");
+    match redirect::send_address(&client_con, port) {
+        Ok(()) => {
+            info!("[{}] Redirected to LSP on port {}", client, port);
+            redirect::wait_for_close(&client_con, || {
+                matches!(lsp_proc.try_wait(), Ok(Some(_)) | Err(_))
+            });
+        }
+        Err(err) => warn!("[{}] Failed to redirect client: {}", client, err),
+    }
+
+    let crashed = server_crashed(&mut lsp_proc, false);
+    record.server_exit = stop_server(context, record, lsp_proc);
+    trace.end_span(redirect_span);
+    info!("[{}] Finished handling a redirected connection!", client);
+    session_end_reason(context, crashed, false)
+}
+
+/// Spawn a language server for the client and relay between them until either side disconnects
+///
+/// The bytes relayed and the workspace root are recorded in `record`
+fn serve_connection(
+    client_con: TcpStream,
+    lsp_cmd: Command,
+    startup_timeout: Duration,
+    options: &RelayOptions,
+    context: &Context,
+    record: &mut SessionRecord,
+    trace: &mut SessionTrace,
+) -> ExitReason {
+    let port = record.port;
+    let client = record.client.clone();
+
+    let started = context
+        .server_transport
+        .prepare(port)
+        .and_then(|server| {
+            context.hooks.pre_spawn(record.id, port)?;
+            Ok(server)
+        })
+        .and_then(|server| {
+            start_server(
+                lsp_cmd,
+                port,
+                &client,
+                startup_timeout,
+                context,
+                trace,
+                |_port| server.connect(),
+            )
+        });
+    let (mut lsp_proc, server_con) = match started {
+        Ok(started) => started,
+        Err(err) => {
+            spawn_failed(context, &client, &err);
+            return ExitReason::from(&err);
+        }
+    };
+    let lsp = server_con
+        .peer_addr()
+        .map_or_else(|_| format!("port {}", port), |addr| addr.to_string());
+    info!("[{}] Connected to LSP at {}", client, lsp);
+
+    let session = Session::start(
+        client.clone(),
+        client_con,
+        server_con,
+        options.clone(),
+        &context.clients,
+    );
+    let session = match session {
+        Ok(session) => session,
+        Err(err) => {
+            error!(
+                "[{}] Failed to clone the streams, for independent processing of writes and reads: {}",
+                client, err
+            );
+            record.server_exit = stop_server(context, record, lsp_proc);
+            return ExitReason::SetupFailed;
+        }
+    };
+    let scheduled_kill = context
+        .chaos
+        .as_ref()
+        .and_then(|chaos| chaos.schedule_kill(&client, lsp_proc.id()));
+
+    let relay_span = trace.start_span("relay");
+    let (relayed, (crashed, server_exit)) = session.relay(|closed_connection| {
+        drop(scheduled_kill);
+        let crashed = server_crashed(&mut lsp_proc, closed_connection);
+        debug!("[{}] Stopping LSP at {}", client, lsp);
+        (crashed, stop_server(context, record, lsp_proc))
     });
+    record.server_exit = server_exit;
+    let client_to_server = &relayed.client_to_server;
+    record.bytes_client_to_server = client_to_server.bytes;
+    record.client_to_server_end = Some(client_to_server.classification());
+    record.client_to_server_checksums = client_to_server.checksums;
+    record.relay_error = relay_error(client_to_server);
+    record.root_uri = json::find_string_field(
+        &String::from_utf8_lossy(&relayed.initial_traffic),
+        "rootUri",
+    );
+    if let Some(server_to_client) = &relayed.server_to_client {
+        record.bytes_server_to_client = server_to_client.bytes;
+        record.server_to_client_end = Some(server_to_client.classification());
+        record.server_to_client_checksums = server_to_client.checksums;
+        if record.relay_error.is_none() {
+            record.relay_error = relay_error(server_to_client);
+        }
+    }
+    trace.end_span(relay_span);
+    info!("[{}] Finished handling a connection and cleanup!", client);
+    session_end_reason(context, crashed, record.relay_error.is_some())
+}
+
+/// Why writing failed in the direction of `outcome`, if it ended the relay
+fn relay_error(outcome: &RelayOutcome) -> Option<String> {
+    outcome
+        .write_error()
+        .map(|err| format!("{}: {}", outcome.direction, err))
+}
+
+/// Whether the server exited unsuccessfully on its own, rather than being stopped by the proxy
+///
+/// A server that closed its connection may still be exiting, so it is given a moment to finish
+fn server_crashed(lsp_proc: &mut Child, closed_connection: bool) -> bool {
+    let started = Instant::now();
+    loop {
+        match lsp_proc.try_wait() {
+            Ok(Some(status)) => return !status.success(),
+            Ok(None) if closed_connection && started.elapsed() < SERVER_EXIT_GRACE => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Why a session that was relayed to its server ended
+fn session_end_reason(context: &Context, crashed: bool, relay_failed: bool) -> ExitReason {
+    // the servers stopped on exit exit unsuccessfully as well, so this takes precedence
+    if context.servers.stopped() {
+        if shutdown::draining() {
+            ExitReason::AdminDrain
+        } else {
+            ExitReason::ProxyShutdown
+        }
+    } else if crashed {
+        // writing to a crashed server fails as well, so this takes precedence
+        ExitReason::ServerCrashed
+    } else if relay_failed {
+        ExitReason::RelayFailed
+    } else {
+        ExitReason::ClientClosed
+    }
 }
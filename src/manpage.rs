@@ -0,0 +1,33 @@
+use structopt::clap::App;
+
+/// A man page of the proxy in roff, describing its options by the long help of `app`,
+/// so that it never lags behind the options
+pub fn render(app: &mut App) -> Result<String, String> {
+    let mut help = Vec::new();
+    app.write_long_help(&mut help)
+        .map_err(|err| format!("Failed to render the help: {}", err))?;
+    let help = String::from_utf8_lossy(&help);
+
+    let name = env!("CARGO_PKG_NAME");
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{} {}\"\n.SH NAME\n{} \\- spawn a language server per client connection\n.SH DESCRIPTION\n.nf\n",
+        escape(&name.to_uppercase()),
+        escape(name),
+        env!("CARGO_PKG_VERSION"),
+        escape(name)
+    );
+    for line in help.lines() {
+        // lines starting with a control character would be taken as requests
+        if line.starts_with('.') || line.starts_with('\'') {
+            page.push_str("\\&");
+        }
+        page.push_str(&escape(line));
+        page.push('\n');
+    }
+    page.push_str(".fi\n");
+    Ok(page)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+}
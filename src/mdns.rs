@@ -0,0 +1,419 @@
+use crate::socket;
+use log::{debug, info, warn};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+/// The DNS-SD service type language servers are advertised as
+const SERVICE_TYPE: &str = "_lsp._tcp.local";
+/// The name under which DNS-SD browsers enumerate the available service types
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells receivers to replace rather than add to their cached records, see RFC 6762 Section 10.2
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// TTL of the records naming hosts, see RFC 6762 Section 10
+const HOST_TTL: u32 = 120;
+/// TTL of the other records
+const SERVICE_TTL: u32 = 4500;
+
+/// Answers mDNS queries for our `_lsp._tcp` service instance
+pub struct Advertisement {
+    socket: UdpSocket,
+    records: Records,
+}
+
+impl Advertisement {
+    /// Start answering queries for the service instance `name` on `port` from a background thread
+    /// and announce it to the local network
+    pub fn start(name: &str, port: u16) -> Result<Self, String> {
+        if name.is_empty() || name.len() > 63 {
+            return Err(String::from(
+                "the mDNS service name should be between 1 and 63 bytes long",
+            ));
+        }
+
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT));
+        let socket = socket::bind_shared_udp(address)
+            .map_err(|err| format!("Failed to bind mDNS socket: {}", err))?;
+        socket
+            .join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)
+            .map_err(|err| format!("Failed to join mDNS multicast group: {}", err))?;
+        let address = local_address()
+            .map_err(|err| format!("Failed to determine address to advertise: {}", err))?;
+
+        let records = Records {
+            instance: format!("{}.{}", name, SERVICE_TYPE),
+            host: format!("{}.local", hostname()),
+            address,
+            port,
+        };
+        info!(
+            "Advertising {} at {}:{} via mDNS",
+            records.instance, records.host, port
+        );
+
+        let responder_socket = socket
+            .try_clone()
+            .map_err(|err| format!("Failed to clone mDNS socket: {}", err))?;
+        let responder_records = records.clone();
+        std::thread::spawn(move || respond(&responder_socket, &responder_records));
+
+        let advertisement = Self { socket, records };
+        // announce twice, one second apart, see RFC 6762 Section 8.3
+        advertisement.announce(false);
+        std::thread::sleep(Duration::from_secs(1));
+        advertisement.announce(false);
+        Ok(advertisement)
+    }
+
+    /// Tell the local network that the service is going away
+    pub fn withdraw(&self) {
+        info!(
+            "Withdrawing mDNS advertisement of {}",
+            self.records.instance
+        );
+        self.announce(true);
+    }
+
+    fn announce(&self, goodbye: bool) {
+        let message = self.records.response(0, goodbye);
+        if let Err(err) = self.socket.send_to(&message, (MDNS_GROUP, MDNS_PORT)) {
+            warn!("Failed to send mDNS announcement: {}", err);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Records {
+    instance: String,
+    host: String,
+    address: Ipv4Addr,
+    port: u16,
+}
+
+impl Records {
+    /// Whether a question for `name` of type `qtype` concerns our records
+    fn answers(&self, name: &str, qtype: u16) -> bool {
+        let matches = |expected: &str, types: &[u16]| {
+            name.eq_ignore_ascii_case(expected) && (qtype == TYPE_ANY || types.contains(&qtype))
+        };
+        matches(SERVICE_TYPE, &[TYPE_PTR])
+            || matches(SERVICE_ENUMERATION, &[TYPE_PTR])
+            || matches(&self.instance, &[TYPE_SRV, TYPE_TXT])
+            || matches(&self.host, &[TYPE_A])
+    }
+
+    /// Build a response carrying all our records, with a TTL of zero if this is a `goodbye`
+    fn response(&self, id: u16, goodbye: bool) -> Vec<u8> {
+        let ttl = |ttl: u32| if goodbye { 0 } else { ttl };
+        let mut message = Vec::with_capacity(512);
+        message.extend_from_slice(&id.to_be_bytes());
+        // a response with the authoritative answer bit set
+        message.extend_from_slice(&0x8400u16.to_be_bytes());
+        // no questions, five answers, no authority or additional records
+        for count in &[0u16, 5, 0, 0] {
+            message.extend_from_slice(&count.to_be_bytes());
+        }
+
+        let mut ptr = Vec::new();
+        encode_name(&mut ptr, &self.instance);
+        write_record(
+            &mut message,
+            SERVICE_TYPE,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(SERVICE_TTL),
+            &ptr,
+        );
+
+        let mut enumeration = Vec::new();
+        encode_name(&mut enumeration, SERVICE_TYPE);
+        write_record(
+            &mut message,
+            SERVICE_ENUMERATION,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(SERVICE_TTL),
+            &enumeration,
+        );
+
+        let mut srv = Vec::new();
+        // priority and weight
+        srv.extend_from_slice(&[0, 0, 0, 0]);
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        encode_name(&mut srv, &self.host);
+        write_record(
+            &mut message,
+            &self.instance,
+            TYPE_SRV,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(HOST_TTL),
+            &srv,
+        );
+
+        // a single empty string, as required for records without any key value pairs
+        write_record(
+            &mut message,
+            &self.instance,
+            TYPE_TXT,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(SERVICE_TTL),
+            &[0],
+        );
+
+        write_record(
+            &mut message,
+            &self.host,
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(HOST_TTL),
+            &self.address.octets(),
+        );
+        message
+    }
+}
+
+fn respond(socket: &UdpSocket, records: &Records) {
+    let mut buffer = [0; 9000];
+    loop {
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("Failed to receive mDNS query: {}", err);
+                continue;
+            }
+        };
+        let id = match query_concerning(&buffer[..len], records) {
+            Some(id) => id,
+            None => continue,
+        };
+        debug!("Answering mDNS query from {}", source);
+
+        // queries not sent from the mDNS port come from simple resolvers expecting a unicast reply,
+        // see RFC 6762 Section 6.7
+        let result = if source.port() == MDNS_PORT {
+            socket.send_to(&records.response(0, false), (MDNS_GROUP, MDNS_PORT))
+        } else {
+            socket.send_to(&records.response(id, false), source)
+        };
+        if let Err(err) = result {
+            warn!("Failed to send mDNS response: {}", err);
+        }
+    }
+}
+
+/// If `message` is a query with a question about our records returns its id
+fn query_concerning(message: &[u8], records: &Records) -> Option<u16> {
+    let id = u16::from_be_bytes([*message.first()?, *message.get(1)?]);
+    let flags = u16::from_be_bytes([*message.get(2)?, *message.get(3)?]);
+    if flags & 0x8000 != 0 {
+        // a response of another responder
+        return None;
+    }
+    let questions = u16::from_be_bytes([*message.get(4)?, *message.get(5)?]);
+
+    let mut offset = 12;
+    let mut concerned = false;
+    for _ in 0..questions {
+        let (name, end) = decode_name(message, offset)?;
+        let qtype = u16::from_be_bytes([*message.get(end)?, *message.get(end + 1)?]);
+        offset = end + 4;
+        concerned |= records.answers(&name, qtype);
+    }
+    if concerned {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+fn write_record(message: &mut Vec<u8>, name: &str, rtype: u16, class: u16, ttl: u32, data: &[u8]) {
+    encode_name(message, name);
+    message.extend_from_slice(&rtype.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+}
+
+/// Encode a name as a sequence of labels, the first label of an instance name may contain dots
+fn encode_name(buffer: &mut Vec<u8>, name: &str) {
+    let labels: Vec<&str> = if let Some(instance) = name.strip_suffix(&format!(".{}", SERVICE_TYPE))
+    {
+        std::iter::once(instance)
+            .chain(SERVICE_TYPE.split('.'))
+            .collect()
+    } else {
+        name.split('.').collect()
+    };
+    for label in labels {
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label.as_bytes());
+    }
+    buffer.push(0);
+}
+
+/// Decode the name starting at `offset`, following compression pointers
+///
+/// Returns the name and the offset just past it
+fn decode_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bound the number of pointers followed, so that a pointer loop can't keep us busy
+    for _ in 0..128 {
+        let len = *message.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        } else if len & 0xC0 == 0xC0 {
+            let pointer = (len & 0x3F) << 8 | *message.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+        } else {
+            let label = message.get(offset + 1..offset + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + len;
+        }
+    }
+    None
+}
+
+/// The address of the interface used to reach the mDNS group
+fn local_address() -> std::io::Result<Ipv4Addr> {
+    // connecting a udp socket sends nothing, but makes the kernel pick the outgoing interface
+    let probe = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    probe.connect((MDNS_GROUP, MDNS_PORT))?;
+    match probe.local_addr()? {
+        SocketAddr::V4(address) => Ok(*address.ip()),
+        SocketAddr::V6(_) => Ok(Ipv4Addr::LOCALHOST),
+    }
+}
+
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // Safety: the pointer and length describe the buffer above
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    let len = buffer
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(buffer.len());
+    match std::str::from_utf8(&buffer[..len]) {
+        Ok(hostname) if result == 0 && !hostname.is_empty() => {
+            // only the first label, the domain is replaced by .local
+            hostname.split('.').next().unwrap_or(hostname).to_string()
+        }
+        _ => String::from("lsp-on-demand"),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("lsp-on-demand"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCE: &[u8] = b"\x09My.Server\x04_lsp\x04_tcp\x05local\x00";
+    const SERVICE: &[u8] = b"\x04_lsp\x04_tcp\x05local\x00";
+    const HOST: &[u8] = b"\x03box\x05local\x00";
+
+    fn records() -> Records {
+        Records {
+            instance: String::from("My.Server._lsp._tcp.local"),
+            host: String::from("box.local"),
+            address: Ipv4Addr::new(192, 168, 1, 2),
+            port: 5007,
+        }
+    }
+
+    /// The announcement of `records()`, with the TTLs of host and service records
+    fn expected_response(host_ttl: &[u8; 4], service_ttl: &[u8; 4]) -> Vec<u8> {
+        let mut expected = Vec::new();
+        // the id, the flags of an authoritative response and five answers
+        expected.extend_from_slice(b"\x12\x34\x84\x00\x00\x00\x00\x05\x00\x00\x00\x00");
+
+        expected.extend_from_slice(SERVICE);
+        expected.extend_from_slice(b"\x00\x0c\x00\x01");
+        expected.extend_from_slice(service_ttl);
+        expected.extend_from_slice(&[0, INSTANCE.len() as u8]);
+        expected.extend_from_slice(INSTANCE);
+
+        expected.extend_from_slice(b"\x09_services\x07_dns-sd\x04_udp\x05local\x00");
+        expected.extend_from_slice(b"\x00\x0c\x00\x01");
+        expected.extend_from_slice(service_ttl);
+        expected.extend_from_slice(&[0, SERVICE.len() as u8]);
+        expected.extend_from_slice(SERVICE);
+
+        // SRV with priority and weight 0, port 5007 and the cache flush bit set
+        expected.extend_from_slice(INSTANCE);
+        expected.extend_from_slice(b"\x00\x21\x80\x01");
+        expected.extend_from_slice(host_ttl);
+        expected.extend_from_slice(&[0, 6 + HOST.len() as u8]);
+        expected.extend_from_slice(b"\x00\x00\x00\x00\x13\x8f");
+        expected.extend_from_slice(HOST);
+
+        // TXT with a single empty string
+        expected.extend_from_slice(INSTANCE);
+        expected.extend_from_slice(b"\x00\x10\x80\x01");
+        expected.extend_from_slice(service_ttl);
+        expected.extend_from_slice(b"\x00\x01\x00");
+
+        expected.extend_from_slice(HOST);
+        expected.extend_from_slice(b"\x00\x01\x80\x01");
+        expected.extend_from_slice(host_ttl);
+        expected.extend_from_slice(b"\x00\x04\xc0\xa8\x01\x02");
+        expected
+    }
+
+    #[test]
+    fn announces_all_records() {
+        // 120 seconds for host records, 4500 seconds for the others
+        assert_eq!(
+            records().response(0x1234, false),
+            expected_response(b"\x00\x00\x00\x78", b"\x00\x00\x11\x94")
+        );
+    }
+
+    #[test]
+    fn says_goodbye_with_a_ttl_of_zero() {
+        assert_eq!(
+            records().response(0x1234, true),
+            expected_response(&[0; 4], &[0; 4])
+        );
+    }
+
+    #[test]
+    fn answers_queries_about_its_records() {
+        // a PTR question for the service type, and an A question for the host
+        // ending in a compression pointer to the `local` label of the first question
+        let mut query = b"\x56\x78\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00".to_vec();
+        query.extend_from_slice(SERVICE);
+        query.extend_from_slice(b"\x00\x0c\x00\x01");
+        query.extend_from_slice(b"\x03box\xc0\x16");
+        query.extend_from_slice(b"\x00\x01\x00\x01");
+        assert_eq!(query_concerning(&query, &records()), Some(0x5678));
+
+        // the host is not asked about, only its address
+        let mut other = query[..12].to_vec();
+        other[5] = 1;
+        other.extend_from_slice(HOST);
+        other.extend_from_slice(b"\x00\x10\x00\x01");
+        assert_eq!(query_concerning(&other, &records()), None);
+
+        // responses of other responders aren't answered
+        let mut response = query;
+        response[2] = 0x84;
+        assert_eq!(query_concerning(&response, &records()), None);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::filter::MessageFilter;
+use crate::json;
+use crate::lsp::Message;
+use crate::relay::Direction;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The upper bounds of the latency buckets in seconds, from hovers to layouts of large diagrams
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// How many methods get a histogram of their own, as clients choose the methods
+const MAX_METHODS: usize = 100;
+/// The method the latencies of all further methods are recorded as
+const OTHER_METHOD: &str = "other";
+
+/// How long the language servers took to answer requests, by method, for `/metrics`
+#[derive(Debug, Default)]
+pub struct RequestLatencies {
+    methods: Mutex<BTreeMap<String, Histogram>>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// How many latencies fell into each bucket, not counting the smaller buckets
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl RequestLatencies {
+    pub fn record(&self, method: &str, latency: Duration) {
+        let mut methods = self.lock();
+        let method = if methods.contains_key(method) || methods.len() < MAX_METHODS {
+            method
+        } else {
+            OTHER_METHOD
+        };
+        let histogram = methods.entry(method.to_string()).or_default();
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// The histograms in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::from(concat!(
+            "# HELP lsp_request_duration_seconds How long the language servers took to answer requests.\n",
+            "# TYPE lsp_request_duration_seconds histogram\n"
+        ));
+        for (method, histogram) in self.lock().iter() {
+            let method = label_value(method);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "lsp_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    method, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "lsp_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                method, histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "lsp_request_duration_seconds_sum{{method=\"{}\"}} {}",
+                method, histogram.sum
+            );
+            let _ = writeln!(
+                text,
+                "lsp_request_duration_seconds_count{{method=\"{}\"}} {}",
+                method, histogram.count
+            );
+        }
+        text
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Histogram>> {
+        // a poisoned lock only means another thread panicked, the histograms are still valid
+        self.methods
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Escape a label value of the Prometheus text format
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Times the requests of the client until the server answered them, recording the latencies
+#[derive(Debug)]
+pub struct RequestTimer {
+    latencies: Arc<RequestLatencies>,
+    /// The method of the requests relayed to the server and when, by their id
+    pending: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl RequestTimer {
+    pub fn new(latencies: Arc<RequestLatencies>) -> Self {
+        Self {
+            latencies,
+            pending: Mutex::default(),
+        }
+    }
+}
+
+impl MessageFilter for RequestTimer {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        let id = match json::top_level_value(&content, "id") {
+            Some(id) => id.to_json(),
+            None => return Some(message),
+        };
+        // a poisoned lock only means another relay thread panicked, the requests are still valid
+        let mut pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match (direction, json::top_level_string_field(&content, "method")) {
+            (Direction::ClientToServer, Some(method)) => {
+                pending.insert(id, (method, Instant::now()));
+            }
+            (Direction::ServerToClient, None) => {
+                if let Some((method, since)) = pending.remove(&id) {
+                    self.latencies.record(&method, since.elapsed());
+                }
+            }
+            // requests of the server to the client are not timed
+            _ => {}
+        }
+        Some(message)
+    }
+}
@@ -0,0 +1,381 @@
+use crate::socket;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// The size of a frame header: stream id (u32), kind (u8) and payload length (u32), all big endian
+const HEADER_LEN: usize = 9;
+/// The largest payload a single frame may carry
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// The kinds of frames of the multiplexing protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Payload for a stream
+    Data = 0,
+    /// Opens a new stream, sent by the client only
+    Open = 1,
+    /// The sender won't send any more data on the stream
+    Close = 2,
+    /// The stream was aborted, in both directions
+    Reset = 3,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Data),
+            1 => Some(Self::Open),
+            2 => Some(Self::Close),
+            3 => Some(Self::Reset),
+            _ => None,
+        }
+    }
+}
+
+/// Writes frames to the multiplexed connection on behalf of all streams
+#[derive(Clone)]
+struct FrameWriter {
+    con: Arc<Mutex<TcpStream>>,
+}
+
+impl FrameWriter {
+    fn send(&self, id: u32, kind: Kind, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.push(kind as u8);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        // a poisoned lock only means another writer panicked, the connection itself is still usable
+        let mut con = self
+            .con
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        con.write_all(&frame)
+    }
+}
+
+/// The loopback end of an open stream
+struct Stream {
+    con: TcpStream,
+    /// Whether the client sent a close frame
+    client_closed: bool,
+    /// Whether we sent a close frame
+    server_closed: bool,
+}
+
+type Streams = Arc<Mutex<HashMap<u32, Stream>>>;
+
+/// Serve the streams multiplexed over `con` until it is closed
+///
+/// Each stream the client opens is bridged to a loopback connection,
+/// whose other end is passed to `open` to be served like any other client connection.
+pub fn serve<F>(con: TcpStream, mut open: F)
+where
+    F: FnMut(TcpStream),
+{
+    let peer = con
+        .peer_addr()
+        .map_or_else(|_| String::from("unknown"), |address| address.to_string());
+    let writer = match con.try_clone() {
+        Ok(clone) => FrameWriter {
+            con: Arc::new(Mutex::new(clone)),
+        },
+        Err(err) => {
+            warn!("[{}] Failed to clone multiplexed connection: {}", peer, err);
+            return;
+        }
+    };
+    let streams: Streams = Arc::default();
+
+    if let Err(err) = read_frames(con, &peer, &writer, &streams, &mut open) {
+        warn!("[{}] Multiplexed connection failed: {}", peer, err);
+    }
+
+    debug!("[{}] Multiplexed connection closed", peer);
+    for (_, stream) in lock(&streams).drain() {
+        let _ = stream.con.shutdown(Shutdown::Both);
+    }
+}
+
+fn read_frames<F>(
+    mut con: TcpStream,
+    peer: &str,
+    writer: &FrameWriter,
+    streams: &Streams,
+    open: &mut F,
+) -> std::io::Result<()>
+where
+    F: FnMut(TcpStream),
+{
+    let mut header = [0; HEADER_LEN];
+    let mut payload = vec![0; MAX_PAYLOAD];
+    loop {
+        match con.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let kind = Kind::from_u8(header[4]).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown frame kind {}", header[4]),
+            )
+        })?;
+        if len > MAX_PAYLOAD {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("frame payload of {} bytes exceeds the limit", len),
+            ));
+        }
+        con.read_exact(&mut payload[..len])?;
+
+        match kind {
+            Kind::Open => {
+                if lock(streams).contains_key(&id) {
+                    warn!("[{}] Stream {} opened twice, resetting it", peer, id);
+                    reset(id, writer, streams);
+                    continue;
+                }
+                match open_stream(id, peer, writer, streams) {
+                    Ok(remote) => open(remote),
+                    Err(err) => {
+                        warn!("[{}] Failed to open stream {}: {}", peer, id, err);
+                        writer.send(id, Kind::Reset, &[])?;
+                    }
+                }
+            }
+            Kind::Data => {
+                let result = match lock(streams).get_mut(&id) {
+                    Some(stream) => stream.con.write_all(&payload[..len]),
+                    None => continue,
+                };
+                if let Err(err) = result {
+                    debug!(
+                        "[{}] Failed to forward data of stream {}: {}",
+                        peer, id, err
+                    );
+                    reset(id, writer, streams);
+                }
+            }
+            Kind::Close => {
+                let mut streams = lock(streams);
+                if let Some(stream) = streams.get_mut(&id) {
+                    let _ = stream.con.shutdown(Shutdown::Write);
+                    stream.client_closed = true;
+                    if stream.server_closed {
+                        streams.remove(&id);
+                    }
+                }
+            }
+            Kind::Reset => {
+                if let Some(stream) = lock(streams).remove(&id) {
+                    let _ = stream.con.shutdown(Shutdown::Both);
+                }
+            }
+        }
+    }
+}
+
+/// Create the loopback connection for stream `id` and start forwarding what is sent on it
+///
+/// Returns the end of the loopback connection that is to be served
+fn open_stream(
+    id: u32,
+    peer: &str,
+    writer: &FrameWriter,
+    streams: &Streams,
+) -> std::io::Result<TcpStream> {
+    let (local, remote) = socket::loopback_pair()?;
+    let local_reader = local.try_clone()?;
+    lock(streams).insert(
+        id,
+        Stream {
+            con: local,
+            client_closed: false,
+            server_closed: false,
+        },
+    );
+    debug!("[{}] Opened stream {}", peer, id);
+
+    let writer = writer.clone();
+    let streams = streams.clone();
+    std::thread::spawn(move || forward(id, local_reader, &writer, &streams));
+    Ok(remote)
+}
+
+/// Send everything read from `local` as data frames of stream `id`
+fn forward(id: u32, mut local: TcpStream, writer: &FrameWriter, streams: &Streams) {
+    let mut buffer = vec![0; MAX_PAYLOAD];
+    loop {
+        match local.read(&mut buffer) {
+            Ok(0) => {
+                {
+                    let mut streams = lock(streams);
+                    if let Some(stream) = streams.get_mut(&id) {
+                        stream.server_closed = true;
+                        if stream.client_closed {
+                            streams.remove(&id);
+                        }
+                    }
+                }
+                let _ = writer.send(id, Kind::Close, &[]);
+                break;
+            }
+            Ok(len) => {
+                if writer.send(id, Kind::Data, &buffer[..len]).is_err() {
+                    break;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => {
+                reset(id, writer, streams);
+                break;
+            }
+        }
+    }
+}
+
+fn reset(id: u32, writer: &FrameWriter, streams: &Streams) {
+    if let Some(stream) = lock(streams).remove(&id) {
+        let _ = stream.con.shutdown(Shutdown::Both);
+    }
+    let _ = writer.send(id, Kind::Reset, &[]);
+}
+
+fn lock(streams: &Streams) -> std::sync::MutexGuard<'_, HashMap<u32, Stream>> {
+    // a poisoned lock only means another thread panicked, the streams are still usable
+    streams
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn frame(id: u32, kind: Kind, payload: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_be_bytes().to_vec();
+        frame.push(kind as u8);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn read_frame(con: &mut TcpStream) -> (u32, Kind, Vec<u8>) {
+        let mut header = [0; HEADER_LEN];
+        con.read_exact(&mut header).unwrap();
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let mut payload = vec![0; len];
+        con.read_exact(&mut payload).unwrap();
+        (id, Kind::from_u8(header[4]).unwrap(), payload)
+    }
+
+    /// Serve a multiplexed connection in the background, returning its client end and the streams it opens
+    fn start() -> (TcpStream, mpsc::Receiver<TcpStream>) {
+        let (client, con) = socket::loopback_pair().unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let (opened, streams) = mpsc::channel();
+        std::thread::spawn(move || {
+            serve(con, |remote| {
+                let _ = opened.send(remote);
+            })
+        });
+        (client, streams)
+    }
+
+    fn receive(remote: &mut TcpStream, len: usize) -> Vec<u8> {
+        remote.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut received = vec![0; len];
+        remote.read_exact(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn relays_streams_in_both_directions() {
+        let (mut client, streams) = start();
+        client.write_all(&frame(1, Kind::Open, &[])).unwrap();
+        client.write_all(&frame(1, Kind::Data, b"ping")).unwrap();
+        let mut remote = streams.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(receive(&mut remote, 4), b"ping");
+
+        remote.write_all(b"pong").unwrap();
+        assert_eq!(read_frame(&mut client), (1, Kind::Data, b"pong".to_vec()));
+
+        // closing ends the input of the stream, and the stream closing its output ends the stream
+        client.write_all(&frame(1, Kind::Close, &[])).unwrap();
+        assert_eq!(remote.read(&mut [0; 1]).unwrap(), 0);
+        drop(remote);
+        assert_eq!(read_frame(&mut client), (1, Kind::Close, Vec::new()));
+    }
+
+    #[test]
+    fn reassembles_frames_split_across_reads() {
+        let (mut client, streams) = start();
+        client.set_nodelay(true).unwrap();
+        let mut frames = frame(1, Kind::Open, &[]);
+        frames.extend(frame(1, Kind::Data, b"split"));
+        // headers and payloads arrive in pieces
+        for piece in frames.chunks(2) {
+            client.write_all(piece).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let mut remote = streams.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(receive(&mut remote, 5), b"split");
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let (mut client, con) = socket::loopback_pair().unwrap();
+        let mut header = frame(1, Kind::Data, &[]);
+        header[5..].copy_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+        // the payload is rejected before it is read, so none is sent
+        client.write_all(&header).unwrap();
+
+        let writer = FrameWriter {
+            con: Arc::new(Mutex::new(con.try_clone().unwrap())),
+        };
+        let err =
+            read_frames(con, "client", &writer, &Streams::default(), &mut |_| {}).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_unknown_frame_kinds() {
+        let (mut client, con) = socket::loopback_pair().unwrap();
+        let mut header = frame(1, Kind::Data, &[]);
+        header[4] = 4;
+        client.write_all(&header).unwrap();
+
+        let writer = FrameWriter {
+            con: Arc::new(Mutex::new(con.try_clone().unwrap())),
+        };
+        let err =
+            read_frames(con, "client", &writer, &Streams::default(), &mut |_| {}).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ignores_frames_of_unknown_streams() {
+        let (mut client, streams) = start();
+        client.write_all(&frame(7, Kind::Data, b"lost")).unwrap();
+        client.write_all(&frame(7, Kind::Close, &[])).unwrap();
+        client.write_all(&frame(7, Kind::Reset, &[])).unwrap();
+
+        // the connection keeps serving the streams that were opened
+        client.write_all(&frame(1, Kind::Open, &[])).unwrap();
+        client.write_all(&frame(1, Kind::Data, b"kept")).unwrap();
+        let mut remote = streams.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(receive(&mut remote, 4), b"kept");
+        assert!(streams.try_recv().is_err(), "No stream 7 was opened");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::lsp::{Message, MESSAGE_TYPE_WARNING};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How long writing the notice to a single client may take, so that a stuck client can't delay the shutdown
+const NOTICE_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the servers asked to shut down are checked for having closed their connection
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The clients of the sessions relayed a message at a time, which can be notified in between messages,
+/// along with their servers, which can be asked to shut down
+#[derive(Default)]
+pub struct ConnectedClients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Connections>>>,
+}
+
+/// The connections of a session relayed a message at a time, which the relay writes to while holding their lock
+pub struct Connections {
+    pub client_write: Arc<Mutex<TcpStream>>,
+    pub server_write: Arc<Mutex<TcpStream>>,
+    /// Set once the server closed its connection
+    server_closed: AtomicBool,
+}
+
+impl Connections {
+    pub fn new(client_write: Arc<Mutex<TcpStream>>, server_write: Arc<Mutex<TcpStream>>) -> Self {
+        Self {
+            client_write,
+            server_write,
+            server_closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Note that the server closed its connection
+    pub fn server_closed(&self) {
+        self.server_closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The registration of a client, removed when dropped
+pub struct Registered {
+    clients: Arc<ConnectedClients>,
+    id: u64,
+}
+
+impl ConnectedClients {
+    /// Register the connections of a session
+    pub fn register(self: &Arc<Self>, connections: Arc<Connections>) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.lock().insert(id, connections);
+        Registered {
+            clients: self.clone(),
+            id,
+        }
+    }
+
+    /// Show `text` to every client and close its connection gracefully,
+    /// so that it sees the language server exit instead of a reset connection,
+    /// returning the number of clients notified
+    pub fn say_goodbye(&self, text: &str) -> usize {
+        let notice = Message::show_message(MESSAGE_TYPE_WARNING, text);
+        self.lock()
+            .values()
+            .filter(|connections| {
+                // a poisoned lock only means a relay thread panicked, the connection is still usable
+                let mut client_write = connections
+                    .client_write
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                let _ = client_write.set_write_timeout(Some(NOTICE_WRITE_TIMEOUT));
+                let notified = notice.write_to(&mut *client_write).is_ok();
+                let _ = client_write.shutdown(Shutdown::Write);
+                notified
+            })
+            .count()
+    }
+
+    /// Ask the servers to shut down and exit, as their clients would at the end of a session,
+    /// and wait up to `grace` for them to close their connections, returning the number of servers asked
+    ///
+    /// This lets the servers clean up before they are terminated along with the proxy.
+    pub fn shut_down_servers(&self, grace: Duration) -> usize {
+        let shutdown = Message::shutdown_and_exit();
+        let asked: Vec<Arc<Connections>> = self
+            .lock()
+            .values()
+            .filter(|connections| {
+                if connections.server_closed.load(Ordering::SeqCst) {
+                    return false;
+                }
+                // a poisoned lock only means a relay thread panicked, the connection is still usable
+                let mut server_write = connections
+                    .server_write
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                let _ = server_write.set_write_timeout(Some(NOTICE_WRITE_TIMEOUT));
+                server_write.write_all(&shutdown).is_ok()
+            })
+            .cloned()
+            .collect();
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline
+            && asked
+                .iter()
+                .any(|connections| !connections.server_closed.load(Ordering::SeqCst))
+        {
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        asked.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<Connections>>> {
+        // a poisoned lock only means another thread panicked, the clients are still valid
+        self.clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.clients.lock().remove(&self.id);
+    }
+}
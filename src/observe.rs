@@ -0,0 +1,122 @@
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How long an observer may take to name the session it wants to observe
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many chunks of traffic may wait for a slow observer, before it is detached
+const OBSERVER_BACKLOG: usize = 256;
+
+/// The sessions observers can attach to, by the id `/status` lists them with
+///
+/// Observers receive a copy of the traffic from the server to the client, read-only,
+/// e.g. for an instructor following the diagnostics of a student.
+#[derive(Debug, Default)]
+pub struct Observable {
+    sessions: Mutex<HashMap<u64, Vec<SyncSender<Vec<u8>>>>>,
+}
+
+/// A session observers can attach to, until it is dropped
+#[derive(Debug)]
+pub struct Observed {
+    observable: Arc<Observable>,
+    id: u64,
+}
+
+impl Observable {
+    /// Let observers attach to the session `id` until the returned handle is dropped
+    pub fn register(self: &Arc<Self>, id: u64) -> Observed {
+        self.lock().insert(id, Vec::new());
+        Observed {
+            observable: self.clone(),
+            id,
+        }
+    }
+
+    /// Accept observers on `listener` in the background
+    ///
+    /// An observer sends the id of the session as a single line,
+    /// then receives the traffic of the session until it ends.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) {
+        let observable = self.clone();
+        std::thread::spawn(move || {
+            for observer in listener.incoming() {
+                match observer {
+                    Ok(observer) => {
+                        let observable = observable.clone();
+                        std::thread::spawn(move || observable.attach(observer));
+                    }
+                    Err(err) => warn!("Failed to accept observer: {}", err),
+                }
+            }
+        });
+    }
+
+    fn attach(&self, observer: TcpStream) {
+        let peer = observer.peer_addr().map_or_else(
+            |_| String::from("unknown observer"),
+            |addr| addr.to_string(),
+        );
+        let id = match read_session_id(&observer) {
+            Some(id) => id,
+            None => {
+                debug!("[{}] Observer did not name a session", peer);
+                return;
+            }
+        };
+        let (sender, receiver) = sync_channel::<Vec<u8>>(OBSERVER_BACKLOG);
+        match self.lock().get_mut(&id) {
+            Some(observers) => observers.push(sender),
+            None => {
+                info!("[{}] Observer asked for unknown session {}", peer, id);
+                let _ = (&observer).write_all(b"unknown session\n");
+                return;
+            }
+        }
+        info!("[{}] Observing session {}", peer, id);
+        let mut observer = observer;
+        // observers are read-only, anything they send is ignored
+        let _ = observer.shutdown(Shutdown::Read);
+        for data in receiver {
+            if observer.write_all(&data).is_err() {
+                break;
+            }
+        }
+        info!("[{}] Stopped observing session {}", peer, id);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Vec<SyncSender<Vec<u8>>>>> {
+        // a poisoned lock only means another thread panicked, the observers are still valid
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Observed {
+    /// Copy `data` relayed from the server to the observers,
+    /// detaching those that disconnected or fell too far behind
+    pub fn relayed(&self, data: &[u8]) {
+        if let Some(observers) = self.observable.lock().get_mut(&self.id) {
+            observers.retain(|observer| observer.try_send(data.to_vec()).is_ok());
+        }
+    }
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        // dropping the senders ends the observers' connections
+        self.observable.lock().remove(&self.id);
+    }
+}
+
+fn read_session_id(observer: &TcpStream) -> Option<u64> {
+    observer.set_read_timeout(Some(ATTACH_TIMEOUT)).ok()?;
+    let mut line = String::new();
+    BufReader::new(observer).read_line(&mut line).ok()?;
+    line.trim().parse().ok()
+}
@@ -0,0 +1,164 @@
+use crate::http::{self, HttpEndpoint};
+use crate::json;
+use log::{debug, info, warn};
+use std::fmt::Write as _;
+use std::sync::mpsc::{channel, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where traces are exported to if the endpoint has no path
+const DEFAULT_PATH: &str = "/v1/traces";
+
+/// A timed operation within a [`SessionTrace`]
+struct Span {
+    id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// Handle to a span started with [`SessionTrace::start_span`]
+#[derive(Debug, Clone, Copy)]
+pub struct SpanHandle(usize);
+
+/// All spans belonging to a single client session
+///
+/// The first span is the root span covering the whole session,
+/// all further spans are its children.
+pub struct SessionTrace {
+    trace_id: [u8; 16],
+    spans: Vec<Span>,
+}
+
+impl SessionTrace {
+    pub fn new() -> Self {
+        let mut trace = Self {
+            trace_id: rand::random(),
+            spans: Vec::new(),
+        };
+        trace.start_span("session");
+        trace
+    }
+
+    pub fn start_span(&mut self, name: &'static str) -> SpanHandle {
+        let parent = self.spans.first().map(|root| root.id);
+        self.spans.push(Span {
+            id: rand::random(),
+            parent,
+            name,
+            start: SystemTime::now(),
+            end: None,
+            attributes: Vec::new(),
+        });
+        SpanHandle(self.spans.len() - 1)
+    }
+
+    pub fn end_span(&mut self, span: SpanHandle) {
+        self.spans[span.0].end = Some(SystemTime::now());
+    }
+
+    /// Add an attribute to the root span
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.spans[0].attributes.push((key, value.to_string()));
+    }
+
+    /// End the root span and any spans that are still open
+    pub fn finish(&mut self) {
+        let now = SystemTime::now();
+        for span in &mut self.spans {
+            span.end.get_or_insert(now);
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut spans = String::new();
+        for (index, span) in self.spans.iter().enumerate() {
+            if index > 0 {
+                spans.push(',');
+            }
+            let _ = write!(
+                spans,
+                "{{\"traceId\":\"{}\",\"spanId\":\"{}\",",
+                hex(&self.trace_id),
+                hex(&span.id)
+            );
+            if let Some(parent) = &span.parent {
+                let _ = write!(spans, "\"parentSpanId\":\"{}\",", hex(parent));
+            }
+            let _ = write!(
+                spans,
+                "\"name\":{},\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+                json::quote(span.name),
+                // SPAN_KIND_SERVER for the root span, SPAN_KIND_INTERNAL otherwise
+                if span.parent.is_none() { 2 } else { 1 },
+                unix_nanos(span.start),
+                unix_nanos(span.end.unwrap_or(span.start))
+            );
+            for (index, (key, value)) in span.attributes.iter().enumerate() {
+                if index > 0 {
+                    spans.push(',');
+                }
+                let _ = write!(
+                    spans,
+                    "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+                    json::quote(key),
+                    json::quote(value)
+                );
+            }
+            spans.push_str("]}");
+        }
+
+        format!(
+            concat!(
+                "{{\"resourceSpans\":[{{",
+                "\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":\"{}\"}}}}]}},",
+                "\"scopeSpans\":[{{\"scope\":{{\"name\":\"{}\",\"version\":\"{}\"}},\"spans\":[{}]}}]",
+                "}}]}}"
+            ),
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            spans
+        )
+    }
+}
+
+/// Exports finished session traces from a background thread,
+/// so that a slow collector does not hold up session teardown
+#[derive(Clone)]
+pub struct Exporter {
+    sender: Sender<SessionTrace>,
+}
+
+impl Exporter {
+    pub fn start(endpoint: HttpEndpoint) -> Self {
+        let endpoint = endpoint.or_path(DEFAULT_PATH);
+        info!("Exporting session traces to {}", endpoint);
+        let (sender, receiver) = channel::<SessionTrace>();
+        std::thread::spawn(move || {
+            for trace in receiver {
+                match http::post(&endpoint, &trace.to_json()) {
+                    Ok(()) => debug!("Exported session trace {}", hex(&trace.trace_id)),
+                    Err(err) => warn!("Failed to export trace to {}: {}", endpoint, err),
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    pub fn export(&self, trace: SessionTrace) {
+        // the receiver only goes away if the exporter thread panicked
+        let _ = self.sender.send(trace);
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,678 @@
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often the reaper checks whether its children exited
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a terminated process is checked for having exited within its grace period
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether a process with `pid` exists
+pub fn is_running(pid: u32) -> bool {
+    imp::is_running(pid)
+}
+
+/// The command line a process was started with, if it can be determined
+pub fn command_line(pid: u32) -> Option<Vec<OsString>> {
+    imp::command_line(pid)
+}
+
+/// Kill the process with `pid` immediately
+pub fn kill(pid: u32) -> std::io::Result<()> {
+    imp::kill(pid)
+}
+
+/// Start the process of `command` in a group of its own,
+/// so that it can be killed together with the processes it forks
+pub fn own_process_group(command: &mut Command) {
+    imp::own_process_group(command)
+}
+
+/// Keep the process of `command` and everything it runs from gaining privileges,
+/// e.g. via setuid binaries, which is also what unprivileged sandboxes require for seccomp filters
+pub fn no_new_privileges(command: &mut Command) {
+    imp::no_new_privileges(command)
+}
+
+/// Run the process of `command` with the niceness `nice`,
+/// e.g. 10 so that it does not starve interactive processes (Unix only)
+pub fn set_niceness(command: &mut Command, nice: i32) {
+    imp::set_niceness(command, nice)
+}
+
+/// Restrict the process of `command` to the CPUs with the given indices (Linux only)
+pub fn pin_to_cpus(command: &mut Command, cpus: &[usize]) {
+    imp::pin_to_cpus(command, cpus)
+}
+
+/// Kill `child` and everything else in its process group immediately
+pub fn kill_group(child: &mut Child) -> std::io::Result<()> {
+    imp::kill_group(child)
+}
+
+/// Ask `child` and its process group to terminate, and kill them if they haven't exited after `grace`
+///
+/// Without a grace period they are killed right away
+pub fn stop_group(child: &mut Child, grace: Duration) -> std::io::Result<ExitStatus> {
+    if grace > Duration::default() && imp::terminate_group_of(child.id()).is_ok() {
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            std::thread::sleep(TERMINATE_POLL_INTERVAL);
+        }
+        debug!(
+            "Process {} did not exit within {:?} of being terminated",
+            child.id(),
+            grace
+        );
+    }
+    kill_group(child)?;
+    child.wait()
+}
+
+/// The signal that terminated a process, if any
+pub fn exit_signal(status: ExitStatus) -> Option<i32> {
+    imp::exit_signal(status)
+}
+
+/// An OS user to run the language servers as
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl User {
+    /// Look up the user called `name`
+    pub fn lookup(name: &str) -> std::io::Result<Self> {
+        imp::lookup_user(name)
+    }
+
+    /// Whether this process may start processes running as the user
+    pub fn may_switch_to(&self) -> bool {
+        imp::may_switch_to(self)
+    }
+
+    /// Run the process of `command` as the user, with the user's primary group only
+    pub fn run(&self, command: &mut Command) {
+        imp::run_as(command, self)
+    }
+
+    /// Make the user the owner of `path`, so that processes running as the user can write to it
+    pub fn give(&self, path: &Path) -> std::io::Result<()> {
+        imp::chown(path, self)
+    }
+}
+
+/// The process groups of the running language servers, to kill those still running when the proxy exits
+///
+/// On Windows each server is put into a job object instead, which is killed once the server was reaped,
+/// taking the processes the server started along.
+#[derive(Default)]
+pub struct ServerGroups {
+    groups: Mutex<BTreeMap<u32, Option<job::Job>>>,
+    stopped: AtomicBool,
+}
+
+impl ServerGroups {
+    pub fn spawned(&self, child: &Child) {
+        let job = job::Job::contain(child)
+            .map_err(|err| {
+                warn!(
+                    "Failed to contain LSP process {} in a job, only it will be killed: {}",
+                    child.id(),
+                    err
+                )
+            })
+            .ok();
+        self.lock().insert(child.id(), job);
+    }
+
+    pub fn reaped(&self, pid: u32) {
+        // dropping the job kills what the server left running
+        self.lock().remove(&pid);
+    }
+
+    /// Whether the servers were stopped because the proxy is exiting
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Stop the servers of sessions that have not finished yet,
+    /// killing those that don't exit within `grace` of being terminated
+    pub fn kill_all(&self, grace: Duration) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let groups = std::mem::take(&mut *self.lock());
+        if groups.is_empty() {
+            return;
+        }
+        warn!(
+            "Stopping {} language servers of unfinished sessions",
+            groups.len()
+        );
+        if grace > Duration::default() {
+            for &group in groups.keys() {
+                let _ = imp::terminate_group_of(group);
+            }
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline && groups.keys().any(|&group| imp::group_exists(group))
+            {
+                std::thread::sleep(TERMINATE_POLL_INTERVAL);
+            }
+        }
+        for (group, job) in groups {
+            let killed = match &job {
+                Some(job) => job.kill(group),
+                None => imp::kill_group_of(group),
+            };
+            if let Err(err) = killed {
+                warn!("Failed to kill LSP process group {}: {}", group, err);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, Option<job::Job>>> {
+        // a poisoned lock only means another thread panicked, the groups are still valid
+        self.groups
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+type ExitCallback = Box<dyn FnOnce(ExitStatus) + Send>;
+
+/// Waits for children nobody else waits for, so that none of them is left as a zombie once it exits
+pub struct Reaper {
+    children: Mutex<Vec<(Child, ExitCallback)>>,
+}
+
+impl Reaper {
+    pub fn start() -> Arc<Self> {
+        let reaper = Arc::new(Self {
+            children: Mutex::default(),
+        });
+        let background = reaper.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(REAP_INTERVAL);
+            background.reap();
+        });
+        reaper
+    }
+
+    /// Wait for `child` in the background, calling `on_exit` with its status once it exited
+    pub fn adopt(&self, child: Child, on_exit: impl FnOnce(ExitStatus) + Send + 'static) {
+        self.lock().push((child, Box::new(on_exit)));
+    }
+
+    fn reap(&self) {
+        let mut exited = Vec::new();
+        {
+            let mut children = self.lock();
+            let mut index = 0;
+            while index < children.len() {
+                match children[index].0.try_wait() {
+                    Ok(None) => index += 1,
+                    Ok(Some(status)) => {
+                        let (_, on_exit) = children.swap_remove(index);
+                        exited.push((on_exit, status));
+                    }
+                    Err(err) => {
+                        let (child, _) = children.swap_remove(index);
+                        warn!("Failed to wait for process {}: {}", child.id(), err);
+                    }
+                }
+            }
+        }
+        // call back without holding the lock, so that the callbacks may adopt further children
+        for (on_exit, status) in exited {
+            on_exit(status);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<(Child, ExitCallback)>> {
+        // a poisoned lock only means another thread panicked, the children are still valid
+        self.children
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::User;
+    use std::ffi::{CString, OsString};
+    use std::io::ErrorKind;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::path::Path;
+    use std::process::{Child, Command, ExitStatus};
+
+    /// Room for the strings of a passwd entry, far more than real entries need
+    const PASSWD_BUFFER_SIZE: usize = 16 * 1024;
+
+    pub fn lookup_user(name: &str) -> std::io::Result<User> {
+        let c_name = CString::new(name).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidInput, "the user name contains a NUL byte")
+        })?;
+        // Safety: passwd is plain old data, for which all zeros are a valid value
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; PASSWD_BUFFER_SIZE];
+        let mut result = std::ptr::null_mut();
+        // Safety: all pointers are valid, the buffer for the length given,
+        // and the entry is only read while the buffer it points into is alive
+        let err = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if err != 0 {
+            return Err(std::io::Error::from_raw_os_error(err));
+        }
+        if result.is_null() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("there is no user {}", name),
+            ));
+        }
+        Ok(User {
+            name: name.to_string(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+        })
+    }
+
+    pub fn may_switch_to(user: &User) -> bool {
+        // Safety: geteuid can't fail
+        let euid = unsafe { libc::geteuid() };
+        euid == 0 || euid == user.uid
+    }
+
+    pub fn run_as(command: &mut Command, user: &User) {
+        // the supplementary groups are dropped as well when switching users as root
+        command.uid(user.uid).gid(user.gid);
+    }
+
+    pub fn chown(path: &Path, user: &User) -> std::io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidInput, "the path contains a NUL byte")
+        })?;
+        // Safety: the path is a valid C string
+        if unsafe { libc::chown(c_path.as_ptr(), user.uid, user.gid) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    pub fn is_running(pid: u32) -> bool {
+        // Safety: signal 0 only checks whether the process exists, nothing is delivered
+        let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        // a process we may not signal still exists
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    pub fn command_line(pid: u32) -> Option<Vec<OsString>> {
+        // only available where there is a procfs
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        Some(
+            cmdline
+                .split(|&byte| byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| OsString::from_vec(arg.to_vec()))
+                .collect(),
+        )
+    }
+
+    pub fn kill(pid: u32) -> std::io::Result<()> {
+        // Safety: kill has no memory safety requirements
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn no_new_privileges(command: &mut Command) {
+        // Safety: prctl is async-signal-safe, so it may be called between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn no_new_privileges(_command: &mut Command) {
+        log::warn!("Can't keep the language servers from gaining privileges on this platform");
+    }
+
+    pub fn set_niceness(command: &mut Command, nice: i32) {
+        // Safety: setpriority is async-signal-safe, so it may be called between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn pin_to_cpus(command: &mut Command, cpus: &[usize]) {
+        // Safety: cpu_set_t is plain old data, zeroed is the empty set
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+            // Safety: the index is within the set
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        // Safety: sched_setaffinity is async-signal-safe, so it may be called between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn pin_to_cpus(_command: &mut Command, _cpus: &[usize]) {
+        log::warn!("Can't pin the language servers to CPUs on this platform");
+    }
+
+    pub fn own_process_group(command: &mut Command) {
+        // Safety: setpgid is async-signal-safe, so it may be called between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+
+    pub fn kill_group(child: &mut Child) -> std::io::Result<()> {
+        match kill_group_of(child.id()) {
+            Ok(()) => Ok(()),
+            // the group is gone already, or the child is not leading one
+            Err(_) => child.kill(),
+        }
+    }
+
+    pub fn exit_signal(status: ExitStatus) -> Option<i32> {
+        status.signal()
+    }
+
+    /// The child leads its group, so the group has the id of the child
+    pub fn kill_group_of(group: u32) -> std::io::Result<()> {
+        signal_group(group, libc::SIGKILL)
+    }
+
+    pub fn terminate_group_of(group: u32) -> std::io::Result<()> {
+        signal_group(group, libc::SIGTERM)
+    }
+
+    /// Whether any process of the group still exists, including zombies not waited for yet
+    pub fn group_exists(group: u32) -> bool {
+        signal_group(group, 0).is_ok()
+    }
+
+    fn signal_group(group: u32, signal: libc::c_int) -> std::io::Result<()> {
+        // Safety: killpg has no memory safety requirements
+        if unsafe { libc::killpg(group as libc::pid_t, signal) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::User;
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::process::{Child, Command, ExitStatus};
+
+    pub fn lookup_user(_name: &str) -> std::io::Result<User> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "running servers as another user is only supported on unix",
+        ))
+    }
+
+    pub fn may_switch_to(_user: &User) -> bool {
+        false
+    }
+
+    pub fn run_as(_command: &mut Command, _user: &User) {
+        // users can't be looked up, so there is none to run as
+    }
+
+    pub fn chown(_path: &Path, _user: &User) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn is_running(_pid: u32) -> bool {
+        // without a way to tell, rather assume it is
+        true
+    }
+
+    pub fn command_line(_pid: u32) -> Option<Vec<OsString>> {
+        None
+    }
+
+    pub fn kill(_pid: u32) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "killing processes by pid is only supported on unix",
+        ))
+    }
+
+    pub fn own_process_group(_command: &mut Command) {
+        // there are no process groups, the server is put into a job once it was spawned instead
+    }
+
+    pub fn no_new_privileges(_command: &mut Command) {
+        // there are no setuid binaries to guard against
+    }
+
+    pub fn set_niceness(_command: &mut Command, _nice: i32) {
+        log::warn!("Can't change the niceness of the language servers on this platform");
+    }
+
+    pub fn pin_to_cpus(_command: &mut Command, _cpus: &[usize]) {
+        log::warn!("Can't pin the language servers to CPUs on this platform");
+    }
+
+    pub fn kill_group(child: &mut Child) -> std::io::Result<()> {
+        child.kill()
+    }
+
+    pub fn kill_group_of(group: u32) -> std::io::Result<()> {
+        kill(group)
+    }
+
+    pub fn terminate_group_of(_group: u32) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "terminating processes is only supported on unix",
+        ))
+    }
+
+    pub fn group_exists(_group: u32) -> bool {
+        false
+    }
+
+    pub fn exit_signal(_status: ExitStatus) -> Option<i32> {
+        None
+    }
+}
+
+/// The process group a server was started in is all it takes to kill the processes it started
+#[cfg(not(windows))]
+mod job {
+    use std::process::Child;
+
+    pub struct Job;
+
+    impl Job {
+        pub fn contain(_child: &Child) -> std::io::Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn kill(&self, group: u32) -> std::io::Result<()> {
+            super::imp::kill_group_of(group)
+        }
+    }
+}
+
+/// Job objects, the Windows counterpart of process groups, declared here as the libc crate doesn't cover them
+#[cfg(windows)]
+mod job {
+    use std::ffi::c_void;
+    use std::os::windows::io::AsRawHandle;
+    use std::process::Child;
+
+    type Handle = *mut c_void;
+
+    /// The information class of [`ExtendedLimitInformation`]
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+
+    /// `JOBOBJECT_BASIC_LIMIT_INFORMATION`
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    /// `IO_COUNTERS`
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        read_operation_count: u64,
+        write_operation_count: u64,
+        other_operation_count: u64,
+        read_transfer_count: u64,
+        write_transfer_count: u64,
+        other_transfer_count: u64,
+    }
+
+    /// `JOBOBJECT_EXTENDED_LIMIT_INFORMATION`
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(job_attributes: *mut c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(
+            job: Handle,
+            information_class: i32,
+            information: *mut c_void,
+            information_length: u32,
+        ) -> i32;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    /// A job object holding a server and the processes it starts, which are all killed once it is dropped,
+    /// also when the proxy exits without dropping it
+    pub struct Job(Handle);
+
+    // Safety: the handle of a job may be used and closed from any thread
+    unsafe impl Send for Job {}
+
+    impl Job {
+        /// Put `child` into a job of its own
+        ///
+        /// Processes the child started before, during the moment since it was spawned, are not part of it.
+        pub fn contain(child: &Child) -> std::io::Result<Self> {
+            // Safety: a job without security attributes or name is valid
+            let handle = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+            if handle.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            let job = Self(handle);
+            let mut limits = ExtendedLimitInformation {
+                basic_limit_information: BasicLimitInformation {
+                    limit_flags: JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+                    ..BasicLimitInformation::default()
+                },
+                ..ExtendedLimitInformation::default()
+            };
+            // Safety: the information matches its class and has the size given
+            check(unsafe {
+                SetInformationJobObject(
+                    job.0,
+                    JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                    (&mut limits as *mut ExtendedLimitInformation).cast(),
+                    std::mem::size_of::<ExtendedLimitInformation>() as u32,
+                )
+            })?;
+            // Safety: the process handle is valid as long as the child is alive, which it is borrowed for
+            check(unsafe { AssignProcessToJobObject(job.0, child.as_raw_handle() as Handle) })?;
+            Ok(job)
+        }
+
+        pub fn kill(&self, _group: u32) -> std::io::Result<()> {
+            // Safety: the handle is valid until the job is dropped
+            check(unsafe { TerminateJobObject(self.0, 1) })
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            // Safety: the handle is owned by the job and closed only here
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    fn check(result: i32) -> std::io::Result<()> {
+        if result != 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::socket;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// The preamble a client sends to ask for the address of its language server
+/// instead of having its session relayed
+const PREAMBLE: &[u8] = b"LSP-REDIRECT\r\n";
+/// How long to wait for a new client to send its preamble
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to check whether the server exited while the client is connected directly
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the client asks to be redirected, consuming its preamble if so
+pub fn requested(con: &TcpStream) -> std::io::Result<bool> {
+    con.set_read_timeout(Some(PREAMBLE_TIMEOUT))?;
+    let requested = socket::peek_prefix(con, PREAMBLE);
+    con.set_read_timeout(None)?;
+    if requested? {
+        let mut preamble = [0; PREAMBLE.len()];
+        let mut con = con;
+        con.read_exact(&mut preamble)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Tell the client where to connect to its language server listening on `port`
+///
+/// The server is reachable at the address the client connected to the proxy on
+pub fn send_address(mut con: &TcpStream, port: u16) -> std::io::Result<()> {
+    // clients connecting via IPv4 to an IPv6 listener get an IPv4 address back
+    let ip = socket::unmap_ipv4(con.local_addr()?.ip());
+    write!(con, "LSP-REDIRECT {}\r\n", SocketAddr::new(ip, port))
+}
+
+/// Tell the client that no language server could be started for it
+pub fn send_unavailable(mut con: &TcpStream) {
+    let _ = con.write_all(b"LSP-REDIRECT unavailable\r\n");
+}
+
+/// Wait until the client closes its connection to the proxy, which ends the session,
+/// or `server_exited` reports the server to be gone
+pub fn wait_for_close(mut con: &TcpStream, mut server_exited: impl FnMut() -> bool) {
+    if con.set_read_timeout(Some(POLL_INTERVAL)).is_err() {
+        return;
+    }
+    let mut buf = [0; 64];
+    loop {
+        match con.read(&mut buf) {
+            // anything the client sends after the preamble is ignored
+            Ok(len) if len > 0 => {}
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if server_exited() {
+                    return;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            _ => return,
+        }
+    }
+}
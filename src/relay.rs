@@ -0,0 +1,594 @@
+use crate::arguments::Backpressure;
+use crate::buffer::SendBuffer;
+use crate::chaos::Chaos;
+use crate::checksum::Crc32;
+use crate::error::MessageTooLarge;
+use crate::filter::{self, MessageFilter};
+use crate::json;
+use crate::logging::ProtocolTrace;
+use crate::lsp::{Message, MessageReader, INVALID_REQUEST};
+use crate::observe::Observed;
+use crate::socket::Splice;
+use crate::watchdog::RequestWatchdog;
+use log::warn;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How much of the relayed traffic is captured at most
+const CAPTURE_LIMIT: usize = 16 * 1024;
+/// The notification sent to keep idle connections alive,
+/// clients are free to ignore notifications starting with `$/`
+const KEEPALIVE_METHOD: &str = "$/lspOnDemand/keepalive";
+/// The longest `exit` notification a client might send, with whitespace and an empty `params`
+const EXIT_NOTIFICATION_MAX_LEN: usize = 128;
+
+/// How sessions are relayed
+#[derive(Debug, Clone, Default)]
+pub struct RelayOptions {
+    /// Relay whole LSP messages instead of raw bytes
+    pub parse_messages: bool,
+    /// Send a keepalive notification to clients whose connection was idle this long,
+    /// requires `parse_messages`
+    pub keepalive: Option<Duration>,
+    /// The filters every message passes through, requires `parse_messages`
+    pub filters: Vec<Arc<dyn MessageFilter>>,
+    /// Reject messages with more content than this many bytes, requires `parse_messages`
+    pub max_message_size: Option<usize>,
+    /// Limits the bandwidth of the session, shared by both directions
+    pub throttle: Option<Arc<Throttle>>,
+    /// Logs the relayed traffic while the client is traced
+    pub protocol_trace: Option<ProtocolTrace>,
+    /// Copies the traffic from the server to the observers attached to the session
+    pub observed: Option<Arc<Observed>>,
+    /// Mangles the relayed traffic on purpose
+    pub chaos: Option<Arc<Chaos>>,
+    /// Reports the requests the server takes too long to answer, also one of the `filters`
+    pub watchdog: Option<Arc<RequestWatchdog>>,
+    /// How many bytes each direction may buffer for a receiver that can't keep up,
+    /// instead of waiting for it to receive each write
+    pub send_buffer: Option<usize>,
+    /// What to do once the send buffer is full
+    pub backpressure: Backpressure,
+    /// Keep checksums of what each direction received and sent, see [`Checksums`]
+    pub checksums: bool,
+    /// Relay raw bytes with as little delay as possible, without Nagle's algorithm
+    /// and within the kernel where supported, once the start of the traffic is captured
+    ///
+    /// Spliced traffic bypasses the chaos, throttle, protocol trace, observers and checksums,
+    /// so they are to be left out, as is a send buffer, which rules out splicing.
+    pub low_latency: bool,
+}
+
+/// Where a direction of a session writes what it relays, directly or through a send buffer
+enum Outlet {
+    Direct(Arc<Mutex<TcpStream>>),
+    /// The buffer and a handle to shut down the connection to the receiver,
+    /// without waiting for the lock held by a write blocked on it
+    Buffered(SendBuffer, TcpStream),
+}
+
+impl Outlet {
+    fn new(tx: &Arc<Mutex<TcpStream>>, options: &RelayOptions) -> Self {
+        let capacity = match options.send_buffer {
+            Some(capacity) => capacity,
+            None => return Self::Direct(tx.clone()),
+        };
+        match lock(tx).try_clone() {
+            Ok(handle) => Self::Buffered(
+                SendBuffer::start(tx.clone(), capacity, options.backpressure),
+                handle,
+            ),
+            Err(err) => {
+                warn!(
+                    "Failed to clone a stream for its send buffer, writing directly: {}",
+                    err
+                );
+                Self::Direct(tx.clone())
+            }
+        }
+    }
+
+    /// Write `data` to the receiver, failing if that failed or it fell too far behind
+    fn send(&self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Direct(tx) => lock(tx).write_all(data),
+            Self::Buffered(buffer, _) => buffer.push(data),
+        }
+    }
+
+    /// Move the `len` bytes in the pipe of `splice` to the receiver, which can't be buffered
+    fn send_spliced(&self, splice: &Splice, len: usize) -> std::io::Result<()> {
+        match self {
+            Self::Direct(tx) => splice.drain(&lock(tx), len),
+            Self::Buffered(..) => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "spliced traffic can't be buffered",
+            )),
+        }
+    }
+
+    /// Shut down writing to the receiver once everything sent was written to it,
+    /// failing if writing what was still buffered failed,
+    /// or with `Shutdown::Both` the whole connection right away, discarding what is still buffered
+    fn close(self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Self::Direct(tx) => {
+                // the peer may already be gone, in which case there is nothing left to shut down
+                let _ = lock(&tx).shutdown(how);
+                Ok(())
+            }
+            Self::Buffered(buffer, handle) => {
+                if how == Shutdown::Both {
+                    // also ends a write blocked on the receiver
+                    let _ = handle.shutdown(how);
+                }
+                let written = buffer.finish();
+                let _ = handle.shutdown(how);
+                written
+            }
+        }
+    }
+
+    /// Shut down the whole connection to the receiver after writing to it or its sender failed,
+    /// which also ends the relay in the opposite direction reading from it
+    fn abort(self) {
+        // what was still buffered is discarded anyway
+        let _ = self.close(Shutdown::Both);
+    }
+}
+
+/// When traffic was last relayed over a connection
+pub struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.lock() = Instant::now();
+    }
+
+    fn idle(&self) -> Duration {
+        self.lock().elapsed()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Instant> {
+        // a poisoned lock only means another thread panicked, the instant is still valid
+        self.last
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Limits the rate at which a session relays traffic
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: f64,
+    /// When the traffic relayed so far is paid off
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until relaying `bytes` more stays within the rate
+    fn consume(&self, bytes: usize) {
+        let delay = {
+            // a poisoned lock only means another thread panicked, the instant is still valid
+            let mut next_free = self
+                .next_free
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let start = (*next_free).max(now);
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second);
+            start - now
+        };
+        // sleep without holding the lock, so the other direction can take its turn
+        if delay > Duration::default() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientToServer => write!(f, "client -> server"),
+            Self::ServerToClient => write!(f, "server -> client"),
+        }
+    }
+}
+
+/// How one direction of a relay ended
+#[derive(Debug)]
+pub enum RelayEnd {
+    /// The sending side closed its end of the connection
+    Closed,
+    /// Reading from the sending side failed
+    Failed(std::io::Error),
+    /// Writing to the receiving side, or answering the sending side in its place, failed
+    WriteFailed(std::io::Error),
+}
+
+/// Running checksums of what one direction of a relay received and sent,
+/// which only differ if the relay changed the traffic
+///
+/// Relaying raw bytes they cover the bytes, relaying messages only their content,
+/// which the filters may change on purpose, e.g. by compressing it.
+/// Messages the proxy sends on its own, like keepalives and errors, are not covered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Checksums {
+    pub received: Crc32,
+    pub sent: Crc32,
+}
+
+/// The result of relaying one direction of a session
+#[derive(Debug)]
+pub struct RelayOutcome {
+    pub direction: Direction,
+    pub bytes: u64,
+    pub end: RelayEnd,
+    /// What was received and sent, if the options asked for checksums
+    pub checksums: Option<Checksums>,
+}
+
+impl RelayOutcome {
+    /// A short classification of how the relay ended, for logs and session summaries
+    pub fn classification(&self) -> &'static str {
+        match &self.end {
+            RelayEnd::Closed => "closed",
+            RelayEnd::Failed(err) => match err.kind() {
+                ErrorKind::ConnectionReset => "reset-by-peer",
+                ErrorKind::ConnectionAborted => "aborted",
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => "timeout",
+                ErrorKind::BrokenPipe => "broken-pipe",
+                _ => "error",
+            },
+            RelayEnd::WriteFailed(_) => "write-failed",
+        }
+    }
+}
+
+impl RelayOutcome {
+    /// Why writing failed, if it ended the relay
+    pub fn write_error(&self) -> Option<&std::io::Error> {
+        match &self.end {
+            RelayEnd::WriteFailed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RelayOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} relay ended after {} bytes: {}",
+            self.direction,
+            self.bytes,
+            self.classification()
+        )?;
+        if let RelayEnd::Failed(err) | RelayEnd::WriteFailed(err) = &self.end {
+            write!(f, " ({})", err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Relay everything read from `rx` to `tx`
+///
+/// When `rx` reaches the end of its stream, only the write side of `tx` is shut down,
+/// so that the peer can still finish sending in the other direction.
+/// Both sides are closed once the streams are dropped after both directions finished.
+///
+/// If writing to `tx` fails, the relay ends and both sides of `tx` are shut down,
+/// which ends the relay in the opposite direction as well.
+///
+/// If `capture` is given the start of the relayed traffic is copied into it,
+/// with a throttle in the `options` the traffic is relayed no faster than it allows,
+/// with a send buffer the relay ends if the receiver falls too far behind and the policy drops the session,
+/// with `low_latency` the traffic is spliced where supported
+pub fn relay_connection(
+    mut rx: TcpStream,
+    tx: TcpStream,
+    direction: Direction,
+    mut capture: Option<&mut Vec<u8>>,
+    options: &RelayOptions,
+) -> RelayOutcome {
+    let splice = if options.low_latency {
+        // small LSP messages are not to wait for more to send along
+        let _ = rx.set_nodelay(true);
+        let _ = tx.set_nodelay(true);
+        options.send_buffer.is_none().then(Splice::new).flatten()
+    } else {
+        None
+    };
+    let outlet = Outlet::new(&Arc::new(Mutex::new(tx)), options);
+    let mut checksums = options.checksums.then(Checksums::default);
+    let mut buf = [0; 1024];
+    let mut relayed = 0;
+    let end = loop {
+        // the captured start of the traffic has to pass through user space
+        let spliced = splice.as_ref().filter(|_| {
+            capture
+                .as_ref()
+                .map_or(true, |captured| captured.len() >= CAPTURE_LIMIT)
+        });
+        let received = match spliced {
+            Some(splice) => splice.fill(&rx),
+            None => rx.read(&mut buf),
+        };
+        match received {
+            Ok(0) => match outlet.close(Shutdown::Write) {
+                Ok(()) => break RelayEnd::Closed,
+                Err(err) => break RelayEnd::WriteFailed(err),
+            },
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                outlet.abort();
+                break RelayEnd::Failed(err);
+            }
+            Ok(bytes) => {
+                if let Some(splice) = spliced {
+                    if let Err(err) = outlet.send_spliced(splice, bytes) {
+                        outlet.abort();
+                        break RelayEnd::WriteFailed(err);
+                    }
+                    relayed += bytes as u64;
+                    continue;
+                }
+                if let Some(checksums) = checksums.as_mut() {
+                    checksums.received.update(&buf[..bytes]);
+                }
+                let data = mangle(options, direction, &buf[..bytes]);
+                if let Some(throttle) = &options.throttle {
+                    throttle.consume(data.len());
+                }
+                if let Err(err) = outlet.send(&data) {
+                    outlet.abort();
+                    break RelayEnd::WriteFailed(err);
+                }
+                if let Some(checksums) = checksums.as_mut() {
+                    checksums.sent.update(&data);
+                }
+                if let Some(protocol_trace) = &options.protocol_trace {
+                    protocol_trace.relayed(direction, &data);
+                }
+                observe(options, direction, &data);
+                relayed += data.len() as u64;
+                if let Some(captured) = capture.as_mut() {
+                    let remaining = CAPTURE_LIMIT.saturating_sub(captured.len());
+                    captured.extend_from_slice(&data[..data.len().min(remaining)]);
+                }
+            }
+        }
+    };
+    RelayOutcome {
+        direction,
+        bytes: relayed,
+        end,
+        checksums,
+    }
+}
+
+/// Relay the LSP messages read from `rx` to `tx`, like [`relay_connection`] but a message at a time
+///
+/// Each message, followed by the copies the filters fan it out to, passes through the filters of the `options` and is then written
+/// while holding the lock on `tx`, so that other threads can inject messages in between.
+/// Oversized and rejected requests are answered with an error written to `reply`, the connection back to the sender.
+/// Failing to write either ends the relay like failing to write to `tx` does.
+/// Relaying a message counts as `activity`.
+pub fn relay_messages(
+    rx: TcpStream,
+    tx: &Arc<Mutex<TcpStream>>,
+    reply: &Mutex<TcpStream>,
+    direction: Direction,
+    mut capture: Option<&mut Vec<u8>>,
+    activity: Option<&Activity>,
+    options: &RelayOptions,
+) -> RelayOutcome {
+    let mut reader = match options.max_message_size {
+        Some(limit) => MessageReader::with_max_content_length(rx, limit),
+        None => MessageReader::new(rx),
+    };
+    let outlet = Outlet::new(tx, options);
+    let mut checksums = options.checksums.then(Checksums::default);
+    let mut relayed = 0;
+    let mut client_exited = false;
+    let end = 'relay: loop {
+        match reader.read_message() {
+            Ok(None) => {
+                if direction == Direction::ClientToServer && !client_exited {
+                    ask_to_exit(&outlet);
+                }
+                match outlet.close(Shutdown::Write) {
+                    Ok(()) => break RelayEnd::Closed,
+                    Err(err) => break RelayEnd::WriteFailed(err),
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                if let Some(too_large) = err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<MessageTooLarge>())
+                {
+                    match reject_oversized(reply, direction, too_large) {
+                        Ok(()) => continue,
+                        Err(err) => {
+                            outlet.abort();
+                            break RelayEnd::WriteFailed(err);
+                        }
+                    }
+                }
+                if direction == Direction::ClientToServer && !client_exited {
+                    ask_to_exit(&outlet);
+                }
+                outlet.abort();
+                break RelayEnd::Failed(err);
+            }
+            Ok(Some(message)) => {
+                match direction {
+                    Direction::ClientToServer => client_exited |= is_exit(&message),
+                    // the client never asked for the response to the shutdown sent in its place
+                    Direction::ServerToClient if message.answers_proxy_shutdown() => continue,
+                    Direction::ServerToClient => {}
+                }
+                if let Some(checksums) = checksums.as_mut() {
+                    checksums.received.update(&message.content);
+                }
+                if let Some(response) = filter::reject(&options.filters, direction, &message) {
+                    if let Err(err) = response.write_to(&mut *lock(reply)) {
+                        outlet.abort();
+                        break RelayEnd::WriteFailed(err);
+                    }
+                    continue;
+                }
+                // copies of a response for duplicate requests are relayed like any other message
+                let copies = filter::fan_out(&options.filters, direction, &message);
+                for message in std::iter::once(message).chain(copies) {
+                    // observers get the messages as the server sent them, e.g. uncompressed,
+                    // as they negotiated nothing with the filters
+                    if options.observed.is_some() {
+                        observe(options, direction, &message.to_bytes());
+                    }
+                    let message = match filter::apply(&options.filters, direction, message) {
+                        Some(message) => message,
+                        None => continue,
+                    };
+                    let message_bytes = message.to_bytes();
+                    let bytes = mangle(options, direction, &message_bytes);
+                    if let Some(throttle) = &options.throttle {
+                        throttle.consume(bytes.len());
+                    }
+                    if let Err(err) = outlet.send(&bytes) {
+                        outlet.abort();
+                        break 'relay RelayEnd::WriteFailed(err);
+                    }
+                    if let Some(checksums) = checksums.as_mut() {
+                        checksums.sent.update(&message.content);
+                    }
+                    if let Some(protocol_trace) = &options.protocol_trace {
+                        protocol_trace.relayed(direction, &bytes);
+                    }
+                    relayed += bytes.len() as u64;
+                    if let Some(activity) = activity {
+                        activity.touch();
+                    }
+                    if let Some(captured) = capture.as_mut() {
+                        let remaining = CAPTURE_LIMIT.saturating_sub(captured.len());
+                        captured.extend_from_slice(&bytes[..bytes.len().min(remaining)]);
+                    }
+                }
+            }
+        }
+    };
+    RelayOutcome {
+        direction,
+        bytes: relayed,
+        end,
+        checksums,
+    }
+}
+
+/// Whether `message` is the `exit` notification, a tiny message so larger ones aren't searched for it
+fn is_exit(message: &Message) -> bool {
+    message.content.len() <= EXIT_NOTIFICATION_MAX_LEN
+        && json::top_level_string_field(&String::from_utf8_lossy(&message.content), "method")
+            .map_or(false, |method| method == "exit")
+}
+
+/// Ask the server to shut down and exit in place of a client that left without doing so,
+/// so that it isn't terminated while it still cleans up
+fn ask_to_exit(outlet: &Outlet) {
+    // the server may already be gone, in which case it doesn't need to be asked
+    let _ = outlet.send(&Message::shutdown_and_exit());
+}
+
+/// The `data` to relay, mangled if the `options` inject faults
+fn mangle<'a>(options: &RelayOptions, direction: Direction, data: &'a [u8]) -> Cow<'a, [u8]> {
+    match &options.chaos {
+        Some(chaos) => chaos.mangle(direction, data),
+        None => Cow::Borrowed(data),
+    }
+}
+
+/// Copy `data` relayed from the server to the observers of the session
+fn observe(options: &RelayOptions, direction: Direction, data: &[u8]) {
+    if let (Some(observed), Direction::ServerToClient) = (&options.observed, direction) {
+        observed.relayed(data);
+    }
+}
+
+/// Answer an oversized request with an error, oversized notifications and responses are just dropped
+fn reject_oversized(
+    reply: &Mutex<TcpStream>,
+    direction: Direction,
+    too_large: &MessageTooLarge,
+) -> std::io::Result<()> {
+    let prefix = String::from_utf8_lossy(&too_large.prefix);
+    match json::top_level_value(&prefix, "id") {
+        Some(id) if json::has_top_level_field(&prefix, "method") => {
+            warn!(
+                "Rejecting {} request {}: {}",
+                direction,
+                id.to_json(),
+                too_large
+            );
+            let response = Message::error_response(&id, INVALID_REQUEST, &too_large.to_string());
+            response.write_to(&mut *lock(reply))
+        }
+        _ => {
+            warn!("Dropping {} message: {}", direction, too_large);
+            Ok(())
+        }
+    }
+}
+
+/// Send a keepalive notification to `tx` whenever there was no `activity` for `interval`, until `done` is set
+pub fn keep_alive(
+    tx: &Mutex<TcpStream>,
+    activity: &Activity,
+    interval: Duration,
+    done: &AtomicBool,
+) {
+    let keepalive = Message::notification(KEEPALIVE_METHOD);
+    while !done.load(Ordering::SeqCst) {
+        let idle = activity.idle();
+        if idle >= interval {
+            if keepalive.write_to(&mut *lock(tx)).is_err() {
+                return;
+            }
+            activity.touch();
+        } else {
+            // wake up regularly to notice the end of the session
+            std::thread::sleep((interval - idle).min(Duration::from_secs(1)));
+        }
+    }
+}
+
+fn lock(tx: &Mutex<TcpStream>) -> MutexGuard<'_, TcpStream> {
+    // a poisoned lock only means another writer panicked, the stream itself is still usable
+    tx.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,427 @@
+use crate::socket;
+use log::{debug, info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The preamble a client sends to opt into a resumable session, followed by `new` or a session id
+const PREAMBLE: &[u8] = b"LSP-SESSION ";
+/// How long to wait for a new client to send its preamble
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PREAMBLE_LEN: usize = 128;
+/// How many bytes of server output are buffered for a disconnected client at most
+const MAX_PENDING: usize = 16 * 1024 * 1024;
+
+/// The sessions that can be resumed by reconnecting clients
+pub struct Registry {
+    window: Duration,
+    sessions: Mutex<HashMap<String, Handle>>,
+}
+
+struct Handle {
+    resume: Sender<TcpStream>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// The state of a resumable session shared between its threads
+#[derive(Default)]
+struct Shared {
+    /// The currently attached client
+    client: Option<TcpStream>,
+    /// Server output that still has to be delivered to the client
+    pending: Vec<u8>,
+    /// Whether we cut the connection of the attached client,
+    /// because writing to it failed or a resuming client replaces it
+    detached: bool,
+    /// Whether the server side of the session closed
+    server_closed: bool,
+}
+
+impl Registry {
+    /// Sessions whose client disconnected can be resumed within `window`
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            sessions: Mutex::default(),
+        }
+    }
+
+    /// Handle the preamble of a new client connection
+    ///
+    /// Returns the connection the session is to be served on,
+    /// or nothing if the connection resumed an existing session or the handshake failed.
+    /// Clients not sending a preamble are served directly.
+    pub fn negotiate(self: &Arc<Self>, con: TcpStream, client: &str) -> Option<TcpStream> {
+        let requested = match read_preamble(&con) {
+            Ok(Some(requested)) => requested,
+            Ok(None) => return Some(con),
+            Err(err) => {
+                warn!("[{}] Failed to read session preamble: {}", client, err);
+                return None;
+            }
+        };
+
+        if requested == "new" {
+            match self.start(con) {
+                Ok((id, local)) => {
+                    info!("[{}] Started resumable session {}", client, id);
+                    Some(local)
+                }
+                Err(err) => {
+                    warn!("[{}] Failed to start resumable session: {}", client, err);
+                    None
+                }
+            }
+        } else {
+            self.resume(&requested, con, client);
+            None
+        }
+    }
+
+    /// Start a resumable session for `con`, returning its id and the connection to serve it on
+    fn start(self: &Arc<Self>, mut con: TcpStream) -> std::io::Result<(String, TcpStream)> {
+        let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let (local, remote) = socket::loopback_pair()?;
+        let local_reader = local.try_clone()?;
+        write!(con, "LSP-SESSION {}\r\n", id)?;
+
+        let (resume, resumed) = channel();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        self.lock().insert(
+            id.clone(),
+            Handle {
+                resume,
+                shared: shared.clone(),
+            },
+        );
+
+        let pump_shared = shared.clone();
+        std::thread::spawn(move || pump(local_reader, &pump_shared));
+        let registry = self.clone();
+        let session_id = id.clone();
+        std::thread::spawn(move || {
+            registry.attach_clients(&session_id, con, local, &resumed, &shared);
+            registry.lock().remove(&session_id);
+        });
+        Ok((id, remote))
+    }
+
+    fn resume(&self, id: &str, mut con: TcpStream, client: &str) {
+        let sessions = self.lock();
+        match sessions.get(id) {
+            Some(handle) => {
+                info!("[{}] Resuming session {}", client, id);
+                if write!(con, "LSP-SESSION {}\r\n", id).is_err() {
+                    return;
+                }
+                let mut shared = lock(&handle.shared);
+                // the previous client may still be connected, either because its connection has not been
+                // noticed to be dead yet or because the session moves to another machine, it is replaced either way
+                if let Some(previous) = &shared.client {
+                    info!(
+                        "[{}] Taking over session {} from {}",
+                        client,
+                        id,
+                        previous.peer_addr().map_or_else(
+                            |_| String::from("its previous client"),
+                            |addr| addr.to_string()
+                        )
+                    );
+                    let _ = previous.shutdown(Shutdown::Both);
+                }
+                shared.detached = shared.client.is_some();
+                let _ = handle.resume.send(con);
+            }
+            None => {
+                warn!("[{}] Can't resume unknown session {}", client, id);
+                let _ = con.write_all(b"LSP-SESSION unknown\r\n");
+            }
+        }
+    }
+
+    /// Relay from the attached client to `local`, attaching resuming clients when it disconnects
+    fn attach_clients(
+        &self,
+        id: &str,
+        mut client: TcpStream,
+        mut local: TcpStream,
+        resumed: &Receiver<TcpStream>,
+        shared: &Mutex<Shared>,
+    ) {
+        loop {
+            let mut reader = match client.try_clone() {
+                Ok(reader) => reader,
+                Err(err) => {
+                    warn!("Failed to clone client stream of session {}: {}", id, err);
+                    let _ = local.shutdown(Shutdown::Both);
+                    return;
+                }
+            };
+            {
+                let mut shared = lock(shared);
+                shared.detached = false;
+                shared.client = Some(client);
+                deliver(&mut shared, &[]);
+                if shared.server_closed {
+                    if let Some(client) = &shared.client {
+                        let _ = client.shutdown(Shutdown::Write);
+                    }
+                }
+            }
+
+            let closed = copy(&mut reader, &mut local);
+            let mut shared_guard = lock(shared);
+            if closed && !shared_guard.detached {
+                // the client ended the session deliberately
+                let _ = local.shutdown(Shutdown::Write);
+                return;
+            }
+            if let Some(client) = shared_guard.client.take() {
+                let _ = client.shutdown(Shutdown::Both);
+            }
+            if shared_guard.server_closed {
+                return;
+            }
+            drop(shared_guard);
+
+            debug!(
+                "Client of session {} disconnected, waiting up to {:?} for it to resume",
+                id, self.window
+            );
+            match resumed.recv_timeout(self.window) {
+                Ok(resuming) => client = resuming,
+                Err(_) => {
+                    info!("Session {} was not resumed in time", id);
+                    let _ = local.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Handle>> {
+        // a poisoned lock only means another thread panicked, the sessions are still usable
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Read the preamble, if the client sent one, returning what it requested
+fn read_preamble(con: &TcpStream) -> std::io::Result<Option<String>> {
+    con.set_read_timeout(Some(PREAMBLE_TIMEOUT))?;
+    let requested = socket::peek_prefix(con, PREAMBLE);
+    con.set_read_timeout(None)?;
+    if !requested? {
+        return Ok(None);
+    }
+
+    let mut line = Vec::new();
+    let mut byte = [0];
+    let mut con = con;
+    while line.len() < MAX_PREAMBLE_LEN {
+        con.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            let line = String::from_utf8_lossy(&line[PREAMBLE.len()..]);
+            return Ok(Some(line.trim().to_string()));
+        }
+        line.push(byte[0]);
+    }
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        "session preamble is too long",
+    ))
+}
+
+/// Copy from `rx` to `tx` until either fails, returning whether `rx` was closed regularly
+fn copy(rx: &mut TcpStream, tx: &mut TcpStream) -> bool {
+    let mut buffer = [0; 8192];
+    loop {
+        match rx.read(&mut buffer) {
+            Ok(0) => return true,
+            Ok(len) => {
+                if tx.write_all(&buffer[..len]).is_err() {
+                    return false;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Deliver everything read from `local` to the attached client, or buffer it while none is attached
+fn pump(mut local: TcpStream, shared: &Mutex<Shared>) {
+    let mut buffer = [0; 8192];
+    loop {
+        match local.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => {
+                let mut shared = lock(shared);
+                deliver(&mut shared, &buffer[..len]);
+                if shared.pending.len() > MAX_PENDING {
+                    warn!("Too much output buffered for a disconnected client, ending the session");
+                    let _ = local.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+
+    let mut shared = lock(shared);
+    shared.server_closed = true;
+    if let Some(client) = &shared.client {
+        let _ = client.shutdown(Shutdown::Write);
+    }
+}
+
+fn deliver(shared: &mut Shared, data: &[u8]) {
+    shared.pending.extend_from_slice(data);
+    if let Some(client) = &mut shared.client {
+        if client.write_all(&shared.pending).is_ok() {
+            shared.pending.clear();
+        } else {
+            // keep the output for a resuming client
+            let _ = client.shutdown(Shutdown::Both);
+            shared.client = None;
+            shared.detached = true;
+        }
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    // a poisoned lock only means another thread panicked, the state is still usable
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A client connected to the proxy, and the proxy's end of its connection
+    fn connect() -> (TcpStream, TcpStream) {
+        let (client, con) = socket::loopback_pair().unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+        (client, con)
+    }
+
+    fn read_line(con: &mut TcpStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while byte[0] != b'\n' {
+            con.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    fn receive(con: &mut TcpStream, len: usize) -> Vec<u8> {
+        con.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut received = vec![0; len];
+        con.read_exact(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn starts_and_resumes_sessions() {
+        let registry = Arc::new(Registry::new(TIMEOUT));
+        let (mut first, con) = connect();
+        first.write_all(b"LSP-SESSION new\r\nhello").unwrap();
+        let mut served = registry.negotiate(con, "first").unwrap();
+        let id = read_line(&mut first)
+            .strip_prefix("LSP-SESSION ")
+            .unwrap()
+            .trim_end()
+            .to_string();
+        assert_eq!(id.len(), 32, "{}", id);
+        assert_eq!(receive(&mut served, 5), b"hello");
+
+        // a client resuming the session takes it over from the one still connected
+        let (mut second, con) = connect();
+        write!(second, "LSP-SESSION {}\r\n", id).unwrap();
+        assert!(registry.negotiate(con, "second").is_none());
+        assert_eq!(read_line(&mut second), format!("LSP-SESSION {}\r\n", id));
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+        second.write_all(b"again").unwrap();
+        assert_eq!(receive(&mut served, 5), b"again");
+        served.write_all(b"answer").unwrap();
+        assert_eq!(receive(&mut second, 6), b"answer");
+    }
+
+    #[test]
+    fn serves_clients_without_preamble_directly() {
+        let registry = Arc::new(Registry::new(TIMEOUT));
+        let (mut client, con) = connect();
+        let message = b"Content-Length: 2\r\n\r\n{}";
+        client.write_all(message).unwrap();
+        let mut served = registry.negotiate(con, "client").unwrap();
+        // nothing the client sent was consumed
+        assert_eq!(receive(&mut served, message.len()), message);
+        assert!(registry.lock().is_empty());
+    }
+
+    #[test]
+    fn rejects_overlong_preambles() {
+        let (mut client, con) = connect();
+        client.write_all(PREAMBLE).unwrap();
+        client.write_all(&[b'x'; MAX_PREAMBLE_LEN]).unwrap();
+        let err = read_preamble(&con).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let registry = Arc::new(Registry::new(TIMEOUT));
+        let (mut client, con) = connect();
+        client.write_all(PREAMBLE).unwrap();
+        client.write_all(&[b'x'; MAX_PREAMBLE_LEN]).unwrap();
+        assert!(registry.negotiate(con, "client").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_sessions() {
+        let registry = Arc::new(Registry::new(TIMEOUT));
+        let (mut client, con) = connect();
+        client
+            .write_all(b"LSP-SESSION 0123456789abcdef0123456789abcdef\r\n")
+            .unwrap();
+        assert!(registry.negotiate(con, "client").is_none());
+        assert_eq!(read_line(&mut client), "LSP-SESSION unknown\r\n");
+    }
+
+    #[test]
+    fn ends_sessions_buffering_too_much_output() {
+        let (mut server, local) = socket::loopback_pair().unwrap();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let pump_shared = shared.clone();
+        let pump = std::thread::spawn(move || pump(local, &pump_shared));
+
+        // no client is attached, so all of it is buffered until the limit is exceeded
+        let chunk = vec![0; 64 * 1024];
+        let mut written = 0;
+        while written <= MAX_PENDING {
+            server.write_all(&chunk).unwrap();
+            written += chunk.len();
+        }
+        pump.join().unwrap();
+        let shared = lock(&shared);
+        assert!(shared.server_closed);
+        // nothing is buffered beyond the read that exceeded the limit
+        assert!(
+            shared.pending.len() <= MAX_PENDING + 8192,
+            "{}",
+            shared.pending.len()
+        );
+        drop(shared);
+        // the server's connection was cut
+        server.set_read_timeout(Some(TIMEOUT)).unwrap();
+        assert_eq!(server.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
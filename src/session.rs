@@ -0,0 +1,283 @@
+use crate::notice::{ConnectedClients, Connections, Registered};
+use crate::relay::{self, Activity, Direction, RelayEnd, RelayOptions, RelayOutcome};
+use log::{debug, info, warn};
+use std::marker::PhantomData;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long the server may keep sending after the client finished sending, before it is stopped
+const HALF_CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+/// A client and the language server started for it, relayed to each other
+///
+/// The session owns both connections and the threads serving it, which are joined once the relay ended.
+pub struct Session {
+    client: String,
+    options: RelayOptions,
+    client_read: TcpStream,
+    writers: Writers,
+    /// Receives how the server -> client relay ended, which runs in the background
+    relayed: mpsc::Receiver<RelayOutcome>,
+    relay_thread: JoinHandle<()>,
+    /// The keepalive and watchdog threads, by their name
+    timers: Vec<(&'static str, JoinHandle<()>)>,
+    /// Set once the relay ended, to stop the timers
+    done: Arc<AtomicBool>,
+}
+
+/// Where the client -> server relay writes to
+enum Writers {
+    /// Relaying raw bytes, each direction owns the stream it writes to
+    Raw(TcpStream),
+    /// Relaying messages, the streams are shared with the threads injecting messages
+    Messages {
+        connections: Arc<Connections>,
+        activity: Arc<Activity>,
+        /// Told about a shutdown of the proxy until the client is done sending
+        registered: Registered,
+    },
+}
+
+/// What a session relayed
+pub struct Relayed {
+    pub client_to_server: RelayOutcome,
+    /// How the server -> client relay ended, unless its thread panicked
+    pub server_to_client: Option<RelayOutcome>,
+    /// The start of the traffic from the client
+    pub initial_traffic: Vec<u8>,
+}
+
+impl Session {
+    /// Start relaying from the server to the client in the background,
+    /// along with the keepalives and the watchdog of the `options`
+    ///
+    /// Relaying messages, the client is registered with the connected `clients`.
+    pub fn start(
+        client: String,
+        client_con: TcpStream,
+        server_con: TcpStream,
+        options: RelayOptions,
+        clients: &Arc<ConnectedClients>,
+    ) -> std::io::Result<Self> {
+        let client_read = client_con.try_clone()?;
+        let server_read = server_con.try_clone()?;
+        let (relayed_sender, relayed) = mpsc::channel();
+        let done = Arc::new(AtomicBool::new(false));
+        let mut timers = Vec::new();
+
+        let (writers, relay_thread) = if options.parse_messages {
+            let client_write = Arc::new(Mutex::new(client_con));
+            let server_write = Arc::new(Mutex::new(server_con));
+            let activity = Arc::new(Activity::new());
+            let connections =
+                Arc::new(Connections::new(client_write.clone(), server_write.clone()));
+            let registered = clients.register(connections.clone());
+            if let Some(interval) = options.keepalive {
+                let client_write = client_write.clone();
+                let activity = activity.clone();
+                let done = done.clone();
+                timers.push((
+                    "keepalive",
+                    std::thread::spawn(move || {
+                        relay::keep_alive(&client_write, &activity, interval, &done)
+                    }),
+                ));
+            }
+            if let Some(watchdog) = options.watchdog.clone() {
+                let client = client.clone();
+                let client_write = client_write.clone();
+                let server_write = server_write.clone();
+                let done = done.clone();
+                timers.push((
+                    "watchdog",
+                    std::thread::spawn(move || {
+                        watchdog.watch(&client, &client_write, &server_write, &done)
+                    }),
+                ));
+            }
+
+            let relay_thread = {
+                let client_write = client_write.clone();
+                let server_write = server_write.clone();
+                let activity = activity.clone();
+                let connections = connections.clone();
+                let options = options.clone();
+                std::thread::spawn(move || {
+                    let outcome = relay::relay_messages(
+                        server_read,
+                        &client_write,
+                        &server_write,
+                        Direction::ServerToClient,
+                        None,
+                        Some(&activity),
+                        &options,
+                    );
+                    connections.server_closed();
+                    let _ = relayed_sender.send(outcome);
+                })
+            };
+            let writers = Writers::Messages {
+                connections,
+                activity,
+                registered,
+            };
+            (writers, relay_thread)
+        } else {
+            let options = options.clone();
+            let relay_thread = std::thread::spawn(move || {
+                let outcome = relay::relay_connection(
+                    server_read,
+                    client_con,
+                    Direction::ServerToClient,
+                    None,
+                    &options,
+                );
+                let _ = relayed_sender.send(outcome);
+            });
+            (Writers::Raw(server_con), relay_thread)
+        };
+
+        Ok(Self {
+            client,
+            options,
+            client_read,
+            writers,
+            relayed,
+            relay_thread,
+            timers,
+            done,
+        })
+    }
+
+    /// Relay from the client to the server until the client is done sending,
+    /// then give the server a moment to finish sending as well
+    /// and `stop_server`, passing whether it closed its connection by then
+    ///
+    /// Relaying messages, a server whose client left without the `exit` notification is asked to shut down first,
+    /// so that it only needs to be terminated if it doesn't exit within the moment.
+    ///
+    /// Returns what was relayed, once all threads of the session ended, and what stopping the server returned.
+    pub fn relay<T>(self, stop_server: impl FnOnce(bool) -> T) -> (Relayed, T) {
+        let client = self.client;
+        let teardown = Teardown {
+            client: client.clone(),
+            stop_server: Some(stop_server),
+            done: self.done.clone(),
+            stopped: PhantomData,
+        };
+        let mut initial_traffic = Vec::new();
+        let client_to_server = match self.writers {
+            Writers::Raw(server_write) => relay::relay_connection(
+                self.client_read,
+                server_write,
+                Direction::ClientToServer,
+                Some(&mut initial_traffic),
+                &self.options,
+            ),
+            Writers::Messages {
+                connections,
+                activity,
+                registered,
+            } => {
+                let outcome = relay::relay_messages(
+                    self.client_read,
+                    &connections.server_write,
+                    &connections.client_write,
+                    Direction::ClientToServer,
+                    Some(&mut initial_traffic),
+                    Some(&activity),
+                    &self.options,
+                );
+                drop(registered);
+                outcome
+            }
+        };
+        log_outcome(&client, &client_to_server);
+
+        // the client is done sending, but the server may still be flushing its responses
+        let server_to_client = self.relayed.recv_timeout(HALF_CLOSE_GRACE);
+        if server_to_client.is_err() {
+            debug!(
+                "[{}] LSP did not close its side of the connection within {:?}",
+                client, HALF_CLOSE_GRACE
+            );
+        }
+        let stopped = teardown.stop_server(server_to_client.is_ok());
+        // stopping the server closed its connection, so the relay thread is finishing up if it didn't already
+        let server_to_client = server_to_client.or_else(|_| self.relayed.recv()).ok();
+        if let Some(server_to_client) = &server_to_client {
+            log_outcome(&client, server_to_client);
+        }
+
+        if let Err(_err) = self.relay_thread.join() {
+            warn!(
+                "[{}] Failed to join panicked server -> client relay thread",
+                client
+            );
+        }
+        self.done.store(true, Ordering::SeqCst);
+        for (name, timer) in self.timers {
+            if let Err(_err) = timer.join() {
+                warn!("[{}] Failed to join panicked {} thread", client, name);
+            }
+        }
+        let relayed = Relayed {
+            client_to_server,
+            server_to_client,
+            initial_traffic,
+        };
+        (relayed, stopped)
+    }
+}
+
+/// Stops the server and the timers of a session when relaying panics,
+/// so that neither outlives the session
+struct Teardown<F: FnOnce(bool) -> T, T> {
+    client: String,
+    stop_server: Option<F>,
+    done: Arc<AtomicBool>,
+    stopped: PhantomData<T>,
+}
+
+impl<F: FnOnce(bool) -> T, T> Teardown<F, T> {
+    fn stop_server(mut self, closed_connection: bool) -> T {
+        let stop_server = self
+            .stop_server
+            .take()
+            .expect("the server is only stopped once");
+        stop_server(closed_connection)
+    }
+}
+
+impl<F: FnOnce(bool) -> T, T> Drop for Teardown<F, T> {
+    fn drop(&mut self) {
+        if let Some(stop_server) = self.stop_server.take() {
+            warn!("[{}] Relaying panicked, stopping the LSP", self.client);
+            self.done.store(true, Ordering::SeqCst);
+            stop_server(false);
+        }
+    }
+}
+
+fn log_outcome(client: &str, outcome: &RelayOutcome) {
+    match outcome.end {
+        RelayEnd::Closed => debug!("[{}] {}", client, outcome),
+        RelayEnd::Failed(_) | RelayEnd::WriteFailed(_) => warn!("[{}] {}", client, outcome),
+    }
+    if let Some(checksums) = &outcome.checksums {
+        if checksums.received == checksums.sent {
+            info!(
+                "[{}] {} relay received and sent {}",
+                client, outcome.direction, checksums.sent
+            );
+        } else {
+            warn!(
+                "[{}] {} relay received {}, but sent {}",
+                client, outcome.direction, checksums.received, checksums.sent
+            );
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::error::ServerError;
+use crate::json;
+use crate::process;
+use crate::relay::Checksums;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The language server process could not be spawned, or its pre-spawn hook failed
+    SpawnFailed,
+    /// The language server exited before we could connect to it
+    ServerExited,
+    /// The port chosen for the language server was already in use
+    PortConflict,
+    /// The language server did not accept connections in time
+    StartupTimeout,
+    /// Setting up the relay failed
+    SetupFailed,
+    /// The client closed the connection
+    ClientClosed,
+    /// The language server exited unsuccessfully during the session
+    ServerCrashed,
+    /// Writing to the client or the language server failed during the session
+    RelayFailed,
+    /// The server was stopped because the proxy exited while draining the sessions after a handoff
+    AdminDrain,
+    /// The server was stopped because the proxy exited
+    ProxyShutdown,
+}
+
+impl ExitReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::SpawnFailed => "spawn-failed",
+            Self::ServerExited => "server-exited",
+            Self::PortConflict => "port-conflict",
+            Self::StartupTimeout => "startup-timeout",
+            Self::SetupFailed => "setup-failed",
+            Self::ClientClosed => "client-closed",
+            Self::ServerCrashed => "server-crashed",
+            Self::RelayFailed => "relay-failed",
+            Self::AdminDrain => "admin-drain",
+            Self::ProxyShutdown => "proxy-shutdown",
+        }
+    }
+
+    /// Whether the session got as far as being relayed to a language server
+    pub fn served(self) -> bool {
+        matches!(
+            self,
+            Self::ClientClosed
+                | Self::ServerCrashed
+                | Self::RelayFailed
+                | Self::AdminDrain
+                | Self::ProxyShutdown
+        )
+    }
+}
+
+impl From<&ServerError> for ExitReason {
+    fn from(err: &ServerError) -> Self {
+        match err {
+            ServerError::SpawnFailed(_) => Self::SpawnFailed,
+            ServerError::PortConflict(_) => Self::PortConflict,
+            ServerError::ExitedWithStatus(_) => Self::ServerExited,
+            ServerError::Timeout(_) => Self::StartupTimeout,
+            ServerError::PreSpawnHookFailed(_) => Self::SpawnFailed,
+        }
+    }
+}
+
+/// Accounting information about a single client session
+pub struct SessionRecord {
+    /// The id `/status` listed the session with
+    pub id: u64,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub client: String,
+    pub root_uri: Option<String>,
+    pub port: u16,
+    /// Whether the server was started from the canary jar
+    pub canary: bool,
+    pub bytes_client_to_server: u64,
+    pub bytes_server_to_client: u64,
+    /// How the client -> server direction of the relay ended
+    pub client_to_server_end: Option<&'static str>,
+    /// How the server -> client direction of the relay ended
+    pub server_to_client_end: Option<&'static str>,
+    /// Why writing failed, if it ended the relay
+    pub relay_error: Option<String>,
+    /// What the client -> server direction received and sent, with `--checksums`
+    pub client_to_server_checksums: Option<Checksums>,
+    /// What the server -> client direction received and sent, with `--checksums`
+    pub server_to_client_checksums: Option<Checksums>,
+    /// How the language server exited, if it was started and waited for
+    pub server_exit: Option<ExitStatus>,
+    pub exit_reason: ExitReason,
+}
+
+impl SessionRecord {
+    pub fn new(id: u64, client: String, port: u16) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id,
+            start: now,
+            end: now,
+            client,
+            root_uri: None,
+            port,
+            canary: false,
+            bytes_client_to_server: 0,
+            bytes_server_to_client: 0,
+            client_to_server_end: None,
+            server_to_client_end: None,
+            relay_error: None,
+            client_to_server_checksums: None,
+            server_to_client_checksums: None,
+            server_exit: None,
+            exit_reason: ExitReason::SetupFailed,
+        }
+    }
+
+    pub fn finish(&mut self, exit_reason: ExitReason) {
+        self.end = SystemTime::now();
+        self.exit_reason = exit_reason;
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{",
+                "\"id\":{},",
+                "\"start\":\"{}\",",
+                "\"end\":\"{}\",",
+                "\"client\":{},",
+                "\"root_uri\":{},",
+                "\"port\":{},",
+                "\"canary\":{},",
+                "\"bytes_client_to_server\":{},",
+                "\"bytes_server_to_client\":{},",
+                "\"client_to_server_end\":{},",
+                "\"server_to_client_end\":{},",
+                "\"relay_error\":{},",
+                "\"client_to_server_crc32\":{},",
+                "\"server_to_client_crc32\":{},",
+                "\"server_exit_code\":{},",
+                "\"server_exit_signal\":{},",
+                "\"exit_reason\":\"{}\"",
+                "}}"
+            ),
+            self.id,
+            format_rfc3339(self.start),
+            format_rfc3339(self.end),
+            json::quote(&self.client),
+            json::quote_optional(self.root_uri.as_deref()),
+            self.port,
+            self.canary,
+            self.bytes_client_to_server,
+            self.bytes_server_to_client,
+            json::quote_optional(self.client_to_server_end),
+            json::quote_optional(self.server_to_client_end),
+            json::quote_optional(self.relay_error.as_deref()),
+            checksums_json(self.client_to_server_checksums),
+            checksums_json(self.server_to_client_checksums),
+            optional_number(self.server_exit.and_then(|status| status.code())),
+            optional_number(self.server_exit.and_then(process::exit_signal)),
+            self.exit_reason.as_str(),
+        )
+    }
+}
+
+/// Appends one JSON record per line for each completed session
+pub struct SessionLog {
+    file: Mutex<File>,
+}
+
+impl SessionLog {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, record: &SessionRecord) -> std::io::Result<()> {
+        let mut line = record.to_json();
+        line.push('\n');
+        // a poisoned lock only means another writer panicked, the file itself is still usable
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+fn optional_number(value: Option<i32>) -> String {
+    value.map_or_else(|| String::from("null"), |value| value.to_string())
+}
+
+/// The CRCs a direction received and sent as hex strings, or `null` without checksums
+fn checksums_json(checksums: Option<Checksums>) -> String {
+    checksums.map_or_else(
+        || String::from("null"),
+        |checksums| {
+            format!(
+                "{{\"received\":\"{:08x}\",\"sent\":\"{:08x}\"}}",
+                checksums.received.value(),
+                checksums.sent.value()
+            )
+        },
+    )
+}
+
+/// Format a timestamp as an RFC 3339 UTC date-time with millisecond precision
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Convert days since the unix epoch into a (year, month, day) triple of the proleptic gregorian calendar
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_days_around_leap_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // 2000 is a leap year, as it is divisible by 400
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        // 2100 is not, as it is divisible by 100 only
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_secs(11016 * 86400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_secs(4107628799)),
+            "2100-03-01T23:59:59.000Z"
+        );
+        // sub-second precision is truncated to milliseconds
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::new(1234567890, 123456789)),
+            "2009-02-13T23:31:30.123Z"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static HANDOFF_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+static LOG_CYCLE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    // only async-signal-safe operations are allowed here,
+    // storing to an atomic is one of them
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn request_handoff(_signal: libc::c_int) {
+    HANDOFF_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn request_log_cycle(_signal: libc::c_int) {
+    LOG_CYCLE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install handlers for SIGINT and SIGTERM that request a graceful shutdown
+/// instead of terminating the process immediately
+///
+/// On unix SIGUSR2 additionally requests handing off the listener to a freshly started instance,
+/// and SIGUSR1 cycling through more verbose log levels
+pub fn install_handlers() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // Safety: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(signal, handler);
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        let handler = request_handoff as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Safety: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGUSR2, handler);
+        }
+        let handler = request_log_cycle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Safety: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGUSR1, handler);
+        }
+    }
+}
+
+/// Request a graceful shutdown from within the process
+pub fn request() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a shutdown has been requested
+pub fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Note that the listeners were handed off and only the remaining sessions are being served
+pub fn start_draining() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Whether the listeners were handed off to a new instance
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Refuse new sessions and exit once the running ones ended, e.g. before maintaining the machine
+pub fn start_maintenance() {
+    MAINTENANCE.store(true, Ordering::SeqCst);
+}
+
+/// Whether new sessions are refused for maintenance
+pub fn in_maintenance() -> bool {
+    MAINTENANCE.load(Ordering::SeqCst)
+}
+
+/// Whether cycling the log level has been requested by a signal since the last call
+pub fn take_log_cycle_request() -> bool {
+    LOG_CYCLE_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Whether a handoff has been requested by a signal since the last call
+pub fn take_handoff_request() -> bool {
+    HANDOFF_REQUESTED.swap(false, Ordering::SeqCst)
+}
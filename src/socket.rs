@@ -0,0 +1,405 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// Socket options that have to be applied before binding a listener,
+/// which the standard library does not expose
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListenerOptions {
+    /// Allow multiple listeners to bind the same address, the kernel balances connections between them
+    pub reuse_port: bool,
+    /// Restrict an IPv6 listener to IPv6, so that the same port can also be bound for IPv4
+    pub only_v6: bool,
+    /// How many connections may wait to be accepted, 128 like the standard library otherwise
+    pub backlog: Option<u32>,
+}
+
+impl ListenerOptions {
+    fn is_default(&self) -> bool {
+        !self.reuse_port && !self.only_v6 && self.backlog.is_none()
+    }
+}
+
+/// A spare file descriptor, given up to accept and close a pending connection
+/// while the process is out of descriptors, so that the connection stops waking up the accept loop
+pub struct ReserveFd(Option<File>);
+
+impl ReserveFd {
+    pub fn new() -> Self {
+        Self(imp::open_reserve())
+    }
+
+    /// Close the next connection pending on `listener` by means of the reserved descriptor
+    pub fn reject_pending(&mut self, listener: &TcpListener) {
+        self.0 = None;
+        if let Ok((con, _)) = listener.accept() {
+            drop(con);
+        }
+        self.0 = imp::open_reserve();
+    }
+}
+
+/// Whether `err` means that the process or system ran out of file descriptors
+pub fn out_of_fds(err: &std::io::Error) -> bool {
+    imp::out_of_fds(err)
+}
+
+/// Bind a listener to the first address of `addrs` that can be bound, applying `options`
+pub fn bind(addrs: &[SocketAddr], options: ListenerOptions) -> std::io::Result<TcpListener> {
+    if options.is_default() {
+        return TcpListener::bind(addrs);
+    }
+
+    let mut last_err = None;
+    for addr in addrs {
+        match imp::bind(addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// Bind a udp socket to `addr` with SO_REUSEADDR set (unix only),
+/// so that it can share a well known port with other processes
+pub fn bind_shared_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    imp::bind_shared_udp(&addr)
+}
+
+/// Whether the peer starts with sending `prefix`, without consuming anything
+///
+/// A peer that does not send anything before the read timeout of `con` does not send the prefix
+pub fn peek_prefix(con: &TcpStream, prefix: &[u8]) -> std::io::Result<bool> {
+    let mut buffer = vec![0; prefix.len()];
+    loop {
+        let len = match con.peek(&mut buffer) {
+            Ok(len) => len,
+            // the client is waiting for us, so it is not sending the prefix
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        };
+        if len == 0 || buffer[..len] != prefix[..len] {
+            return Ok(false);
+        } else if len == prefix.len() {
+            return Ok(true);
+        }
+        // only part of the prefix has arrived so far
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A pipe through which data is moved from one socket to another within the kernel,
+/// without copying it through user space
+pub struct Splice(imp::Pipe);
+
+impl Splice {
+    /// A pipe to splice through, if the platform supports splicing
+    pub fn new() -> Option<Self> {
+        imp::Pipe::new().map(Self)
+    }
+
+    /// Move what `rx` received into the pipe, returning how many bytes, 0 at the end of the stream
+    pub fn fill(&self, rx: &TcpStream) -> std::io::Result<usize> {
+        self.0.fill(rx)
+    }
+
+    /// Move the `len` bytes in the pipe on to `tx`
+    pub fn drain(&self, tx: &TcpStream, len: usize) -> std::io::Result<()> {
+        self.0.drain(tx, len)
+    }
+}
+
+/// The IPv4 address of a peer connected via IPv4 to an IPv6 socket, any other address as is
+pub fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(mapped) if ip.segments()[5] == 0xffff => IpAddr::V4(mapped),
+            _ => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+/// A connected pair of loopback tcp streams
+pub fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let expected = local.local_addr()?;
+    // make sure no other local process snuck in between bind and connect
+    loop {
+        let (remote, address) = listener.accept()?;
+        if address == expected {
+            return Ok((local, remote));
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::ListenerOptions;
+    use std::fs::File;
+    use std::mem::size_of;
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+    /// The backlog the standard library uses for its listeners
+    const BACKLOG: libc::c_int = 128;
+    /// The most bytes moved into a pipe at once, its default capacity
+    #[cfg(target_os = "linux")]
+    const SPLICE_LEN: usize = 64 * 1024;
+
+    #[cfg(target_os = "linux")]
+    pub struct Pipe {
+        read: File,
+        write: File,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Pipe {
+        pub fn new() -> Option<Self> {
+            let mut fds = [0; 2];
+            // Safety: fds is a valid array of two descriptors to fill
+            cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }).ok()?;
+            // Safety: the descriptors were just created and are owned by the files right away
+            Some(unsafe {
+                Self {
+                    read: File::from_raw_fd(fds[0]),
+                    write: File::from_raw_fd(fds[1]),
+                }
+            })
+        }
+
+        pub fn fill(&self, rx: &TcpStream) -> std::io::Result<usize> {
+            splice(rx.as_raw_fd(), self.write.as_raw_fd(), SPLICE_LEN)
+        }
+
+        pub fn drain(&self, tx: &TcpStream, mut len: usize) -> std::io::Result<()> {
+            while len > 0 {
+                match splice(self.read.as_raw_fd(), tx.as_raw_fd(), len) {
+                    Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                    Ok(moved) => len -= moved,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice(from: RawFd, to: RawFd, len: usize) -> std::io::Result<usize> {
+        // Safety: both descriptors are open for the duration of the call, without offsets their positions are used
+        let moved = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if moved < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(moved as usize)
+        }
+    }
+
+    /// Splicing is only supported on linux
+    #[cfg(not(target_os = "linux"))]
+    pub enum Pipe {}
+
+    #[cfg(not(target_os = "linux"))]
+    impl Pipe {
+        pub fn new() -> Option<Self> {
+            None
+        }
+
+        pub fn fill(&self, _rx: &TcpStream) -> std::io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn drain(&self, _tx: &TcpStream, _len: usize) -> std::io::Result<()> {
+            match *self {}
+        }
+    }
+
+    pub fn bind(addr: &SocketAddr, options: ListenerOptions) -> std::io::Result<TcpListener> {
+        let listener: TcpListener = open(addr, libc::SOCK_STREAM, options)?;
+        let backlog = options.backlog.map_or(BACKLOG, |backlog| {
+            libc::c_int::try_from(backlog).unwrap_or(libc::c_int::MAX)
+        });
+        // Safety: listen on the socket we just bound
+        cvt(unsafe { libc::listen(listener.as_raw_fd(), backlog) })?;
+        Ok(listener)
+    }
+
+    pub fn open_reserve() -> Option<File> {
+        File::open("/dev/null").ok()
+    }
+
+    pub fn out_of_fds(err: &std::io::Error) -> bool {
+        matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+    }
+
+    pub fn bind_shared_udp(addr: &SocketAddr) -> std::io::Result<UdpSocket> {
+        open(addr, libc::SOCK_DGRAM, ListenerOptions::default())
+    }
+
+    /// Create a socket of type `ty` bound to `addr`
+    fn open<S: FromRawFd>(
+        addr: &SocketAddr,
+        ty: libc::c_int,
+        options: ListenerOptions,
+    ) -> std::io::Result<S> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+
+        // Safety: plain socket creation, the descriptor is owned by the socket right away
+        let fd = cvt(unsafe { libc::socket(domain, ty, 0) })?;
+        let socket = unsafe { S::from_raw_fd(fd) };
+
+        set_flag(fd, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        if options.reuse_port {
+            set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        if options.only_v6 && domain == libc::AF_INET6 {
+            set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        }
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                // Safety: sockaddr_in is plain old data for which all zeros is a valid value
+                let mut raw: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                };
+                // Safety: the pointer and length describe the sockaddr_in above
+                cvt(unsafe {
+                    libc::bind(
+                        fd,
+                        &raw as *const libc::sockaddr_in as *const libc::sockaddr,
+                        size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    )
+                })?;
+            }
+            SocketAddr::V6(addr) => {
+                // Safety: sockaddr_in6 is plain old data for which all zeros is a valid value
+                let mut raw: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                // Safety: the pointer and length describe the sockaddr_in6 above
+                cvt(unsafe {
+                    libc::bind(
+                        fd,
+                        &raw as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                        size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    )
+                })?;
+            }
+        }
+
+        Ok(socket)
+    }
+
+    fn set_option(
+        fd: RawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> std::io::Result<()> {
+        // Safety: the pointer and length describe the c_int value
+        cvt(unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn set_flag(
+        fd: RawFd,
+        get: libc::c_int,
+        set: libc::c_int,
+        flag: libc::c_int,
+    ) -> std::io::Result<()> {
+        // Safety: fcntl on a descriptor we own has no memory safety implications
+        let flags = cvt(unsafe { libc::fcntl(fd, get) })?;
+        cvt(unsafe { libc::fcntl(fd, set, flags | flag) })?;
+        Ok(())
+    }
+
+    fn cvt(result: libc::c_int) -> std::io::Result<libc::c_int> {
+        if result < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::ListenerOptions;
+    use std::fs::File;
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+
+    /// Splicing is only supported on linux
+    pub enum Pipe {}
+
+    impl Pipe {
+        pub fn new() -> Option<Self> {
+            None
+        }
+
+        pub fn fill(&self, _rx: &TcpStream) -> std::io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn drain(&self, _tx: &TcpStream, _len: usize) -> std::io::Result<()> {
+            match *self {}
+        }
+    }
+
+    pub fn bind(_addr: &SocketAddr, _options: ListenerOptions) -> std::io::Result<TcpListener> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "custom listener options are only supported on unix",
+        ))
+    }
+
+    pub fn bind_shared_udp(addr: &SocketAddr) -> std::io::Result<UdpSocket> {
+        UdpSocket::bind(addr)
+    }
+
+    pub fn open_reserve() -> Option<File> {
+        None
+    }
+
+    pub fn out_of_fds(_err: &std::io::Error) -> bool {
+        false
+    }
+}
@@ -0,0 +1,186 @@
+use crate::json::Value;
+use crate::process;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// The prefix of the state files, followed by the pid of the proxy they belong to
+const PREFIX: &str = "lsp-on-demand-";
+const SUFFIX: &str = ".state";
+
+/// Records the language servers spawned by this instance of the proxy in a file of its own,
+/// so that the servers left behind by a crashed instance can be found by the next one
+///
+/// The file is removed when dropped, when the proxy exits cleanly.
+pub struct StateFile {
+    path: PathBuf,
+    /// The ports of the running servers by their pid
+    servers: Mutex<BTreeMap<u32, u16>>,
+}
+
+impl StateFile {
+    /// Create the state file of this instance below `dir`
+    pub fn create(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let state = Self {
+            path: dir.join(format!("{}{}{}", PREFIX, std::process::id(), SUFFIX)),
+            servers: Mutex::default(),
+        };
+        state.write(&state.lock())?;
+        Ok(state)
+    }
+
+    /// Record a server that was spawned on `port`
+    pub fn server_spawned(&self, pid: u32, port: u16) {
+        let mut servers = self.lock();
+        servers.insert(pid, port);
+        self.update(&servers);
+    }
+
+    /// Forget a server that was killed or exited
+    pub fn server_reaped(&self, pid: u32) {
+        let mut servers = self.lock();
+        if servers.remove(&pid).is_some() {
+            self.update(&servers);
+        }
+    }
+
+    fn update(&self, servers: &BTreeMap<u32, u16>) {
+        if let Err(err) = self.write(servers) {
+            warn!(
+                "Failed to update state file {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    /// Replace the file at once, so that it is never read half written
+    fn write(&self, servers: &BTreeMap<u32, u16>) -> std::io::Result<()> {
+        let contents: String = servers
+            .iter()
+            .map(|(pid, port)| format!("{{\"pid\":{},\"port\":{}}}\n", pid, port))
+            .collect();
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        std::fs::write(&temp_path, contents)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, u16>> {
+        // a poisoned lock only means another thread panicked, the servers are still valid
+        self.servers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for StateFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove state file {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Find the servers spawned by instances of the proxy below `dir` that are no longer running
+/// and kill them if `kill` is set, otherwise only report them
+///
+/// Only processes still running one of the `servers`, the jars or executable of the servers,
+/// are considered, the pid may have been reused by another process
+pub fn reap_orphans(dir: &Path, servers: &[&Path], kill: bool) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!(
+                "Not looking for orphaned servers in {}: {}",
+                dir.display(),
+                err
+            );
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let proxy = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|rest| rest.strip_suffix(SUFFIX))
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let proxy = match proxy {
+            Some(proxy) => proxy,
+            None => continue,
+        };
+        // the servers of a running instance, e.g. one we were handed off from, are still in use
+        if proxy == std::process::id() || process::is_running(proxy) {
+            continue;
+        }
+
+        let path = entry.path();
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                warn!("Failed to read state file {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        let mut remaining = 0;
+        for (pid, port) in contents.lines().filter_map(parse_server) {
+            if !process::is_running(pid) || !runs(pid, servers) {
+                continue;
+            }
+            if kill {
+                match process::kill(pid) {
+                    Ok(()) => info!("Killed orphaned LSP with pid {} holding port {}", pid, port),
+                    Err(err) => {
+                        warn!("Failed to kill orphaned LSP with pid {}: {}", pid, err);
+                        remaining += 1;
+                    }
+                }
+            } else {
+                warn!(
+                    "Orphaned LSP with pid {} of a crashed instance still holds port {}, pass --kill-orphans to kill it",
+                    pid, port
+                );
+                remaining += 1;
+            }
+        }
+
+        // keep the file until all its servers are gone, so that they can still be killed later
+        if remaining == 0 {
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("Failed to remove state file {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+fn parse_server(line: &str) -> Option<(u32, u16)> {
+    let server = Value::parse(line)?;
+    let pid = server.get("pid")?.as_u64()?;
+    let port = server.get("port")?.as_u64()?;
+    Some((pid as u32, port as u16))
+}
+
+/// Whether the process with `pid` runs `server`, so that it is one of our servers
+fn runs(pid: u32, servers: &[&Path]) -> bool {
+    match process::command_line(pid) {
+        Some(args) => args.iter().any(|arg| {
+            servers
+                .iter()
+                .any(|server| arg.as_os_str() == server.as_os_str())
+        }),
+        None => {
+            debug!(
+                "Can't tell whether pid {} is an orphaned LSP, leaving it alone",
+                pid
+            );
+            false
+        }
+    }
+}
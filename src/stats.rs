@@ -0,0 +1,273 @@
+use crate::json;
+use crate::session_log::ExitReason;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How many of the most recent readiness latencies are kept for the percentiles
+const READINESS_SAMPLES: usize = 1000;
+/// How many readiness latencies are needed before a latency counts as degraded
+const MIN_BASELINE_SAMPLES: usize = 5;
+
+/// Counters collected over the lifetime of the proxy,
+/// shared between the accept loop and all connection handlers
+pub struct Statistics {
+    started: Instant,
+    sessions_served: AtomicU64,
+    active_sessions: AtomicU64,
+    peak_concurrency: AtomicU64,
+    spawn_failures: AtomicU64,
+    rejected_connections: AtomicU64,
+    session_duration_millis: AtomicU64,
+    bytes_relayed: AtomicU64,
+    /// The time from spawning a server to the first successful connection, oldest first
+    readiness_latencies: Mutex<VecDeque<Duration>>,
+    /// How many sessions ended for each reason
+    exit_reasons: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            sessions_served: AtomicU64::new(0),
+            active_sessions: AtomicU64::new(0),
+            peak_concurrency: AtomicU64::new(0),
+            spawn_failures: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            session_duration_millis: AtomicU64::new(0),
+            bytes_relayed: AtomicU64::new(0),
+            readiness_latencies: Mutex::new(VecDeque::new()),
+            exit_reasons: Mutex::default(),
+        }
+    }
+
+    /// Record a newly accepted client connection
+    pub fn session_opened(&self) {
+        let active = self.active_sessions.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_concurrency.fetch_max(active, Ordering::SeqCst);
+    }
+
+    /// Record the end of a client connection
+    ///
+    /// Only sessions that were actually relayed to a language server count as served
+    pub fn session_closed(&self, duration: Duration, served: bool) {
+        self.active_sessions.fetch_sub(1, Ordering::SeqCst);
+        if served {
+            self.sessions_served.fetch_add(1, Ordering::SeqCst);
+            self.session_duration_millis
+                .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Record why a session ended
+    pub fn session_ended(&self, reason: ExitReason) {
+        // a poisoned lock only means another thread panicked, the counts are still valid
+        let mut exit_reasons = self
+            .exit_reasons
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *exit_reasons.entry(reason.as_str()).or_default() += 1;
+    }
+
+    /// Record that an opened session was rejected before it started
+    pub fn session_rejected(&self) {
+        self.active_sessions.fetch_sub(1, Ordering::SeqCst);
+        self.rejected_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn active_sessions(&self) -> u64 {
+        self.active_sessions.load(Ordering::SeqCst)
+    }
+
+    pub fn spawn_failed(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn add_bytes_relayed(&self, bytes: u64) {
+        self.bytes_relayed.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Record how long a server took from being spawned to accepting a connection
+    pub fn server_ready(&self, latency: Duration) {
+        let mut latencies = self.readiness_latencies();
+        if latencies.len() == READINESS_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    /// The median readiness latency, once enough servers started to tell what is usual
+    pub fn usual_readiness_latency(&self) -> Option<Duration> {
+        let latencies = self.readiness_latencies();
+        if latencies.len() < MIN_BASELINE_SAMPLES {
+            return None;
+        }
+        percentiles(&latencies).map(|percentiles| percentiles.p50)
+    }
+
+    fn readiness_latencies(&self) -> MutexGuard<'_, VecDeque<Duration>> {
+        // a poisoned lock only means another thread panicked, the samples are still valid
+        self.readiness_latencies
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn report(&self) -> Report {
+        let sessions_served = self.sessions_served.load(Ordering::SeqCst);
+        let total_duration_millis = self.session_duration_millis.load(Ordering::SeqCst);
+        let average_session_duration = Duration::from_millis(
+            total_duration_millis
+                .checked_div(sessions_served)
+                .unwrap_or_default(),
+        );
+
+        Report {
+            uptime: self.started.elapsed(),
+            sessions_served,
+            active_sessions: self.active_sessions.load(Ordering::SeqCst),
+            peak_concurrency: self.peak_concurrency.load(Ordering::SeqCst),
+            spawn_failures: self.spawn_failures.load(Ordering::SeqCst),
+            rejected_connections: self.rejected_connections.load(Ordering::SeqCst),
+            average_session_duration,
+            bytes_relayed: self.bytes_relayed.load(Ordering::SeqCst),
+            readiness_latency: percentiles(&self.readiness_latencies()),
+            exit_reasons: self
+                .exit_reasons
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .iter()
+                .map(|(&reason, &count)| (reason, count))
+                .collect(),
+        }
+    }
+}
+
+/// Percentiles of a set of durations
+#[derive(Debug, Clone, Copy)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    fn to_json(self) -> String {
+        format!(
+            "{{\"p50\":{:.3},\"p90\":{:.3},\"p99\":{:.3},\"max\":{:.3}}}",
+            self.p50.as_secs_f64(),
+            self.p90.as_secs_f64(),
+            self.p99.as_secs_f64(),
+            self.max.as_secs_f64()
+        )
+    }
+}
+
+/// The nearest-rank percentiles of `samples`, nothing if there are none
+fn percentiles(samples: &VecDeque<Duration>) -> Option<Percentiles> {
+    let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+    sorted.sort();
+    let max = *sorted.last()?;
+    let percentile = |percent: usize| {
+        let rank = (sorted.len() * percent + 99) / 100;
+        sorted[rank.max(1) - 1]
+    };
+    Some(Percentiles {
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max,
+    })
+}
+
+/// A snapshot of the [`Statistics`] taken at a single point in time
+#[derive(Debug)]
+pub struct Report {
+    pub uptime: Duration,
+    pub sessions_served: u64,
+    pub active_sessions: u64,
+    pub peak_concurrency: u64,
+    pub spawn_failures: u64,
+    pub rejected_connections: u64,
+    pub average_session_duration: Duration,
+    pub bytes_relayed: u64,
+    /// The readiness latencies of the most recently started servers
+    pub readiness_latency: Option<Percentiles>,
+    /// How many sessions ended for each reason, ordered by reason
+    pub exit_reasons: Vec<(&'static str, u64)>,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        format!("{{{}}}", self.json_fields())
+    }
+
+    /// The members of the JSON object of the report, for documents embedding them
+    pub fn json_fields(&self) -> String {
+        format!(
+            concat!(
+                "\"uptime_secs\":{:.3},",
+                "\"sessions_served\":{},",
+                "\"active_sessions\":{},",
+                "\"peak_concurrency\":{},",
+                "\"spawn_failures\":{},",
+                "\"rejected_connections\":{},",
+                "\"average_session_duration_secs\":{:.3},",
+                "\"bytes_relayed\":{},",
+                "\"readiness_latency_secs\":{},",
+                "\"exit_reasons\":{{{}}}",
+            ),
+            self.uptime.as_secs_f64(),
+            self.sessions_served,
+            self.active_sessions,
+            self.peak_concurrency,
+            self.spawn_failures,
+            self.rejected_connections,
+            self.average_session_duration.as_secs_f64(),
+            self.bytes_relayed,
+            self.readiness_latency
+                .map_or_else(|| String::from("null"), Percentiles::to_json),
+            self.exit_reasons
+                .iter()
+                .map(|(reason, count)| format!("{}:{}", json::quote(reason), count))
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "served {} sessions in {:.0?} (peak concurrency {}, {} still active), {} spawn failures, {} rejected connections, average session duration {:.1?}, {} bytes relayed",
+            self.sessions_served,
+            self.uptime,
+            self.peak_concurrency,
+            self.active_sessions,
+            self.spawn_failures,
+            self.rejected_connections,
+            self.average_session_duration,
+            self.bytes_relayed
+        )?;
+        if let Some(latency) = &self.readiness_latency {
+            write!(
+                f,
+                ", server readiness latency p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {:.1?}",
+                latency.p50, latency.p90, latency.p99, latency.max
+            )?;
+        }
+        for (index, (reason, count)) in self.exit_reasons.iter().enumerate() {
+            let separator = if index == 0 {
+                ", sessions ended by"
+            } else {
+                ","
+            };
+            write!(f, "{} {} {}", separator, reason, count)?;
+        }
+        Ok(())
+    }
+}
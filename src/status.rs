@@ -0,0 +1,135 @@
+use crate::guard::Guardrails;
+use crate::json;
+use crate::session_log::format_rfc3339;
+use crate::stats::Report;
+use crate::workers::WorkerPool;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+
+/// What `GET /status` reports besides the statistics,
+/// for dashboards and the server status page of the IDE plugin
+pub struct Status {
+    /// A JSON object summarizing the configuration, which does not change while running
+    pub config: String,
+    pub workers: WorkerPool,
+    pub sessions: Arc<ActiveSessions>,
+    pub guardrails: Arc<Guardrails>,
+}
+
+impl Status {
+    pub fn to_json(&self, report: &Report) -> String {
+        let pool = self.workers.state();
+        format!(
+            "{{{},\"config\":{},\"pool\":{{\"workers\":{},\"busy\":{},\"overflow\":{},\"overflowing\":{},\"queued\":{},\"queue_capacity\":{}}},\"subnets\":[{}],\"sessions\":{}}}",
+            report.json_fields(),
+            self.config,
+            pool.workers,
+            pool.workers - pool.idle,
+            pool.overflow,
+            pool.overflowing,
+            pool.queued,
+            pool.queue_capacity,
+            self.subnets_json(),
+            self.sessions.to_json()
+        )
+    }
+
+    /// The subnet quotas with the number of sessions counted against each
+    fn subnets_json(&self) -> String {
+        let sessions = self.guardrails.subnet_sessions();
+        self.guardrails
+            .subnet_quotas
+            .iter()
+            .zip(sessions)
+            .map(|(quota, sessions)| {
+                format!(
+                    "{{\"network\":{},\"limit\":{},\"sessions\":{}}}",
+                    json::quote(&quota.network.to_string()),
+                    quota.limit,
+                    sessions
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// The sessions currently being served
+#[derive(Default)]
+pub struct ActiveSessions {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, ActiveSession>>,
+}
+
+struct ActiveSession {
+    client: String,
+    port: u16,
+    started: SystemTime,
+    since: Instant,
+}
+
+/// The listing of a session, removed when dropped
+pub struct Listed {
+    sessions: Arc<ActiveSessions>,
+    id: u64,
+}
+
+impl ActiveSessions {
+    /// List the session of `client` with its server on `port` until the returned listing is dropped
+    pub fn list(self: &Arc<Self>, client: &str, port: u16) -> Listed {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.lock().insert(
+            id,
+            ActiveSession {
+                client: client.to_string(),
+                port,
+                started: SystemTime::now(),
+                since: Instant::now(),
+            },
+        );
+        Listed {
+            sessions: self.clone(),
+            id,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let sessions: Vec<String> = self
+            .lock()
+            .iter()
+            .map(|(id, session)| {
+                format!(
+                    "{{\"id\":{},\"client\":{},\"port\":{},\"start\":\"{}\",\"duration_secs\":{:.3}}}",
+                    id,
+                    json::quote(&session.client),
+                    session.port,
+                    format_rfc3339(session.started),
+                    session.since.elapsed().as_secs_f64()
+                )
+            })
+            .collect();
+        format!("[{}]", sessions.join(","))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, ActiveSession>> {
+        // a poisoned lock only means another thread panicked, the sessions are still valid
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Listed {
+    /// The id `/status` lists the session with
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Listed {
+    fn drop(&mut self) {
+        self.sessions.lock().remove(&self.id);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::process;
+use log::{debug, info, warn};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+
+/// The prefix of the directories created for language servers, followed by the pid of the proxy
+const PREFIX: &str = "lsp-on-demand-server-";
+
+/// A temporary directory for a single language server, removed when dropped
+pub struct ServerTempDir {
+    path: PathBuf,
+}
+
+impl ServerTempDir {
+    /// Create a new directory below `base`
+    pub fn create(base: &Path) -> std::io::Result<Self> {
+        let path = base.join(format!(
+            "{}{}-{:016x}",
+            PREFIX,
+            std::process::id(),
+            rand::thread_rng().gen::<u64>()
+        ));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ServerTempDir {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            warn!(
+                "Failed to remove temporary directory {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Remove the directories below `base` left behind by instances of the proxy that are no longer running
+pub fn sweep(base: &Path) {
+    let entries = match std::fs::read_dir(base) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Not sweeping {}: {}", base.display(), err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let pid = match pid {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == std::process::id() || process::is_running(pid) {
+            continue;
+        }
+
+        let path = entry.path();
+        info!("Removing leftover temporary directory {}", path.display());
+        if let Err(err) = std::fs::remove_dir_all(&path) {
+            warn!(
+                "Failed to remove leftover temporary directory {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// The values of the placeholders in the configured launch of a language server,
+/// i.e. in its JVM and server arguments, environment, working directory and sandbox
+///
+/// `{port}` is replaced by the port the server is to listen on, `{session_id}` by the id of the session,
+/// `{workspace}` by the directory the workspace is synchronized into, or else the working directory of the proxy,
+/// `{tmpdir}` by the temporary directory of the server and `{client_ip}` by the address of the client.
+/// `{host}` is the address of the proxy, for servers connecting to it, and `{temp-dir}` the same as `{tmpdir}`.
+/// Anything else in braces is kept as is.
+#[derive(Debug)]
+pub struct LaunchContext {
+    pub port: u16,
+    pub session_id: u64,
+    pub workspace: PathBuf,
+    pub tmpdir: PathBuf,
+    /// Unknown e.g. for clients whose connection failed right away, replaced by nothing then
+    pub client_ip: Option<IpAddr>,
+}
+
+impl LaunchContext {
+    /// `template` with its placeholders replaced, in a single pass so that values are never replaced in turn
+    pub fn render(&self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest
+                .find('}')
+                .and_then(|end| Some((end, self.value(&rest[1..end])?)));
+            match placeholder {
+                Some((end, value)) => {
+                    rendered.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "port" => self.port.to_string(),
+            "session_id" => self.session_id.to_string(),
+            "workspace" => self.workspace.to_string_lossy().into_owned(),
+            "tmpdir" | "temp-dir" => self.tmpdir.to_string_lossy().into_owned(),
+            "client_ip" => self
+                .client_ip
+                .map_or_else(String::new, |client_ip| client_ip.to_string()),
+            "host" => Ipv4Addr::LOCALHOST.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
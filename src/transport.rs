@@ -0,0 +1,309 @@
+use crate::arguments::SpawnDirection;
+use crate::error::ServerError;
+use crate::socket::{self, ReserveFd};
+use log::{debug, error, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often transports waiting for clients check whether to keep going
+pub const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to pause accepting after an error, doubled for every further error in a row
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const RENDEZVOUS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the clients of the proxy come from, each of them served a session of its own
+///
+/// Clients not reaching the proxy over TCP are bridged to one end of a loopback connection, like [`Stdio`] does,
+/// so that the sessions, the relay and the pool only ever deal with a `TcpStream`.
+pub trait ClientTransport: Send {
+    /// Wait for the next client until `keep_going` returns false,
+    /// or nothing if the transport has no more clients
+    fn next_client(&mut self, keep_going: &dyn Fn() -> bool) -> Option<TcpStream>;
+}
+
+/// Clients connecting to a listener, which is polled so that `keep_going` is checked between connections
+pub struct Listener {
+    listener: TcpListener,
+    /// Released to reject connections while out of file descriptors
+    reserve: ReserveFd,
+    backoff: Duration,
+}
+
+impl Listener {
+    /// Accept the clients connecting to the non-blocking `listener`
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            reserve: ReserveFd::new(),
+            backoff: ACCEPT_BACKOFF_MIN,
+        }
+    }
+}
+
+impl ClientTransport for Listener {
+    fn next_client(&mut self, keep_going: &dyn Fn() -> bool) -> Option<TcpStream> {
+        while keep_going() {
+            match self.listener.accept() {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL)
+                }
+                Err(err) => {
+                    if socket::out_of_fds(&err) {
+                        // the pending connection can't be served, but would keep the listener readable
+                        error!("Rejecting a connection, out of file descriptors: {}", err);
+                        self.reserve.reject_pending(&self.listener);
+                    } else {
+                        error!("Failed to accept a connection: {}", err);
+                    }
+                    std::thread::sleep(self.backoff);
+                    self.backoff = (self.backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+                Ok((con, _)) => {
+                    self.backoff = ACCEPT_BACKOFF_MIN;
+                    // some platforms let the accepted stream inherit the non-blocking mode
+                    if let Err(err) = con.set_nonblocking(false) {
+                        error!("Failed to configure client connection: {}", err);
+                        continue;
+                    }
+                    return Some(con);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Clients forwarded by a rendezvous host, over an idle connection the proxy keeps open to it
+pub struct Rendezvous {
+    address: String,
+}
+
+impl Rendezvous {
+    pub fn new(address: String) -> Self {
+        Self { address }
+    }
+}
+
+impl ClientTransport for Rendezvous {
+    fn next_client(&mut self, keep_going: &dyn Fn() -> bool) -> Option<TcpStream> {
+        let rendezvous = &self.address;
+        while keep_going() {
+            let con = match TcpStream::connect(rendezvous) {
+                Ok(con) => con,
+                Err(err) => {
+                    warn!(
+                        "Failed to connect to rendezvous host {}: {}",
+                        rendezvous, err
+                    );
+                    let retry_at = Instant::now() + RENDEZVOUS_RETRY_INTERVAL;
+                    while keep_going() && Instant::now() < retry_at {
+                        std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                    continue;
+                }
+            };
+            debug!(
+                "Connected to rendezvous host {}, waiting for a client",
+                rendezvous
+            );
+
+            // the connection is idle until the relay forwards the first bytes of a client,
+            // poll for them so that a requested shutdown is noticed in the meantime
+            if let Err(err) = con.set_read_timeout(Some(ACCEPT_POLL_INTERVAL)) {
+                error!("Failed to configure rendezvous connection: {}", err);
+                continue;
+            }
+            let mut first_byte = [0];
+            let client_arrived = loop {
+                if !keep_going() {
+                    break false;
+                }
+                match con.peek(&mut first_byte) {
+                    Ok(0) => {
+                        debug!("Rendezvous host {} closed the idle connection", rendezvous);
+                        break false;
+                    }
+                    Ok(_) => break true,
+                    Err(err)
+                        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(err) => {
+                        warn!(
+                            "Idle connection to rendezvous host {} failed: {}",
+                            rendezvous, err
+                        );
+                        break false;
+                    }
+                }
+            };
+            if !client_arrived {
+                continue;
+            }
+
+            if let Err(err) = con.set_read_timeout(None) {
+                error!("Failed to configure rendezvous connection: {}", err);
+                continue;
+            }
+            return Some(con);
+        }
+        None
+    }
+}
+
+/// The single client of an editor that only launches language servers speaking LSP via stdio
+pub struct Stdio {
+    session: Option<TcpStream>,
+}
+
+impl Stdio {
+    /// Bridge stdin and stdout to the end of a loopback connection served as the session,
+    /// returning the thread copying the output of the session to stdout
+    pub fn start() -> std::io::Result<(Self, JoinHandle<()>)> {
+        let (editor, session) = socket::loopback_pair()?;
+        let mut to_session = editor.try_clone()?;
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            let _ = std::io::copy(&mut stdin.lock(), &mut to_session);
+            // the editor closing stdin ends the session like a client closing its connection
+            let _ = to_session.shutdown(Shutdown::Write);
+        });
+        let output = std::thread::spawn(move || {
+            let mut editor = editor;
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            let mut buf = [0; 8 * 1024];
+            loop {
+                match editor.read(&mut buf) {
+                    Ok(0) => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                    Ok(bytes) => {
+                        // stdout is line buffered, but messages don't end with a line break
+                        let written = stdout
+                            .write_all(&buf[..bytes])
+                            .and_then(|()| stdout.flush());
+                        if written.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok((
+            Self {
+                session: Some(session),
+            },
+            output,
+        ))
+    }
+}
+
+impl ClientTransport for Stdio {
+    fn next_client(&mut self, _keep_going: &dyn Fn() -> bool) -> Option<TcpStream> {
+        self.session.take()
+    }
+}
+
+/// How the proxy reaches the language servers it spawns
+pub trait ServerTransport: Send + Sync {
+    /// Get ready to reach a server about to be spawned on `port`, failing if the port is taken
+    fn prepare(&self, port: u16) -> Result<Box<dyn PendingServer>, ServerError>;
+}
+
+/// A language server being spawned, until it can be reached
+pub trait PendingServer {
+    /// The connection to the server, if it is ready yet
+    fn connect(&self) -> Option<TcpStream>;
+}
+
+/// The transport to the servers for the `direction` they are spawned with
+pub fn for_direction(direction: SpawnDirection) -> Arc<dyn ServerTransport> {
+    match direction {
+        SpawnDirection::Listen => Arc::new(ServerListens),
+        SpawnDirection::Connect => Arc::new(ServerConnects),
+    }
+}
+
+/// The server listens on its port and the proxy connects to it
+struct ServerListens;
+
+impl ServerTransport for ServerListens {
+    fn prepare(&self, port: u16) -> Result<Box<dyn PendingServer>, ServerError> {
+        check_port_available(port)?;
+        Ok(Box::new(ListeningServer { port }))
+    }
+}
+
+struct ListeningServer {
+    port: u16,
+}
+
+impl PendingServer for ListeningServer {
+    /// Connect to the server on its port, which is ready once it accepts the connection
+    fn connect(&self) -> Option<TcpStream> {
+        let lsp_addrs = [
+            SocketAddr::from((Ipv6Addr::LOCALHOST, self.port)),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, self.port)),
+        ];
+        TcpStream::connect(lsp_addrs.as_slice()).ok()
+    }
+}
+
+/// The proxy listens on the port and the server connects to it
+struct ServerConnects;
+
+impl ServerTransport for ServerConnects {
+    fn prepare(&self, port: u16) -> Result<Box<dyn PendingServer>, ServerError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(|err| {
+            if err.kind() == ErrorKind::AddrInUse {
+                ServerError::PortConflict(port)
+            } else {
+                ServerError::SpawnFailed(err)
+            }
+        })?;
+        // polled while waiting for the server to start up
+        listener
+            .set_nonblocking(true)
+            .map_err(ServerError::SpawnFailed)?;
+        Ok(Box::new(ConnectingServer { listener }))
+    }
+}
+
+struct ConnectingServer {
+    listener: TcpListener,
+}
+
+impl PendingServer for ConnectingServer {
+    /// Accept the connection of the server, if it connected already
+    fn connect(&self) -> Option<TcpStream> {
+        let (server_con, _) = self.listener.accept().ok()?;
+        server_con.set_nonblocking(false).ok()?;
+        Some(server_con)
+    }
+}
+
+/// Fail early if something is listening on `port` already,
+/// as a server that can't bind its port only fails after starting up
+pub fn check_port_available(port: u16) -> Result<(), ServerError> {
+    match server_listening(port) {
+        Some(()) => Err(ServerError::PortConflict(port)),
+        None => Ok(()),
+    }
+}
+
+/// Whether something is listening on `port`, without connecting to it
+///
+/// For servers that only ever accept a single connection, which must not be taken by a probe
+pub fn server_listening(port: u16) -> Option<()> {
+    let wildcard_addrs = [
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+    ];
+    match TcpListener::bind(wildcard_addrs.as_slice()) {
+        Err(err) if err.kind() == ErrorKind::AddrInUse => Some(()),
+        _ => None,
+    }
+}
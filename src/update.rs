@@ -0,0 +1,137 @@
+use crate::arguments::version_numbers;
+use crate::checksum;
+use crate::http::{self, HttpEndpoint};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Replace the binary with the latest release published below a URL, e.g. to keep lab machines up to date
+#[derive(Debug, StructOpt)]
+pub struct UpdateArguments {
+    /// Where the releases are published, e.g. http://mirror.example.org/lsp_on_demand/, see the README
+    ///
+    /// Only plain http:// is supported, there is no TLS and hence no default to download from GitHub
+    #[structopt(long = "url", env = "LSP_UPDATE_URL")]
+    url: HttpEndpoint,
+
+    /// The SHA-256 the new binary must have, taken from a trusted source like the release notes,
+    /// as anyone on the path to the unencrypted URL could replace a checksum published next to the binary
+    #[structopt(long = "sha256", required_unless = "check")]
+    sha256: Option<String>,
+
+    /// Only report whether an update is available
+    #[structopt(long = "check")]
+    check: bool,
+}
+
+/// Check for a newer release and install it unless only checking
+pub fn run(args: &UpdateArguments) -> Result<(), String> {
+    let expected = args
+        .sha256
+        .as_deref()
+        .map(|sha256| sha256.trim().to_ascii_lowercase());
+    if let Some(expected) = &expected {
+        if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("{:?} is not a SHA-256 in hex", expected));
+        }
+    }
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = fetch_text(&args.url.join("latest"))?;
+    let latest = latest.trim();
+    // the version becomes part of the URL of the release
+    let plausible = latest
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || ".-+_".contains(c));
+    if !plausible || version_numbers(latest).is_empty() {
+        return Err(format!(
+            "The latest release {:?} published at {} is not a version",
+            latest, args.url
+        ));
+    }
+    if version_numbers(latest) <= version_numbers(current) {
+        println!(
+            "{} {} is up to date, the latest release is {}",
+            env!("CARGO_PKG_NAME"),
+            current,
+            latest
+        );
+        return Ok(());
+    }
+    if args.check {
+        println!(
+            "{} {} can be updated to {}",
+            env!("CARGO_PKG_NAME"),
+            current,
+            latest
+        );
+        return Ok(());
+    }
+    let expected = expected.ok_or_else(|| {
+        format!(
+            "Pass the SHA-256 of {} from the release notes with --sha256 to install it",
+            latest
+        )
+    })?;
+
+    let asset = format!(
+        "{}-{}-{}{}",
+        env!("CARGO_PKG_NAME"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::env::consts::EXE_SUFFIX
+    );
+    let release = args.url.join(latest).join(&asset);
+    println!("Downloading {}", release);
+    let binary = http::get(&release).map_err(|err| format!("Failed to download: {}", err))?;
+    let actual = checksum::sha256_hex(&binary);
+    if actual != expected {
+        return Err(format!(
+            "The SHA-256 of {} is {}, but {} was expected, the binary was not replaced",
+            release, actual, expected
+        ));
+    }
+
+    let exe = std::env::current_exe()
+        .map_err(|err| format!("Failed to locate the running binary: {}", err))?;
+    replace(&exe, &binary)
+        .map_err(|err| format!("Failed to replace {}: {}", exe.display(), err))?;
+    println!(
+        "Updated {} from {} to {}, running instances keep running the old version until restarted, \
+         or on unix until they are sent SIGUSR2 to hand over to the new one",
+        exe.display(),
+        current,
+        latest
+    );
+    Ok(())
+}
+
+fn fetch_text(endpoint: &HttpEndpoint) -> Result<String, String> {
+    let body = http::get(endpoint).map_err(|err| format!("Failed to fetch: {}", err))?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Replace `exe` with the `binary`, which is written next to it first,
+/// so that the binary is never left half written
+fn replace(exe: &Path, binary: &[u8]) -> std::io::Result<()> {
+    let staged = with_suffix(exe, ".new");
+    let written = File::create(&staged).and_then(|mut file| {
+        file.write_all(binary)?;
+        file.sync_all()?;
+        std::fs::set_permissions(&staged, std::fs::metadata(exe)?.permissions())
+    });
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&staged);
+        return Err(err);
+    }
+    // a running binary can't be replaced on windows, but it can be renamed
+    #[cfg(windows)]
+    std::fs::rename(exe, with_suffix(exe, ".old"))?;
+    std::fs::rename(&staged, exe)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
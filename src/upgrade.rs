@@ -0,0 +1,85 @@
+use crate::socket;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long to wait for a new client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest request head accepted, reverse proxies add a few headers but not many
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Complete the HTTP/1.1 handshake of a client connecting via a reverse proxy, if it sent one,
+/// after which the connection carries the LSP traffic like any other
+///
+/// Both a `CONNECT` request and a request with an `Upgrade` header, e.g. `GET /lsp` with `Upgrade: lsp`,
+/// are accepted, anything else is answered with an error.
+/// The response identifies the proxy and its language server by the `server` header, e.g. to spot mismatched deployments.
+/// Returns whether there was a handshake.
+pub fn accept(con: &TcpStream, server: &str) -> std::io::Result<bool> {
+    con.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let head = read_head(con);
+    con.set_read_timeout(None)?;
+    let head = match head? {
+        Some(head) => head,
+        None => return Ok(false),
+    };
+
+    let mut con = con;
+    let mut lines = head.lines();
+    let method = lines
+        .next()
+        .and_then(|request_line| request_line.split_whitespace().next())
+        .unwrap_or_default();
+    let upgrade = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("upgrade")
+            .then(|| value.trim().to_string())
+    });
+    match (method, upgrade) {
+        ("CONNECT", _) => write!(
+            con,
+            "HTTP/1.1 200 Connection Established\r\nServer: {}\r\n\r\n",
+            server
+        )?,
+        (_, Some(protocol)) => write!(
+            con,
+            "HTTP/1.1 101 Switching Protocols\r\nServer: {}\r\nConnection: Upgrade\r\nUpgrade: {}\r\n\r\n",
+            server, protocol
+        )?,
+        (_, None) => {
+            write!(
+                con,
+                "HTTP/1.1 426 Upgrade Required\r\nServer: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                server
+            )?;
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the request neither is a CONNECT request nor asks for an upgrade",
+            ));
+        }
+    }
+    Ok(true)
+}
+
+/// Read the head of the HTTP request the client starts with, if it does
+fn read_head(con: &TcpStream) -> std::io::Result<Option<String>> {
+    if !socket::peek_prefix(con, b"CONNECT ")? && !socket::peek_prefix(con, b"GET ")? {
+        return Ok(None);
+    }
+    let mut head = Vec::new();
+    let mut byte = [0];
+    let mut con = con;
+    // byte by byte, so that none of the LSP traffic following the head is consumed
+    while head.len() < MAX_HEAD_LEN {
+        con.read_exact(&mut byte)?;
+        head.push(byte[0]);
+        if head.ends_with(b"\r\n\r\n") {
+            return Ok(Some(String::from_utf8_lossy(&head).into_owned()));
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        "the request head is too long",
+    ))
+}
@@ -0,0 +1,185 @@
+use crate::filter::MessageFilter;
+use crate::json::{self, Value};
+use crate::lsp::{Message, SERVER_CANCELLED};
+use crate::relay::Direction;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often to look for overdue requests at most
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks the requests of the client until the server answered them,
+/// reporting those it takes longer than the deadline for and optionally cancelling them
+#[derive(Debug)]
+pub struct RequestWatchdog {
+    deadline: Duration,
+    /// Whether to cancel overdue requests, instead of only reporting them
+    cancel: bool,
+    requests: Mutex<Requests>,
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    /// The requests relayed to the server, by their id
+    pending: HashMap<String, Pending>,
+    /// The ids of the requests cancelled by the proxy, whose late responses are dropped
+    cancelled: HashSet<String>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    id: Value,
+    method: String,
+    since: Instant,
+    reported: bool,
+}
+
+impl RequestWatchdog {
+    pub fn new(deadline: Duration, cancel: bool) -> Self {
+        Self {
+            deadline,
+            cancel,
+            requests: Mutex::default(),
+        }
+    }
+
+    /// Look for overdue requests until `done` is set, cancelling them on the `server` and failing them
+    /// on the `client` if enabled, both written to while holding their lock like the relay does
+    pub fn watch(
+        &self,
+        client: &str,
+        client_write: &Mutex<TcpStream>,
+        server_write: &Mutex<TcpStream>,
+        done: &AtomicBool,
+    ) {
+        while !done.load(Ordering::SeqCst) {
+            std::thread::sleep(self.deadline.min(CHECK_INTERVAL));
+            for (id, method) in self.overdue() {
+                warn!(
+                    "[{}] The server did not answer {} request {} within {}s",
+                    client,
+                    method,
+                    id.to_json(),
+                    self.deadline.as_secs()
+                );
+                if !self.cancel {
+                    continue;
+                }
+                let cancel = Message {
+                    headers: Vec::new(),
+                    content: format!(
+                        "{{\"jsonrpc\":\"2.0\",\"method\":\"$/cancelRequest\",\"params\":{{\"id\":{}}}}}",
+                        id.to_json()
+                    )
+                    .into_bytes(),
+                };
+                let error = Message::error_response(
+                    &id,
+                    SERVER_CANCELLED,
+                    &format!(
+                        "the language server did not answer within {}s",
+                        self.deadline.as_secs()
+                    ),
+                );
+                let cancelled =
+                    write(server_write, &cancel).and_then(|()| write(client_write, &error));
+                if let Err(err) = cancelled {
+                    debug!(
+                        "[{}] Failed to cancel request {}: {}",
+                        client,
+                        id.to_json(),
+                        err
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The id and method of the requests that just became overdue, forgotten if they are cancelled
+    fn overdue(&self) -> Vec<(Value, String)> {
+        let mut requests = self.lock();
+        let overdue: Vec<String> = requests
+            .pending
+            .iter()
+            .filter(|(_, pending)| !pending.reported && pending.since.elapsed() >= self.deadline)
+            .map(|(key, _)| key.clone())
+            .collect();
+        overdue
+            .into_iter()
+            .filter_map(|key| {
+                if self.cancel {
+                    let pending = requests.pending.remove(&key)?;
+                    requests.cancelled.insert(key);
+                    Some((pending.id, pending.method))
+                } else {
+                    let pending = requests.pending.get_mut(&key)?;
+                    pending.reported = true;
+                    Some((pending.id.clone(), pending.method.clone()))
+                }
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Requests> {
+        // a poisoned lock only means another thread panicked, the requests are still valid
+        self.requests
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MessageFilter for RequestWatchdog {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        let content = String::from_utf8_lossy(&message.content);
+        let id = match json::top_level_value(&content, "id") {
+            Some(id) => id,
+            None => return Some(message),
+        };
+        let method = json::top_level_string_field(&content, "method");
+        let mut requests = self.lock();
+        match (direction, method) {
+            (Direction::ClientToServer, Some(method)) => {
+                requests.pending.insert(
+                    id.to_json(),
+                    Pending {
+                        id,
+                        method,
+                        since: Instant::now(),
+                        reported: false,
+                    },
+                );
+            }
+            (Direction::ServerToClient, None) => {
+                let key = id.to_json();
+                if requests.cancelled.remove(&key) {
+                    debug!("Dropping the late response to cancelled request {}", key);
+                    return None;
+                }
+                if let Some(pending) = requests.pending.remove(&key) {
+                    if pending.reported {
+                        info!(
+                            "The server answered {} request {} after {:.1}s",
+                            pending.method,
+                            key,
+                            pending.since.elapsed().as_secs_f64()
+                        );
+                    }
+                }
+            }
+            // requests of the server to the client and their responses are not watched
+            _ => {}
+        }
+        Some(message)
+    }
+}
+
+fn write(tx: &Mutex<TcpStream>, message: &Message) -> std::io::Result<()> {
+    // a poisoned lock only means another writer panicked, the stream itself is still usable
+    let mut tx = tx.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    message.write_to(&mut *tx)
+}
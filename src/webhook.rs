@@ -0,0 +1,193 @@
+use crate::alert::Alerts;
+use crate::error::UnknownEventError;
+use crate::http::{self, HttpEndpoint};
+use crate::json;
+use crate::mdns;
+use crate::session_log::format_rfc3339;
+use log::{debug, info, warn};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The events webhooks are notified of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// A language server exited unsuccessfully during its session
+    ServerCrash,
+    /// A language server could not be started for a session
+    SpawnFailed,
+    /// A connection was rejected, as all session workers were busy and the queue was full
+    PoolExhausted,
+    /// The proxy is exiting
+    ProxyShutdown,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [
+        Event::ServerCrash,
+        Event::SpawnFailed,
+        Event::PoolExhausted,
+        Event::ProxyShutdown,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ServerCrash => "server-crash",
+            Self::SpawnFailed => "spawn-failed",
+            Self::PoolExhausted => "pool-exhausted",
+            Self::ProxyShutdown => "proxy-shutdown",
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = UnknownEventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|event| event.as_str() == s.trim())
+            .ok_or_else(|| UnknownEventError {
+                event: s.to_string(),
+            })
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Posts a JSON document to a webhook for every event of interest, from a background thread,
+/// so that a slow receiver does not hold up sessions
+///
+/// Besides the `event`, the `client` it concerns, if any, and its `details` the document contains a `text`
+/// summarizing them, which is what Slack and Teams compatible receivers display.
+/// Repetitions of an event with the same details within the alert interval are posted as a single summary
+/// with their `count` at the end of the interval.
+#[derive(Clone)]
+pub struct Webhooks {
+    poster: Poster,
+    events: Arc<[Event]>,
+    alerts: Arc<Alerts<(Event, String)>>,
+}
+
+/// Builds and posts the documents
+#[derive(Clone)]
+struct Poster {
+    sender: Sender<String>,
+    endpoint: Arc<HttpEndpoint>,
+    host: Arc<str>,
+    interval: Duration,
+}
+
+impl Webhooks {
+    /// Notify `endpoint` of the `events`, coalescing the repetitions within `interval`
+    pub fn start(endpoint: HttpEndpoint, events: Vec<Event>, interval: Duration) -> Self {
+        info!(
+            "Notifying {} of {}",
+            endpoint,
+            events
+                .iter()
+                .map(|event| event.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let (sender, receiver) = channel::<String>();
+        let endpoint = Arc::new(endpoint);
+        let target = endpoint.clone();
+        std::thread::spawn(move || {
+            for body in receiver {
+                if let Err(err) = http::post(&target, &body) {
+                    warn!("Failed to notify webhook {}: {}", target, err);
+                }
+            }
+        });
+        let poster = Poster {
+            sender,
+            endpoint,
+            host: mdns::hostname().into(),
+            interval,
+        };
+        let summarizer = poster.clone();
+        let alerts = Alerts::start(
+            interval,
+            move |(event, details): &(Event, String), count| {
+                summarizer.send(summarizer.body(*event, None, details, Some(count)));
+            },
+        );
+        Self {
+            poster,
+            events: events.into(),
+            alerts,
+        }
+    }
+
+    /// Notify the webhook of `event` concerning `client` in the background, if it is interested in it,
+    /// unless the same event was posted within the alert interval, which is then counted for the summary
+    pub fn notify(&self, event: Event, client: Option<&str>, details: &str) {
+        if self.events.contains(&event) && self.alerts.report(&(event, details.to_string())) {
+            self.poster
+                .send(self.poster.body(event, client, details, None));
+        }
+    }
+
+    /// Notify the webhook of `event` and wait for it to be delivered, e.g. before exiting
+    pub fn notify_now(&self, event: Event, details: &str) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        let endpoint = &self.poster.endpoint;
+        match http::post(endpoint, &self.poster.body(event, None, details, None)) {
+            Ok(()) => debug!("Notified webhook {} of {}", endpoint, event),
+            Err(err) => warn!("Failed to notify webhook {}: {}", endpoint, err),
+        }
+    }
+}
+
+impl Poster {
+    fn send(&self, body: String) {
+        // the receiver only goes away if the notifying thread panicked
+        let _ = self.sender.send(body);
+    }
+
+    /// The document for a single `event`, or the summary of its `repeated` repetitions
+    fn body(
+        &self,
+        event: Event,
+        client: Option<&str>,
+        details: &str,
+        repeated: Option<u64>,
+    ) -> String {
+        let text = match (repeated, client) {
+            (Some(repeated), _) => format!(
+                "lsp_on_demand on {}: {} {} more times in the last {}s: {}",
+                self.host,
+                event,
+                repeated,
+                self.interval.as_secs(),
+                details
+            ),
+            (None, Some(client)) => format!(
+                "lsp_on_demand on {}: {} for {}: {}",
+                self.host, event, client, details
+            ),
+            (None, None) => format!("lsp_on_demand on {}: {}: {}", self.host, event, details),
+        };
+        format!(
+            "{{\"event\":{},\"host\":{},\"pid\":{},\"time\":\"{}\",\"client\":{},\"count\":{},\"details\":{},\"text\":{}}}",
+            json::quote(event.as_str()),
+            json::quote(&self.host),
+            std::process::id(),
+            format_rfc3339(SystemTime::now()),
+            json::quote_optional(client),
+            repeated.unwrap_or(1),
+            json::quote(details),
+            json::quote(&text)
+        )
+    }
+}
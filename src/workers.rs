@@ -0,0 +1,252 @@
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Identifies a queued job, to look up its position in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket(u64);
+
+/// Which jobs are run first when more are queued than workers are idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+/// A fixed number of threads working through a bounded queue of jobs,
+/// so that a flood of connections can't create an unbounded number of threads
+///
+/// During bursts up to a bounded number of overflow threads are started on demand,
+/// each running a single job and exiting right after instead of waiting for the next one.
+#[derive(Clone)]
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    queued: Condvar,
+    workers: usize,
+    overflow: usize,
+    capacity: usize,
+}
+
+/// A snapshot of how busy the pool is
+pub struct PoolState {
+    pub workers: usize,
+    pub idle: usize,
+    pub overflow: usize,
+    /// The overflow threads currently running, or starting to
+    pub overflowing: usize,
+    pub queued: usize,
+    pub queue_capacity: usize,
+}
+
+/// The queued jobs, high priority jobs are run before any normal priority one
+#[derive(Default)]
+struct Queue {
+    high: VecDeque<(Ticket, Job)>,
+    normal: VecDeque<(Ticket, Job)>,
+    /// The workers waiting for a job, which take queued jobs right away
+    idle: usize,
+    overflowing: usize,
+    next_ticket: u64,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, overflow: usize, queue_capacity: usize) -> std::io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            queued: Condvar::new(),
+            workers,
+            overflow,
+            capacity: queue_capacity,
+        });
+        for index in 0..workers {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{}", index))
+                .spawn(move || work(&shared))?;
+        }
+        Ok(Self { shared })
+    }
+
+    /// Queue `job` to be run by the next idle worker, before all queued jobs of lower `priority`
+    ///
+    /// If no worker is idle to take it, an overflow thread is started to take the next queued job, if one may be.
+    /// Returns nothing, dropping the job, if the queue is full
+    pub fn try_execute<F>(&self, priority: Priority, job: F) -> Option<Ticket>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut queue = self.shared.lock();
+        let spare_overflow = self.shared.overflow - queue.overflowing;
+        let queued = queue.high.len() + queue.normal.len();
+        if queued >= self.shared.capacity + queue.idle + spare_overflow {
+            return None;
+        }
+        let ticket = Ticket(queue.next_ticket);
+        queue.next_ticket += 1;
+        match priority {
+            Priority::High => queue.high.push_back((ticket, Box::new(job))),
+            Priority::Normal => queue.normal.push_back((ticket, Box::new(job))),
+        }
+        let waits = queued >= queue.idle;
+        if waits && spare_overflow > 0 {
+            queue.overflowing += 1;
+            let index = queue.overflowing;
+            drop(queue);
+            let shared = self.shared.clone();
+            let started = std::thread::Builder::new()
+                .name(format!("overflow-{}", index))
+                .spawn(move || overflow(&shared));
+            if let Err(err) = started {
+                warn!(
+                    "Failed to start an overflow worker, the session stays queued: {}",
+                    err
+                );
+                self.shared.lock().overflowing -= 1;
+            }
+        } else {
+            self.shared.queued.notify_one();
+        }
+        Some(ticket)
+    }
+
+    /// The number of queued jobs run before the job with `ticket`, nothing once it was taken by a worker
+    pub fn position(&self, ticket: Ticket) -> Option<usize> {
+        let queue = self.shared.lock();
+        let index_in =
+            |jobs: &VecDeque<(Ticket, Job)>| jobs.iter().position(|(queued, _)| *queued == ticket);
+        index_in(&queue.high)
+            .or_else(|| index_in(&queue.normal).map(|index| queue.high.len() + index))
+    }
+
+    pub fn state(&self) -> PoolState {
+        let queue = self.shared.lock();
+        PoolState {
+            workers: self.shared.workers,
+            idle: queue.idle,
+            overflow: self.shared.overflow,
+            overflowing: queue.overflowing,
+            queued: queue.high.len() + queue.normal.len(),
+            queue_capacity: self.shared.capacity,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // a poisoned lock only means another thread panicked, the queue is still valid
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Run the next queued job, unless a worker took it in the meantime, and exit
+fn overflow(shared: &Shared) {
+    let job = {
+        let mut queue = shared.lock();
+        queue.high.pop_front().or_else(|| queue.normal.pop_front())
+    };
+    if let Some((_, job)) = job {
+        info!("All session workers are busy, serving a session on an overflow worker");
+        if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("A job panicked on an overflow worker");
+        }
+    }
+    shared.lock().overflowing -= 1;
+}
+
+fn work(shared: &Shared) {
+    loop {
+        // only hold the lock while waiting for a job, not while running it
+        let job = {
+            let mut queue = shared.lock();
+            queue.idle += 1;
+            loop {
+                if let Some((_, job)) = queue.high.pop_front().or_else(|| queue.normal.pop_front())
+                {
+                    queue.idle -= 1;
+                    break job;
+                }
+                queue = shared
+                    .queued
+                    .wait(queue)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+        };
+        if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("A job panicked, the worker continues with the next one");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A pool whose only worker is busy until the returned sender sends or is dropped
+    fn busy_pool(overflow: usize, queue_capacity: usize) -> (WorkerPool, mpsc::Sender<()>) {
+        let pool = WorkerPool::new(1, overflow, queue_capacity).unwrap();
+        let (started, busy) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        pool.try_execute(Priority::Normal, move || {
+            started.send(()).unwrap();
+            let _ = released.recv();
+        })
+        .unwrap();
+        busy.recv_timeout(TIMEOUT).unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn runs_high_priority_jobs_first() {
+        let (pool, release) = busy_pool(0, 2);
+        let (ran, order) = mpsc::channel();
+        let normal = {
+            let ran = ran.clone();
+            pool.try_execute(Priority::Normal, move || ran.send("normal").unwrap())
+                .unwrap()
+        };
+        let high = pool
+            .try_execute(Priority::High, move || ran.send("high").unwrap())
+            .unwrap();
+        assert_eq!(pool.position(high), Some(0));
+        assert_eq!(pool.position(normal), Some(1));
+
+        release.send(()).unwrap();
+        assert_eq!(order.recv_timeout(TIMEOUT), Ok("high"));
+        assert_eq!(order.recv_timeout(TIMEOUT), Ok("normal"));
+        // taken jobs are no longer queued
+        assert_eq!(pool.position(high), None);
+        assert_eq!(pool.position(normal), None);
+    }
+
+    #[test]
+    fn rejects_jobs_once_the_queue_is_full() {
+        let (pool, _release) = busy_pool(0, 1);
+        assert!(pool.try_execute(Priority::Normal, || {}).is_some());
+        assert_eq!(pool.try_execute(Priority::Normal, || {}), None);
+        // the priority of a job doesn't make room for it
+        assert_eq!(pool.try_execute(Priority::High, || {}), None);
+        assert_eq!(pool.state().queued, 1);
+    }
+
+    #[test]
+    fn overflows_into_extra_threads() {
+        let (pool, _release) = busy_pool(1, 0);
+        let (ran, overflowed) = mpsc::channel();
+        pool.try_execute(Priority::Normal, move || ran.send(()).unwrap())
+            .unwrap();
+        overflowed.recv_timeout(TIMEOUT).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use crate::filter::{MessageFilter, UriMapping};
+use crate::json::Value;
+use crate::lsp::Message;
+use crate::relay::Direction;
+use log::{debug, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Uploads a file of the workspace that is not open in the editor, params `{ uri, text }`
+const SYNC_FILE_METHOD: &str = "$/lspOnDemand/syncFile";
+/// Removes an uploaded file, params `{ uri }`
+const DELETE_FILE_METHOD: &str = "$/lspOnDemand/deleteFile";
+
+/// Mirrors the workspace of a remote client into a directory of its own,
+/// so that language servers reading files from disk see the client's files
+///
+/// Files are written when the client opens or changes a document or uploads a file,
+/// and the URIs below the client's `rootUri` are rewritten to point into the directory.
+/// The directory is removed once the session ends.
+#[derive(Debug)]
+pub struct WorkspaceSync {
+    directory: PathBuf,
+    state: Mutex<SyncState>,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    mapping: Option<UriMapping>,
+    /// The current text of the open documents, by their client URI
+    documents: HashMap<String, String>,
+}
+
+impl WorkspaceSync {
+    /// Create a new session directory below `base`
+    pub fn new(base: &Path) -> std::io::Result<Self> {
+        let directory = base.join(format!(
+            "lsp-on-demand-{:016x}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            state: Mutex::default(),
+        })
+    }
+
+    /// The directory the workspace is mirrored into
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SyncState> {
+        // a poisoned lock only means another thread panicked, the state is still usable
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Mirror the effect of a client message on the workspace, returns whether it is to be forwarded
+    fn sync(&self, state: &mut SyncState, request: &Value) -> bool {
+        let method = request.get("method").and_then(Value::as_str);
+        let params = request.get("params");
+        let param = |path: &[&str]| params.and_then(|params| params.pointer(path));
+        match method {
+            Some("initialize") => {
+                if let Some(root_uri) = param(&["rootUri"]).and_then(Value::as_str) {
+                    let client_root = if root_uri.ends_with('/') {
+                        root_uri.to_string()
+                    } else {
+                        format!("{}/", root_uri)
+                    };
+                    let server_root = format!("file://{}/", encode_path(&self.directory));
+                    debug!("Mirroring {} into {}", client_root, server_root);
+                    state.mapping = Some(UriMapping {
+                        client_root,
+                        server_root,
+                    });
+                }
+            }
+            Some("textDocument/didOpen") => {
+                let uri = param(&["textDocument", "uri"]).and_then(Value::as_str);
+                let text = param(&["textDocument", "text"]).and_then(Value::as_str);
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    state.documents.insert(uri.to_string(), text.to_string());
+                    self.write(state, uri, text);
+                }
+            }
+            Some("textDocument/didChange") => {
+                let uri = param(&["textDocument", "uri"]).and_then(Value::as_str);
+                let changes = param(&["contentChanges"]).and_then(Value::as_array);
+                if let (Some(uri), Some(changes)) = (uri, changes) {
+                    let mut text = state.documents.remove(uri).unwrap_or_default();
+                    for change in changes {
+                        apply_change(&mut text, change);
+                    }
+                    self.write(state, uri, &text);
+                    state.documents.insert(uri.to_string(), text);
+                }
+            }
+            Some("textDocument/didClose") => {
+                if let Some(uri) = param(&["textDocument", "uri"]).and_then(Value::as_str) {
+                    state.documents.remove(uri);
+                }
+            }
+            Some(SYNC_FILE_METHOD) => {
+                let uri = param(&["uri"]).and_then(Value::as_str);
+                let text = param(&["text"]).and_then(Value::as_str);
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.write(state, uri, text);
+                }
+                return false;
+            }
+            Some(DELETE_FILE_METHOD) => {
+                if let Some(path) = param(&["uri"])
+                    .and_then(Value::as_str)
+                    .and_then(|uri| self.path_for(state, uri))
+                {
+                    if let Err(err) = std::fs::remove_file(&path) {
+                        warn!(
+                            "Failed to remove synchronized file {}: {}",
+                            path.display(),
+                            err
+                        );
+                    }
+                }
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// The path a client URI is mirrored to, if it is below the client's root
+    fn path_for(&self, state: &SyncState, uri: &str) -> Option<PathBuf> {
+        let relative = uri.strip_prefix(state.mapping.as_ref()?.client_root.as_str())?;
+        let relative = PathBuf::from(decode_percent(relative)?);
+        // don't let a client write outside of its directory
+        if relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            Some(self.directory.join(relative))
+        } else {
+            None
+        }
+    }
+
+    fn write(&self, state: &SyncState, uri: &str, text: &str) {
+        let path = match self.path_for(state, uri) {
+            Some(path) => path,
+            None => {
+                debug!("Not synchronizing {}, it is outside of the workspace", uri);
+                return;
+            }
+        };
+        let written = match path.parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|()| std::fs::write(&path, text));
+        if let Err(err) = written {
+            warn!(
+                "Failed to write synchronized file {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+impl MessageFilter for WorkspaceSync {
+    fn filter(&self, direction: Direction, message: Message) -> Option<Message> {
+        let mut state = self.lock();
+        if direction == Direction::ClientToServer {
+            if let Some(request) = Value::parse(&String::from_utf8_lossy(&message.content)) {
+                if !self.sync(&mut state, &request) {
+                    return None;
+                }
+            }
+        }
+        match &state.mapping {
+            Some(mapping) => mapping.filter(direction, message),
+            None => Some(message),
+        }
+    }
+}
+
+impl Drop for WorkspaceSync {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.directory) {
+            warn!(
+                "Failed to remove synchronized workspace {}: {}",
+                self.directory.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Apply a `TextDocumentContentChangeEvent` to `text`
+fn apply_change(text: &mut String, change: &Value) {
+    let new_text = change.get("text").and_then(Value::as_str).unwrap_or("");
+    let range = match change.get("range") {
+        Some(range) => range,
+        None => {
+            // without a range the change replaces the whole document
+            *text = new_text.to_string();
+            return;
+        }
+    };
+    let start = range.get("start").and_then(|start| offset(text, start));
+    let end = range.get("end").and_then(|end| offset(text, end));
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => text.replace_range(start..end, new_text),
+        _ => warn!("Ignoring a document change with an invalid range"),
+    }
+}
+
+/// The byte offset of an LSP `Position`, whose character is counted in UTF-16 code units
+///
+/// Positions past the end of a line or the document are clamped, as the specification demands
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position.get("line")?.as_u64()?;
+    let character = position.get("character")?.as_u64()? as usize;
+
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return Some(text.len()),
+        }
+    }
+
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' || c == '\r' {
+            return Some(line_start + index);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+/// Percent-encode the bytes of a path that may not appear in a URI path as is
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode_percent(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,722 @@
+//! End-to-end tests running the proxy against the mock language server of [`mock_server`]
+//!
+//! The proxy spawns this test binary as its "java", which then acts as the language server,
+//! hence the tests bring their own harness instead of the default one.
+//! Arguments not starting with `-` select the tests whose name contains them.
+
+mod mock_server;
+
+use mock_server::{number_field, read_message, string_field, write_message};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long to wait for anything the proxy does, before failing the test
+const TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Test = (&'static str, fn());
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(port) = mock_server::requested_port(&args) {
+        mock_server::run(port);
+    }
+
+    let filters: Vec<&String> = args
+        .iter()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let tests: Vec<Test> = TESTS
+        .iter()
+        .filter(|(name, _)| {
+            filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str()))
+        })
+        .copied()
+        .collect();
+
+    println!("\nrunning {} tests", tests.len());
+    // each test runs its own proxy, so they can run side by side
+    let running: Vec<_> = tests
+        .into_iter()
+        .map(|(name, test)| (name, std::thread::spawn(test)))
+        .collect();
+    let mut failed = Vec::new();
+    for (name, handle) in running {
+        if handle.join().is_ok() {
+            println!("test {} ... ok", name);
+        } else {
+            println!("test {} ... FAILED", name);
+            failed.push(name);
+        }
+    }
+    if !failed.is_empty() {
+        println!("\nfailures:\n    {}", failed.join("\n    "));
+        std::process::exit(101);
+    }
+}
+
+const TESTS: &[Test] = &[
+    ("relays_a_session", relays_a_session),
+    ("reports_crashed_servers", reports_crashed_servers),
+    (
+        "single_client_exits_after_its_session",
+        single_client_exits_after_its_session,
+    ),
+    ("limits_sessions_per_client", limits_sessions_per_client),
+    ("runs_native_servers", runs_native_servers),
+    ("leases_ports_in_order", leases_ports_in_order),
+    (
+        "patches_the_initialize_result",
+        patches_the_initialize_result,
+    ),
+    ("gates_outdated_clients", gates_outdated_clients),
+    ("relays_content_unchanged", relays_content_unchanged),
+    (
+        "observers_get_uncompressed_messages",
+        observers_get_uncompressed_messages,
+    ),
+    #[cfg(unix)]
+    (
+        "shuts_down_servers_in_place_of_clients",
+        shuts_down_servers_in_place_of_clients,
+    ),
+    #[cfg(unix)]
+    (
+        "shutdown_kills_servers_of_active_sessions",
+        shutdown_kills_servers_of_active_sessions,
+    ),
+];
+
+fn relays_a_session() {
+    let mut proxy = Proxy::start("relay", &[]);
+    let mut client = proxy.connect();
+    client.initialize();
+    let response = client.request("textDocument/hover");
+    assert_eq!(
+        string_field(&response, "result").as_deref(),
+        Some("textDocument/hover")
+    );
+    client.shut_down();
+    assert_eq!(
+        client.receive(),
+        None,
+        "The session should end once the server exited"
+    );
+    drop(client);
+
+    let session = proxy.wait_for_session();
+    assert!(
+        session.contains("\"exit_reason\":\"client-closed\""),
+        "{}",
+        session
+    );
+    assert!(session.contains("\"server_exit_code\":0"), "{}", session);
+    proxy.stop();
+}
+
+fn reports_crashed_servers() {
+    let mut proxy = Proxy::start("crash", &[]);
+    let mut client = proxy.connect();
+    client.initialize();
+    client.send("{\"jsonrpc\":\"2.0\",\"method\":\"mock/crash\"}");
+    assert_eq!(
+        client.receive(),
+        None,
+        "The session should end once the server crashed"
+    );
+    drop(client);
+
+    let session = proxy.wait_for_session();
+    assert!(
+        session.contains("\"exit_reason\":\"server-crashed\""),
+        "{}",
+        session
+    );
+    let exit_code = format!("\"server_exit_code\":{}", mock_server::CRASH_EXIT_CODE);
+    assert!(session.contains(&exit_code), "{}", session);
+
+    // the proxy keeps serving after a crashed session
+    let mut client = proxy.connect();
+    client.initialize();
+    client.shut_down();
+    proxy.stop();
+}
+
+fn single_client_exits_after_its_session() {
+    let mut proxy = Proxy::start("single", &["--single"]);
+    let mut client = proxy.connect();
+    client.initialize();
+    client.shut_down();
+    drop(client);
+    let status = proxy.wait();
+    assert!(status.success(), "The proxy exited with {}", status);
+}
+
+fn limits_sessions_per_client() {
+    let mut proxy = Proxy::start("quota", &["--max-sessions-per-client", "1"]);
+    let mut first = proxy.connect();
+    first.initialize();
+
+    let mut second = proxy.connect();
+    second.send("{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{}}");
+    let shown = second.receive().expect("The refusal should be shown");
+    assert!(shown.contains("window/showMessage"), "{}", shown);
+    let response = second
+        .receive()
+        .expect("The initialize request should fail");
+    assert!(response.contains("\"error\""), "{}", response);
+    assert!(response.contains("maximum of 1 sessions"), "{}", response);
+    drop(second);
+
+    // the quota is freed once the first session ended
+    first.shut_down();
+    assert_eq!(first.receive(), None);
+    drop(first);
+    proxy.wait_for_session();
+    let mut third = proxy.connect();
+    third.initialize();
+    third.shut_down();
+    proxy.stop();
+}
+
+fn runs_native_servers() {
+    let this = std::env::current_exe().expect("Failed to locate the test binary");
+    let args = [
+        OsString::from("--server"),
+        this.into(),
+        "--server-args".into(),
+        "--port {port}".into(),
+    ];
+    let mut proxy = Proxy::start_with("native", &args);
+    let mut client = proxy.connect();
+    client.initialize();
+    client.shut_down();
+    drop(client);
+    let session = proxy.wait_for_session();
+    assert!(session.contains("\"server_exit_code\":0"), "{}", session);
+    proxy.stop();
+}
+
+fn patches_the_initialize_result() {
+    let mut proxy = Proxy::start(
+        "initialize-patch",
+        &[
+            "--parse-lsp",
+            "--patch-initialize-result",
+            "{\"capabilities\":{\"hoverProvider\":true},\"serverInfo\":{\"name\":\"patched\"}}",
+        ],
+    );
+    let mut client = proxy.connect();
+    let response = client.request("initialize");
+    assert!(
+        response.contains(
+            "\"result\":{\"capabilities\":{\"hoverProvider\":true},\"serverInfo\":{\"name\":\"patched\"}}"
+        ),
+        "{}",
+        response
+    );
+    // only the response to initialize is patched
+    let response = client.request("textDocument/hover");
+    assert_eq!(
+        string_field(&response, "result").as_deref(),
+        Some("textDocument/hover")
+    );
+    client.shut_down();
+    proxy.stop();
+}
+
+fn gates_outdated_clients() {
+    let mut proxy = Proxy::start(
+        "client-versions",
+        &[
+            "--min-client-version",
+            "Old IDE=2.0",
+            "--recommended-client-version",
+            "New IDE=3.0",
+        ],
+    );
+    let initialize = |name: &str, version: &str| {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{{\"clientInfo\":{{\"name\":\"{}\",\"version\":\"{}\"}}}}}}",
+            name, version
+        )
+    };
+
+    let mut outdated = proxy.connect();
+    outdated.send(&initialize("Old IDE", "1.9.3"));
+    let shown = outdated.receive().expect("The refusal should be shown");
+    assert!(shown.contains("window/showMessage"), "{}", shown);
+    let response = outdated
+        .receive()
+        .expect("The initialize request should fail");
+    assert!(response.contains("\"error\""), "{}", response);
+    assert!(
+        response.contains("Old IDE 1.9.3 is outdated, please update it to version 2.0 or newer"),
+        "{}",
+        response
+    );
+    drop(outdated);
+
+    let mut warned = proxy.connect();
+    warned.send(&initialize("New IDE", "2.4"));
+    let shown = warned.receive().expect("The warning should be shown");
+    assert!(shown.contains("window/showMessage"), "{}", shown);
+    assert!(shown.contains("version 3.0 or newer"), "{}", shown);
+    let response = warned
+        .receive()
+        .expect("The initialize request should be answered");
+    assert!(response.contains("\"capabilities\""), "{}", response);
+    warned.shut_down();
+    proxy.stop();
+}
+
+fn relays_content_unchanged() {
+    // every printable character up to three bytes of UTF-8 and some of four,
+    // long enough to be relayed in many reads
+    let text: String = (' '..'\u{ffff}')
+        .chain('\u{1f600}'..'\u{1f650}')
+        .filter(|c| !matches!(c, '"' | '\\'))
+        .collect();
+    let params = format!("\"{}\"", text);
+    for (name, args) in [
+        ("content-raw", &["--checksums"][..]),
+        ("content-messages", &["--checksums", "--parse-lsp"][..]),
+        // spliced past the captured start, which the checksums would rule out
+        ("content-fast-path", &["--local-fast-path"][..]),
+    ] {
+        let mut proxy = Proxy::start(name, args);
+        let mut client = proxy.connect();
+        client.initialize();
+        let response = client.request_with_params("mock/echo", &params);
+        assert!(
+            response.contains(&params),
+            "The content changed with {:?}",
+            args
+        );
+        client.shut_down();
+        drop(client);
+
+        let session = proxy.wait_for_session();
+        if !args.contains(&"--checksums") {
+            proxy.stop();
+            continue;
+        }
+        for direction in ["client_to_server", "server_to_client"] {
+            let start = session
+                .find(&format!("\"{}_crc32\":", direction))
+                .unwrap_or_else(|| panic!("No {} checksums in {}", direction, session));
+            let checksums = &session[start..];
+            assert_eq!(
+                string_field(checksums, "received"),
+                string_field(checksums, "sent"),
+                "{}",
+                session
+            );
+        }
+        proxy.stop();
+    }
+}
+
+fn observers_get_uncompressed_messages() {
+    let observer_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Failed to find a free port")
+        .port()
+        .to_string();
+    let mut proxy = Proxy::start(
+        "observe-compressed",
+        &[
+            "--parse-lsp",
+            "--compression-threshold",
+            "1000",
+            "--observer-port",
+            &observer_port,
+        ],
+    );
+    let mut client = proxy.connect();
+    let initialize = "{\"jsonrpc\":\"2.0\",\"id\":0,\"method\":\"initialize\",\"params\":{}}";
+    write!(
+        client.writer,
+        "Content-Length: {}\r\nAccept-Encoding: deflate\r\n\r\n{}",
+        initialize.len(),
+        initialize
+    )
+    .expect("Failed to send a message");
+    let response = client
+        .receive()
+        .expect("The session ended during initialize");
+    assert!(response.contains("\"capabilities\""), "{}", response);
+
+    // the first session has the id 0
+    let mut observer =
+        TcpStream::connect((Ipv4Addr::LOCALHOST, observer_port.parse::<u16>().unwrap()))
+            .expect("Failed to connect the observer");
+    observer
+        .write_all(b"0\n")
+        .expect("Failed to name the session");
+    observer
+        .set_read_timeout(Some(POLL_INTERVAL * 4))
+        .expect("Failed to set a timeout");
+    let mut observer = BufReader::new(observer);
+    // the observer is attached once it receives the responses the client receives
+    let started = Instant::now();
+    loop {
+        client.request("textDocument/hover");
+        if read_message(&mut observer).is_some() {
+            break;
+        }
+        assert!(started.elapsed() < TIMEOUT, "The observer didn't attach");
+    }
+    observer
+        .get_ref()
+        .set_read_timeout(Some(TIMEOUT))
+        .expect("Failed to set a timeout");
+
+    let params = format!("\"{}\"", "compressible ".repeat(200));
+    client.send(&format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":99,\"method\":\"mock/echo\",\"params\":{}}}",
+        params
+    ));
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        client
+            .reader
+            .read_line(&mut line)
+            .expect("Failed to read the response");
+        if line.trim().is_empty() {
+            break;
+        }
+        headers.push(line.trim().to_string());
+    }
+    assert!(
+        headers
+            .iter()
+            .any(|header| header == "Content-Encoding: deflate"),
+        "The client should receive the response compressed: {:?}",
+        headers
+    );
+    let observed = read_message(&mut observer).expect("The observer should receive the response");
+    assert!(
+        observed.contains(&params),
+        "The observer should receive the response uncompressed: {}",
+        observed
+    );
+    drop(client);
+    proxy.stop();
+}
+
+fn leases_ports_in_order() {
+    let mut ports: Vec<u16> = (0..2)
+        .map(|_| {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("Failed to bind");
+            listener
+                .local_addr()
+                .expect("Failed to get the port")
+                .port()
+        })
+        .collect();
+    ports.sort_unstable();
+    let lease_file =
+        std::env::temp_dir().join(format!("lsp-on-demand-test-{}-leases", std::process::id()));
+    let spawn_ports = format!("{},{}", ports[0], ports[1]);
+    let mut proxy = Proxy::start(
+        "lease",
+        &[
+            "--spawn",
+            &spawn_ports,
+            "--port-lease-file",
+            lease_file.to_str().expect("The temp dir is not UTF-8"),
+        ],
+    );
+    let leased = |port: u16| {
+        std::fs::read_to_string(&lease_file)
+            .expect("Failed to read the lease file")
+            .contains(&format!("\"port\":{},", port))
+    };
+
+    let mut first = proxy.connect();
+    first.initialize();
+    assert!(leased(ports[0]) && !leased(ports[1]));
+    let mut second = proxy.connect();
+    second.initialize();
+    assert!(leased(ports[1]));
+
+    // with all ports leased further clients are turned away
+    let mut third = proxy.connect();
+    assert_eq!(third.receive(), None);
+
+    first.shut_down();
+    drop(first);
+    let released = Instant::now() + TIMEOUT;
+    while leased(ports[0]) {
+        assert!(Instant::now() < released, "The port was not released");
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    second.shut_down();
+    proxy.stop();
+    let _ = std::fs::remove_file(&lease_file);
+}
+
+#[cfg(unix)]
+fn shuts_down_servers_in_place_of_clients() {
+    let mut proxy = Proxy::start("exit", &["--parse-lsp"]);
+    // a client leaving without shutting down its server
+    let mut client = proxy.connect();
+    client.initialize();
+    drop(client);
+    let session = proxy.wait_for_session();
+    assert!(
+        session.contains("\"exit_reason\":\"client-closed\""),
+        "{}",
+        session
+    );
+    let exits = std::fs::read_to_string(&proxy.exit_file).unwrap_or_default();
+    assert_eq!(exits, "exit\n", "The server should have been asked to exit");
+
+    // a client still connected when the proxy shuts down
+    let mut client = proxy.connect();
+    client.initialize();
+    let status = proxy.stop();
+    assert!(status.success(), "The proxy exited with {}", status);
+    let notice = client
+        .receive()
+        .expect("The session ended without a notice");
+    assert_eq!(
+        string_field(&notice, "method").as_deref(),
+        Some("window/showMessage")
+    );
+    assert_eq!(
+        client.receive(),
+        None,
+        "The response to the shutdown request of the proxy should not be relayed"
+    );
+    let exits = std::fs::read_to_string(&proxy.exit_file).unwrap_or_default();
+    assert_eq!(
+        exits, "exit\nexit\n",
+        "The server should have been asked to exit"
+    );
+}
+
+#[cfg(unix)]
+fn shutdown_kills_servers_of_active_sessions() {
+    let mut proxy = Proxy::start("shutdown", &[]);
+    let mut client = proxy.connect();
+    client.initialize();
+
+    let pids = std::fs::read_to_string(&proxy.pid_file).expect("The server didn't record its pid");
+    let server: libc::pid_t = pids.trim().parse().expect("Invalid pid recorded");
+    // like a real client, close the connection once the proxy closed the session
+    let closed = std::thread::spawn(move || client.receive());
+    let status = proxy.stop();
+    assert!(status.success(), "The proxy exited with {}", status);
+    assert_eq!(
+        closed.join().expect("Failed to receive"),
+        None,
+        "The session should end with the proxy"
+    );
+    // the server may linger as a zombie until it is reaped by init
+    let started = Instant::now();
+    // Safety: signal 0 only checks whether the process exists
+    while unsafe { libc::kill(server, 0) } == 0 {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "The server {} outlived the proxy",
+            server
+        );
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    let session = std::fs::read_to_string(&proxy.session_log).unwrap_or_default();
+    assert!(
+        session.contains("\"exit_reason\":\"proxy-shutdown\""),
+        "{}",
+        session
+    );
+}
+
+/// A proxy running in the background, killed when dropped
+struct Proxy {
+    child: Child,
+    port: u16,
+    session_log: PathBuf,
+    pid_file: PathBuf,
+    exit_file: PathBuf,
+    /// Kept open, so that the proxy can't fail writing to its stdout
+    _stdout: BufReader<ChildStdout>,
+}
+
+impl Proxy {
+    /// Start a proxy on a free port, spawning the mock server as its java, with the `extra_args`
+    ///
+    /// `name` tells the files of concurrently running proxies apart
+    fn start(name: &str, extra_args: &[&str]) -> Self {
+        let this = std::env::current_exe().expect("Failed to locate the test binary");
+        let mut args = vec![
+            OsString::from("--jvm"),
+            this.clone().into(),
+            "--jar".into(),
+            this.into(),
+        ];
+        args.extend(extra_args.iter().map(OsString::from));
+        Self::start_with(name, &args)
+    }
+
+    /// Start a proxy on a free port with the `args`, which have to choose the server
+    fn start_with(name: &str, args: &[OsString]) -> Self {
+        let scratch = std::env::temp_dir().join(format!(
+            "lsp-on-demand-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let session_log = scratch.with_extension("sessions");
+        let pid_file = scratch.with_extension("pids");
+        let exit_file = scratch.with_extension("exits");
+        let _ = std::fs::remove_file(&session_log);
+        let _ = std::fs::remove_file(&pid_file);
+        let _ = std::fs::remove_file(&exit_file);
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lsp_on_demand"))
+            .arg("--session-log")
+            .arg(&session_log)
+            .args(["--port", "0", "--startup-delay", "0"])
+            .args(args)
+            .env(mock_server::PID_FILE_VAR, &pid_file)
+            .env(mock_server::EXIT_FILE_VAR, &exit_file)
+            .env_remove("RUST_LOG")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the proxy");
+
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut line = String::new();
+        stdout
+            .read_line(&mut line)
+            .expect("Failed to read the port of the proxy");
+        let port = line
+            .trim()
+            .strip_prefix("LSP_PORT=")
+            .and_then(|port| port.parse().ok())
+            .unwrap_or_else(|| panic!("Expected the port of the proxy, got {:?}", line));
+        Self {
+            child,
+            port,
+            session_log,
+            pid_file,
+            exit_file,
+            _stdout: stdout,
+        }
+    }
+
+    fn connect(&self) -> Client {
+        let con = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).expect("Failed to connect");
+        con.set_read_timeout(Some(TIMEOUT))
+            .expect("Failed to set a timeout");
+        Client {
+            writer: con.try_clone().expect("Failed to clone the connection"),
+            reader: BufReader::new(con),
+            next_id: 1,
+        }
+    }
+
+    /// Wait until the proxy logged a session, returning the first one
+    fn wait_for_session(&self) -> String {
+        let started = Instant::now();
+        loop {
+            let log = std::fs::read_to_string(&self.session_log).unwrap_or_default();
+            if let Some(session) = log.lines().next() {
+                return session.to_string();
+            }
+            assert!(started.elapsed() < TIMEOUT, "No session was logged");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Ask the proxy to shut down and wait for it to exit
+    fn stop(&mut self) -> ExitStatus {
+        #[cfg(unix)]
+        {
+            // Safety: the proxy has not been waited for yet, so its pid can't have been reused
+            unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = self.child.kill();
+        }
+        self.wait()
+    }
+
+    fn wait(&mut self) -> ExitStatus {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().expect("Failed to wait for the proxy") {
+                let _ = std::fs::remove_file(&self.pid_file);
+                return status;
+            }
+            assert!(started.elapsed() < TIMEOUT, "The proxy didn't exit");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.session_log);
+        let _ = std::fs::remove_file(&self.pid_file);
+        let _ = std::fs::remove_file(&self.exit_file);
+    }
+}
+
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_id: u64,
+}
+
+impl Client {
+    fn send(&mut self, content: &str) {
+        write_message(&mut self.writer, content).expect("Failed to send a message");
+    }
+
+    /// The content of the next message, or nothing once the session ended
+    fn receive(&mut self) -> Option<String> {
+        read_message(&mut self.reader)
+    }
+
+    fn request(&mut self, method: &str) -> String {
+        self.request_with_params(method, "{}")
+    }
+
+    fn request_with_params(&mut self, method: &str, params: &str) -> String {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"{}\",\"params\":{}}}",
+            id, method, params
+        ));
+        let response = self
+            .receive()
+            .unwrap_or_else(|| panic!("The session ended before the {} response", method));
+        assert_eq!(number_field(&response, "id"), Some(id), "{}", response);
+        response
+    }
+
+    fn initialize(&mut self) {
+        let response = self.request("initialize");
+        assert!(response.contains("\"capabilities\""), "{}", response);
+    }
+
+    /// Shut the server down like a well-behaved client
+    fn shut_down(&mut self) {
+        self.request("shutdown");
+        self.send("{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}");
+    }
+}
@@ -0,0 +1,137 @@
+//! A minimal language server standing in for the KIELER server, so that the proxy can be tested without Java
+//!
+//! It is started by the proxy like the real server, with `-Dport=<port>` among its arguments,
+//! or with `--port <port>` as a native server, and serves a single connection on that port:
+//! - `initialize` is answered with empty capabilities, `shutdown` with `null`
+//! - the `exit` notification ends the server regularly, the `mock/crash` notification with a failure
+//! - `mock/echo` is answered with its params as the result, which have to be last in the request
+//! - any other request is answered with its method as the result
+//!
+//! If `MOCK_LSP_PID_FILE` is set the server appends its pid to that file,
+//! so that tests can check that the proxy doesn't leave it running.
+//! If `MOCK_LSP_EXIT_FILE` is set the server appends how it ended regularly to that file,
+//! `exit` for the notification and `closed` if the connection closed without it.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+pub const PID_FILE_VAR: &str = "MOCK_LSP_PID_FILE";
+pub const EXIT_FILE_VAR: &str = "MOCK_LSP_EXIT_FILE";
+/// The exit code of a server ended by `mock/crash`
+pub const CRASH_EXIT_CODE: i32 = 3;
+
+/// The port the proxy asked the server to listen on, if the arguments are those of a server
+///
+/// That is `-Dport=<port>` when started like the java server, or `--port <port>` like a native one
+pub fn requested_port(args: &[String]) -> Option<u16> {
+    let java = args.iter().find_map(|arg| arg.strip_prefix("-Dport="));
+    let native = args
+        .windows(2)
+        .find(|pair| pair[0] == "--port")
+        .map(|pair| pair[1].as_str());
+    java.or(native).and_then(|port| port.parse().ok())
+}
+
+/// Serve a single connection on `port`, then exit
+pub fn run(port: u16) -> ! {
+    if let Some(pid_file) = std::env::var_os(PID_FILE_VAR) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(pid_file)
+            .expect("Failed to open the pid file");
+        writeln!(file, "{}", std::process::id()).expect("Failed to write the pid file");
+    }
+
+    let listener =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, port)).expect("Failed to bind the server port");
+    let (con, _) = listener.accept().expect("Failed to accept the proxy");
+    let mut writer = con.try_clone().expect("Failed to clone the connection");
+    let mut reader = BufReader::new(con);
+
+    while let Some(content) = read_message(&mut reader) {
+        let method = string_field(&content, "method");
+        let id = number_field(&content, "id")
+            .map(|id| id.to_string())
+            .or_else(|| string_field(&content, "id").map(|id| format!("\"{}\"", id)));
+        match (method.as_deref(), id) {
+            (Some("exit"), None) => exit("exit"),
+            (Some("mock/crash"), None) => std::process::exit(CRASH_EXIT_CODE),
+            (Some("initialize"), Some(id)) => respond(&mut writer, &id, "{\"capabilities\":{}}"),
+            (Some("shutdown"), Some(id)) => respond(&mut writer, &id, "null"),
+            (Some("mock/echo"), Some(id)) => {
+                let params = content
+                    .find("\"params\":")
+                    .map_or("null", |start| &content[start + 9..content.len() - 1]);
+                respond(&mut writer, &id, params)
+            }
+            (Some(method), Some(id)) => respond(&mut writer, &id, &format!("\"{}\"", method)),
+            _ => {}
+        }
+    }
+    exit("closed")
+}
+
+/// Exit regularly, recording `how` in the exit file if there is one
+fn exit(how: &str) -> ! {
+    if let Some(exit_file) = std::env::var_os(EXIT_FILE_VAR) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(exit_file)
+            .expect("Failed to open the exit file");
+        writeln!(file, "{}", how).expect("Failed to write the exit file");
+    }
+    std::process::exit(0)
+}
+
+/// Read the content of the next message, or nothing once the connection closed
+pub fn read_message(reader: &mut impl BufRead) -> Option<String> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse().ok();
+        }
+    }
+    let mut content = vec![0; content_length?];
+    reader.read_exact(&mut content).ok()?;
+    String::from_utf8(content).ok()
+}
+
+/// Write a whole message at once, so that its parts aren't held back waiting for acknowledgements
+pub fn write_message(writer: &mut impl Write, content: &str) -> std::io::Result<()> {
+    let message = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
+    writer.write_all(message.as_bytes())
+}
+
+fn respond(writer: &mut TcpStream, id: &str, result: &str) {
+    let response = format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
+        id, result
+    );
+    write_message(writer, &response).expect("Failed to respond");
+}
+
+/// The value of the string member `name`, good enough for the messages sent by the tests
+pub fn string_field(content: &str, name: &str) -> Option<String> {
+    let start = content.find(&format!("\"{}\":\"", name))? + name.len() + 4;
+    let len = content[start..].find('"')?;
+    Some(content[start..start + len].to_string())
+}
+
+/// The value of the number member `name`, good enough for the messages sent by the tests
+pub fn number_field(content: &str, name: &str) -> Option<u64> {
+    let start = content.find(&format!("\"{}\":", name))? + name.len() + 3;
+    let digits = content[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(content.len() - start);
+    content[start..start + digits].parse().ok()
+}